@@ -0,0 +1,89 @@
+//! Benchmark of the engine: searches a fixed suite of positions to a
+//! fixed depth in a single thread. The total number of nodes doesn't
+//! depend on the machine, so it's a fingerprint of the search and the
+//! move generator: it changes only when their behaviour does.
+
+use std::time::Instant;
+
+use failure::{format_err, Fallible};
+
+use rokumon_core::ai::{Evaluator, TT_BITS};
+use rokumon_core::search::{self, SearchLimits, SearchStats, TranspositionTable};
+
+use crate::record::GameRecord;
+
+/// Depth of the search used unless given with `--ai-depth`.
+pub const DEFAULT_DEPTH: u32 = 4;
+
+/// Positions of the benchmark, as game records.
+const SUITE: &[&str] = &[
+    "layout: bricks7\ncards: JJJJGGG\nrules: fight surprise\n",
+    "layout: bricks7\ncards: JGJJGGJ\nrules: fight surprise\n\
+     1. place r2 at r1c1\n2. place b5 at r1c2\n",
+    "layout: bricks7\ncards: GGGJJJJ\nrules: fight\n\
+     1. place r2 at r1c1\n2. place b5 at r1c2\n3. move r2 from r1c1 to r2c3\n\
+     4. place w1 at r1c1\n5. place r4 at r2c1\n",
+    "layout: hex7\ncards: JJGGJGJ\nrules: fight surprise\n\
+     1. place r2 at r3c1\n2. place b5 at r2c2\n3. move r2 from r3c1 to r1c2\n",
+    "layout: rectangle6\ncards: GJGJGJ\nrules: none\n\
+     1. place r2 at r2c2\n2. place b1 at r1c1\n3. place r2 at r2c3\n",
+];
+
+/// Runs the benchmark, printing the results for every position and in
+/// total. Returns the counters summed over all the positions.
+pub fn run(depth: u32) -> Fallible<SearchStats> {
+    let evaluator = Evaluator::default();
+    let limits = SearchLimits {
+        depth: Some(depth),
+        ..Default::default()
+    };
+    let mut total = SearchStats::default();
+    let start = Instant::now();
+
+    for (ix, record) in SUITE.iter().enumerate() {
+        let record: GameRecord = record.parse()?;
+        let mut game = record.start()?;
+        for m in record.game_moves()? {
+            game.apply_move(&m)?;
+        }
+
+        // Every position starts with an empty table, so that the
+        // results don't depend on the order of the positions.
+        let tt = TranspositionTable::new(TT_BITS);
+        let now = Instant::now();
+        let result = search::search(&game, 1, limits.clone(), &evaluator, &tt, None)
+            .ok_or_else(|| format_err!("No moves in benchmark position {}", ix + 1))?;
+        println!(
+            "position {}: {:9} nodes, time: {:>8} best move: {}",
+            ix + 1,
+            result.stats.nodes,
+            format!("{:.0?},", now.elapsed()),
+            game.describe_move(result.best_move())
+        );
+        total += result.stats;
+    }
+
+    let elapsed = start.elapsed();
+    println!(
+        "total: {} nodes, time: {:.0?}, speed: {:.0} nodes/s",
+        total.nodes,
+        elapsed,
+        total.nodes as f64 / elapsed.as_secs_f64()
+    );
+    println!("{}", total);
+    Ok(total)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bench() -> Fallible<()> {
+        // The node count is the same every time.
+        let stats = run(2)?;
+        assert!(stats.nodes > 0);
+        assert_eq!(run(2)?.nodes, stats.nodes);
+        Ok(())
+    }
+}
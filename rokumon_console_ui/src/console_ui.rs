@@ -1,19 +1,58 @@
+use rustyline::completion::Completer;
 use rustyline::error::ReadlineError;
-use rustyline::Editor;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{Context, Editor, Helper};
+use std::fs;
 use std::process::exit;
 
-use crate::parsers;
+use failure::{bail, Fallible};
 
-use rokumon_core::ai::AlphaBetaAI;
+use crate::parsers::{self, ParseError};
+use crate::record::GameRecord;
+use crate::render::{last_move_recap, Renderer};
+
+use rokumon_core::ai::{pp_evaluation, AlphaBetaAI, EvalBreakdown, EvalWeights};
+use rokumon_core::card::{DiceColor, Die};
+use rokumon_core::clock::Clock;
 use rokumon_core::coord::Coord;
-use rokumon_core::game::{Game, GameMove};
-use rokumon_core::play::Strategy;
+use rokumon_core::game::{Game, GameMove, HistoryEntry};
+use rokumon_core::play::{Decision, Strategy};
 
-pub struct Human;
+pub struct Human {
+    /// Plies taken back by `undo`: two against an AI, so that it's
+    /// our turn again.
+    undo_plies: usize,
+    renderer: Renderer,
+}
+
+impl Human {
+    pub fn new(against_ai: bool, renderer: Renderer) -> Self {
+        Human {
+            undo_plies: if against_ai { 2 } else { 1 },
+            renderer,
+        }
+    }
+}
 
 impl Strategy for Human {
+    fn name(&self) -> String {
+        "Human".to_string()
+    }
+
     fn get_move(&mut self, game: &Game) -> GameMove<Coord> {
-        let mut rl = Editor::<()>::new();
+        loop {
+            match self.decide(game, None) {
+                Decision::Play(m) => break m,
+                _ => println!("[ERR] Moves can't be taken back here."),
+            }
+        }
+    }
+
+    fn decide(&mut self, game: &Game, _clock: Option<&Clock>) -> Decision {
+        let mut rl = Editor::new();
+        rl.set_helper(Some(InputHelper::new(game)));
         if rl.load_history("history.txt").is_err() {
             println!("No previous history.");
         }
@@ -41,15 +80,82 @@ impl Strategy for Human {
                         let mut ai = AlphaBetaAI::to_completion(game.player1_moves);
                         let m = ai.get_move(game);
                         println!("AI recommends: {}", game.userify_move(&m));
+                    } else if cmd.starts_with("analy") {
+                        let parts: Vec<_> = cmd.split_whitespace().collect();
+                        let dur = if parts.len() > 1 { parts[1].parse().unwrap() } else { 10 };
+                        let mut ai = AlphaBetaAI::with_duration(game.player1_moves, dur);
+                        let ply = game.ply_to_be_played();
+                        for (ix, (m, score, pv)) in ai.analyze(game).iter().enumerate() {
+                            let pv: Vec<_> = pv.iter().skip(1).map(|m| game.userify_move(m).to_string()).collect();
+                            println!(
+                                "{:>2}. {:<28} {:>10}   {}",
+                                ix + 1,
+                                game.userify_move(m).to_string(),
+                                pp_evaluation(*score, ply),
+                                pv.join(", ")
+                            );
+                        }
+                    } else if cmd.starts_with("solve") {
+                        let parts: Vec<_> = cmd.split_whitespace().collect();
+                        let plies = if parts.len() > 1 { parts[1].parse().unwrap() } else { 5 };
+                        match game.find_forced_win(plies) {
+                            Some(line) => {
+                                println!("Forced win in {} plies:", line.len());
+                                for (ix, m) in line.iter().enumerate() {
+                                    println!("{}: {}", ix + 1, game.userify_move(m));
+                                }
+                            }
+                            None => println!("No forced win within {} plies", plies),
+                        }
+                    } else if cmd.starts_with("undo") || cmd.starts_with("redo") {
+                        let parts: Vec<_> = cmd.split_whitespace().collect();
+                        let plies = if parts.len() > 1 {
+                            parts[1].parse().unwrap()
+                        } else {
+                            self.undo_plies
+                        };
+                        rl.save_history("history.txt").unwrap();
+                        if cmd.starts_with("undo") {
+                            break Decision::TakeBack(plies);
+                        } else {
+                            break Decision::Replay(plies);
+                        }
+                    } else if let Some(path) = cmd.strip_prefix("save ") {
+                        match fs::write(path.trim(), GameRecord::of(game).to_string()) {
+                            Ok(()) => println!("Game saved to {}", path.trim()),
+                            Err(err) => println!("[ERR] Can't save game: {}", err),
+                        }
+                    } else if let Some(path) = cmd.strip_prefix("load ") {
+                        match load(path.trim()) {
+                            Ok(decision) => {
+                                rl.save_history("history.txt").unwrap();
+                                break decision;
+                            }
+                            Err(err) => println!("[ERR] Can't load game: {}", err),
+                        }
+                    } else if cmd.starts_with("eval") {
+                        print_eval_breakdown(game);
+                    } else if let Some(place) = cmd.strip_prefix("fightinfo") {
+                        if let Err(err) = print_fight_info(game, place.trim()) {
+                            println!("[ERR] {}", err);
+                        }
+                    } else if let Some(place) = cmd.strip_prefix("targets") {
+                        if let Err(err) = print_surprise_targets(game, place.trim()) {
+                            println!("[ERR] {}", err);
+                        }
+                    } else if cmd.starts_with("last") {
+                        print_last_move(game);
+                    } else if cmd.starts_with("legal") {
+                        print_legal_moves(game);
                     } else if cmd.starts_with("pos") {
-                        println!("{}", game);
+                        println!("{}", self.renderer.game(game));
                     } else if cmd.starts_with("moves") {
                         println!("Game moves:");
                         if game.history.is_empty() {
                             println!("None so far");
                         } else {
-                            for (ix, m) in game.history.iter().enumerate() {
-                                println!("{}: {}", ix + 1, game.userify_move(&m));
+                            for (ix, entry) in game.history.iter().enumerate() {
+                                println!("{}: {}", ix + 1, pp_history_entry(game, entry));
                             }
                         }
                     } else if cmd.starts_with("help") || cmd.starts_with("?") {
@@ -57,11 +163,22 @@ impl Strategy for Human {
                         println!("  place w1 at r2c2");
                         println!("  move b3 from r1c1 to r1c2");
                         println!("  fight at r2c3");
+                        println!("  fight at <1, 0, -1>  (cards can be given in internal coordinates too)");
+                        println!("  surprise from r1c1 to rightof r2c4  (or aboveleft, belowright, ...)");
+                        println!("Press Tab to complete commands and moves.");
                         println!();
 
                         println!("Other useful commands for inspecting current game");
                         println!("pos:         display current game position");
                         println!("moves:       display moves made so far");
+                        println!("last:        recap the last move and the cards it has changed");
+                        println!("legal:       display all the moves allowed now");
+                        println!("fightinfo c: show who would win a fight at card `c` (e.g. r2c1)");
+                        println!("targets c:   show where card `c` can go with a surprise move (e.g. r1c1)");
+                        println!("undo n:      take back `n` moves (your last move by default)");
+                        println!("redo n:      replay `n` moves taken back");
+                        println!("save file:   save the game to `file`");
+                        println!("load file:   continue the game saved to `file`");
                         println!();
 
                         println!("AI related commands allow to get analysis from AI:");
@@ -69,17 +186,23 @@ impl Strategy for Human {
                         println!("duration s:  get a hint by running AI for `s` seconds");
                         println!("depth d:     get a hint by running AI down to `d` moves depth");
                         println!("complete:    run AI until it completes its analysis fully");
+                        println!("analyse s:   score every move by running AI for `s` seconds in total");
+                        println!("eval:        explain the static evaluation of the current position");
+                        println!("solve n:     look for a forced win within `n` plies");
                     } else {
                         // Try to parse as a move.
                         match parsers::parse_move(input.trim_end()) {
                             Ok(mov) => match game.convert_move_coords(&mov) {
                                 Ok(m) => {
                                     rl.save_history("history.txt").unwrap();
-                                    break m;
+                                    break Decision::Play(m);
                                 }
                                 Err(_) => println!("[ERR] invalid user coordinate."),
                             },
-                            Err(msg) => println!("[ERR] {}. Use `help` to see how to enter moves", msg),
+                            Err(err) => {
+                                println!("{}", caret(&err));
+                                println!("[ERR] {}. Use `help` to see how to enter moves", err);
+                            }
                         }
                     }
                     rl.save_history("history.txt").unwrap();
@@ -101,3 +224,329 @@ impl Strategy for Human {
         }
     }
 }
+
+/// Commands of the prompt (besides the moves).
+const COMMANDS: &[&str] = &[
+    "analyse",
+    "complete",
+    "depth",
+    "duration",
+    "eval",
+    "fightinfo",
+    "help",
+    "hint",
+    "last",
+    "legal",
+    "load",
+    "moves",
+    "pos",
+    "redo",
+    "save",
+    "solve",
+    "targets",
+    "undo",
+];
+
+/// Whether the line is handled as a command rather than a move (the
+/// same checks as in `Human::decide`).
+fn is_command(line: &str) -> bool {
+    let prefixes = [
+        "hint",
+        "dur",
+        "dep",
+        "comp",
+        "analy",
+        "solve",
+        "undo",
+        "redo",
+        "save ",
+        "load ",
+        "eval",
+        "fightinfo",
+        "targets",
+        "last",
+        "legal",
+        "pos",
+        "moves",
+        "help",
+        "?",
+    ];
+    prefixes.iter().any(|p| line.starts_with(p))
+}
+
+/// Completes commands and legal moves word by word and doesn't accept
+/// lines which are neither commands nor legal moves.
+struct InputHelper {
+    game: Game,
+    /// Legal moves as the user types them.
+    moves: Vec<String>,
+    coords: Vec<String>,
+}
+
+impl InputHelper {
+    fn new(game: &Game) -> Self {
+        InputHelper {
+            game: game.clone(),
+            moves: game
+                .generate_moves()
+                .iter()
+                .map(|m| game.describe_move(m).to_string())
+                .collect(),
+            coords: game
+                .board
+                .coords_iter()
+                .map(|c| game.board.userify_coord(c).to_string())
+                .collect(),
+        }
+    }
+
+    /// Start of the word being completed and the ways to complete it.
+    fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let start = line[..pos].rfind(char::is_whitespace).map_or(0, |ix| ix + 1);
+        let prefix = line[start..pos].to_lowercase();
+        let before: Vec<_> = line[..start].split_whitespace().map(str::to_lowercase).collect();
+
+        let mut words: Vec<String> = match before.first().map(String::as_str) {
+            None => COMMANDS.iter().map(|c| c.to_string()).collect(),
+            Some("fightinfo") | Some("targets") if before.len() == 1 => self.coords.clone(),
+            _ => vec![],
+        };
+        for m in &self.moves {
+            let move_words: Vec<_> = m.split_whitespace().collect();
+            if move_words.len() > before.len() && move_words[..before.len()] == before[..] {
+                words.push(move_words[before.len()].to_string());
+            }
+        }
+        words.retain(|w| w.starts_with(&prefix));
+        words.sort();
+        words.dedup();
+        (start, words)
+    }
+
+    /// Why the line can't be accepted, if it can't.
+    fn check(&self, line: &str) -> Option<String> {
+        if line.trim().is_empty() || is_command(line) {
+            return None;
+        }
+        let m = match parsers::parse_move(line.trim_end()) {
+            Ok(m) => m,
+            Err(err) => return Some(format!("{}\n[ERR] {}. Use Tab to complete moves", caret(&err), err)),
+        };
+        let checked = self
+            .game
+            .convert_move_coords(&m)
+            .and_then(|m| self.game.validate_move(&m));
+        checked
+            .err()
+            .map(|err| format!("[ERR] {}. Use Tab to complete moves", err))
+    }
+}
+
+impl Completer for InputHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(line, pos))
+    }
+}
+
+impl Validator for InputHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        Ok(match self.check(ctx.input()) {
+            Some(err) => ValidationResult::Invalid(Some(format!("\n{}", err))),
+            None => ValidationResult::Valid(None),
+        })
+    }
+}
+
+impl Hinter for InputHelper {}
+impl Highlighter for InputHelper {}
+impl Helper for InputHelper {}
+
+/// Points at the wrong part of a move typed after the "> " prompt.
+fn caret(err: &ParseError) -> String {
+    format!("  {}", err.caret())
+}
+
+/// Asks a yes/no question, no is the default.
+pub fn confirm(question: &str) -> bool {
+    let mut rl = Editor::<()>::new();
+    match rl.readline(&format!("{} [y/N] ", question)) {
+        Ok(answer) => ["y", "yes"].contains(&answer.trim().to_lowercase().as_str()),
+        Err(_) => false,
+    }
+}
+
+fn load(path: &str) -> Fallible<Decision> {
+    let record: GameRecord = fs::read_to_string(path)?.parse()?;
+    Ok(Decision::Load {
+        start: Box::new(record.start()?),
+        moves: record.game_moves()?,
+    })
+}
+
+/// Move with its fight result and the time spent on it, if known.
+fn pp_history_entry(game: &Game, entry: &HistoryEntry) -> String {
+    let mut s = game.userify_move(&entry.game_move).to_string();
+    if let Some(fight) = &entry.fight_result {
+        s.push_str(&format!(" ({} lost)", fight.losing_die));
+    }
+    if let Some(elapsed) = entry.elapsed {
+        s.push_str(&format!(" [{:.1}s]", elapsed.as_secs_f64()));
+    }
+    s
+}
+
+fn print_last_move(game: &Game) {
+    let m = match game.last_move() {
+        Some(m) => m,
+        None => {
+            println!("No moves so far");
+            return;
+        }
+    };
+    println!("{}", last_move_recap(game).unwrap());
+    for (name, coord) in &[("source", m.source()), ("target", m.target())] {
+        if let Some(card) = coord.and_then(|c| game.board.card_at(&c)) {
+            let user_coord = game.board.userify_coord(&coord.unwrap());
+            println!("  {} {}: {}", name, user_coord, card);
+        }
+    }
+}
+
+fn print_legal_moves(game: &Game) {
+    let kind = |m: &GameMove<Coord>| match m {
+        GameMove::Place(..) => "Place",
+        GameMove::Move(..) => "Move",
+        GameMove::Fight(..) => "Fight",
+        GameMove::Surprise(..) => "Surprise",
+        GameMove::Submit => "Submit",
+    };
+
+    let moves = game.generate_moves();
+    if moves.is_empty() {
+        println!("No legal moves");
+    }
+    for name in &["Place", "Move", "Fight", "Surprise"] {
+        let group: Vec<_> = moves.iter().filter(|m| kind(m) == *name).collect();
+        if !group.is_empty() {
+            println!("{} ({}):", name, group.len());
+            for m in group {
+                println!("  {}", game.describe_move(m));
+            }
+        }
+    }
+}
+
+fn print_eval_breakdown(game: &Game) {
+    let breakdown = EvalBreakdown::of(game);
+    let weights = EvalWeights::default();
+    println!("Static evaluation (positive is good for the first player):");
+    for (name, value) in breakdown.terms.named().iter() {
+        let weight = weights.get(name).unwrap();
+        println!("  {:<10} {:>4} x {:>3} = {:>5}", name, value, weight, value * weight);
+    }
+    println!("  {:<10} {:>18}", "total", breakdown.terms.score(&weights));
+
+    println!("Cards (triples, covered):");
+    for (coord, triples, covered) in &breakdown.cards {
+        println!("  {}: {:>3} {:>3}", game.board.userify_coord(coord), triples, covered);
+    }
+}
+
+fn print_fight_info(game: &Game, place: &str) -> Fallible<()> {
+    let user_coord = parsers::parse_user_coord(place)?;
+    let coord = game.board.convert_coordinates(&user_coord)?;
+    let dice = match game.board.card_at(&coord) {
+        Some(card) => &card.dice,
+        None => bail!("No card at {}", user_coord),
+    };
+    if dice.len() != 2 {
+        bail!(
+            "Fights need two dice on a card, there are {} at {}",
+            dice.len(),
+            user_coord
+        );
+    }
+
+    let (top, bottom) = (dice[1], dice[0]);
+    let highest_red = game.rules().die_values(DiceColor::Red).max;
+    let (winner, loser, _) = Die::compare_dice_up_to(top, bottom, highest_red);
+    println!("Fight at {}: {} (top) vs {} (bottom)", user_coord, top, bottom);
+    println!("{} would win, {} would go back to the stock", winner, loser);
+    if let Err(err) = game.validate_move(&GameMove::Fight(coord)) {
+        println!("But the fight is not allowed now: {}", err);
+    }
+    Ok(())
+}
+
+/// Lists the valid destinations of a surprise move for the given card.
+fn print_surprise_targets(game: &Game, place: &str) -> Fallible<()> {
+    let user_coord = parsers::parse_user_coord(place)?;
+    let from = game.board.convert_coordinates(&user_coord)?;
+    let targets: Vec<_> = game
+        .generate_moves()
+        .into_iter()
+        .filter_map(|m| match m {
+            GameMove::Surprise(f, to) if f == from => Some(to),
+            _ => None,
+        })
+        .collect();
+    if !game.rules().surprise_enabled() {
+        bail!("Surprise moves are disabled in the rules");
+    }
+    if targets.is_empty() {
+        bail!("No surprise moves for {} now", user_coord);
+    }
+    println!("{} can go to:", user_coord);
+    for to in targets {
+        println!("  {} ({})", game.board.describe_position(&to, Some(&from)), to);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rokumon_core::board::Layout;
+    use rokumon_core::card::Deck;
+    use rokumon_core::coord::Direction;
+    use rokumon_core::game::Rules;
+
+    #[test]
+    fn test_input_helper() -> Fallible<()> {
+        let game = Game::new(Layout::Bricks7, Deck::ordered("JGJGJGG")?, Rules::default());
+        let helper = InputHelper::new(&game);
+
+        let (start, words) = helper.candidates("pl", 2);
+        assert_eq!((start, words), (0, vec![String::from("place")]));
+        let (_, words) = helper.candidates("", 0);
+        assert!(words.contains(&String::from("legal")));
+        assert!(!words.contains(&String::from("move")));
+
+        // Dice of the stock and then the cards to place them on.
+        let (start, words) = helper.candidates("place ", 6);
+        assert_eq!(start, 6);
+        assert!(!words.is_empty() && words.iter().all(|w| w.starts_with('r')));
+        let line = format!("place {} at ", words[0]);
+        let (_, coords) = helper.candidates(&line, line.len());
+        assert_eq!(coords.len(), 7);
+        assert_eq!(helper.candidates("fightinfo r2", 12).1.len(), 4);
+
+        assert_eq!(helper.check("legal"), None);
+        assert_eq!(helper.check(&format!("{}{}", line, coords[0])), None);
+        assert!(helper.check("place r7 at r1c1").is_some());
+        assert!(helper.check("fight at r1c1").is_some());
+
+        // Surprise destinations are offered next to the cards.
+        let mut game = game;
+        for _ in 0..2 {
+            let m = game.generate_moves()[0].clone();
+            game.apply_move(&m)?;
+        }
+        let helper = InputHelper::new(&game);
+        let (_, words) = helper.candidates("surprise from r1c1 to ", 22);
+        assert!(!words.is_empty() && words.iter().all(|w| w.parse::<Direction>().is_ok()));
+        Ok(())
+    }
+}
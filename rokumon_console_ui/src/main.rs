@@ -1,29 +1,58 @@
+mod bench;
 mod console_ui;
 mod parsers;
 mod perft;
-
-use std::fmt::{self, Display, Formatter};
+mod record;
+mod render;
+#[cfg(feature = "rpc")]
+mod rpc;
+#[cfg(feature = "tui")]
+mod tui;
+
+use std::fmt::{self, Display, Formatter, Write as _};
+use std::fs;
 use std::str::FromStr;
 use std::time::Instant;
 
-use failure::{bail, Fallible};
+use failure::{bail, ensure, format_err, Fallible};
 use structopt::StructOpt;
 
-use rokumon_core::ai::AlphaBetaAI;
+use rokumon_core::ai::{pp_evaluation, AlphaBetaAI, EvalWeights, Evaluator, GreedyAI, HeuristicAI};
 use rokumon_core::board::Layout;
 use rokumon_core::card::Deck;
-use rokumon_core::game::{Game, Rules};
-use rokumon_core::play::{self, RandomAI};
+use rokumon_core::clock::TimeControl;
+use rokumon_core::coord::{EitherCoord, Position};
+use rokumon_core::game::{Game, GameMove, Handicap, Rules};
+use rokumon_core::match_runner::{self, MatchConfig, MatchReport, PrintingMatchObserver};
+use rokumon_core::play::{
+    self, Adjudication, GameObserver, GameReport, PlaySettings, PrintingObserver, RandomAI, Strategy,
+};
+#[cfg(feature = "plugins")]
+use rokumon_core::plugin::WasmStrategy;
+use rokumon_core::pns;
+#[cfg(feature = "script")]
+use rokumon_core::script::ScriptedAI;
+use rokumon_core::tune::{self, TuneConfig};
 
 use console_ui::Human;
-use perft::{parallel_perft, perft};
+use perft::{hash_perft, parallel_perft, perft};
+use record::GameRecord;
+use render::{ColorMode, FinalPositionPrinter, Renderer};
 
 #[derive(Debug)]
 enum Mode {
     Perft,
     ParallelPerft,
+    HashPerft,
+    PerftCheck,
+    Bench,
     Play,
     Match,
+    Tune,
+    Solve,
+    Review,
+    Rpc,
+    Verify,
 }
 
 impl FromStr for Mode {
@@ -33,49 +62,225 @@ impl FromStr for Mode {
         match s.to_lowercase().as_str() {
             "perft" => Ok(Perft),
             "par_perft" => Ok(ParallelPerft),
+            "hperft" => Ok(HashPerft),
+            "perft-check" => Ok(PerftCheck),
+            "bench" => Ok(Bench),
             "play" => Ok(Play),
             "match" => Ok(Match),
+            "tune" => Ok(Tune),
+            "solve" => Ok(Solve),
+            "review" => Ok(Review),
+            "rpc" => Ok(Rpc),
+            "verify" => Ok(Verify),
             _ => bail!("Can't parse play mode: {}", s),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-enum Opponents {
-    HumanHuman,
-    HumanAI,
-    AIHuman,
-    AIAI,
-    RandomRandom,
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum EvalKind {
+    Terms,
+    NeuralNet,
 }
 
-impl FromStr for Opponents {
+impl FromStr for EvalKind {
+    type Err = failure::Error;
+    fn from_str(s: &str) -> Fallible<Self> {
+        match s.to_lowercase().as_str() {
+            "terms" => Ok(EvalKind::Terms),
+            "nn" => Ok(EvalKind::NeuralNet),
+            _ => bail!("Can't parse evaluation kind (expected terms or nn): {}", s),
+        }
+    }
+}
+
+/// Player making the first move (`--first-player`).
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum FirstPlayer {
+    One,
+    Two,
+    /// Chosen for every game by its seed (if any).
+    Random,
+}
+
+impl FromStr for FirstPlayer {
     type Err = failure::Error;
     fn from_str(s: &str) -> Fallible<Self> {
-        use Opponents::*;
         match s.to_lowercase().as_str() {
-            "humanhuman" => Ok(HumanHuman),
-            "humanai" => Ok(HumanAI),
-            "aihuman" => Ok(AIHuman),
-            "aiai" => Ok(AIAI),
-            "rr" | "randomrandom" => Ok(RandomRandom),
-            _ => bail!("Can't parse opponents specification: {}", s),
+            "1" => Ok(FirstPlayer::One),
+            "2" => Ok(FirstPlayer::Two),
+            "random" => Ok(FirstPlayer::Random),
+            _ => bail!("Can't parse first player (expected 1, 2 or random): {}", s),
+        }
+    }
+}
+
+/// Where a player is going to play.
+#[derive(Debug, Clone, Copy)]
+struct Seat {
+    /// Whether the player plays as the first player (with red dice),
+    /// who doesn't have to move first (see `--first-player`).
+    first: bool,
+    /// Whether it's the second of two players of the same kind (the
+    /// second AI uses the `--second-ai-*` options).
+    second: bool,
+    /// Whether the opponent is a human.
+    against_human: bool,
+}
+
+/// Kind of player which can be given in `--opponents`.
+struct PlayerKind {
+    name: &'static str,
+    human: bool,
+    create: fn(&Opt, Seat) -> Box<dyn Strategy>,
+}
+
+impl fmt::Debug for PlayerKind {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+const PLAYERS: &[PlayerKind] = &[
+    PlayerKind {
+        name: "human",
+        human: true,
+        create: |opt, seat| human(opt, !seat.against_human),
+    },
+    PlayerKind {
+        name: "ai",
+        human: false,
+        create: |opt, seat| Box::new(mk_bot(seat.first, seat.second, opt)),
+    },
+    PlayerKind {
+        name: "greedy",
+        human: false,
+        create: |opt, seat| Box::new(GreedyAI::new(evaluator(opt, seat.second))),
+    },
+    PlayerKind {
+        name: "heuristic",
+        human: false,
+        create: |_, _| Box::new(HeuristicAI),
+    },
+    PlayerKind {
+        name: "random",
+        human: false,
+        create: |_, _| Box::new(RandomAI),
+    },
+    #[cfg(feature = "script")]
+    PlayerKind {
+        name: "script",
+        human: false,
+        // The script is checked in `main`.
+        create: |opt, _| Box::new(ScriptedAI::from_file(opt.script.as_ref().unwrap()).unwrap()),
+    },
+    #[cfg(feature = "plugins")]
+    PlayerKind {
+        name: "plugin",
+        human: false,
+        // The module is checked in `main`.
+        create: |opt, _| Box::new(WasmStrategy::from_file(opt.plugin.as_ref().unwrap()).unwrap()),
+    },
+];
+
+/// The two players, like `HumanAI` or `ai:random` (any two of the
+/// `PLAYERS` names, optionally separated by a colon).
+#[derive(Debug)]
+struct Opponents([&'static PlayerKind; 2]);
+
+impl Opponents {
+    fn with_human(&self) -> bool {
+        self.0.iter().any(|p| p.human)
+    }
+
+    /// Creates the `ix`-th opponent (0 or 1), given whether it plays
+    /// first.
+    fn create(&self, opt: &Opt, ix: usize, first: bool) -> Box<dyn Strategy> {
+        let (player, other) = (self.0[ix], self.0[1 - ix]);
+        let seat = Seat {
+            first,
+            second: ix == 1 && player.name == other.name,
+            against_human: other.human,
+        };
+        (player.create)(opt, seat)
+    }
+}
+
+impl FromStr for Opponents {
+    type Err = failure::Error;
+    fn from_str(s: &str) -> Fallible<Self> {
+        let spec = match s.to_lowercase().as_str() {
+            "rr" => "randomrandom".to_string(),
+            spec => spec.to_string(),
+        };
+        for first in PLAYERS {
+            if let Some(rest) = spec.strip_prefix(first.name) {
+                let rest = rest.strip_prefix(':').unwrap_or(rest);
+                if let Some(second) = PLAYERS.iter().find(|p| p.name == rest) {
+                    return Ok(Opponents([first, second]));
+                }
+            }
         }
+        let names: Vec<_> = PLAYERS.iter().map(|p| p.name).collect();
+        bail!(
+            "Can't parse opponents specification (expected two of {}): {}",
+            names.join(", "),
+            s
+        )
     }
 }
 
 #[derive(Debug, StructOpt)]
 struct Opt {
     /// Run mode (use `play` to play the game). perft is used to test performance of the move generator.
-    #[structopt(short, long, default_value = "play", help = "play | perft | par_perft")]
+    #[structopt(
+        short,
+        long,
+        default_value = "play",
+        help = "play | match | tune | solve | review | rpc | perft | par_perft | hperft | perft-check | verify | bench"
+    )]
     mode: Mode,
 
+    /// Game record to review in `review` mode.
+    record: Option<String>,
+
+    /// File to save the record of the game to (in `play` mode, overwritten by rematches).
+    #[structopt(long)]
+    save_game: Option<String>,
+
+    /// Save games with moves in the shorthand notation (like `pr2@r1c1`).
+    #[structopt(long)]
+    short_notation: bool,
+
+    /// Whether to show positions in colors: auto (when writing to a terminal), always or never.
+    #[structopt(long, default_value = "auto")]
+    color: ColorMode,
+
+    /// Play in a full-screen terminal UI, entering moves with the cursor.
+    #[cfg(feature = "tui")]
+    #[structopt(long)]
+    tui: bool,
+
+    /// Rhai script of the `script` opponent (see `rokumon_core::script`).
+    #[cfg(feature = "script")]
+    #[structopt(long)]
+    script: Option<String>,
+
+    /// WebAssembly module of the `plugin` opponent (see `rokumon_core::plugin`).
+    #[cfg(feature = "plugins")]
+    #[structopt(long)]
+    plugin: Option<String>,
+
+    /// Evaluation drop marking a move as a blunder ("??") in `review` mode, half of it marks an inaccuracy ("?!").
+    #[structopt(long, default_value = "50")]
+    blunder_threshold: i64,
+
     /// Opponents for the game.
     #[structopt(
         short,
         long,
         default_value = "HumanAI",
-        help = "HumanHuman | HumanAI | AIHuman | AIAI | RandomRandom"
+        help = "Two of human | ai | greedy | heuristic | random | script | plugin, like HumanAI or ai:greedy"
     )]
     opponents: Opponents,
 
@@ -83,6 +288,10 @@ struct Opt {
     #[structopt(long, default_value = "5")]
     perft_depth: usize,
 
+    /// File with more cases for `--mode perft-check`, one per line like `bricks7 gggjjjj fight+surprise 3 106224`.
+    #[structopt(long)]
+    perft_cases: Option<String>,
+
     /// Cards to be used in the game (g - gold, j - jade)
     #[structopt(long, default_value = "gggjjjj")]
     cards: String,
@@ -91,6 +300,11 @@ struct Opt {
     #[structopt(long)]
     no_shuffle: bool,
 
+    /// File with the moves to play before the game starts, one per line (use with --no-shuffle or --seed to
+    /// get the same cards every time).
+    #[structopt(long)]
+    transcript: Option<String>,
+
     /// Layout: Bricks7 or Square6 (for Act I).
     #[structopt(short, long, default_value = "bricks7")]
     layout: Layout,
@@ -107,6 +321,40 @@ struct Opt {
     #[structopt(long)]
     ai_to_completion: bool,
 
+    /// Number of threads used by AI for its analysis.
+    #[structopt(long, default_value = "1")]
+    ai_threads: usize,
+
+    /// Weights of AI evaluation terms (like triples=10,covered=5) or a TOML file with them.
+    #[structopt(long, parse(try_from_str = parse_eval_weights))]
+    eval_weights: Option<EvalWeights>,
+
+    /// AI evaluation function: weighted terms (`terms`) or a neural network (`nn`, needs `--nn-weights`).
+    #[structopt(long, default_value = "terms")]
+    ai_eval: EvalKind,
+
+    /// File with the weights of the evaluation network used with `--ai-eval nn`.
+    #[structopt(long, parse(try_from_str = parse_nn_weights))]
+    nn_weights: Option<Evaluator>,
+
+    /// How much AI dislikes draws by repetition (in evaluation units): positive values make it avoid them against
+    /// weaker opponents, negative ones make it seek them against stronger ones.
+    #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+    ai_contempt: i32,
+
+    /// Write the tree explored by AI (not the second one) to this file after every search: JSON if the name
+    /// ends with `.json`, graphviz DOT otherwise.
+    #[structopt(long)]
+    ai_tree_dump: Option<String>,
+
+    /// How many plies of the search tree to write with `--ai-tree-dump`.
+    #[structopt(long, default_value = "2")]
+    ai_tree_plies: u32,
+
+    /// Let AI generate endgame tablebases once there are that many dice left in the stocks.
+    #[structopt(long)]
+    tablebase_dice: Option<usize>,
+
     /// How deep second AI should analyse the position (in plies, i.e. half-moves).
     #[structopt(long)]
     second_ai_depth: Option<u32>,
@@ -119,10 +367,73 @@ struct Opt {
     #[structopt(long)]
     second_ai_to_completion: bool,
 
+    /// Weights of second AI evaluation terms (like triples=10,covered=5) or a TOML file with them.
+    #[structopt(long, parse(try_from_str = parse_eval_weights))]
+    second_eval_weights: Option<EvalWeights>,
+
+    /// Second AI evaluation function: `terms` or `nn`.
+    #[structopt(long, default_value = "terms")]
+    second_ai_eval: EvalKind,
+
+    /// How much second AI dislikes draws by repetition (see `--ai-contempt`).
+    #[structopt(long, default_value = "0", allow_hyphen_values = true)]
+    second_ai_contempt: i32,
+
     /// Number of matches to play (for AI vs AI games).
     #[structopt(long, default_value = "10")]
     samples: u32,
 
+    /// Whether opponents should swap sides after every game in a match.
+    #[structopt(long)]
+    alternate_colors: bool,
+
+    /// Whether every deck in a match should be played twice, with the opponents on both sides.
+    #[structopt(long)]
+    paired_decks: bool,
+
+    /// File with the openings for match mode, one per line with the moves separated by `;` (like
+    /// `pr2@r2c1; pb1@r1c1`). Each opening is played twice with the opponents on both sides.
+    #[structopt(long, parse(try_from_str = parse_openings))]
+    openings: Option<Openings>,
+
+    /// File to write the weights found in `tune` mode to.
+    #[structopt(long, default_value = "tuned_weights.toml")]
+    tune_output: String,
+
+    /// Maximal number of nodes in each proof-number search in `solve` mode.
+    #[structopt(long, default_value = "1000000")]
+    solve_nodes: usize,
+
+    /// How many plies of the proof tree to print in `solve` mode.
+    #[structopt(long, default_value = "3")]
+    proof_depth: usize,
+
+    /// Seed for shuffling the deck (game `n` of a match uses `seed + n`).
+    #[structopt(long)]
+    seed: Option<u64>,
+
+    /// Adjudicate a game as a draw after this number of plies.
+    #[structopt(long)]
+    max_plies: Option<usize>,
+
+    /// Adjudicate a game as lost by AI whose evaluation has been lost by this margin for that many of its moves
+    /// in a row (like 300/3).
+    #[structopt(long)]
+    resign_threshold: Option<Adjudication>,
+
+    /// Adjudicate a game as a draw once both AIs have evaluated it within this margin of zero for that many of
+    /// their moves in a row (like 10/8). See also `--max-plies`.
+    #[structopt(long)]
+    draw_adjudication: Option<Adjudication>,
+
+    /// Play with a chess clock: base time and increment in seconds (like 300+2).
+    #[structopt(long)]
+    time_control: Option<TimeControl>,
+
+    /// Let AI think during the opponent's time.
+    #[structopt(long)]
+    ponder: bool,
+
     /// Allows 'Fight' move in the game rules (disabled by default).
     #[structopt(short = "f", long)]
     enable_fight_move: bool,
@@ -130,18 +441,28 @@ struct Opt {
     /// Allows 'Surprise' move in the game rules (disabled by default).
     #[structopt(short = "s", long)]
     enable_surprise_move: bool,
+
+    /// Player making the first move: 1 (with red dice), 2 (with black and white dice) or random (by the seed of the game, if any).
+    #[structopt(long, default_value = "1")]
+    first_player: FirstPlayer,
+
+    /// Handicap: an extra die (extra:r4), a removed die (remove:b5) and/or the player placing their first two dice in a row before the normal turns, who then starts the game (placements:1 or placements:2), separated by commas.
+    #[structopt(long, default_value = "none")]
+    handicap: Handicap,
 }
 
 impl Display for Opt {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(
             f,
-            "Options: mode={:?}, opponents={:?}, cards={:?}, no_shuffle={}, samples={}, layout={:?}, with_fight={}, \
-             with_surprise={}, ai_duration={:?}, ai_depth={:?}, second_ai_duration={:?}, second_ai_depth={:?}",
+            "Options: mode={:?}, opponents={:?}, cards={:?}, no_shuffle={}, seed={:?}, samples={}, layout={:?}, \
+             with_fight={}, with_surprise={}, ai_duration={:?}, ai_depth={:?}, second_ai_duration={:?}, \
+             second_ai_depth={:?}, ai_threads={}",
             self.mode,
             self.opponents,
             self.cards,
             self.no_shuffle,
+            self.seed,
             self.samples,
             self.layout,
             self.enable_fight_move,
@@ -150,38 +471,153 @@ impl Display for Opt {
             self.ai_depth,
             self.second_ai_duration,
             self.second_ai_depth,
+            self.ai_threads,
         )
     }
 }
 
-fn play_game(opt: &Opt, rules: &Rules) -> i8 {
+/// Parses evaluation weights given either directly or in a TOML file.
+fn parse_eval_weights(s: &str) -> Fallible<EvalWeights> {
+    if s.ends_with(".toml") {
+        EvalWeights::from_toml(&fs::read_to_string(s)?)
+    } else {
+        s.parse()
+    }
+}
+
+type Openings = Vec<Vec<GameMove<EitherCoord, Position>>>;
+
+/// Loads the openings for match mode from a file.
+fn parse_openings(path: &str) -> Fallible<Openings> {
+    parsers::parse_openings(&fs::read_to_string(path)?)
+}
+
+/// Loads an evaluation network from a file.
+fn parse_nn_weights(path: &str) -> Fallible<Evaluator> {
+    Evaluator::neural_net(fs::read_to_string(path)?.parse()?)
+}
+
+fn new_game(opt: &Opt, rules: &Rules, seed: Option<u64>) -> Game {
+    try_new_game(opt, rules, seed).unwrap()
+}
+
+fn try_new_game(opt: &Opt, rules: &Rules, seed: Option<u64>) -> Fallible<Game> {
+    let rules = if opt.first_player == FirstPlayer::Random {
+        rules.with_random_starting_player(seed)
+    } else {
+        *rules
+    };
     let cards_spec = opt.cards.as_str();
     let deck = if opt.no_shuffle {
         Deck::ordered(cards_spec)
+    } else if let Some(seed) = seed {
+        Deck::shuffled_with_seed(cards_spec, seed)
     } else {
         Deck::shuffled(cards_spec)
+    }?;
+
+    match &opt.transcript {
+        Some(path) => {
+            let moves = parsers::parse_transcript(&fs::read_to_string(path)?)?;
+            Game::from_user_moves(opt.layout.clone(), deck, rules, &moves)
+        }
+        None => Game::try_new(opt.layout.clone(), deck, rules),
     }
-    .unwrap();
-
-    let game = Game::new(Layout::Bricks7, deck, rules.clone());
-    match opt.opponents {
-        Opponents::HumanHuman => play::play_game(game, Human, Human),
-        Opponents::RandomRandom => play::play_game(game, RandomAI, RandomAI),
-        Opponents::HumanAI => play::play_game(game, Human, mk_bot(false, &opt)),
-        Opponents::AIHuman => play::play_game(game, mk_bot(true, &opt), Human),
-        Opponents::AIAI => play::play_game(game, mk_bot(true, &opt), mk_bot(false, &opt)),
+}
+
+fn play_settings(opt: &Opt) -> PlaySettings {
+    PlaySettings {
+        max_plies: opt.max_plies,
+        time_control: opt.time_control,
+        ponder: opt.ponder,
+        resign: opt.resign_threshold,
+        draw: opt.draw_adjudication,
     }
 }
 
-fn mk_bot(for_first_player: bool, opt: &Opt) -> AlphaBetaAI {
-    let (duration_option, depth_option, to_completion_option) = if for_first_player || opt.opponents != Opponents::AIAI
+/// Plays games in `play` mode. When humans play, they are offered a
+/// rematch with swapped sides after every game, keeping the score.
+fn play_session(opt: &Opt, rules: &Rules) -> MatchReport {
+    let settings = play_settings(opt);
+    let renderer = Renderer::new(opt.color);
+    let mut final_position = FinalPositionPrinter(renderer);
+    let observers: &mut [&mut dyn GameObserver] = &mut [&mut PrintingObserver, &mut final_position];
+
+    let mut ix = 0;
+    let new_game = || {
+        ix += 1;
+        // Every rematch is dealt anew (or in the same way with --no-shuffle).
+        new_game(opt, rules, opt.seed.map(|s| s.wrapping_add(ix - 1)))
+    };
+    let with_human = opt.opponents.with_human();
+    let rematch = |report: &GameReport, _: &MatchReport| {
+        if let Some(path) = &opt.save_game {
+            let record = GameRecord::of(&report.final_position);
+            let text = if opt.short_notation {
+                record.to_short_string()
+            } else {
+                record.to_string()
+            };
+            match fs::write(path, text) {
+                Ok(()) => println!("Game saved to {}", path),
+                Err(err) => println!("[ERR] Can't save game: {}", err),
+            }
+        }
+        with_human && console_ui::confirm("Rematch with sides swapped?")
+    };
+
+    let opponents = &opt.opponents;
+    play::play_session(
+        &settings,
+        new_game,
+        |first| opponents.create(opt, 0, first),
+        |first| opponents.create(opt, 1, first),
+        observers,
+        rematch,
+    )
+}
+
+/// Creates a human player, using the terminal UI if asked to.
+fn human(opt: &Opt, against_ai: bool) -> Box<dyn Strategy> {
+    #[cfg(feature = "tui")]
     {
-        (opt.ai_duration, opt.ai_depth, opt.ai_to_completion)
+        if opt.tui {
+            return Box::new(tui::TuiHuman::new(against_ai));
+        }
+    }
+    Box::new(Human::new(against_ai, Renderer::new(opt.color)))
+}
+
+/// Evaluation function given either in the main AI options or in the
+/// ones for the second AI.
+fn evaluator(opt: &Opt, second_ai: bool) -> Evaluator {
+    let (weights, eval_kind) = if second_ai {
+        (opt.second_eval_weights, opt.second_ai_eval)
+    } else {
+        (opt.eval_weights, opt.ai_eval)
+    };
+    match (eval_kind, weights) {
+        // Presence of the network is checked in `main`.
+        (EvalKind::NeuralNet, _) => opt.nn_weights.clone().unwrap(),
+        (EvalKind::Terms, weights) => Evaluator::Terms(weights.unwrap_or_default()),
+    }
+}
+
+/// Creates an AI using either the main AI options or the ones for
+/// the second AI (`--second-ai-*`).
+fn mk_bot(for_first_player: bool, second_ai: bool, opt: &Opt) -> AlphaBetaAI {
+    let (duration_option, depth_option, to_completion_option, contempt) = if second_ai {
+        (
+            opt.second_ai_duration,
+            opt.second_ai_depth,
+            opt.second_ai_to_completion,
+            opt.second_ai_contempt,
+        )
     } else {
-        (opt.second_ai_duration, opt.second_ai_depth, opt.second_ai_to_completion)
+        (opt.ai_duration, opt.ai_depth, opt.ai_to_completion, opt.ai_contempt)
     };
 
-    if to_completion_option {
+    let mut bot = if to_completion_option {
         AlphaBetaAI::to_completion(for_first_player)
     } else if let Some(dur) = duration_option {
         AlphaBetaAI::with_duration(for_first_player, dur)
@@ -189,50 +625,237 @@ fn mk_bot(for_first_player: bool, opt: &Opt) -> AlphaBetaAI {
         AlphaBetaAI::with_depth(for_first_player, depth)
     } else {
         AlphaBetaAI::with_duration(for_first_player, 2)
+    };
+    bot.set_threads(opt.ai_threads);
+    bot.set_contempt(contempt);
+    bot.set_evaluator(evaluator(opt, second_ai));
+    if let Some(dice) = opt.tablebase_dice {
+        bot.enable_tablebases(dice);
+    }
+    if let (Some(path), false) = (&opt.ai_tree_dump, second_ai) {
+        bot.set_tree_dump(path, opt.ai_tree_plies);
     }
+    bot
+}
+
+/// Plays a match between the two opponents given in `--opponents`.
+/// The first opponent's settings follow it when sides are swapped.
+fn play_match(opt: &Opt, rules: &Rules) -> MatchReport {
+    let config = MatchConfig {
+        games: opt.samples,
+        alternate_colors: opt.alternate_colors,
+        paired: opt.paired_decks,
+        seed: opt.seed,
+        settings: play_settings(opt),
+        openings: opt.openings.clone().unwrap_or_default(),
+    };
+    let new_game = |seed| new_game(opt, rules, seed);
+    let observers: &mut [&mut dyn GameObserver] = &mut [&mut PrintingObserver];
+
+    let opponents = &opt.opponents;
+    match_runner::run_match(
+        &config,
+        new_game,
+        |first| opponents.create(opt, 0, first),
+        |first| opponents.create(opt, 1, first),
+        observers,
+        &mut PrintingMatchObserver,
+    )
 }
 
-fn play_match(opt: &Opt, rules: &Rules) {
-    let n = opt.samples;
-    println!("Starting a match of {} games", n);
-    let mut wins = 0;
-    let mut draws = 0;
-    let mut losses = 0;
-    for ix in 0..n {
-        println!();
-        println!("Starting game {}", ix + 1);
-        let res = play_game(opt, rules);
-        match res {
-            1 => wins += 1,
-            0 => draws += 1,
-            -1 => losses += 1,
-            _ => panic!("Unexpected game result: {}", res),
+/// Tunes evaluation weights on self-play games and writes them to
+/// `--tune-output`.
+fn tune_weights(opt: &Opt, rules: &Rules) -> Fallible<()> {
+    let mut config = TuneConfig {
+        games: opt.samples,
+        seed: opt.seed.unwrap_or(0),
+        depth: opt.ai_depth.unwrap_or(2),
+        ..Default::default()
+    };
+    if let Some(max_plies) = opt.max_plies {
+        config.max_plies = max_plies;
+    }
+    let initial = opt.eval_weights.unwrap_or_default();
+
+    let samples = tune::generate_samples(&config, |seed| new_game(opt, rules, seed), &initial);
+    println!("Tuning on {} positions from {} games", samples.len(), config.games);
+    let (weights, error) = tune::tune(&samples, &initial, config.max_iterations);
+
+    println!("Tuned weights: {} (error: {:.6})", weights, error);
+    fs::write(&opt.tune_output, weights.to_toml())?;
+    println!("Written to {}", opt.tune_output);
+    Ok(())
+}
+
+/// Proves the value of the initial position with proof-number search.
+fn solve(opt: &Opt, rules: &Rules) {
+    let game = new_game(opt, rules, opt.seed);
+    println!("{}", game);
+
+    let now = Instant::now();
+    let solution = pns::solve(&game, opt.solve_nodes);
+    println!("Solved in {:.2?}, {}", now.elapsed(), solution);
+    if let Some(proof) = &solution.proof {
+        print!("{}", proof.pretty_print(&game, opt.proof_depth));
+    }
+}
+
+/// Checks that undoing moves restores the positions exactly, in the
+/// trees of `--perft-depth` plies from `--samples` positions reached by
+/// random moves.
+fn verify(opt: &Opt, rules: &Rules) -> Fallible<()> {
+    for ix in 0..opt.samples {
+        let seed = opt.seed.map(|s| s.wrapping_add(u64::from(ix)));
+        let mut game = try_new_game(opt, rules, seed)?;
+        for _ in 0..ix % 16 {
+            if game.is_game_over() {
+                break;
+            }
+            game.apply_move(&game.random_move())?;
         }
+
+        let now = Instant::now();
+        let checked = game.check_undo_consistency(opt.perft_depth)?;
         println!(
-            "Match status so far: played {} games: {} : {} : {}",
+            "Position {} (ply {}): checked {} moves in {:.2?}",
             ix + 1,
-            wins,
-            draws,
-            losses
+            game.ply_to_be_played(),
+            checked,
+            now.elapsed()
         );
-        println!();
     }
-    println!("Played {} games in total: {} : {} : {}", n, wins, draws, losses);
+    println!("Undo is consistent in all {} positions", opt.samples);
+    Ok(())
+}
+
+/// Replays a saved game evaluating every move with AI (set up by the
+/// `--ai-*` options) and prints the record annotated with "??" for
+/// blunders and "?!" for inaccuracies along with better moves.
+fn review(opt: &Opt) -> Fallible<()> {
+    let path = opt
+        .record
+        .as_ref()
+        .ok_or_else(|| format_err!("Review mode needs a game record"))?;
+    let record: GameRecord = fs::read_to_string(path)?.parse()?;
+    let mut game = record.start()?;
+
+    let mut annotated = String::new();
+    record.write_header(&mut annotated)?;
+    let (mut blunders, mut inaccuracies) = (0, 0);
+    for (ix, user_move) in record.moves.iter().enumerate() {
+        let m = game.convert_move_coords(user_move)?;
+        let analysis = mk_bot(game.player1_moves, false, opt).analyze(&game);
+        let played = analysis
+            .iter()
+            .find(|(a, _, _)| *a == m)
+            .map(|(_, score, _)| *score)
+            .ok_or_else(|| format_err!("Illegal move in game record: {}", user_move))?;
+        let (best_move, best, _) = &analysis[0];
+
+        let drop = i64::from(*best) - i64::from(played);
+        let mark = if drop > opt.blunder_threshold {
+            blunders += 1;
+            "??"
+        } else if drop > opt.blunder_threshold / 2 {
+            inaccuracies += 1;
+            "?!"
+        } else {
+            ""
+        };
+
+        write!(annotated, "{}. {}", ix + 1, user_move)?;
+        if !mark.is_empty() {
+            let ply = game.ply_to_be_played();
+            write!(
+                annotated,
+                " {} {{eval: {}, better: {} ({})}}",
+                mark,
+                pp_evaluation(played, ply),
+                game.userify_move(best_move),
+                pp_evaluation(*best, ply)
+            )?;
+        }
+        writeln!(annotated)?;
+        game.apply_move(&m)?;
+    }
+
+    print!("{}", annotated);
+    println!("Blunders: {}, inaccuracies: {}", blunders, inaccuracies);
+    Ok(())
 }
 
 fn main() -> Fallible<()> {
     let opt = Opt::from_args();
-    println!("{}", opt);
+    // Standard output of the RPC mode is kept for the responses.
+    if !matches!(opt.mode, Mode::Rpc) {
+        println!("{}", opt);
+    }
+    ensure!(
+        opt.nn_weights.is_some() || (opt.ai_eval != EvalKind::NeuralNet && opt.second_ai_eval != EvalKind::NeuralNet),
+        "Neural network evaluation needs --nn-weights"
+    );
 
-    let rules = Rules::new(opt.enable_fight_move, opt.enable_surprise_move);
+    #[cfg(feature = "script")]
+    {
+        if opt.opponents.0.iter().any(|p| p.name == "script") {
+            let path = opt
+                .script
+                .as_ref()
+                .ok_or_else(|| format_err!("Script opponent needs --script"))?;
+            ScriptedAI::from_file(path)?;
+        }
+    }
+
+    #[cfg(feature = "plugins")]
+    {
+        if opt.opponents.0.iter().any(|p| p.name == "plugin") {
+            let path = opt
+                .plugin
+                .as_ref()
+                .ok_or_else(|| format_err!("Plugin opponent needs --plugin"))?;
+            WasmStrategy::from_file(path)?;
+        }
+    }
+
+    let rules = Rules::new(opt.enable_fight_move, opt.enable_surprise_move)
+        .with_player1_starting(opt.first_player != FirstPlayer::Two)
+        .with_handicap(opt.handicap)?;
+    // Check the cards and the moves once instead of failing in every
+    // game.
+    if let Err(err) = try_new_game(&opt, &rules, opt.seed) {
+        match &opt.transcript {
+            Some(path) => bail!("Bad transcript {}: {}", path, err),
+            None => bail!("Can't start a game: {}", err),
+        }
+    }
     match &opt.mode {
         Mode::Play => {
-            play_game(&opt, &rules);
+            play_session(&opt, &rules);
         }
+        Mode::Review => review(&opt)?,
         Mode::Match => {
             play_match(&opt, &rules);
         }
-        Mode::Perft | Mode::ParallelPerft => {
+        Mode::Tune => tune_weights(&opt, &rules)?,
+        Mode::Solve => solve(&opt, &rules),
+        Mode::Verify => verify(&opt, &rules)?,
+        #[cfg(feature = "rpc")]
+        Mode::Rpc => rpc::serve(std::io::stdin().lock(), std::io::stdout().lock())?,
+        #[cfg(not(feature = "rpc"))]
+        Mode::Rpc => bail!("RPC mode needs the `rpc` feature"),
+        Mode::Bench => {
+            bench::run(opt.ai_depth.unwrap_or(bench::DEFAULT_DEPTH))?;
+        }
+        Mode::PerftCheck => {
+            let mut cases = perft::builtin_cases();
+            if let Some(path) = &opt.perft_cases {
+                let extra = perft::parse_cases(&fs::read_to_string(path)?)
+                    .map_err(|err| format_err!("Bad perft cases {}: {}", path, err))?;
+                cases.extend(extra);
+            }
+            perft::check(&cases)?;
+        }
+        Mode::Perft | Mode::ParallelPerft | Mode::HashPerft => {
             let max_depth = opt.perft_depth;
             let cards_spec = opt.cards.as_str();
             let deck = if opt.no_shuffle {
@@ -241,26 +864,57 @@ fn main() -> Fallible<()> {
                 Deck::shuffled(cards_spec)
             }?;
 
-            let mut game = Game::new(Layout::Bricks7, deck, rules);
+            let mut game = Game::try_new(Layout::Bricks7, deck, rules)?;
             for depth in 1..=max_depth {
                 let now = Instant::now();
+                let mut unique = None;
                 let perft = match &opt.mode {
                     Mode::Perft => perft(&mut game, depth),
                     Mode::ParallelPerft => parallel_perft(&game, depth),
+                    Mode::HashPerft => {
+                        let hperft = hash_perft(&mut game, depth);
+                        unique = hperft.unique_positions.last().copied();
+                        hperft.leaves
+                    }
                     _ => unreachable!(),
                 };
                 let elapsed = now.elapsed();
                 let ratio = perft as f64 / now.elapsed().as_micros() as f64 * 1e6;
-                println!(
+                print!(
                     "perft({}): {:9}, time: {:>8} speed: {:.0} moves/s",
                     depth,
                     perft,
                     format!("{:.0?},", elapsed),
                     ratio
                 );
+                match unique {
+                    Some(unique) => println!(", unique positions: {}", unique),
+                    None => println!(),
+                }
             }
         }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_opponents() -> Fallible<()> {
+        let names = |s: &str| -> Fallible<Vec<&str>> { Ok(s.parse::<Opponents>()?.0.iter().map(|p| p.name).collect()) };
+        assert_eq!(names("HumanAI")?, vec!["human", "ai"]);
+        assert_eq!(names("aiai")?, vec!["ai", "ai"]);
+        assert_eq!(names("ai:random")?, vec!["ai", "random"]);
+        assert_eq!(names("GreedyAI")?, vec!["greedy", "ai"]);
+        assert_eq!(names("HeuristicHuman")?, vec!["heuristic", "human"]);
+        assert_eq!(names("rr")?, vec!["random", "random"]);
+        assert!("HumanAI".parse::<Opponents>()?.with_human());
+        assert!(!"AIRandom".parse::<Opponents>()?.with_human());
+        assert!("aihal".parse::<Opponents>().is_err());
+        assert!("ai".parse::<Opponents>().is_err());
+        Ok(())
+    }
+}
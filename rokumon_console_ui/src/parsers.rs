@@ -7,44 +7,74 @@
 /// - move B3 from R1C1 to R1C2
 /// - fight at R2C3
 /// - surprise from R1C2 to <3, -2, -1>
+/// - surprise from R1C2 to rightof R1C3
 /// - submit
-use std::convert::TryFrom;
+///
+/// or in the shorthand notation (see `Shorthand`):
+///
+/// - pW1@R2C2
+/// - mB3 R1C1>R1C2
+/// - f R2C3
+/// - s R1C2><3,-2>
+/// - s R1C2>rightof R1C3
+///
+/// Cards can be given in the internal coordinates too, like `place W1
+/// at <0, 1, -1>`. The destination of a surprise move has no card yet,
+/// so it is given either in them, in user coordinates of an empty
+/// position (like `r0c2` for the row above the top one, see
+/// `Board::convert_coordinates_to_user`) or next to a card: `leftof`,
+/// `rightof`, `aboveleft`, `aboveright`, `belowleft` and `belowright`
+/// for hex grids, `leftof`, `rightof`, `above` and `below` for square
+/// ones (see `Position`).
+use std::fmt;
 
 use failure::{bail, Fallible};
 
 use nom::branch::alt;
 use nom::bytes::complete::tag_no_case;
-use nom::character::complete::{char, digit1, one_of, space0, space1};
+use nom::character::complete::{char, digit1, space0, space1};
 use nom::combinator::{all_consuming, map, map_opt, map_res, value};
+use nom::error::{context, ErrorKind, ParseError as _, VerboseError, VerboseErrorKind};
 use nom::sequence::{delimited, pair, terminated, tuple};
 use nom::IResult;
-use nom::{do_parse, tag_no_case};
+use nom::{call, do_parse};
 
 use rokumon_core::card::{DiceColor, Die};
-use rokumon_core::coord::{Coord, UserCoord};
+use rokumon_core::coord::{Coord, Direction, EitherCoord, Position, UserCoord};
 use rokumon_core::game::GameMove;
 
-fn unsigned(i: &str) -> IResult<&str, i8> {
+/// Parsers keep the whole trace of an error, so that `parse_move` can
+/// tell where it went wrong and what was expected there.
+type Res<'a, T> = IResult<&'a str, T, VerboseError<&'a str>>;
+
+/// Case-insensitive keyword (or separator), given in backticks like
+/// "`at`", which is also how errors show it.
+fn keyword<'a>(label: &'static str) -> impl Fn(&'a str) -> Res<'a, &'a str> {
+    context(label, tag_no_case(label.trim_matches('`')))
+}
+
+fn unsigned(i: &str) -> Res<'_, i8> {
     map_res(digit1, |s: &str| s.parse())(i)
 }
 
-fn u8_parser(i: &str) -> IResult<&str, u8> {
+fn u8_parser(i: &str) -> Res<'_, u8> {
     map_res(digit1, |s: &str| s.parse())(i)
 }
 
-fn sign(i: &str) -> IResult<&str, i8> {
+fn sign(i: &str) -> Res<'_, i8> {
     alt((value(-1, char('-')), |i| Ok((i, 1))))(i)
 }
 
-fn signed(i: &str) -> IResult<&str, i8> {
+fn signed(i: &str) -> Res<'_, i8> {
     map(pair(sign, unsigned), |(s, u)| s * u)(i)
 }
 
-fn die_value(i: &str) -> IResult<&str, u8> {
-    map_opt(one_of("123456"), |c| c.to_digit(10).and_then(|d| u8::try_from(d).ok()))(i)
+/// Any positive value: dice beyond 1 to 6 are up to the rules.
+fn die_value(i: &str) -> Res<'_, u8> {
+    map_opt(digit1, |s: &str| s.parse().ok().filter(|&v| v > 0))(i)
 }
 
-fn die_color(i: &str) -> IResult<&str, DiceColor> {
+fn die_color(i: &str) -> Res<'_, DiceColor> {
     alt((
         value(DiceColor::Black, tag_no_case("B")),
         value(DiceColor::White, tag_no_case("W")),
@@ -52,99 +82,388 @@ fn die_color(i: &str) -> IResult<&str, DiceColor> {
     ))(i)
 }
 
-fn die(i: &str) -> IResult<&str, Die> {
-    map(pair(die_color, die_value), |(color, value)| Die::new(color, value))(i)
+fn die(i: &str) -> Res<'_, Die> {
+    context(
+        "a die like r2",
+        map(pair(die_color, die_value), |(color, value)| Die::new(color, value)),
+    )(i)
 }
 
-fn user_coord(i: &str) -> IResult<&str, UserCoord> {
+fn user_coord(i: &str) -> Res<'_, UserCoord> {
     do_parse!(
         i,
-        tag_no_case!("R") >> row: u8_parser >> tag_no_case!("C") >> card: u8_parser >> (UserCoord::new(row, card))
+        call!(tag_no_case("R")) >> row: u8_parser >> call!(tag_no_case("C")) >> card: u8_parser >> (UserCoord::new(row, card))
     )
 }
 
-fn coord_sep(i: &str) -> IResult<&str, ()> {
+fn coord_sep(i: &str) -> Res<'_, ()> {
     value((), terminated(char(','), space0))(i)
 }
 
-fn coord(i: &str) -> IResult<&str, Coord> {
-    map(
-        delimited(
-            char('<'),
-            tuple((signed, coord_sep, signed, coord_sep, signed)),
-            char('>'),
+fn coord(i: &str) -> Res<'_, Coord> {
+    context(
+        "coordinates like <1, 0, -1>",
+        map(
+            delimited(
+                char('<'),
+                tuple((signed, coord_sep, signed, coord_sep, signed)),
+                char('>'),
+            ),
+            |(x, _, y, _, _)| Coord::new_hex(x, y),
         ),
-        |(x, _, y, _, _)| Coord::new_hex(x, y),
     )(i)
 }
 
-fn place_cmd(i: &str) -> IResult<&str, GameMove<UserCoord>> {
+/// Card in either user or internal coordinates.
+fn either_coord(i: &str) -> Res<'_, EitherCoord> {
+    context(
+        "a card like r1c2 or <1, 0, -1>",
+        alt((map(user_coord, EitherCoord::User), map(coord, EitherCoord::Internal))),
+    )(i)
+}
+
+fn direction(i: &str) -> Res<'_, Direction> {
+    // Longer names go first, so that `aboveleft` is not taken for `above`.
+    alt((
+        value(Direction::AboveLeft, tag_no_case("aboveleft")),
+        value(Direction::AboveRight, tag_no_case("aboveright")),
+        value(Direction::BelowLeft, tag_no_case("belowleft")),
+        value(Direction::BelowRight, tag_no_case("belowright")),
+        value(Direction::LeftOf, tag_no_case("leftof")),
+        value(Direction::RightOf, tag_no_case("rightof")),
+        value(Direction::Above, tag_no_case("above")),
+        value(Direction::Below, tag_no_case("below")),
+    ))(i)
+}
+
+/// Position next to a card, like `leftof r1c1`.
+fn next_to(i: &str) -> Res<'_, Position> {
+    map(tuple((direction, space1, either_coord)), |(dir, _, card)| {
+        Position::Next(dir, card)
+    })(i)
+}
+
+/// Destination of a surprise move.
+fn position(i: &str) -> Res<'_, Position> {
+    context(
+        "a position like leftof r1c1, r0c2 or <1, 0, -1>",
+        alt((map(coord, Position::Internal), map(user_coord, Position::User), next_to)),
+    )(i)
+}
+
+fn place_cmd(i: &str) -> Res<'_, GameMove<EitherCoord, Position>> {
     do_parse!(
         i,
-        tag_no_case!("place")
+        call!(keyword("`place`"))
             >> space1
             >> d: die
             >> space1
-            >> tag_no_case!("at")
+            >> call!(keyword("`at`"))
             >> space1
-            >> c: user_coord
+            >> c: either_coord
             >> (GameMove::Place(d, c))
     )
 }
 
-fn move_cmd(i: &str) -> IResult<&str, GameMove<UserCoord>> {
+fn move_cmd(i: &str) -> Res<'_, GameMove<EitherCoord, Position>> {
     do_parse!(
         i,
-        tag_no_case!("move")
+        call!(keyword("`move`"))
             >> space1
             >> d: die
             >> space1
-            >> tag_no_case!("from")
+            >> call!(keyword("`from`"))
             >> space1
-            >> from: user_coord
+            >> from: either_coord
             >> space1
-            >> tag_no_case!("to")
+            >> call!(keyword("`to`"))
             >> space1
-            >> to: user_coord
+            >> to: either_coord
             >> (GameMove::Move(d, from, to))
     )
 }
 
-fn fight_cmd(i: &str) -> IResult<&str, GameMove<UserCoord>> {
+fn fight_cmd(i: &str) -> Res<'_, GameMove<EitherCoord, Position>> {
     do_parse!(
         i,
-        tag_no_case!("fight") >> space1 >> tag_no_case!("at") >> space1 >> c: user_coord >> (GameMove::Fight(c))
+        call!(keyword("`fight`")) >> space1 >> call!(keyword("`at`")) >> space1 >> c: either_coord >> (GameMove::Fight(c))
     )
 }
 
-fn surprise_cmd(i: &str) -> IResult<&str, GameMove<UserCoord>> {
+fn surprise_cmd(i: &str) -> Res<'_, GameMove<EitherCoord, Position>> {
     do_parse!(
         i,
-        tag_no_case!("surprise")
+        call!(keyword("`surprise`"))
             >> space1
-            >> tag_no_case!("from")
+            >> call!(keyword("`from`"))
             >> space1
-            >> from: user_coord
+            >> from: either_coord
             >> space1
-            >> tag_no_case!("to")
+            >> call!(keyword("`to`"))
             >> space1
-            >> to: coord
+            >> to: position
             >> (GameMove::Surprise(from, to))
     )
 }
 
-fn submit_cmd(i: &str) -> IResult<&str, GameMove<UserCoord>> {
+fn submit_cmd(i: &str) -> Res<'_, GameMove<EitherCoord, Position>> {
     value(GameMove::Submit, tag_no_case("submit"))(i)
 }
 
-fn game_move(i: &str) -> IResult<&str, GameMove<UserCoord>> {
-    all_consuming(alt((place_cmd, move_cmd, fight_cmd, surprise_cmd, submit_cmd)))(i)
+/// Surprise destination in the shorthand notation: `<x,y>` (the third
+/// cube coordinate follows from the other two) or `<x,y,z>`.
+fn short_coord(i: &str) -> Res<'_, Coord> {
+    context(
+        "coordinates like <2, -2>",
+        alt((
+            coord,
+            map(
+                delimited(char('<'), tuple((signed, coord_sep, signed)), char('>')),
+                |(x, _, y)| Coord::new_hex(x, y),
+            ),
+        )),
+    )(i)
+}
+
+fn short_position(i: &str) -> Res<'_, Position> {
+    context(
+        "a position like leftof r1c1, r0c2 or <2, -2>",
+        alt((
+            map(short_coord, Position::Internal),
+            map(user_coord, Position::User),
+            next_to,
+        )),
+    )(i)
+}
+
+fn short_place(i: &str) -> Res<'_, GameMove<EitherCoord, Position>> {
+    do_parse!(
+        i,
+        call!(keyword("`p`")) >> d: die >> space0 >> call!(keyword("`@`")) >> space0 >> c: either_coord >> (GameMove::Place(d, c))
+    )
+}
+
+fn short_move(i: &str) -> Res<'_, GameMove<EitherCoord, Position>> {
+    do_parse!(
+        i,
+        call!(keyword("`m`"))
+            >> d: die
+            >> space1
+            >> from: either_coord
+            >> space0
+            >> call!(keyword("`>`"))
+            >> space0
+            >> to: either_coord
+            >> (GameMove::Move(d, from, to))
+    )
+}
+
+fn short_fight(i: &str) -> Res<'_, GameMove<EitherCoord, Position>> {
+    do_parse!(i, call!(keyword("`f`")) >> space1 >> c: either_coord >> (GameMove::Fight(c)))
+}
+
+fn short_surprise(i: &str) -> Res<'_, GameMove<EitherCoord, Position>> {
+    do_parse!(
+        i,
+        call!(keyword("`s`"))
+            >> space1
+            >> from: either_coord
+            >> space0
+            >> call!(keyword("`>`"))
+            >> space0
+            >> to: short_position
+            >> (GameMove::Surprise(from, to))
+    )
+}
+
+type MoveParser = fn(&str) -> Res<'_, GameMove<EitherCoord, Position>>;
+
+const MOVE_PARSERS: [MoveParser; 9] = [
+    place_cmd,
+    move_cmd,
+    fight_cmd,
+    surprise_cmd,
+    submit_cmd,
+    short_place,
+    short_move,
+    short_fight,
+    short_surprise,
+];
+
+/// Tries all kinds of moves, returning the error of the one which got
+/// the furthest in the input (`alt` would just keep the last one).
+fn game_move(i: &str) -> Res<'_, GameMove<EitherCoord, Position>> {
+    let mut furthest: Option<VerboseError<&str>> = None;
+    for parser in &MOVE_PARSERS {
+        let err = match all_consuming(parser)(i) {
+            Ok(res) => return Ok(res),
+            Err(nom::Err::Error(err)) | Err(nom::Err::Failure(err)) => err,
+            Err(nom::Err::Incomplete(_)) => continue,
+        };
+        let rest = |e: &VerboseError<&str>| e.errors.first().map_or(i.len(), |(rest, _)| rest.len());
+        if furthest.as_ref().is_none_or(|f| rest(&err) < rest(f)) {
+            furthest = Some(err);
+        }
+    }
+    Err(nom::Err::Error(
+        furthest.unwrap_or_else(|| VerboseError::from_error_kind(i, ErrorKind::Alt)),
+    ))
+}
+
+/// Why a move couldn't be parsed: where it went wrong (as a byte
+/// offset in the input) and what was expected there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub input: String,
+    pub offset: usize,
+    pub expected: String,
+}
+
+impl ParseError {
+    fn new(input: &str, err: &VerboseError<&str>) -> Self {
+        let offset = |rest: &str| input.len() - rest.len();
+        let deepest = err.errors.first().map_or(0, |(rest, _)| offset(rest));
+        if deepest == 0 {
+            return ParseError {
+                input: input.to_string(),
+                offset: 0,
+                expected: "a move: place, move, fight, surprise or submit".to_string(),
+            };
+        }
+
+        // Contexts are added on the way out, so the first one is the
+        // innermost. Of those starting at the same place, the outermost
+        // one is the most helpful (a card rather than its coordinates).
+        let contexts: Vec<_> = err
+            .errors
+            .iter()
+            .filter_map(|(rest, kind)| match kind {
+                VerboseErrorKind::Context(label) => Some((offset(rest), label.to_string())),
+                _ => None,
+            })
+            .collect();
+        let (offset, expected) = match contexts.first() {
+            Some(&(at, _)) => contexts.into_iter().rfind(|(o, _)| *o == at).unwrap(),
+            None => {
+                let expected = match &err.errors[0].1 {
+                    VerboseErrorKind::Nom(ErrorKind::Eof) => "the end of the move".to_string(),
+                    VerboseErrorKind::Nom(ErrorKind::Space) => "a space".to_string(),
+                    VerboseErrorKind::Char(c) => format!("`{}`", c),
+                    _ => "something else".to_string(),
+                };
+                (deepest, expected)
+            }
+        };
+        ParseError {
+            input: input.to_string(),
+            offset,
+            expected,
+        }
+    }
+
+    /// Caret under the offending character, to be printed right below
+    /// the input.
+    pub fn caret(&self) -> String {
+        format!("{}^", " ".repeat(self.input[..self.offset].chars().count()))
+    }
 }
 
-pub fn parse_move(s: &str) -> Fallible<GameMove<UserCoord>> {
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Failed to parse move from '{}': expected {} at position {}",
+            self.input,
+            self.expected,
+            self.input[..self.offset].chars().count() + 1
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Shows a move in the shorthand notation, like `pr2@r1c1`, `mr2
+/// r1c1>r2c1`, `f r2c1` or `s r2c4><2,-2>`.
+pub struct Shorthand<'a, C, P = Coord>(pub &'a GameMove<C, P>);
+
+impl<C: fmt::Display, P: Clone + Into<Position>> fmt::Display for Shorthand<'_, C, P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use GameMove::*;
+        match self.0 {
+            Place(die, c) => write!(f, "p{}@{}", die, c),
+            Move(die, from, to) => write!(f, "m{} {}>{}", die, from, to),
+            Fight(c) => write!(f, "f {}", c),
+            Surprise(from, to) => match to.clone().into() {
+                Position::Internal(to) => write!(f, "s {}><{},{}>", from, to.x, to.y),
+                Position::User(to) => write!(f, "s {}>{}", from, to),
+                Position::Next(dir, card) => write!(f, "s {}>{} {}", from, dir, card),
+            },
+            Submit => write!(f, "submit"),
+        }
+    }
+}
+
+pub fn parse_move(s: &str) -> Result<GameMove<EitherCoord, Position>, ParseError> {
     match game_move(s) {
-        Ok((_, res)) => return Ok(res),
-        Err(_) => bail!("Failed to parse move from '{}'", s),
+        Ok((_, res)) => Ok(res),
+        Err(nom::Err::Error(err)) | Err(nom::Err::Failure(err)) => Err(ParseError::new(s, &err)),
+        Err(nom::Err::Incomplete(_)) => unreachable!("complete parsers only"),
+    }
+}
+
+/// Parses a line of a transcript: a move, optionally numbered (like
+/// "3. fight at r2c1") and followed by annotations (starting with `?`,
+/// `!` or `{`) or a comment (starting with `#`). Returns None if there
+/// is no move on the line.
+pub fn parse_transcript_line(line: &str) -> Fallible<Option<GameMove<EitherCoord, Position>>> {
+    let line = line.split(['#', '?', '!', '{']).next().unwrap().trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    // Skip the move number, if any.
+    let m = match line.split_once(". ") {
+        Some((n, m)) if n.chars().all(|c| c.is_ascii_digit()) => m,
+        _ => line,
+    };
+    Ok(Some(parse_move(m.trim())?))
+}
+
+/// Parses a transcript of a game: one move per line, see
+/// `parse_transcript_line`.
+pub fn parse_transcript(s: &str) -> Fallible<Vec<GameMove<EitherCoord, Position>>> {
+    let mut moves = vec![];
+    for (ix, line) in s.lines().enumerate() {
+        match parse_transcript_line(line) {
+            Ok(Some(m)) => moves.push(m),
+            Ok(None) => {}
+            Err(err) => bail!("Line {}: {}", ix + 1, err),
+        }
+    }
+    Ok(moves)
+}
+
+/// Parses openings for matches: one per line, with the moves separated
+/// by `;` (like "pr2@r2c1; pb1@r1c1"). Comments start with `#`.
+pub fn parse_openings(s: &str) -> Fallible<Vec<Vec<GameMove<EitherCoord, Position>>>> {
+    let mut openings = vec![];
+    for (ix, line) in s.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let opening: Result<Vec<_>, _> = line.split(';').map(|m| parse_move(m.trim())).collect();
+        match opening {
+            Ok(opening) => openings.push(opening),
+            Err(err) => bail!("Line {}: {}", ix + 1, err),
+        }
+    }
+    Ok(openings)
+}
+
+pub fn parse_user_coord(s: &str) -> Fallible<UserCoord> {
+    match all_consuming(user_coord)(s) {
+        Ok((_, res)) => Ok(res),
+        Err(_) => bail!("Failed to parse card coordinates from '{}'", s),
     }
 }
 
@@ -193,34 +512,157 @@ mod test {
         test!(die("R3") => d(Red, 3));
         test!(die("W1") => d(White, 1));
         test!(die("b6") => d(Black, 6));
+        test!(die("r12") => d(Red, 12));
 
         test_failure!(die("w 1"));
-        test_failure!(die("b7"));
+        test_failure!(die("b256"));
         test_failure!(die("r0"));
         test_failure!(die("R0"));
         test_failure!(die(" R1"));
     }
 
+    #[test]
+    fn test_user_coord() {
+        test!(user_coord("r2c1") => UserCoord::new(2, 1));
+        test!(user_coord("R1C12") => UserCoord::new(1, 12));
+        assert_eq!(parse_user_coord("r2c3").unwrap(), UserCoord::new(2, 3));
+
+        test_failure!(user_coord("2c1"));
+        assert!(parse_user_coord("r2c3 ").is_err());
+    }
+
     #[test]
     fn test_move() {
         use DiceColor::*;
         use GameMove::*;
 
         let d = Die::new;
-        let c = Coord::new_hex;
-        let uc = UserCoord::new;
+        let c = |x, y| Position::Internal(Coord::new_hex(x, y));
+        let uc = |row, card| EitherCoord::User(UserCoord::new(row, card));
+        let ic = |x, y| EitherCoord::Internal(Coord::new_hex(x, y));
 
         test!(game_move("place R3 at R1C2") => Place(d(Red, 3), uc(1, 2)));
         test!(game_move("move W1 from R1C1 to R2C2") => Move(d(White, 1), uc(1, 1), uc(2, 2)));
         test!(game_move("fight at R2C1") => Fight(uc(2, 1)));
         test!(game_move("fight at r1c3") => Fight(uc(1, 3)));
         test!(game_move("surprise from R2C2 to <0, 1, -1>") => Surprise(uc(2, 2), c(0, 1)));
+        test!(game_move("place r2 at <1,0,-1>") => Place(d(Red, 2), ic(1, 0)));
+        test!(game_move("move b1 from <0, 0, 0> to r1c1") => Move(d(Black, 1), ic(0, 0), uc(1, 1)));
+        test!(game_move("fight at <0,0,0>") => Fight(ic(0, 0)));
+        test!(game_move("surprise from <0, 1, -1> to <2, -2, 0>") => Surprise(ic(0, 1), c(2, -2)));
         test!(game_move("surprise FROM R1C2 TO <0,1, -1>") => Surprise(uc(1, 2), c(0, 1)));
+        test!(game_move("surprise from r1c1 to rightof r1c3") => Surprise(uc(1, 1), Position::Next(Direction::RightOf, uc(1, 3))));
+        test!(game_move("surprise from r1c1 to aboveleft <0,0,0>") => Surprise(uc(1, 1), Position::Next(Direction::AboveLeft, ic(0, 0))));
+        test!(game_move("surprise from r1c1 to Above r2c1") => Surprise(uc(1, 1), Position::Next(Direction::Above, uc(2, 1))));
         test!(game_move("submit") => Submit);
         test!(game_move("SUBMIT") => Submit);
 
         test_failure!(game_move("fight R2C1"));
         test_failure!(game_move("fightat R2C1"));
-        test_failure!(game_move("fight at <0,0,0>"));
+        test_failure!(game_move("fight at <0,0>"));
+        test!(game_move("surprise from r1c1 to r0c2") => Surprise(uc(1, 1), Position::User(UserCoord::new(0, 2))));
+        test_failure!(game_move("surprise from r1c1 to left r1c2"));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let err = |s| parse_move(s).unwrap_err();
+        let expected = |s| {
+            let e = err(s);
+            (e.offset, e.expected)
+        };
+
+        assert_eq!(expected("place r0 at r1c1"), (6, "a die like r2".to_string()));
+        assert_eq!(expected("move r2 from r1c1 into r1c2"), (18, "`to`".to_string()));
+        assert_eq!(
+            expected("place r2 at r1"),
+            (12, "a card like r1c2 or <1, 0, -1>".to_string())
+        );
+        assert_eq!(expected("fight at r1c1 now"), (13, "the end of the move".to_string()));
+        assert_eq!(
+            expected("s r2c4><2>"),
+            (7, "a position like leftof r1c1, r0c2 or <2, -2>".to_string())
+        );
+        assert_eq!(
+            expected("jump"),
+            (0, "a move: place, move, fight, surprise or submit".to_string())
+        );
+
+        assert_eq!(err("place r0 at r1c1").caret(), "      ^");
+        assert_eq!(
+            err("fight r1c1").to_string(),
+            "Failed to parse move from 'fight r1c1': expected `at` at position 7"
+        );
+    }
+
+    #[test]
+    fn test_shorthand() -> Fallible<()> {
+        use DiceColor::*;
+        use GameMove::*;
+
+        let d = Die::new;
+        let uc = |row, card| EitherCoord::User(UserCoord::new(row, card));
+
+        test!(game_move("pr2@r1c1") => Place(d(Red, 2), uc(1, 1)));
+        test!(game_move("PW1 @ R2C2") => Place(d(White, 1), uc(2, 2)));
+        test!(game_move("mr2 r1c1>r2c1") => Move(d(Red, 2), uc(1, 1), uc(2, 1)));
+        test!(game_move("f r2c1") => Fight(uc(2, 1)));
+        let c = |x, y| Position::Internal(Coord::new_hex(x, y));
+        test!(game_move("s r2c4><2,-2>") => Surprise(uc(2, 4), c(2, -2)));
+        test!(game_move("s r2c4 > <2, -2, 0>") => Surprise(uc(2, 4), c(2, -2)));
+        test!(game_move("s r2c4>belowleft r2c1") => Surprise(uc(2, 4), Position::Next(Direction::BelowLeft, uc(2, 1))));
+
+        test_failure!(game_move("pr2 r1c1"));
+        test_failure!(game_move("fr2c1"));
+
+        // Shown moves are parsed back.
+        for s in &[
+            "pr2@r1c1",
+            "mb3 r1c1>r1c2",
+            "f r2c3",
+            "s r1c2><3,-2>",
+            "s r1c2>rightof r1c3",
+            "submit",
+        ] {
+            assert_eq!(Shorthand(&parse_move(s)?).to_string(), *s);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcript() -> Fallible<()> {
+        use rokumon_core::board::Layout;
+        use rokumon_core::card::Deck;
+        use rokumon_core::game::{Game, Rules};
+
+        let transcript = "# Bug report: the second fight was not allowed\n\
+                          1. place r2 at r2c1\n\
+                          2. place b1 at r1c1 ?!\n\
+                          \n\
+                          move r2 from r2c1 to r1c1 {threatening a fight}\n";
+        let moves = parse_transcript(transcript)?;
+        assert_eq!(moves.len(), 3);
+        assert_eq!(moves[2], parse_move("move r2 from r2c1 to r1c1")?);
+
+        let game = Game::from_user_moves(Layout::Bricks7, Deck::ordered("gggjjjj")?, Rules::default(), &moves)?;
+        let fight = game.convert_move_coords(&parse_move("fight at r1c1")?)?;
+        game.validate_move(&fight)?;
+
+        let err = parse_transcript("place r2 at r2c1\nplace r2 r2c1").unwrap_err();
+        assert!(err.to_string().starts_with("Line 2:"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_openings() -> Fallible<()> {
+        let openings = parse_openings("# centre first\npr2@r2c2; pb1@r1c1\n\nplace r2 at <0, 0, 0>\n")?;
+        assert_eq!(openings.len(), 2);
+        assert_eq!(openings[0].len(), 2);
+        assert_eq!(openings[0][1], parse_move("place b1 at r1c1")?);
+        assert_eq!(openings[1].len(), 1);
+
+        let err = parse_openings("pr2@r2c2\npr2@r2c2; pb1 r1c1").unwrap_err();
+        assert!(err.to_string().starts_with("Line 2:"));
+        Ok(())
     }
 }
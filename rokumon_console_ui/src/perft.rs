@@ -1,30 +1,67 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Instant;
+
+use failure::{bail, ensure, Fallible};
 use rayon::prelude::*;
 
+use rokumon_core::board::Layout;
+use rokumon_core::card::Deck;
 use rokumon_core::coord::Coord;
-use rokumon_core::game::{Game, GameMove};
+use rokumon_core::game::{Game, GameMove, Rules};
+
+/// Number of plies split between the threads in `parallel_perft`.
+const SPLIT_PLIES: usize = 2;
 
-/// This perft uses a clone of the game every time it explores a new
-/// move. Hence it makes a lot of allocations and uses a lot of
-/// memory, but can be parallelized.
+/// This perft splits the tree into the lines of the first few plies
+/// and explores them in parallel. Every worker has its own copy of
+/// the game and uses incremental updates (see `perft`) below the split
+/// point, so there are few allocations.
 pub fn parallel_perft(game: &Game, depth: usize) -> usize {
-    fn go(game: &Game, depth: usize, m: &GameMove<Coord>) -> usize {
-        let mut copy_game = game.clone();
-        copy_game.apply_move_unchecked(m);
-        parallel_perft(&copy_game, depth - 1)
+    if depth <= SPLIT_PLIES {
+        return perft(&mut game.clone(), depth);
     }
 
-    let moves = game.generate_moves();
-    if depth == 1 {
-        moves.len()
-    } else {
-        moves.par_iter().map(|m| go(game, depth, m)).sum()
+    let lines = split(&mut game.clone(), SPLIT_PLIES);
+    lines
+        .par_iter()
+        .map_init(
+            || game.clone(),
+            |game, line| {
+                let fight_results: Vec<_> = line.iter().map(|m| game.apply_move_unchecked(m)).collect();
+                let result = perft(game, depth - line.len());
+                for (m, fight_result) in line.iter().zip(fight_results).rev() {
+                    game.undo_move(m, fight_result);
+                }
+                result
+            },
+        )
+        .sum()
+}
+
+/// All the lines of exactly `plies` moves.
+fn split(game: &mut Game, plies: usize) -> Vec<Vec<GameMove<Coord>>> {
+    if plies == 0 {
+        return vec![vec![]];
     }
+
+    let mut lines = vec![];
+    for m in game.generate_moves() {
+        let fight_result = game.apply_move_unchecked(&m);
+        for mut line in split(game, plies - 1) {
+            line.insert(0, m.clone());
+            lines.push(line);
+        }
+        game.undo_move(&m, fight_result);
+    }
+    lines
 }
 
 /// This perft uses incremental updates, i.e. mutates the game
 /// constantly by applying and undoing moves. It does not do much
-/// allocations, but can't be easily parallelized because of the
-/// mutations.
+/// allocations, but runs in a single thread (`parallel_perft` runs it
+/// in every worker).
 pub fn perft(game: &mut Game, depth: usize) -> usize {
     let moves = game.generate_moves();
     if depth == 1 {
@@ -42,14 +79,168 @@ pub fn perft(game: &mut Game, depth: usize) -> usize {
     result
 }
 
+/// Result of `hash_perft`.
+pub struct HashPerft {
+    /// Number of leaves, the same as given by `perft`.
+    pub leaves: usize,
+    /// Number of different positions after each ply (the first one is
+    /// after the first move).
+    pub unique_positions: Vec<usize>,
+}
+
+/// Position of the game as a key of the `hash_perft` tables. The
+/// result is a part of the key because a game can end with a row
+/// uncovered in flight, which is not seen on the board.
+type PositionKey = (u64, bool);
+
+/// This perft caches the number of leaves under every position by its
+/// hash (see `Game::position_hash`), so transpositions are explored
+/// once. It also counts different positions at each depth, but unlike
+/// `perft` it has to apply the moves of the last ply to do so.
+pub fn hash_perft(game: &mut Game, depth: usize) -> HashPerft {
+    let mut tables = vec![HashMap::new(); depth];
+    let leaves = hash_perft_go(game, depth, &mut tables);
+    HashPerft {
+        leaves,
+        unique_positions: tables.iter().map(HashMap::len).collect(),
+    }
+}
+
+/// `tables[0]` has the leaf counts of the positions after the next
+/// move, `tables[1]` after the one following it and so on.
+fn hash_perft_go(game: &mut Game, depth: usize, tables: &mut [HashMap<PositionKey, usize>]) -> usize {
+    let (table, deeper) = tables.split_first_mut().expect("hash_perft: depth should be positive");
+    let mut result = 0;
+
+    for m in game.generate_moves() {
+        let fight_result = game.apply_move_unchecked(&m);
+        let key = (game.position_hash(), game.is_game_over());
+        result += match table.get(&key) {
+            Some(&leaves) => leaves,
+            None => {
+                let leaves = if depth == 1 {
+                    1
+                } else {
+                    hash_perft_go(game, depth - 1, deeper)
+                };
+                table.insert(key, leaves);
+                leaves
+            }
+        };
+        game.undo_move(&m, fight_result);
+    }
+
+    result
+}
+
+/// Perft of a starting position with the expected number of leaves,
+/// written like `bricks7 gggjjjj fight+surprise 3 106224` (rules are
+/// `fight`, `surprise`, both joined by `+` or `none`).
+#[derive(Debug, Clone)]
+pub struct PerftCase {
+    pub layout: Layout,
+    pub cards: String,
+    pub rules: Rules,
+    pub depth: usize,
+    pub expected: usize,
+}
+
+impl FromStr for PerftCase {
+    type Err = failure::Error;
+    fn from_str(s: &str) -> Fallible<Self> {
+        let words: Vec<_> = s.split_whitespace().collect();
+        let (layout, cards, rules, depth, expected) = match words.as_slice() {
+            [layout, cards, rules, depth, expected] => (layout, cards, rules, depth, expected),
+            _ => bail!(
+                "Perft case should be like `bricks7 gggjjjj fight+surprise 3 106224`: {}",
+                s
+            ),
+        };
+
+        let rules: Vec<_> = rules.split('+').collect();
+        if let Some(r) = rules.iter().find(|r| !["fight", "surprise", "none"].contains(r)) {
+            bail!("Unknown rule in perft case: {}", r);
+        }
+        let depth: usize = depth.parse()?;
+        ensure!(depth > 0, "Perft depth should be positive: {}", s);
+
+        Ok(PerftCase {
+            layout: layout.to_lowercase().parse()?,
+            cards: cards.to_string(),
+            rules: Rules::new(rules.contains(&"fight"), rules.contains(&"surprise")),
+            depth,
+            expected: expected.parse()?,
+        })
+    }
+}
+
+impl fmt::Display for PerftCase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rules = match (self.rules.fight_enabled(), self.rules.surprise_enabled()) {
+            (true, true) => "fight+surprise",
+            (true, false) => "fight",
+            (false, true) => "surprise",
+            (false, false) => "none",
+        };
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.layout, self.cards, rules, self.depth, self.expected
+        )
+    }
+}
+
+/// Cases checked by `--mode perft-check`.
+const BUILTIN_CASES: &str = "
+bricks7 gggjjjj fight+surprise 2 3422
+bricks7 gggjjjj fight 4 130800
+bricks7 gggjjjj none 6 201744
+bricks7 jgjgjgj fight+surprise 3 106224
+hex7 gggjjjj none 5 24396
+hex7 gggjjjj fight 3 7608
+hex7 gggjjjj fight+surprise 3 91668
+rectangle6 gggjjj none 5 9558
+rectangle6 gggjjj fight+surprise 3 4440
+";
+
+/// Perft cases, one per line. Empty lines and comments (starting with
+/// `#`) are skipped.
+pub fn parse_cases(s: &str) -> Fallible<Vec<PerftCase>> {
+    s.lines()
+        .map(|line| line.split('#').next().unwrap().trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.parse())
+        .collect()
+}
+
+pub fn builtin_cases() -> Vec<PerftCase> {
+    parse_cases(BUILTIN_CASES).expect("builtin perft cases should be valid")
+}
+
+/// Runs perft for all the cases, printing the results. Fails if any of
+/// them doesn't give the expected number of leaves.
+pub fn check(cases: &[PerftCase]) -> Fallible<()> {
+    let mut failed = 0;
+    for case in cases {
+        let game = Game::new(case.layout.clone(), Deck::ordered(&case.cards)?, case.rules);
+        let now = Instant::now();
+        let leaves = parallel_perft(&game, case.depth);
+        if leaves == case.expected {
+            println!("ok    {} ({:.0?})", case, now.elapsed());
+        } else {
+            println!("FAIL  {}: got {}", case, leaves);
+            failed += 1;
+        }
+    }
+
+    ensure!(failed == 0, "{} of {} perft cases failed", failed, cases.len());
+    Ok(())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
-    use rokumon_core::board::Layout;
-    use rokumon_core::card::Deck;
-    use rokumon_core::game::{Game, Rules};
-
     #[test]
     pub fn test_perft() -> failure::Fallible<()> {
         let deck = Deck::ordered("gggjjjj")?;
@@ -64,6 +255,13 @@ mod test {
         assert_eq!(perft(&mut game, 2), 504);
         assert_eq!(perft(&mut game, 3), 7608);
         assert_eq!(perft(&mut game, 4), 130800);
+        assert_eq!(parallel_perft(&game, 4), 130800);
+        assert_eq!(parallel_perft(&game, 2), 504);
+        let hperft = hash_perft(&mut game, 4);
+        assert_eq!(hperft.leaves, 130800);
+        assert_eq!(hperft.unique_positions.len(), 4);
+        assert_eq!(hperft.unique_positions[0], 21);
+        assert!(hperft.unique_positions[3] < 130800);
 
         let rules = Rules::new(false, false);
         let mut game = Game::new(Layout::Bricks7, deck.clone(), rules);
@@ -71,4 +269,22 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    pub fn test_perft_cases() -> failure::Fallible<()> {
+        let cases = parse_cases("# comment\n\nbricks7 gggjjjj fight+surprise 2 3422\nr6 gggjjj none 1 6 # quick\n")?;
+        assert_eq!(cases.len(), 2);
+        assert_eq!(cases[0].to_string(), "bricks7 gggjjjj fight+surprise 2 3422");
+        assert_eq!(cases[1].to_string(), "rectangle6 gggjjj none 1 6");
+        check(&cases)?;
+
+        let mut wrong = cases[1].clone();
+        wrong.expected = 7;
+        assert!(check(&[wrong]).is_err());
+        assert!(parse_cases("bricks7 gggjjjj magic 2 3422").is_err());
+        assert!(parse_cases("bricks7 gggjjjj 2 3422").is_err());
+        assert_eq!(builtin_cases().len(), 9);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,236 @@
+/// Saved games: the initial position and the moves in a simple text
+/// format, for example:
+///
+/// layout: bricks7
+/// cards: JGJJJGG
+/// rules: fight surprise (or none, `player2-first` if the second player
+/// starts)
+/// handicap: extra:r4,placements:2 (optional, see `Handicap`)
+/// dice: r1-8,b2-12 (optional, the colors with other values than 1-6,
+/// see `DieValues`)
+/// 1. place r2 at r2c3
+/// 2. place b1 at r2c1 ?? {better: place b1 at r1c2}
+///
+/// Moves can be written in the shorthand notation too (`1. pr2@r2c3`).
+/// Move numbers, annotations (starting with `?`, `!` or `{`) and
+/// comments (starting with `#`) are ignored when reading.
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+use failure::{bail, format_err, Fallible};
+
+use rokumon_core::board::Layout;
+use rokumon_core::card::{Deck, DiceColor, DieValues};
+use rokumon_core::coord::{Coord, EitherCoord, Position};
+use rokumon_core::game::{Game, GameMove, Handicap, Rules};
+
+use crate::parsers::{self, Shorthand};
+
+#[derive(Debug, Clone)]
+pub struct GameRecord {
+    pub layout: Layout,
+    pub cards: String,
+    pub rules: Rules,
+    pub moves: Vec<GameMove<EitherCoord, Position>>,
+}
+
+impl GameRecord {
+    /// Record of the moves played in `game` so far.
+    pub fn of(game: &Game) -> Self {
+        GameRecord {
+            layout: game.board.layout.clone(),
+            cards: game.board.dealt_cards(),
+            rules: game.rules(),
+            moves: game
+                .moves()
+                .iter()
+                .map(|m| game.userify_move(m).map_destination(|to| Position::Internal(*to)))
+                .collect(),
+        }
+    }
+
+    /// The initial position of the game.
+    pub fn start(&self) -> Fallible<Game> {
+        Game::try_new(self.layout.clone(), Deck::ordered(&self.cards)?, self.rules)
+    }
+
+    /// The moves of the record in the internal coordinates (checking
+    /// that they are legal).
+    pub fn game_moves(&self) -> Fallible<Vec<GameMove<Coord>>> {
+        let deck = Deck::ordered(&self.cards)?;
+        Ok(Game::from_user_moves(self.layout.clone(), deck, self.rules, &self.moves)?.moves())
+    }
+
+    /// Writes the header of the record (everything but the moves).
+    pub fn write_header(&self, f: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(f, "layout: {}", self.layout)?;
+        writeln!(f, "cards: {}", self.cards)?;
+        let mut rules = vec![];
+        if self.rules.fight_enabled() {
+            rules.push("fight");
+        }
+        if self.rules.surprise_enabled() {
+            rules.push("surprise");
+        }
+        if !self.rules.player1_starts() {
+            rules.push("player2-first");
+        }
+        if rules.is_empty() {
+            rules.push("none");
+        }
+        writeln!(f, "rules: {}", rules.join(" "))?;
+        let handicap = self.rules.handicap();
+        if !handicap.is_none() {
+            writeln!(f, "handicap: {}", handicap)?;
+        }
+        let dice: Vec<String> = DiceColor::ALL
+            .iter()
+            .map(|&color| (color, self.rules.die_values(color)))
+            .filter(|(_, values)| *values != DieValues::CUBIC)
+            .map(|(color, values)| format!("{}{}", color.letter(), values))
+            .collect();
+        if !dice.is_empty() {
+            writeln!(f, "dice: {}", dice.join(","))?;
+        }
+        Ok(())
+    }
+
+    /// The record with the moves in the shorthand notation (see
+    /// `parsers::Shorthand`).
+    pub fn to_short_string(&self) -> String {
+        let mut s = String::new();
+        self.write_header(&mut s).unwrap();
+        for (ix, m) in self.moves.iter().enumerate() {
+            s.push_str(&format!("{}. {}\n", ix + 1, Shorthand(m)));
+        }
+        s
+    }
+}
+
+impl fmt::Display for GameRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.write_header(f)?;
+        for (ix, m) in self.moves.iter().enumerate() {
+            writeln!(f, "{}. {}", ix + 1, m)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for GameRecord {
+    type Err = failure::Error;
+    fn from_str(s: &str) -> Fallible<Self> {
+        let mut layout = None;
+        let mut cards = None;
+        let mut rules = None;
+        let mut handicap = Handicap::default();
+        let mut die_values = vec![];
+        let mut moves = vec![];
+
+        for line in s.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            if let Some(value) = line.strip_prefix("layout:") {
+                layout = Some(value.trim().to_lowercase().parse()?);
+            } else if let Some(value) = line.strip_prefix("cards:") {
+                cards = Some(value.trim().to_string());
+            } else if let Some(value) = line.strip_prefix("rules:") {
+                let words: Vec<_> = value.split_whitespace().collect();
+                if let Some(w) = words
+                    .iter()
+                    .find(|w| !["fight", "surprise", "player2-first", "none"].contains(w))
+                {
+                    bail!("Unknown rule in game record: {}", w);
+                }
+                let rules_from_words = Rules::new(words.contains(&"fight"), words.contains(&"surprise"));
+                rules = Some(rules_from_words.with_player1_starting(!words.contains(&"player2-first")));
+            } else if let Some(value) = line.strip_prefix("handicap:") {
+                handicap = value.trim().parse()?;
+            } else if let Some(value) = line.strip_prefix("dice:") {
+                for item in value.split(',').map(str::trim) {
+                    let mut chars = item.chars();
+                    let color = match chars.next() {
+                        Some(c) => DiceColor::try_from(c)?,
+                        None => bail!("No dice color in game record: {}", value),
+                    };
+                    die_values.push((color, chars.as_str().parse::<DieValues>()?));
+                }
+            } else if let Some(m) = parsers::parse_transcript_line(line)? {
+                moves.push(m);
+            }
+        }
+
+        // Die values come first, since the stocks depend on them.
+        let rules = die_values
+            .into_iter()
+            .fold(rules.unwrap_or_default(), |rules, (color, values)| {
+                rules.with_die_values(color, values)
+            });
+        Ok(GameRecord {
+            layout: layout.ok_or_else(|| format_err!("No layout in game record"))?,
+            cards: cards.ok_or_else(|| format_err!("No cards in game record"))?,
+            rules: rules.with_handicap(handicap)?,
+            moves,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_game_record() -> Fallible<()> {
+        let rules = Rules::new(false, false).with_player1_starting(false);
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("JGJJJGG")?, rules);
+        for _ in 0..6 {
+            let m = game.generate_moves().into_iter().last().unwrap();
+            game.apply_move(&m)?;
+        }
+
+        let record = GameRecord::of(&game);
+        let parsed: GameRecord = record.to_string().parse()?;
+        assert_eq!(parsed.cards, "JGJJJGG");
+        assert_eq!(parsed.rules, rules);
+        assert_eq!(parsed.moves, record.moves);
+
+        let mut replayed = parsed.start()?;
+        for m in &parsed.moves {
+            replayed.apply_user_move(m)?;
+        }
+        assert_eq!(replayed.position_key(), game.position_key());
+        assert_eq!(parsed.game_moves()?, game.moves());
+        let short: GameRecord = record.to_short_string().parse()?;
+        assert_eq!(short.moves, record.moves);
+
+        // Annotations and comments are ignored.
+        let annotated = "# review\nlayout: r6\ncards: gggjjj\n1. place r2 at r1c1 ?? {better: place r2 at r2c2}\n";
+        let parsed: GameRecord = annotated.parse()?;
+        assert_eq!(parsed.moves.len(), 1);
+        assert_eq!(parsed.rules, Rules::default());
+
+        let handicapped = Rules::default().with_handicap("extra:b6,placements:2".parse()?)?;
+        let record = GameRecord::of(&Game::new(Layout::Bricks7, Deck::ordered("JGJJJGG")?, handicapped));
+        assert!(record.to_string().contains("handicap: extra:b6,placements:2\n"));
+        assert_eq!(record.to_string().parse::<GameRecord>()?.rules, handicapped);
+
+        let doubled = Rules::default()
+            .with_die_values(DiceColor::Black, "2-12".parse()?)
+            .with_die_values(DiceColor::Red, "d8".parse()?)
+            .with_handicap("remove:b10".parse()?)?;
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("JGJJJGG")?, doubled);
+        game.apply_move(&game.generate_moves()[0])?;
+        let record = GameRecord::of(&game);
+        assert!(record.to_string().contains("dice: r1-8,b2-12\n"));
+        let parsed: GameRecord = record.to_string().parse()?;
+        assert_eq!(parsed.rules, doubled);
+        assert_eq!(parsed.game_moves()?, game.moves());
+        assert!("layout: bricks7\ncards: JGJJJGG\ndice: x1-8"
+            .parse::<GameRecord>()
+            .is_err());
+
+        assert!("cards: gggjjj".parse::<GameRecord>().is_err());
+        assert!("layout: r6\ncards: gggjjj\nrules: magic".parse::<GameRecord>().is_err());
+        Ok(())
+    }
+}
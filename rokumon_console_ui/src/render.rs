@@ -0,0 +1,201 @@
+/// Showing positions in the console: either as plain text (which is
+/// what `Display for Game` does) or with ANSI colors for the dice, the
+/// last move and the winning cards. Both end with a recap of the last
+/// move.
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+
+use failure::{bail, Fallible};
+
+use rokumon_core::card::{DiceColor, Die};
+use rokumon_core::coord::Coord;
+use rokumon_core::game::{Game, WinReason};
+use rokumon_core::play::{GameObserver, GameReport};
+
+const RESET: &str = "\x1b[0m";
+const LAST_MOVE: &str = "\x1b[1;33m";
+const LAST_MOVE_SOURCE: &str = "\x1b[33m";
+const WINNING: &str = "\x1b[1;32m";
+
+/// When to use colors.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ColorMode {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorMode {
+    type Err = failure::Error;
+    fn from_str(s: &str) -> Fallible<Self> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(ColorMode::Auto),
+            "always" => Ok(ColorMode::Always),
+            "never" => Ok(ColorMode::Never),
+            _ => bail!("Can't parse color mode: {}", s),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Renderer {
+    Plain,
+    Color,
+}
+
+impl Renderer {
+    /// In `Auto` mode colors are used only when writing to a terminal.
+    pub fn new(mode: ColorMode) -> Self {
+        let color = match mode {
+            ColorMode::Auto => io::stdout().is_terminal(),
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        };
+        if color {
+            Renderer::Color
+        } else {
+            Renderer::Plain
+        }
+    }
+
+    pub fn game(self, game: &Game) -> String {
+        let mut out = match self {
+            Renderer::Plain => game.to_string(),
+            Renderer::Color => colored_game(game),
+        };
+        if let Some(recap) = last_move_recap(game) {
+            out.push_str(&recap);
+            out.push('\n');
+        }
+        out
+    }
+}
+
+fn colored_die(die: &Die) -> String {
+    let color = match die.color {
+        DiceColor::Red => "\x1b[31m",
+        DiceColor::Black => "\x1b[90m",
+        DiceColor::White => "\x1b[97m",
+    };
+    format!("{}{}{}", color, die, RESET)
+}
+
+/// Who has made the last move and what it was, like "Last move
+/// (Player 2): move b3 from r1c1 to r1c2".
+pub fn last_move_recap(game: &Game) -> Option<String> {
+    let m = game.last_move()?;
+    Some(format!(
+        "Last move ({}): {}",
+        if game.player1_moves { "Player 2" } else { "Player 1" },
+        game.userify_move(m)
+    ))
+}
+
+fn winning_cards(game: &Game) -> Vec<Coord> {
+    match game.win_reason() {
+        Some(WinReason::ThreeInARow { coords }) => coords.to_vec(),
+        Some(WinReason::ThreeInStack { coord }) => vec![coord],
+        _ => vec![],
+    }
+}
+
+/// Same layout as `Display for Game`, but with colors. ANSI codes
+/// take no space on the screen, so the padding is done by hand.
+fn colored_game(game: &Game) -> String {
+    let board = &game.board;
+    let last_move = game.last_move();
+    let (source, target) = (last_move.and_then(|m| m.source()), last_move.and_then(|m| m.target()));
+    let winning = winning_cards(game);
+
+    let mut out = String::from("\n");
+    for row in board.drawing_rows() {
+        let mut cards = String::new();
+        let mut cards_width = 0;
+        for drawn in &row {
+            let (coord, card) = (drawn.coord, drawn.card);
+            let dice: Vec<_> = card.dice.iter().map(colored_die).collect();
+            let highlight = if winning.contains(coord) {
+                WINNING
+            } else if target == Some(*coord) {
+                LAST_MOVE
+            } else if source == Some(*coord) {
+                LAST_MOVE_SOURCE
+            } else {
+                ""
+            };
+            let reset = if highlight.is_empty() { "" } else { RESET };
+            cards.push_str(&" ".repeat(drawn.indent - cards_width));
+            cards.push_str(&format!(
+                "{}[{}{} {}{}{}]{}",
+                highlight,
+                card.kind.letter(),
+                reset,
+                dice.join("<"),
+                " ".repeat(drawn.padding()),
+                highlight,
+                reset
+            ));
+            cards_width = drawn.end();
+        }
+        out.push_str(&format!("{}\n{}\n", board.drawing_labels(&row), cards));
+    }
+
+    for (name, player1) in &[("Player 1", true), ("Player 2", false)] {
+        let dice: Vec<_> = game.stock(*player1).iter().map(colored_die).collect();
+        out.push_str(&format!("{}: [{}]\n", name, dice.join(", ")));
+    }
+    out.push_str(&format!(
+        "To move: {}\n",
+        if game.player1_moves { "Player 1" } else { "Player 2" }
+    ));
+    out
+}
+
+/// Shows the final position (with the winning cards highlighted).
+pub struct FinalPositionPrinter(pub Renderer);
+
+impl GameObserver for FinalPositionPrinter {
+    fn on_game_over(&mut self, report: &GameReport) {
+        println!("Final position: {}", self.0.game(&report.final_position));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rokumon_core::board::Layout;
+    use rokumon_core::card::Deck;
+    use rokumon_core::game::Rules;
+
+    #[test]
+    fn test_renderers() -> Fallible<()> {
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("JGJGJGG")?, Rules::default());
+        let m = game.generate_moves()[0].clone();
+        game.apply_move(&m)?;
+
+        let plain = Renderer::Plain.game(&game);
+        let recap = format!("Last move (Player 1): {}\n", game.userify_move(&m));
+        assert_eq!(plain, format!("{}{}", game, recap));
+        assert!(!plain.contains('\x1b'));
+
+        // Without the escape codes the colored drawing is the same.
+        let colored = Renderer::Color.game(&game);
+        assert!(colored.contains(LAST_MOVE));
+        let mut stripped = String::new();
+        let mut in_code = false;
+        for c in colored.chars() {
+            match c {
+                '\x1b' => in_code = true,
+                'm' if in_code => in_code = false,
+                _ if !in_code => stripped.push(c),
+                _ => {}
+            }
+        }
+        assert_eq!(stripped, plain);
+
+        assert_eq!("never".parse::<ColorMode>()?, ColorMode::Never);
+        assert_eq!(Renderer::new(ColorMode::Always), Renderer::Color);
+        assert!("rainbow".parse::<ColorMode>().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,333 @@
+//! JSON-RPC 2.0 service (`--mode rpc`, needs the `rpc` feature): a
+//! machine interface for GUIs and bots, unlike the console made for
+//! humans. Requests are read from the standard input and responses are
+//! written to the standard output, one per line.
+//!
+//! Methods (games and moves are in the JSON shape of `rokumon_core`):
+//!
+//! - `new_game {layout?, cards?, shuffle?, seed?, rules?}` starts a game
+//!   and returns `{game, position}` with the id of the game,
+//! - `get_game {game}` returns the position,
+//! - `legal_moves {game}` returns the moves of the player to move,
+//! - `apply_move {game, move}` plays the move and returns its outcome,
+//! - `best_move {game, depth?, seconds?}` returns the move found by the
+//!   AI (without making it) with its score and principal variation,
+//! - `close_game {game}` forgets the game.
+
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+
+use failure::Fallible;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use rokumon_core::ai::{pp_evaluation, AlphaBetaAI, NoLog};
+use rokumon_core::board::Layout;
+use rokumon_core::card::Deck;
+use rokumon_core::coord::Coord;
+use rokumon_core::game::{Game, GameMove, Rules};
+use rokumon_core::play::Strategy;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Request is fine, but it can't be done (the move is illegal, the game
+/// is unknown and so on).
+const FAILED: i64 = -32000;
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    /// Requests without ids are notifications, which get no response.
+    id: Option<Value>,
+}
+
+#[derive(Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+}
+
+#[derive(Serialize, Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+fn rpc_error(code: i64, message: impl ToString) -> RpcError {
+    RpcError {
+        code,
+        message: message.to_string(),
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct NewGame {
+    layout: String,
+    cards: String,
+    shuffle: bool,
+    seed: Option<u64>,
+    rules: Rules,
+}
+
+impl Default for NewGame {
+    fn default() -> Self {
+        NewGame {
+            layout: String::from("bricks7"),
+            cards: String::from("gggjjjj"),
+            shuffle: true,
+            seed: None,
+            rules: Rules::default(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct GameId {
+    game: u64,
+}
+
+#[derive(Deserialize)]
+struct ApplyMove {
+    game: u64,
+    #[serde(rename = "move")]
+    game_move: GameMove<Coord>,
+}
+
+#[derive(Deserialize)]
+struct BestMove {
+    game: u64,
+    /// Search depth in plies. The AI thinks for `seconds` (2 by
+    /// default) if it's not given.
+    depth: Option<u32>,
+    seconds: Option<u64>,
+}
+
+/// Games played through the service, by their ids.
+#[derive(Default)]
+pub struct Service {
+    games: HashMap<u64, Game>,
+    next_id: u64,
+}
+
+impl Service {
+    /// Handles a line with a request and returns the line with the
+    /// response (none for notifications).
+    pub fn handle(&mut self, line: &str) -> Option<String> {
+        let (id, result) = match serde_json::from_str::<Value>(line) {
+            Err(err) => (Value::Null, Err(rpc_error(PARSE_ERROR, err))),
+            Ok(value) => match serde_json::from_value::<Request>(value) {
+                Err(err) => (Value::Null, Err(rpc_error(INVALID_REQUEST, err))),
+                Ok(request) if request.jsonrpc != "2.0" => {
+                    let id = request.id.unwrap_or(Value::Null);
+                    (id, Err(rpc_error(INVALID_REQUEST, "Only JSON-RPC 2.0 is supported")))
+                }
+                Ok(request) => {
+                    let result = self.call(&request.method, request.params);
+                    (request.id?, result)
+                }
+            },
+        };
+        let (result, error) = match result {
+            Ok(result) => (Some(result), None),
+            Err(error) => (None, Some(error)),
+        };
+        let response = Response {
+            jsonrpc: "2.0",
+            result,
+            error,
+            id,
+        };
+        Some(serde_json::to_string(&response).expect("Responses are serializable"))
+    }
+
+    fn call(&mut self, method: &str, params: Value) -> Result<Value, RpcError> {
+        match method {
+            "new_game" => {
+                let NewGame {
+                    layout,
+                    cards,
+                    shuffle,
+                    seed,
+                    rules,
+                } = if params.is_null() {
+                    NewGame::default()
+                } else {
+                    parse(params)?
+                };
+                let layout: Layout = layout.parse().map_err(|err| rpc_error(INVALID_PARAMS, err))?;
+                let deck = match (shuffle, seed) {
+                    (false, _) => Deck::ordered(&cards),
+                    (true, Some(seed)) => Deck::shuffled_with_seed(&cards, seed),
+                    (true, None) => Deck::shuffled(&cards),
+                }
+                .map_err(|err| rpc_error(INVALID_PARAMS, err))?;
+                let game = Game::try_new(layout, deck, rules).map_err(|err| rpc_error(INVALID_PARAMS, err))?;
+                let position = to_value(&game)?;
+                let id = self.next_id;
+                self.next_id += 1;
+                self.games.insert(id, game);
+                Ok(json!({ "game": id, "position": position }))
+            }
+            "get_game" => {
+                let GameId { game } = parse(params)?;
+                to_value(self.game(game)?)
+            }
+            "legal_moves" => {
+                let GameId { game } = parse(params)?;
+                let game = self.game(game)?;
+                let moves = if game.is_game_over() {
+                    vec![]
+                } else {
+                    game.generate_moves()
+                };
+                to_value(&moves)
+            }
+            "apply_move" => {
+                let ApplyMove { game, game_move } = parse(params)?;
+                let game = self.game_mut(game)?;
+                if game.is_game_over() {
+                    return Err(rpc_error(FAILED, "The game is over"));
+                }
+                let outcome = game.apply_move(&game_move).map_err(|err| rpc_error(FAILED, err))?;
+                to_value(&outcome)
+            }
+            "best_move" => {
+                let BestMove { game, depth, seconds } = parse(params)?;
+                let game = self.game(game)?;
+                if game.is_game_over() {
+                    return Err(rpc_error(FAILED, "The game is over"));
+                }
+                let mut ai = match depth {
+                    Some(depth) => AlphaBetaAI::with_depth(game.player1_moves, depth),
+                    None => AlphaBetaAI::with_duration(game.player1_moves, seconds.unwrap_or(2)),
+                };
+                // Standard output is taken by the responses.
+                ai.set_log(Arc::new(NoLog));
+                let best = ai.get_move(game);
+                let score = ai.evaluation().unwrap_or_default();
+                Ok(json!({
+                    "move": to_value(&best)?,
+                    "score": score,
+                    "evaluation": pp_evaluation(score, game.ply_to_be_played()),
+                    "pv": to_value(ai.principal_variation())?,
+                }))
+            }
+            "close_game" => {
+                let GameId { game } = parse(params)?;
+                Ok(Value::Bool(self.games.remove(&game).is_some()))
+            }
+            _ => Err(rpc_error(METHOD_NOT_FOUND, format!("Unknown method: {}", method))),
+        }
+    }
+
+    fn game(&self, id: u64) -> Result<&Game, RpcError> {
+        self.games
+            .get(&id)
+            .ok_or_else(|| rpc_error(FAILED, format!("Unknown game: {}", id)))
+    }
+
+    fn game_mut(&mut self, id: u64) -> Result<&mut Game, RpcError> {
+        self.games
+            .get_mut(&id)
+            .ok_or_else(|| rpc_error(FAILED, format!("Unknown game: {}", id)))
+    }
+}
+
+fn parse<T: DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|err| rpc_error(INVALID_PARAMS, err))
+}
+
+fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|err| rpc_error(FAILED, err))
+}
+
+/// Serves the requests until the input is closed.
+pub fn serve(input: impl BufRead, mut output: impl Write) -> Fallible<()> {
+    let mut service = Service::default();
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = service.handle(&line) {
+            writeln!(output, "{}", response)?;
+            output.flush()?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn call(service: &mut Service, request: Value) -> Value {
+        let response = service.handle(&request.to_string()).unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    #[test]
+    fn test_service() {
+        let mut service = Service::default();
+        let mut request = |method: &str, params: Value| {
+            call(
+                &mut service,
+                json!({ "jsonrpc": "2.0", "method": method, "params": params, "id": 1 }),
+            )
+        };
+
+        let created = request("new_game", json!({ "cards": "jggjgjj", "shuffle": false }));
+        let id = created["result"]["game"].clone();
+        assert_eq!(created["result"]["position"]["player1_moves"], true);
+
+        let moves = request("legal_moves", json!({ "game": id }));
+        let first = moves["result"][0].clone();
+        assert!(first.get("Place").is_some());
+
+        let outcome = request("apply_move", json!({ "game": id, "move": first }));
+        assert_eq!(outcome["result"]["result"], "InProgress");
+        let illegal = request("apply_move", json!({ "game": id, "move": first }));
+        assert_eq!(illegal["error"]["code"], FAILED);
+
+        let best = request("best_move", json!({ "game": id, "depth": 2 }));
+        assert!(best["result"]["move"].is_object());
+        let position = request("get_game", json!({ "game": id }));
+        assert_eq!(position["result"]["history"].as_array().unwrap().len(), 1);
+
+        assert_eq!(request("close_game", json!({ "game": id }))["result"], true);
+        assert_eq!(request("get_game", json!({ "game": id }))["error"]["code"], FAILED);
+        assert_eq!(request("resign", json!({}))["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(request("get_game", json!({ "id": 0 }))["error"]["code"], INVALID_PARAMS);
+        assert_eq!(
+            request("new_game", json!({ "cards": "gggjjj" }))["error"]["code"],
+            INVALID_PARAMS
+        );
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let mut service = Service::default();
+        let response = service.handle("{").unwrap();
+        assert!(response.contains(&PARSE_ERROR.to_string()));
+        let response = call(&mut service, json!({ "jsonrpc": "1.0", "method": "new_game", "id": 7 }));
+        assert_eq!(response["error"]["code"], INVALID_REQUEST);
+        assert_eq!(response["id"], 7);
+        // Notifications get no response.
+        assert!(service
+            .handle(&json!({ "jsonrpc": "2.0", "method": "new_game" }).to_string())
+            .is_none());
+        assert_eq!(service.games.len(), 1);
+    }
+}
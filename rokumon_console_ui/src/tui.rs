@@ -0,0 +1,515 @@
+/// Full-screen terminal UI for human players (`--tui`, needs the `tui`
+/// feature). Moves are entered with the cursor: pick a die from the
+/// stock or a card to move a die from, then pick the target card.
+/// Moves can still be typed after `:` (surprise moves need that).
+use std::io;
+use std::process::exit;
+use std::time::{Duration, Instant};
+
+use failure::{bail, Fallible};
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use rokumon_core::ai::{pp_evaluation, AlphaBetaAI, EvalTerms, EvalWeights};
+use rokumon_core::card::{DiceColor, Die};
+use rokumon_core::clock::Clock;
+use rokumon_core::coord::Coord;
+use rokumon_core::game::{Game, GameMove};
+use rokumon_core::play::{Decision, Strategy};
+
+use crate::parsers;
+
+/// Time given to the AI when asked for an analysis with `a`.
+const ANALYSIS_SECONDS: u64 = 3;
+
+/// How often the clocks are redrawn while waiting for a key.
+const REFRESH: Duration = Duration::from_millis(200);
+
+pub struct TuiHuman {
+    /// Plies taken back by `u`: two against an AI, so that it's our
+    /// turn again.
+    undo_plies: usize,
+}
+
+impl TuiHuman {
+    pub fn new(against_ai: bool) -> Self {
+        TuiHuman {
+            undo_plies: if against_ai { 2 } else { 1 },
+        }
+    }
+}
+
+impl Strategy for TuiHuman {
+    fn name(&self) -> String {
+        "Human".to_string()
+    }
+
+    fn get_move(&mut self, game: &Game) -> GameMove<Coord> {
+        loop {
+            match self.decide(game, None) {
+                Decision::Play(m) => break m,
+                _ => continue,
+            }
+        }
+    }
+
+    fn decide(&mut self, game: &Game, clock: Option<&Clock>) -> Decision {
+        let mut screen = Screen::new(game, clock.copied(), self.undo_plies);
+        let mut terminal = ratatui::init();
+        let result = screen.run(&mut terminal);
+        ratatui::restore();
+        match result {
+            Ok(Some(decision)) => decision,
+            Ok(None) => {
+                println!("Good bye.");
+                exit(0);
+            }
+            Err(err) => {
+                println!("Exiting. EГГОГ: {:?}", err);
+                exit(1);
+            }
+        }
+    }
+}
+
+/// Where the cursor is: on a die in the stock of the player to move
+/// or on a card.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum Cursor {
+    Stock(usize),
+    Card(Coord),
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+/// State of the screen during one turn.
+struct Screen<'a> {
+    game: &'a Game,
+    clock: Option<Clock>,
+    started: Instant,
+    undo_plies: usize,
+    cursor: Cursor,
+    /// Where the move starts, once picked.
+    source: Option<Cursor>,
+    /// Move being typed after `:`.
+    typed: Option<String>,
+    message: String,
+    analysis: Vec<String>,
+}
+
+impl<'a> Screen<'a> {
+    fn new(game: &'a Game, clock: Option<Clock>, undo_plies: usize) -> Self {
+        let cursor = if game.stock(game.player1_moves).is_empty() {
+            Cursor::Card(*game.board.coords_iter().min_by_key(|c| (c.y, c.x)).unwrap())
+        } else {
+            Cursor::Stock(0)
+        };
+        Screen {
+            game,
+            clock,
+            started: Instant::now(),
+            undo_plies,
+            cursor,
+            source: None,
+            typed: None,
+            message: String::from("Pick a die or a card with arrows and Enter, `?` for help."),
+            analysis: vec![],
+        }
+    }
+
+    /// Handles the keys until the player decides what to do. Returns
+    /// None if they want to quit.
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<Option<Decision>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            if !event::poll(REFRESH)? {
+                continue;
+            }
+            let key = match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press => key.code,
+                _ => continue,
+            };
+            if self.typed.is_some() {
+                if let Some(decision) = self.on_typing(key) {
+                    return Ok(Some(decision));
+                }
+                continue;
+            }
+            match key {
+                KeyCode::Char('q') => return Ok(None),
+                KeyCode::Char('u') => return Ok(Some(Decision::TakeBack(self.undo_plies))),
+                KeyCode::Char('r') => return Ok(Some(Decision::Replay(self.undo_plies))),
+                KeyCode::Up => self.step(Direction::Up),
+                KeyCode::Down => self.step(Direction::Down),
+                KeyCode::Left => self.step(Direction::Left),
+                KeyCode::Right => self.step(Direction::Right),
+                KeyCode::Esc => {
+                    self.source = None;
+                    self.message.clear();
+                }
+                KeyCode::Enter => match self.select() {
+                    Ok(Some(m)) => return Ok(Some(Decision::Play(m))),
+                    Ok(None) => {}
+                    Err(err) => self.message = format!("[ERR] {}", err),
+                },
+                KeyCode::Char('f') => match self.fight() {
+                    Ok(m) => return Ok(Some(Decision::Play(m))),
+                    Err(err) => self.message = format!("[ERR] {}", err),
+                },
+                KeyCode::Char(':') => {
+                    self.typed = Some(String::new());
+                    self.message = String::from("Type a move, like `surprise r1c1 to r0c2`.");
+                }
+                KeyCode::Char('a') => {
+                    self.message = format!("AI is thinking for {}s...", ANALYSIS_SECONDS);
+                    terminal.draw(|frame| self.draw(frame))?;
+                    self.analyze();
+                    self.message.clear();
+                }
+                KeyCode::Char('?') | KeyCode::Char('h') => self.message = String::from(
+                    "Enter: pick/play, Esc: cancel, f: fight, :: type a move, a: analyse, u: undo, r: redo, q: quit",
+                ),
+                _ => {}
+            }
+        }
+    }
+
+    fn on_typing(&mut self, key: KeyCode) -> Option<Decision> {
+        let typed = self.typed.as_mut().unwrap();
+        match key {
+            KeyCode::Char(c) => typed.push(c),
+            KeyCode::Backspace => {
+                typed.pop();
+            }
+            KeyCode::Esc => {
+                self.typed = None;
+                self.message.clear();
+            }
+            KeyCode::Enter => {
+                let parsed = parsers::parse_move(typed.trim())
+                    .map_err(failure::Error::from)
+                    .and_then(|m| self.game.convert_move_coords(&m));
+                match parsed.and_then(|m| self.game.validate_move(&m).map(|_| m)) {
+                    Ok(m) => return Some(Decision::Play(m)),
+                    Err(err) => self.message = format!("[ERR] {}", err),
+                }
+            }
+            _ => {}
+        }
+        None
+    }
+
+    fn step(&mut self, direction: Direction) {
+        self.cursor = step(self.game, self.cursor, direction);
+    }
+
+    /// Picks the source of the move under the cursor or, if it's
+    /// already picked, returns the move to the card under the cursor.
+    fn select(&mut self) -> Fallible<Option<GameMove<Coord>>> {
+        match (self.source, self.cursor) {
+            (_, Cursor::Stock(_)) => {
+                self.source = Some(self.cursor);
+                self.message = String::from("Now pick the card to place the die on.");
+                Ok(None)
+            }
+            (None, Cursor::Card(coord)) => {
+                let user_coord = self.game.board.userify_coord(&coord);
+                let moves = self.game.generate_moves();
+                if !moves.iter().any(|m| starts_at(m, coord)) {
+                    bail!("No moves from {}", user_coord);
+                }
+                self.source = Some(self.cursor);
+                self.message = format!("Now pick the card to move from {} to.", user_coord);
+                Ok(None)
+            }
+            (Some(source), Cursor::Card(target)) => match find_move(self.game, source, target) {
+                Some(m) => Ok(Some(m)),
+                None => bail!(
+                    "No move to {}, Esc to pick another die or card",
+                    self.game.board.userify_coord(&target)
+                ),
+            },
+        }
+    }
+
+    fn fight(&self) -> Fallible<GameMove<Coord>> {
+        match self.cursor {
+            Cursor::Card(coord) => {
+                let m = GameMove::Fight(coord);
+                self.game.validate_move(&m)?;
+                Ok(m)
+            }
+            Cursor::Stock(_) => bail!("Fights are started on cards"),
+        }
+    }
+
+    fn analyze(&mut self) {
+        let mut ai = AlphaBetaAI::with_duration(self.game.player1_moves, ANALYSIS_SECONDS);
+        let ply = self.game.ply_to_be_played();
+        self.analysis = ai
+            .analyze(self.game)
+            .iter()
+            .take(5)
+            .map(|(m, score, _)| format!("{:>10}  {}", pp_evaluation(*score, ply), self.game.userify_move(m)))
+            .collect();
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [left, right] = Layout::horizontal([Constraint::Min(50), Constraint::Length(40)]).areas(frame.area());
+        let [board, stock, status] =
+            Layout::vertical([Constraint::Min(5), Constraint::Length(3), Constraint::Length(3)]).areas(left);
+        let [clocks, eval, history] =
+            Layout::vertical([Constraint::Length(4), Constraint::Length(9), Constraint::Min(3)]).areas(right);
+
+        frame.render_widget(
+            Paragraph::new(self.board_lines()).block(Block::bordered().title("Board")),
+            board,
+        );
+        frame.render_widget(
+            Paragraph::new(self.stock_line()).block(Block::bordered().title(if self.game.player1_moves {
+                "Stock of Player 1 (to move)"
+            } else {
+                "Stock of Player 2 (to move)"
+            })),
+            stock,
+        );
+        let status_line = match &self.typed {
+            Some(typed) => format!("> {}_", typed),
+            None => self.message.clone(),
+        };
+        frame.render_widget(Paragraph::new(status_line).block(Block::bordered()), status);
+
+        frame.render_widget(
+            Paragraph::new(self.clock_lines()).block(Block::bordered().title("Clocks")),
+            clocks,
+        );
+        frame.render_widget(
+            Paragraph::new(self.eval_lines()).block(Block::bordered().title("Evaluation")),
+            eval,
+        );
+
+        let moves: Vec<_> = self
+            .game
+            .moves()
+            .iter()
+            .enumerate()
+            .map(|(ix, m)| Line::from(format!("{:>3}. {}", ix + 1, self.game.userify_move(m))))
+            .collect();
+        // Show the latest moves when they don't fit.
+        let height = history.height.saturating_sub(2) as usize;
+        let skip = moves.len().saturating_sub(height);
+        frame.render_widget(
+            Paragraph::new(moves[skip..].to_vec()).block(Block::bordered().title("Moves")),
+            history,
+        );
+    }
+
+    fn highlight(&self, cursor: Cursor) -> Style {
+        let mut style = Style::default();
+        if self.source == Some(cursor) {
+            style = style.fg(Color::Yellow).add_modifier(Modifier::BOLD);
+        }
+        if self.cursor == cursor {
+            style = style.add_modifier(Modifier::REVERSED);
+        }
+        style
+    }
+
+    /// Same layout as `Display for Board`.
+    fn board_lines(&self) -> Vec<Line<'static>> {
+        let board = &self.game.board;
+        let mut lines = vec![];
+        for row in board.drawing_rows() {
+            let mut cards = vec![];
+            let mut cards_width = 0;
+            for drawn in &row {
+                let card = drawn.card;
+                let style = self.highlight(Cursor::Card(*drawn.coord));
+                cards.push(Span::raw(" ".repeat(drawn.indent - cards_width)));
+                cards.push(Span::styled(format!("[{} ", card.kind.letter()), style));
+                for (ix, die) in card.dice.iter().enumerate() {
+                    if ix > 0 {
+                        cards.push(Span::styled("<", style));
+                    }
+                    cards.push(Span::styled(die.to_string(), style.patch(die_style(die))));
+                }
+                cards.push(Span::styled(format!("{}]", " ".repeat(drawn.padding())), style));
+                cards_width = drawn.end();
+            }
+            lines.push(Line::from(board.drawing_labels(&row)));
+            lines.push(Line::from(cards));
+        }
+        lines
+    }
+
+    fn stock_line(&self) -> Line<'static> {
+        let stock = self.game.stock(self.game.player1_moves);
+        if stock.is_empty() {
+            return Line::from("empty");
+        }
+        let mut spans = vec![];
+        for (ix, die) in stock.iter().enumerate() {
+            let style = self.highlight(Cursor::Stock(ix));
+            spans.push(Span::styled(die.to_string(), style.patch(die_style(die))));
+            spans.push(Span::raw(" "));
+        }
+        Line::from(spans)
+    }
+
+    fn clock_lines(&self) -> Vec<Line<'static>> {
+        let clock = match self.clock {
+            Some(clock) => clock,
+            None => return vec![Line::from("No time control")],
+        };
+        [("Player 1", true), ("Player 2", false)]
+            .iter()
+            .map(|&(name, player1)| {
+                let mut left = clock.time_left(player1);
+                if player1 == self.game.player1_moves {
+                    left = left.saturating_sub(self.started.elapsed());
+                }
+                Line::from(format!("{}: {}:{:02}", name, left.as_secs() / 60, left.as_secs() % 60))
+            })
+            .collect()
+    }
+
+    fn eval_lines(&self) -> Vec<Line<'static>> {
+        let score = EvalTerms::of(self.game).score(&EvalWeights::default());
+        let mut lines = vec![Line::from(format!("Static (for Player 1): {}", score))];
+        if self.analysis.is_empty() {
+            lines.push(Line::from("Press `a` for AI analysis"));
+        }
+        lines.extend(self.analysis.iter().map(|s| Line::from(s.clone())));
+        lines
+    }
+}
+
+fn die_style(die: &Die) -> Style {
+    Style::default().fg(match die.color {
+        DiceColor::Red => Color::Red,
+        DiceColor::Black => Color::DarkGray,
+        DiceColor::White => Color::White,
+    })
+}
+
+/// Whether the move takes a die from the card at `coord` (or starts a
+/// fight there).
+fn starts_at(m: &GameMove<Coord>, coord: Coord) -> bool {
+    match m {
+        GameMove::Move(_, from, _) | GameMove::Surprise(from, _) => *from == coord,
+        GameMove::Fight(place) => *place == coord,
+        _ => false,
+    }
+}
+
+/// Legal move from `source` to the card at `target`. Picking the same
+/// card twice starts a fight on it.
+fn find_move(game: &Game, source: Cursor, target: Coord) -> Option<GameMove<Coord>> {
+    let stock = game.stock(game.player1_moves);
+    game.moves_iter().find(|m| match (source, m) {
+        (Cursor::Stock(ix), GameMove::Place(die, to)) => stock.get(ix) == Some(die) && *to == target,
+        (Cursor::Card(coord), GameMove::Move(_, from, to)) => *from == coord && *to == target,
+        (Cursor::Card(coord), GameMove::Fight(place)) => coord == target && *place == coord,
+        _ => false,
+    })
+}
+
+/// Cursor moved in the given direction. Up and down go to the nearest
+/// card of the next row; the stock is below the board.
+fn step(game: &Game, cursor: Cursor, direction: Direction) -> Cursor {
+    let board = &game.board;
+    let stock_len = game.stock(game.player1_moves).len();
+    let (_, _, top, bottom) = board.bounding_box();
+
+    // The card in row `y` nearest to the drawing column.
+    let nearest = |y: i8, column: i16| {
+        board
+            .row_positions_iter(y)
+            .min_by_key(|c| (board.drawing_column(c) - column).abs())
+            .copied()
+    };
+
+    match (cursor, direction) {
+        (Cursor::Stock(ix), Direction::Left) => Cursor::Stock(ix.saturating_sub(1)),
+        (Cursor::Stock(ix), Direction::Right) => Cursor::Stock((ix + 1).min(stock_len.saturating_sub(1))),
+        (Cursor::Stock(_), Direction::Up) => (top..=bottom)
+            .rev()
+            .find_map(|y| nearest(y, i16::MIN / 2))
+            .map_or(cursor, Cursor::Card),
+        (Cursor::Stock(_), Direction::Down) => cursor,
+        (Cursor::Card(coord), Direction::Left) | (Cursor::Card(coord), Direction::Right) => {
+            let row: Vec<_> = board.row_positions_iter(coord.y).copied().collect();
+            let ix = row.iter().position(|c| *c == coord).unwrap();
+            let ix = if direction == Direction::Left {
+                ix.saturating_sub(1)
+            } else {
+                (ix + 1).min(row.len() - 1)
+            };
+            Cursor::Card(row[ix])
+        }
+        (Cursor::Card(coord), Direction::Up) => {
+            let column = board.drawing_column(&coord);
+            (top..coord.y)
+                .rev()
+                .find_map(|y| nearest(y, column))
+                .map_or(cursor, Cursor::Card)
+        }
+        (Cursor::Card(coord), Direction::Down) => {
+            let column = board.drawing_column(&coord);
+            match (coord.y + 1..=bottom).find_map(|y| nearest(y, column)) {
+                Some(c) => Cursor::Card(c),
+                None if stock_len > 0 => Cursor::Stock(0),
+                None => cursor,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rokumon_core::board::Layout;
+    use rokumon_core::card::Deck;
+    use rokumon_core::coord::UserCoord;
+    use rokumon_core::game::Rules;
+
+    #[test]
+    fn test_cursor_moves() -> Fallible<()> {
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("JGJGJGG")?, Rules::default());
+        let card = |row, card| -> Fallible<Cursor> {
+            Ok(Cursor::Card(
+                game.board.convert_coordinates(&UserCoord::new(row, card))?,
+            ))
+        };
+
+        // Up from the stock goes to the first card of the bottom row.
+        let bottom_left = step(&game, Cursor::Stock(0), Direction::Up);
+        assert_eq!(bottom_left, card(2, 1)?);
+        assert_eq!(step(&game, bottom_left, Direction::Down), Cursor::Stock(0));
+        assert_eq!(step(&game, bottom_left, Direction::Left), bottom_left);
+        assert_eq!(step(&game, bottom_left, Direction::Right), card(2, 2)?);
+        assert_eq!(step(&game, Cursor::Stock(0), Direction::Left), Cursor::Stock(0));
+        assert_eq!(step(&game, Cursor::Stock(0), Direction::Right), Cursor::Stock(1));
+
+        // Placing the first die of the stock.
+        let target = match card(1, 1)? {
+            Cursor::Card(c) => c,
+            _ => unreachable!(),
+        };
+        let m = find_move(&game, Cursor::Stock(0), target).unwrap();
+        assert_eq!(m, GameMove::Place(game.stock(true)[0], target));
+        game.apply_move(&m)?;
+        assert!(find_move(&game, Cursor::Card(target), target).is_none());
+        Ok(())
+    }
+}
@@ -1,123 +1,633 @@
-// use crate::card::{DiceColor, Die};
+use crate::card::{Card, CardKind, Die};
+use crate::clock::{Clock, StdTime, TimeSource};
 use crate::coord::Coord;
-use crate::game::{Game, GameMove, GameResult};
+use crate::game::{Game, GameMove, GameResult, PositionKey};
+use crate::nn::Mlp;
 use crate::play::Strategy;
+use crate::search::{self, SearchLimits, TranspositionTable};
+use crate::tablebase::Tablebase;
 
-use cfg_if::cfg_if;
-use rubot::{self, Bot, Depth, ToCompletion};
+use failure::{bail, ensure, format_err, Fallible};
+use rubot::alpha_beta::Action;
+use rubot::{self, Bot, Depth, IntoRunCondition, RunCondition, ToCompletion};
 
+use std::cmp::Reverse;
+use std::fmt;
 use std::i32;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-impl rubot::Game for Game {
+/// Evaluation of a won game (before subtracting the number of plies,
+/// see `win_score`).
+const WIN: i32 = i32::MAX;
+
+/// Evaluations with absolute value above this mean a decided game.
+const WIN_THRESHOLD: i32 = WIN - 10_000;
+
+/// Evaluation at which the first player is expected to score about
+/// 73% (see `expected_result`).
+const EXPECTED_RESULT_SCALE: f64 = 100.0;
+
+/// Size of the transposition table used by the parallel search (2^20
+/// entries, 16 Mb).
+pub const TT_BITS: u32 = 20;
+
+/// Maximal size of the tablebases generated during the game.
+const TABLEBASE_MAX_POSITIONS: usize = 1 << 16;
+
+/// Weights of the evaluation terms.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct EvalWeights {
+    /// Uncovered die, for every triple the card is in.
+    pub triples: i32,
+    /// Die covering another die.
+    pub covered: i32,
+    /// Die in the player's stock.
+    pub stock: i32,
+    /// Legal move.
+    pub mobility: i32,
+    /// Two dice in a triple which can be completed with one move.
+    pub threats: i32,
+}
+
+impl Default for EvalWeights {
+    fn default() -> Self {
+        EvalWeights {
+            triples: 10,
+            covered: 10,
+            stock: 3,
+            mobility: 1,
+            threats: 15,
+        }
+    }
+}
+
+impl EvalWeights {
+    /// Names of all the weights.
+    pub const NAMES: [&'static str; 5] = ["triples", "covered", "stock", "mobility", "threats"];
+
+    fn weight_mut(&mut self, name: &str) -> Fallible<&mut i32> {
+        Ok(match name {
+            "triples" => &mut self.triples,
+            "covered" => &mut self.covered,
+            "stock" => &mut self.stock,
+            "mobility" => &mut self.mobility,
+            "threats" => &mut self.threats,
+            _ => bail!("Unknown evaluation weight: {}", name),
+        })
+    }
+
+    /// Returns a weight by its name.
+    pub fn get(&self, name: &str) -> Fallible<i32> {
+        let mut weights = *self;
+        weights.weight_mut(name).map(|w| *w)
+    }
+
+    /// Sets a weight by its name.
+    pub fn set(&mut self, name: &str, value: i32) -> Fallible<()> {
+        *self.weight_mut(name)? = value;
+        Ok(())
+    }
+
+    /// Writes the weights in the format understood by `from_toml`.
+    pub fn to_toml(&self) -> String {
+        Self::NAMES
+            .iter()
+            .map(|name| format!("{} = {}\n", name, self.get(name).unwrap()))
+            .collect()
+    }
+
+    /// Reads weights from a TOML file with `name = value` lines (only
+    /// this subset of TOML is supported, plus comments and section
+    /// headers, which are ignored). Weights not mentioned in the file
+    /// keep their default values.
+    pub fn from_toml(toml: &str) -> Fallible<Self> {
+        let mut weights = EvalWeights::default();
+        for line in toml.lines() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() || line.starts_with('[') {
+                continue;
+            }
+            weights.set_from_str(line, '=')?;
+        }
+        Ok(weights)
+    }
+
+    fn set_from_str(&mut self, s: &str, separator: char) -> Fallible<()> {
+        let mut parts = s.splitn(2, separator);
+        let name = parts.next().unwrap().trim();
+        let value = parts
+            .next()
+            .ok_or_else(|| format_err!("Expected `name{}value` for evaluation weight, got: {}", separator, s))?;
+        match value.trim().parse() {
+            Ok(value) => self.set(name, value),
+            Err(_) => bail!("Can't parse value of evaluation weight {}: {}", name, value),
+        }
+    }
+}
+
+/// Parses weights like "triples=3,covered=1" (the rest keep their
+/// default values).
+impl FromStr for EvalWeights {
+    type Err = failure::Error;
+    fn from_str(s: &str) -> Fallible<Self> {
+        let mut weights = EvalWeights::default();
+        for part in s.split(',').filter(|p| !p.trim().is_empty()) {
+            weights.set_from_str(part, '=')?;
+        }
+        Ok(weights)
+    }
+}
+
+// triples=10,covered=10,stock=3,mobility=1,threats=15
+impl fmt::Display for EvalWeights {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "triples={},covered={},stock={},mobility={},threats={}",
+            self.triples, self.covered, self.stock, self.mobility, self.threats
+        )
+    }
+}
+
+/// Evaluation function used by the AI.
+#[derive(Debug, Clone)]
+pub enum Evaluator {
+    /// Weighted sum of `EvalTerms`.
+    Terms(EvalWeights),
+    /// Neural network over `nn_features`, its output is multiplied by
+    /// `NN_SCALE`.
+    NeuralNet(Arc<Mlp>),
+}
+
+impl Default for Evaluator {
+    fn default() -> Self {
+        Evaluator::Terms(EvalWeights::default())
+    }
+}
+
+impl Evaluator {
+    /// Creates a neural network evaluator checking that the network
+    /// fits `nn_features`.
+    pub fn neural_net(mlp: Mlp) -> Fallible<Self> {
+        ensure!(
+            mlp.inputs() == NN_FEATURES && mlp.outputs() == 1,
+            "Evaluation network should have {} inputs and 1 output, has {} and {}",
+            NN_FEATURES,
+            mlp.inputs(),
+            mlp.outputs()
+        );
+        Ok(Evaluator::NeuralNet(Arc::new(mlp)))
+    }
+
+    /// Evaluation of a game in progress from the first player's
+    /// perspective.
+    fn evaluate(&self, game: &Game) -> i32 {
+        match self {
+            Evaluator::Terms(weights) => EvalTerms::of(game).score(weights),
+            Evaluator::NeuralNet(mlp) => {
+                let eval = (mlp.forward(&nn_features(game))[0] * NN_SCALE) as i32;
+                // Can't be confused with decided games.
+                eval.clamp(-WIN_THRESHOLD + 1, WIN_THRESHOLD - 1)
+            }
+        }
+    }
+}
+
+/// Multiplier for the output of evaluation networks.
+pub const NN_SCALE: f32 = 100.0;
+
+/// Number of cards encoded by `nn_features`.
+const NN_CARDS: usize = 7;
+
+/// Number of values returned by `nn_features`.
+pub const NN_FEATURES: usize = NN_CARDS * 5 + 5;
+
+/// Fixed encoding of a position as input for evaluation networks
+/// (from the first player's perspective, dice of the first player are
+/// encoded as 1 and of the second one as -1). For each card (in the
+/// order of their coordinates, padded with zeros up to `NN_CARDS`):
+/// whether it's jade, whether it's gold, owner of the top die, owner
+/// of the covered die and number of triples the card is in. Then
+/// sizes of both stocks, surprises used by both players and whose
+/// move it is.
+pub fn nn_features(game: &Game) -> Vec<f32> {
+    let owner = |d: Option<&Die>| match d {
+        Some(d) if d.belongs_to_player1() => 1.0,
+        Some(_) => -1.0,
+        None => 0.0,
+    };
+
+    let mut features = Vec::with_capacity(NN_FEATURES);
+    for (coord, card) in game.board.coord_cards_iter().take(NN_CARDS) {
+        let covered = if card.dice.len() > 1 { card.dice.first() } else { None };
+        features.extend_from_slice(&[
+            if card.kind == CardKind::Jade { 1.0 } else { 0.0 },
+            if card.kind == CardKind::Gold { 1.0 } else { 0.0 },
+            owner(card.top_die()),
+            owner(covered),
+            game.board.num_of_adjacent_triples(*coord) as f32,
+        ]);
+    }
+    features.resize(NN_CARDS * 5, 0.0);
+
+    features.extend_from_slice(&[
+        game.stock(true).len() as f32,
+        game.stock(false).len() as f32,
+        f32::from(game.surprises_used(true)),
+        f32::from(game.surprises_used(false)),
+        if game.player1_moves { 1.0 } else { -1.0 },
+    ]);
+    features
+}
+
+/// Game together with the evaluation function (and the tablebase
+/// with exact values, if any), this is what `rubot` searches.
+#[derive(Debug, Clone)]
+pub struct EvaluatedGame {
+    pub game: Game,
+    pub evaluator: Evaluator,
+    pub tablebase: Option<Arc<Tablebase>>,
+}
+
+impl rubot::Game for EvaluatedGame {
     type Player = bool;
     type Fitness = i32;
     type Action = GameMove<Coord>;
     type Actions = Vec<GameMove<Coord>>;
 
     fn actions(&self, player: Self::Player) -> (bool, Self::Actions) {
-        (player == self.player1_moves, self.generate_moves())
+        (player == self.game.player1_moves, self.game.generate_moves())
     }
 
     fn execute(&mut self, action: &Self::Action, player: Self::Player) -> Self::Fitness {
-        self.apply_move_unchecked(action);
-        evaluate_for_player(self, player)
+        self.game.apply_move_unchecked(action);
+        match self.tablebase.as_ref().and_then(|tb| tb.probe(&self.game)) {
+            Some(outcome) => {
+                let score = outcome.score(self.game.ply_to_be_played());
+                if player == self.game.player1_moves {
+                    score
+                } else {
+                    -score
+                }
+            }
+            None => evaluate_for_player(&self.game, player, &self.evaluator),
+        }
     }
 
+    // Nothing is better than winning with the next move.
     #[inline]
     fn is_upper_bound(&self, fitness: Self::Fitness, _player: Self::Player) -> bool {
-        fitness == i32::MAX
+        fitness >= win_score(self.game.ply_to_be_played() + 1)
     }
 
     #[inline]
     fn is_lower_bound(&self, fitness: Self::Fitness, _player: Self::Player) -> bool {
-        fitness == i32::MIN
+        fitness <= -win_score(self.game.ply_to_be_played() + 1)
+    }
+}
+
+/// Evaluation of a game won on a given ply (counted from the start of
+/// the game). Faster wins are evaluated higher and losses are
+/// evaluated as negated wins, so slower losses are preferred too.
+pub fn win_score(ply: usize) -> i32 {
+    WIN - ply as i32
+}
+
+/// Whether the evaluation means that the game is decided (won or
+/// lost).
+pub fn is_decided(score: i32) -> bool {
+    score.abs() >= WIN_THRESHOLD
+}
+
+/// Expected result of the game (1 for a win, 0 for a loss) for the
+/// player with the given evaluation, which is mapped to it with a
+/// logistic function. Useful for displaying the evaluations.
+pub fn expected_result(score: i32) -> f64 {
+    if is_decided(score) {
+        if score > 0 {
+            1.0
+        } else {
+            0.0
+        }
+    } else {
+        1.0 / (1.0 + (-f64::from(score) / EXPECTED_RESULT_SCALE).exp())
     }
 }
 
-fn evaluate_for_player(game: &Game, player: bool) -> i32 {
-    let eval = evaluate_for_first_player(game);
+pub(crate) fn evaluate_for_player(game: &Game, player: bool, evaluator: &Evaluator) -> i32 {
+    let eval = evaluate_for_first_player(game, evaluator);
     if player {
         eval
     } else {
-        if eval == i32::MAX {
-            i32::MIN
-        } else if eval == i32::MIN {
-            i32::MAX
-        } else {
-            -eval
-        }
+        -eval
     }
 }
 
-fn evaluate_for_first_player(game: &Game) -> i32 {
+/// Evaluation of the position from the first player's perspective.
+pub fn evaluate_for_first_player(game: &Game, evaluator: &Evaluator) -> i32 {
     match game.result {
-        GameResult::FirstPlayerWon => std::i32::MAX,
-        GameResult::SecondPlayerWon => std::i32::MIN,
-        GameResult::InProgress => {
-            // Count every uncovered die as much time as it's in
-            // triples (i.e. central cards will be counted as 2 and
-            // side cards as 1 in a typical Bricks7 layout). Get
-            // penalty for covered dice.
-            game.board
+        GameResult::FirstPlayerWon => win_score(game.ply_to_be_played()),
+        GameResult::SecondPlayerWon => -win_score(game.ply_to_be_played()),
+        GameResult::InProgress => evaluator.evaluate(game),
+    }
+}
+
+/// Values of the evaluation terms in a position (from the first
+/// player's perspective). Evaluation is their weighted sum.
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+pub struct EvalTerms {
+    pub triples: i32,
+    pub covered: i32,
+    pub stock: i32,
+    pub mobility: i32,
+    pub threats: i32,
+}
+
+impl EvalTerms {
+    pub fn of(game: &Game) -> Self {
+        let (triples, covered) = triples_terms(game);
+        EvalTerms {
+            triples,
+            covered,
+            stock: game.stock(true).len() as i32 - game.stock(false).len() as i32,
+            mobility: mobility(game, true) - mobility(game, false),
+            threats: threats(game, true) - threats(game, false),
+        }
+    }
+
+    pub fn score(&self, weights: &EvalWeights) -> i32 {
+        weights.triples * self.triples
+            + weights.covered * self.covered
+            + weights.stock * self.stock
+            + weights.mobility * self.mobility
+            + weights.threats * self.threats
+    }
+
+    /// Values of the terms together with their names (see
+    /// `EvalWeights::NAMES`).
+    pub fn named(&self) -> [(&'static str, i32); 5] {
+        [
+            ("triples", self.triples),
+            ("covered", self.covered),
+            ("stock", self.stock),
+            ("mobility", self.mobility),
+            ("threats", self.threats),
+        ]
+    }
+}
+
+/// Evaluation terms of a position together with the contributions of
+/// every card to the `triples` and `covered` ones, for explaining the
+/// evaluation to humans.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct EvalBreakdown {
+    pub terms: EvalTerms,
+    /// Cards with dice and their `triples` and `covered` values.
+    pub cards: Vec<(Coord, i32, i32)>,
+}
+
+impl EvalBreakdown {
+    pub fn of(game: &Game) -> Self {
+        EvalBreakdown {
+            terms: EvalTerms::of(game),
+            cards: game
+                .board
                 .coord_cards_iter()
-                .map(|(coord, card)| match card.dice.as_slice() {
-                    [d1] => {
-                        let triples = game.board.num_of_adjacent_triples(*coord) as i32;
-                        if d1.belongs_to_player1() {
-                            triples
-                        } else {
-                            -triples
-                        }
-                    }
-                    [_, d2] => {
-                        let triples = game.board.num_of_adjacent_triples(*coord) as i32;
-                        if d2.belongs_to_player1() {
-                            triples + 1
-                        } else {
-                            -triples - 1
-                        }
-                    }
-                    _ => 0, // Either empty (doesn't affect the score) or 3 (game over)
+                .filter(|(_, card)| !card.dice.is_empty())
+                .map(|(coord, card)| {
+                    let (triples, covered) = card_terms(game, coord, card);
+                    (*coord, triples, covered)
                 })
-                .sum()
+                .collect(),
         }
     }
 }
 
-#[cfg(feature = "for_wasm")]
-pub mod web_duration {
-    use rubot::{IntoRunCondition, RunCondition};
-    use std::time::Duration;
+/// Count every uncovered die as much time as it's in triples (i.e.
+/// central cards will be counted as 2 and side cards as 1 in a
+/// typical Bricks7 layout) and give a bonus for covering dice.
+/// Returns the two counts separately.
+fn triples_terms(game: &Game) -> (i32, i32) {
+    let mut triples = 0;
+    let mut covered = 0;
+    for (coord, card) in game.board.coord_cards_iter() {
+        let (card_triples, card_covered) = card_terms(game, coord, card);
+        triples += card_triples;
+        covered += card_covered;
+    }
+    (triples, covered)
+}
 
-    pub struct WebDuration(pub Duration);
+/// Contribution of a single card to `triples_terms`.
+fn card_terms(game: &Game, coord: &Coord, card: &Card) -> (i32, i32) {
+    let sign = |d: &Die| if d.belongs_to_player1() { 1 } else { -1 };
+    match card.dice.as_slice() {
+        [d1] => (sign(d1) * game.board.num_of_adjacent_triples(*coord) as i32, 0),
+        [_, d2] => (sign(d2) * game.board.num_of_adjacent_triples(*coord) as i32, sign(d2)),
+        _ => (0, 0), // Either empty (doesn't affect the score) or 3 (game over)
+    }
+}
 
-    impl IntoRunCondition for WebDuration {
-        type RunCondition = WebInstant;
+/// Number of legal moves of a given player (as if it was their turn).
+fn mobility(game: &Game, for_player1: bool) -> i32 {
+    if game.player1_moves == for_player1 {
+        game.moves_iter().count() as i32
+    } else {
+        let mut game = game.clone();
+        game.player1_moves = for_player1;
+        game.moves_iter().count() as i32
+    }
+}
 
-        fn into_run_condition(self) -> WebInstant {
-            let window = web_sys::window().expect("should have a window in this context");
-            let performance = window.performance().expect("performance should be available");
-            let now = performance.now();
-            WebInstant(self.0.as_millis() as f64 + now)
+/// Number of triples where a given player has two top dice and can
+/// complete the row by placing a die on the third (empty) card or by
+/// covering a single opponent's die there.
+fn threats(game: &Game, for_player1: bool) -> i32 {
+    let has_stock = !game.stock(for_player1).is_empty();
+    let mut threats = 0;
+    for &(a, b, c) in game.board.adj_triples_iter() {
+        let mut own = 0;
+        let mut completable = false;
+        for coord in &[a, b, c] {
+            let card = game.board.card_at(coord).unwrap();
+            match card.dice.as_slice() {
+                [] => completable = has_stock,
+                [d] if d.belongs_to_player1() != for_player1 => completable = true,
+                [.., d] if d.belongs_to_player1() == for_player1 => own += 1,
+                _ => {}
+            }
+        }
+        if own == 2 && completable {
+            threats += 1;
         }
     }
+    threats
+}
+
+/// Where the AI writes what it's thinking (the progress of the search,
+/// the evaluation and the principal variation), so that hosts without
+/// a console can show it elsewhere or drop it.
+pub trait AiLog: Send + Sync {
+    fn log(&self, message: fmt::Arguments);
+}
+
+/// Prints the log to stdout (the default).
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdoutLog;
+
+impl AiLog for StdoutLog {
+    fn log(&self, message: fmt::Arguments) {
+        println!("{}", message);
+    }
+}
 
-    pub struct WebInstant(f64);
+/// Drops the log.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoLog;
 
-    impl RunCondition for WebInstant {
-        #[inline]
-        fn step(&mut self) -> bool {
-            let window = web_sys::window().expect("should have a window in this context");
-            let performance = window.performance().expect("performance should be available");
-            performance.now() < self.0
+impl AiLog for NoLog {
+    fn log(&self, _: fmt::Arguments) {}
+}
+
+/// Run condition stopping the search once the time (as given by a
+/// `TimeSource`) is up.
+struct Deadline {
+    time: Arc<dyn TimeSource>,
+    deadline: f64,
+}
+
+impl Deadline {
+    fn after(time: Arc<dyn TimeSource>, duration: Duration) -> Self {
+        let deadline = time.now_ms() + duration.as_secs_f64() * 1000.0;
+        Deadline { time, deadline }
+    }
+}
+
+impl RunCondition for Deadline {
+    #[inline]
+    fn step(&mut self) -> bool {
+        self.time.now_ms() < self.deadline
+    }
+
+    #[inline]
+    fn depth(&mut self, _: u32) -> bool {
+        self.step()
+    }
+}
+
+/// Allocates thinking time for the moves in games played with a
+/// clock.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeManager {
+    /// How many more moves we expect to make in the game.
+    pub moves_to_go: u32,
+    /// Time always kept in reserve to account for the overhead of
+    /// making the move.
+    pub safety_margin: Duration,
+}
+
+impl Default for TimeManager {
+    fn default() -> Self {
+        TimeManager {
+            moves_to_go: 15,
+            safety_margin: Duration::from_millis(50),
+        }
+    }
+}
+
+impl TimeManager {
+    /// Time to spend on the next move in a given position.
+    pub fn budget(&self, game: &Game, time_left: Duration, increment: Duration) -> Duration {
+        let available = time_left
+            .checked_sub(self.safety_margin)
+            .unwrap_or_else(|| Duration::from_secs(0));
+        let base = available / self.moves_to_go.max(1) + increment * 3 / 4;
+
+        // Spend more time in complex positions (with lots of possible
+        // moves) and less in simple ones: from a half to a double of
+        // the base time.
+        let moves = game.generate_moves().len().clamp(16, 64) as u32;
+        let budget = base * moves / 32;
+
+        // Never risk more than a half of what's left.
+        budget.min(available / 2)
+    }
+}
+
+/// Run condition for the search in a background thread, which can be
+/// stopped from another thread.
+struct PonderCondition {
+    stop: Arc<AtomicBool>,
+    max_depth: Option<u32>,
+}
+
+impl RunCondition for PonderCondition {
+    fn step(&mut self) -> bool {
+        !self.stop.load(Ordering::Relaxed)
+    }
+
+    fn depth(&mut self, depth: u32) -> bool {
+        self.step() && self.max_depth.is_none_or(|max| max > depth)
+    }
+}
+
+/// Search running in a background thread on the position we expect
+/// after the opponent's reply. It is stopped when dropped.
+struct Ponder {
+    position: PositionKey,
+    stop: Arc<AtomicBool>,
+    result: Receiver<Option<Action<EvaluatedGame>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Ponder {
+    fn start(game: EvaluatedGame, for_first_player: bool, max_depth: Option<u32>) -> Self {
+        let position = game.game.position_key();
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, result) = mpsc::channel();
+        let condition = PonderCondition {
+            stop: Arc::clone(&stop),
+            max_depth,
+        };
+
+        let handle = thread::spawn(move || {
+            let action = Bot::new(for_first_player).detailed_select(&game, condition);
+            // Nobody is waiting for the result if pondering has been
+            // cancelled.
+            let _ = sender.send(action);
+        });
+
+        Ponder {
+            position,
+            stop,
+            result,
+            handle: Some(handle),
+        }
+    }
+
+    /// Lets the search run for at most `timeout` more (or until it
+    /// ends, if there is no timeout) and returns its result.
+    fn finish(self, timeout: Option<Duration>) -> Option<Action<EvaluatedGame>> {
+        if let Some(timeout) = timeout {
+            if let Ok(action) = self.result.recv_timeout(timeout) {
+                return action;
+            }
+            self.stop.store(true, Ordering::Relaxed);
         }
+        self.result.recv().ok().flatten()
+    }
+}
 
-        #[inline]
-        fn depth(&mut self, _: u32) -> bool {
-            let window = web_sys::window().expect("should have a window in this context");
-            let performance = window.performance().expect("performance should be available");
-            performance.now() < self.0
+impl Drop for Ponder {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
         }
     }
 }
@@ -125,103 +635,693 @@ pub mod web_duration {
 pub struct AlphaBetaAI {
     duration: u64,
     depth: u32,
-    bot: Bot<Game>,
+    bot: Bot<EvaluatedGame>,
+    for_first_player: bool,
+    time_manager: TimeManager,
+    /// Second move of the last principal variation, i.e. the reply
+    /// we expect from the opponent.
+    predicted_reply: Option<GameMove<Coord>>,
+    /// Evaluation of the last move made.
+    last_evaluation: Option<i32>,
+    /// Principal variation behind the last move made.
+    last_pv: Vec<GameMove<Coord>>,
+    ponder: Option<Ponder>,
+    /// Number of threads for the search. With more than one thread
+    /// we use our own parallel search instead of `rubot`.
+    threads: usize,
+    tt: Option<TranspositionTable>,
+    evaluator: Evaluator,
+    /// Generate a tablebase once there are that many dice left in
+    /// the stocks.
+    tablebase_dice: Option<usize>,
+    tablebase: Option<Arc<Tablebase>>,
+    /// File to write the search tree to (and how many plies of it),
+    /// for debugging.
+    tree_dump: Option<(String, u32)>,
+    /// See `SearchLimits::contempt`.
+    contempt: i32,
+    /// Time for the searches limited in time (except our own one).
+    time: Arc<dyn TimeSource>,
+    log: Arc<dyn AiLog>,
 }
 
 impl AlphaBetaAI {
-    pub fn with_duration(for_first_player: bool, duration: u64) -> Self {
+    fn new(for_first_player: bool, duration: u64, depth: u32) -> Self {
         Self {
             bot: Bot::new(for_first_player),
             duration,
-            depth: 0,
+            depth,
+            for_first_player,
+            time_manager: TimeManager::default(),
+            predicted_reply: None,
+            last_evaluation: None,
+            last_pv: vec![],
+            ponder: None,
+            threads: 1,
+            tt: None,
+            evaluator: Evaluator::default(),
+            tablebase_dice: None,
+            tablebase: None,
+            tree_dump: None,
+            contempt: 0,
+            time: Arc::new(StdTime),
+            log: Arc::new(StdoutLog),
         }
     }
 
+    pub fn with_duration(for_first_player: bool, duration: u64) -> Self {
+        Self::new(for_first_player, duration, 0)
+    }
+
     pub fn with_depth(for_first_player: bool, depth: u32) -> Self {
-        Self {
-            bot: Bot::new(for_first_player),
-            duration: 0,
-            depth,
-        }
+        Self::new(for_first_player, 0, depth)
     }
 
     pub fn to_completion(for_first_player: bool) -> Self {
-        Self {
-            bot: Bot::new(for_first_player),
-            duration: 0,
-            depth: 0,
+        Self::new(for_first_player, 0, 0)
+    }
+
+    /// Principal variation found for the last move made, starting
+    /// with the move itself.
+    pub fn principal_variation(&self) -> &[GameMove<Coord>] {
+        &self.last_pv
+    }
+
+    /// Sets where the time of the searches limited in time comes from
+    /// (`StdTime` by default).
+    pub fn set_time_source(&mut self, time: Arc<dyn TimeSource>) {
+        self.time = time;
+    }
+
+    /// Sets where the AI writes its log (`StdoutLog` by default).
+    pub fn set_log(&mut self, log: Arc<dyn AiLog>) {
+        self.log = log;
+    }
+
+    /// Sets a time manager used in games played with a clock.
+    pub fn set_time_manager(&mut self, time_manager: TimeManager) {
+        self.time_manager = time_manager;
+    }
+
+    /// Sets the weights of the evaluation terms.
+    pub fn set_eval_weights(&mut self, weights: EvalWeights) {
+        self.evaluator = Evaluator::Terms(weights);
+    }
+
+    /// Sets the evaluation function.
+    pub fn set_evaluator(&mut self, evaluator: Evaluator) {
+        self.evaluator = evaluator;
+    }
+
+    fn evaluated(&self, game: &Game) -> EvaluatedGame {
+        EvaluatedGame {
+            game: game.clone(),
+            evaluator: self.evaluator.clone(),
+            tablebase: self.tablebase.clone(),
         }
     }
-}
 
-impl Strategy for AlphaBetaAI {
-    fn get_move(&mut self, game: &Game) -> GameMove<Coord> {
-        macro_rules! run_ai_until {
-            ($condition:expr) => {
-                let action = if cfg!(feature = "for_wasm") {
-                    // no logger, since it uses Duration which is not directly supported in WASM
-                    self.bot.detailed_select(&game, $condition).unwrap()
-                } else {
-                    let mut logger = rubot::Logger::new($condition);
-                    let action = self
-                        .bot
-                        .detailed_select(&game, &mut logger)
-                        .expect("Bot returned no moves");
-                    println!(
-                        "AI log: steps: {}, depth: {}, completed: {}, duration: {:?}",
-                        logger.steps(),
-                        logger.depth(),
-                        logger.completed(),
-                        logger.duration()
-                    );
-                    action
-                };
+    /// Makes the AI generate and use tablebases once there are at
+    /// most `max_dice_left` dice left in both stocks.
+    pub fn enable_tablebases(&mut self, max_dice_left: usize) {
+        self.tablebase_dice = Some(max_dice_left);
+    }
 
-                // Evaluation from current player perspective.
-                println!("AI evaluation: {}", pp_evaluation(action.fitness));
-
-                // Evaluations in PV are printed from the first player perspective.
-                println!("PV:");
-                let mut game_tmp = game.clone();
-                for (ix, m) in action.path.iter().enumerate() {
-                    let um = game_tmp.userify_move(&m);
-                    game_tmp.apply_move_unchecked(&m);
-                    let score = evaluate_for_first_player(&game_tmp);
-                    println!("{}: {}, eval: {}", ix + 1, um, pp_evaluation(score));
-                }
-                println!();
+    /// Sets the tablebase to use in the search.
+    pub fn set_tablebase(&mut self, tablebase: Arc<Tablebase>) {
+        self.tablebase = Some(tablebase);
+    }
+
+    /// Generates a new tablebase if there are few enough dice left
+    /// and the current tablebase doesn't have the position.
+    fn update_tablebase(&mut self, game: &Game) {
+        let max_dice_left = match self.tablebase_dice {
+            Some(n) => n,
+            None => return,
+        };
+        let dice_left = game.stock(true).len() + game.stock(false).len();
+        if dice_left > max_dice_left || self.tablebase.as_ref().is_some_and(|tb| tb.probe(game).is_some()) {
+            return;
+        }
+
+        match Tablebase::generate(game, TABLEBASE_MAX_POSITIONS) {
+            Ok(tablebase) => {
+                self.log.log(format_args!(
+                    "AI log: generated tablebase with {} positions",
+                    tablebase.len()
+                ));
+                self.tablebase = Some(Arc::new(tablebase));
+            }
+            Err(e) => {
+                // Bigger tablebases would take too long to generate.
+                self.log
+                    .log(format_args!("AI log: {}, not using tablebases anymore", e));
+                self.tablebase_dice = None;
+            }
+        }
+    }
+
+    /// Sets the number of search threads.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// Makes the AI write the tree explored by every search down to
+    /// `max_ply` to `path`: as JSON if the file name ends with
+    /// ".json" and in graphviz DOT format otherwise. The tree is only
+    /// recorded by our own search, so it's always used then.
+    pub fn set_tree_dump(&mut self, path: &str, max_ply: u32) {
+        self.tree_dump = Some((path.to_string(), max_ply));
+    }
+
+    /// Sets how much the AI dislikes draws by repetition (see
+    /// `SearchLimits::contempt`). Only our own search detects
+    /// repetitions, so it's used with non-zero contempt.
+    pub fn set_contempt(&mut self, contempt: i32) {
+        self.contempt = contempt;
+    }
+
+    /// Whether to use our own search instead of `rubot`.
+    fn own_search(&self) -> bool {
+        self.threads > 1 || self.tree_dump.is_some() || self.contempt != 0
+    }
+
+    /// Runs the search until `condition` says to stop and logs the
+    /// results.
+    fn run_until<C: IntoRunCondition>(&mut self, game: &Game, condition: C) -> Action<EvaluatedGame> {
+        let action = if cfg!(feature = "for_wasm") {
+            // no logger, since it uses Duration which is not directly supported in WASM
+            self.bot.detailed_select(&self.evaluated(game), condition).unwrap()
+        } else {
+            let mut logger = rubot::Logger::new(condition);
+            let action = self
+                .bot
+                .detailed_select(&self.evaluated(game), &mut logger)
+                .expect("Bot returned no moves");
+            self.log.log(format_args!(
+                "AI log: steps: {}, depth: {}, completed: {}, duration: {:?}",
+                logger.steps(),
+                logger.depth(),
+                logger.completed(),
+                logger.duration()
+            ));
+            action
+        };
 
-                return action.path.first().unwrap().clone();
+        print_action(game, &action, &self.evaluator, &*self.log);
+        action
+    }
+
+    /// Runs the search for a given time.
+    fn run_for(&mut self, game: &Game, duration: Duration) -> Action<EvaluatedGame> {
+        if self.own_search() {
+            let limits = SearchLimits {
+                duration: Some(duration),
+                ..Default::default()
             };
+            return self.run_parallel(game, limits);
         }
 
-        if self.duration != 0 {
-            cfg_if! {
-                if #[cfg(feature = "for_wasm")] {
-                    let web_duration = web_duration::WebDuration(Duration::from_secs(self.duration));
-                    run_ai_until!(web_duration);
+        self.log
+            .log(format_args!("Running AI with duration {:?}...", &duration));
+        let deadline = Deadline::after(Arc::clone(&self.time), duration);
+        self.run_until(game, deadline)
+    }
+
+    /// Scores every legal move (from the perspective of the player
+    /// to move) together with its principal variation, starting with
+    /// the move itself. Moves are sorted from the best to the worst.
+    /// The time of the AI is split evenly between the moves.
+    pub fn analyze(&mut self, game: &Game) -> Vec<(GameMove<Coord>, i32, Vec<GameMove<Coord>>)> {
+        let moves = game.generate_moves();
+        let limits = SearchLimits {
+            depth: if self.depth != 0 {
+                Some((self.depth - 1).max(1))
+            } else {
+                None
+            },
+            duration: if self.duration != 0 {
+                Some(Duration::from_secs(self.duration) / moves.len().max(1) as u32)
+            } else {
+                None
+            },
+            // Positions are searched from the opponent's perspective.
+            contempt: -self.contempt,
+            time: Some(Arc::clone(&self.time)),
+        };
+
+        let (threads, evaluator) = (self.threads, &self.evaluator);
+        let tt = self.tt.get_or_insert_with(|| TranspositionTable::new(TT_BITS));
+        let tablebase = self.tablebase.as_deref();
+        let mut analysis: Vec<_> = moves
+            .into_iter()
+            .map(|m| {
+                let mut position = game.clone();
+                position.apply_move_unchecked(&m);
+                let (score, mut pv) = match search::search(&position, threads, limits.clone(), evaluator, tt, tablebase)
+                {
+                    Some(result) => (-result.score, result.pv),
+                    None => (evaluate_for_player(&position, game.player1_moves, evaluator), vec![]),
+                };
+                pv.insert(0, m.clone());
+                (m, score, pv)
+            })
+            .collect();
+
+        analysis.sort_by_key(|(_, score, _)| Reverse(*score));
+        analysis
+    }
+
+    /// Runs the parallel search and logs the results.
+    fn run_parallel(&mut self, game: &Game, mut limits: SearchLimits) -> Action<EvaluatedGame> {
+        limits.contempt = self.contempt;
+        limits.time = Some(Arc::clone(&self.time));
+        self.log.log(format_args!(
+            "Running AI in {} threads with {:?}...",
+            self.threads, limits
+        ));
+        let tt = self.tt.get_or_insert_with(|| TranspositionTable::new(TT_BITS));
+        let tablebase = self.tablebase.as_deref();
+        let result = match &self.tree_dump {
+            Some((path, max_ply)) => {
+                let (result, tree) =
+                    search::search_with_tree(game, self.threads, limits, &self.evaluator, tt, tablebase, *max_ply)
+                        .expect("Search returned no moves");
+                let dump = if path.ends_with(".json") {
+                    tree.to_json()
                 } else {
-                    let duration = Duration::from_secs(self.duration);
-                    println!("Running AI with duration {:?}...", &duration);
-                    run_ai_until!(duration);
+                    tree.to_dot()
+                };
+                match std::fs::write(path, dump) {
+                    Ok(()) => self.log.log(format_args!(
+                        "AI log: search tree with {} nodes written to {}",
+                        tree.nodes.len(),
+                        path
+                    )),
+                    Err(e) => self
+                        .log
+                        .log(format_args!("AI log: can't write search tree to {}: {}", path, e)),
                 }
+                result
             }
+            None => search::search(game, self.threads, limits, &self.evaluator, tt, tablebase)
+                .expect("Search returned no moves"),
+        };
+        self.log.log(format_args!(
+            "AI log: {}, depth: {}, duration: {:?}, speed: {:.0} nodes/s",
+            result.stats,
+            result.depth,
+            result.elapsed,
+            result.nps()
+        ));
+
+        let action = Action {
+            fitness: result.score,
+            path: result.pv,
+        };
+        print_action(game, &action, &self.evaluator, &*self.log);
+        action
+    }
+
+    /// Returns the result of pondering if it has been done on `game`
+    /// position, letting the search run for `duration` more first
+    /// (for AIs which are limited in time).
+    fn finish_ponder(&mut self, game: &Game, duration: Duration) -> Option<Action<EvaluatedGame>> {
+        let ponder = self.ponder.take()?;
+        if ponder.position != game.position_key() {
+            return None;
+        }
+
+        let timeout = if self.duration != 0 { Some(duration) } else { None };
+        let action = ponder.finish(timeout)?;
+        self.log.log(format_args!("Using the pondered analysis"));
+        print_action(game, &action, &self.evaluator, &*self.log);
+        Some(action)
+    }
+
+    /// Remembers the expected reply and the evaluation and returns the
+    /// move to make.
+    fn choose_move(&mut self, action: Action<EvaluatedGame>) -> GameMove<Coord> {
+        self.predicted_reply = action.path.get(1).cloned();
+        self.last_evaluation = Some(action.fitness);
+        self.last_pv = action.path;
+        self.last_pv.first().unwrap().clone()
+    }
+}
+
+/// Logs the evaluation and the principal variation found by the
+/// search.
+fn print_action(game: &Game, action: &Action<EvaluatedGame>, evaluator: &Evaluator, log: &dyn AiLog) {
+    // Evaluation from current player perspective.
+    log.log(format_args!(
+        "AI evaluation: {}",
+        pp_evaluation(action.fitness, game.ply_to_be_played())
+    ));
+
+    // Evaluations in PV are printed from the first player perspective.
+    log.log(format_args!("PV:"));
+    let mut game_tmp = game.clone();
+    for (ix, m) in action.path.iter().enumerate() {
+        let um = game_tmp.userify_move(m);
+        game_tmp.apply_move_unchecked(m);
+        let score = evaluate_for_first_player(&game_tmp, evaluator);
+        log.log(format_args!(
+            "{}: {}, eval: {}",
+            ix + 1,
+            um,
+            pp_evaluation(score, game.ply_to_be_played())
+        ));
+    }
+    log.log(format_args!(""));
+}
+
+impl Strategy for AlphaBetaAI {
+    // Like "AI (depth 5)".
+    fn name(&self) -> String {
+        if self.duration != 0 {
+            format!("AI ({}s)", self.duration)
+        } else if self.depth != 0 {
+            format!("AI (depth {})", self.depth)
+        } else {
+            "AI (to completion)".to_string()
+        }
+    }
+
+    fn get_move(&mut self, game: &Game) -> GameMove<Coord> {
+        self.update_tablebase(game);
+        let duration = Duration::from_secs(self.duration);
+        let action = if let Some(action) = self.finish_ponder(game, duration) {
+            action
+        } else if self.duration != 0 {
+            self.run_for(game, duration)
+        } else if self.own_search() {
+            let limits = SearchLimits {
+                depth: if self.depth != 0 { Some(self.depth) } else { None },
+                ..Default::default()
+            };
+            self.run_parallel(game, limits)
         } else if self.depth != 0 {
             let depth = Depth(self.depth);
-            println!("Running AI with depth {:?}...", &depth);
-            run_ai_until!(depth);
+            self.log.log(format_args!("Running AI with depth {:?}...", &depth));
+            self.run_until(game, depth)
+        } else {
+            self.log.log(format_args!("Running AI until completion..."));
+            self.run_until(game, ToCompletion)
+        };
+
+        self.choose_move(action)
+    }
+
+    fn get_move_with_clock(&mut self, game: &Game, clock: &Clock) -> GameMove<Coord> {
+        // Fixed depth and full analysis don't care about time.
+        if self.duration == 0 {
+            return self.get_move(game);
+        }
+
+        self.update_tablebase(game);
+        let time_left = clock.time_left(game.player1_moves);
+        let budget = self.time_manager.budget(game, time_left, clock.increment);
+
+        if let Some(action) = self.finish_ponder(game, budget) {
+            return self.choose_move(action);
+        }
+
+        // Spend a quarter of the budget first: if it's enough to see
+        // that the game is decided, there is no point in thinking
+        // more.
+        let action = self.run_for(game, budget / 4);
+        let decided = is_decided(action.fitness);
+        let action = if decided {
+            action
+        } else {
+            self.run_for(game, budget - budget / 4)
+        };
+
+        self.choose_move(action)
+    }
+
+    fn start_ponder(&mut self, game: &Game) {
+        self.ponder = None;
+        if let Some(reply) = self.predicted_reply.take() {
+            let mut position = game.clone();
+            if position.apply_move(&reply).is_ok() && !position.is_game_over() {
+                let max_depth = if self.depth != 0 { Some(self.depth) } else { None };
+                self.ponder = Some(Ponder::start(
+                    self.evaluated(&position),
+                    self.for_first_player,
+                    max_depth,
+                ));
+            }
+        }
+    }
+
+    fn stop_ponder(&mut self, game: &Game) {
+        let hit = match &self.ponder {
+            Some(ponder) => ponder.position == game.position_key(),
+            None => false,
+        };
+        if hit {
+            self.log.log(format_args!("Ponder hit, continuing the analysis"));
         } else {
-            println!("Running AI until completion...");
-            run_ai_until!(ToCompletion);
+            self.ponder = None;
+        }
+    }
+
+    fn on_game_over(&mut self, _result: &GameResult) {
+        // Nothing to ponder on.
+        self.ponder = None;
+        self.predicted_reply = None;
+    }
+
+    fn evaluation(&self) -> Option<i32> {
+        self.last_evaluation
+    }
+}
+
+/// Search which deepens one ply per step, for the callers which can't
+/// block for long (like web pages): they can do something else
+/// between the steps and show the progress.
+pub struct SteppedSearch {
+    game: EvaluatedGame,
+    for_first_player: bool,
+    max_depth: Option<u32>,
+    depth: u32,
+    best: Option<Action<EvaluatedGame>>,
+    completed: bool,
+}
+
+/// Run condition stopping at a given depth, which remembers the last
+/// depth the search has asked for: it stops asking once the result is
+/// known.
+struct StepCondition {
+    depth: u32,
+    asked: u32,
+}
+
+impl RunCondition for &mut StepCondition {
+    fn step(&mut self) -> bool {
+        true
+    }
+
+    fn depth(&mut self, depth: u32) -> bool {
+        self.asked = depth;
+        depth < self.depth
+    }
+}
+
+impl SteppedSearch {
+    /// Starts a search for the player to move in `game`, which is
+    /// over at `max_depth` (if given).
+    pub fn new(game: &Game, evaluator: Evaluator, max_depth: Option<u32>) -> Self {
+        SteppedSearch {
+            game: EvaluatedGame {
+                game: game.clone(),
+                evaluator,
+                tablebase: None,
+            },
+            for_first_player: game.player1_moves,
+            max_depth,
+            depth: 0,
+            best: None,
+            completed: game.is_game_over(),
+        }
+    }
+
+    /// Searches one ply deeper than the previous step (unless the
+    /// search is over already).
+    pub fn step(&mut self) {
+        if self.is_over() {
+            return;
+        }
+
+        self.depth += 1;
+        let mut condition = StepCondition {
+            depth: self.depth,
+            asked: 0,
+        };
+        self.best = Bot::new(self.for_first_player).detailed_select(&self.game, &mut condition);
+        self.completed = match &self.best {
+            Some(action) => condition.asked < self.depth || is_decided(action.fitness),
+            None => true,
+        };
+    }
+
+    /// Whether the result is known or the maximal depth is reached.
+    pub fn is_over(&self) -> bool {
+        self.completed || self.max_depth.is_some_and(|max| self.depth >= max)
+    }
+
+    /// Depth of the last step.
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// Best move found so far.
+    pub fn best_move(&self) -> Option<&GameMove<Coord>> {
+        self.best.as_ref().and_then(|action| action.path.first())
+    }
+
+    /// Evaluation of the best move from the perspective of the player
+    /// to move.
+    pub fn score(&self) -> Option<i32> {
+        self.best.as_ref().map(|action| action.fitness)
+    }
+
+    /// Principal variation found so far, starting with the best move.
+    pub fn principal_variation(&self) -> &[GameMove<Coord>] {
+        self.best.as_ref().map_or(&[], |action| action.path.as_slice())
+    }
+}
+
+/// Evaluation of the position from the first player's perspective:
+/// the score of a search `depth` plies deep, or the static evaluation
+/// for depth 0 (and for finished games).
+pub fn search_for_first_player(game: &Game, evaluator: &Evaluator, depth: u32) -> i32 {
+    let mut search = SteppedSearch::new(game, evaluator.clone(), Some(depth));
+    while !search.is_over() {
+        search.step();
+    }
+    match search.score() {
+        Some(score) if game.player1_moves => score,
+        Some(score) => -score,
+        None => evaluate_for_first_player(game, evaluator),
+    }
+}
+
+/// Plays the move with the best static evaluation after it, without
+/// any search. A cheap baseline opponent (and an easy one).
+#[derive(Debug, Clone, Default)]
+pub struct GreedyAI {
+    evaluator: Evaluator,
+}
+
+impl GreedyAI {
+    pub fn new(evaluator: Evaluator) -> Self {
+        GreedyAI { evaluator }
+    }
+}
+
+impl Strategy for GreedyAI {
+    fn get_move(&mut self, game: &Game) -> GameMove<Coord> {
+        let player = game.player1_moves;
+        let win = won_by(player);
+        let mut position = game.clone();
+        let mut best: Option<(i32, GameMove<Coord>)> = None;
+        for m in game.generate_moves() {
+            let fight_result = position.apply_move_unchecked(&m);
+            let won = position.result == win;
+            let score = evaluate_for_player(&position, player, &self.evaluator);
+            position.undo_move(&m, fight_result);
+
+            if won {
+                return m;
+            }
+            if best.as_ref().is_none_or(|(best_score, _)| score > *best_score) {
+                best = Some((score, m));
+            }
+        }
+        best.expect("GreedyAI: no moves").1
+    }
+
+    fn name(&self) -> String {
+        "Greedy AI".to_string()
+    }
+}
+
+/// Plays by a few rules of thumb a beginner would follow, without
+/// any search: complete a row when possible, block the opponent's
+/// rows, go for the central cards and keep the high dice back.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeuristicAI;
+
+impl HeuristicAI {
+    /// Whether the side to move can win right away.
+    fn can_win(position: &mut Game) -> bool {
+        let win = won_by(position.player1_moves);
+        position.generate_moves().iter().any(|m| {
+            let fight_result = position.apply_move_unchecked(m);
+            let won = position.result == win;
+            position.undo_move(m, fight_result);
+            won
+        })
+    }
+}
+
+impl Strategy for HeuristicAI {
+    fn get_move(&mut self, game: &Game) -> GameMove<Coord> {
+        let win = won_by(game.player1_moves);
+        let mut position = game.clone();
+        let mut best = None;
+        for m in game.generate_moves() {
+            let fight_result = position.apply_move_unchecked(&m);
+            let won = position.result == win;
+            // Leaving the opponent a row to complete is the worst thing to do.
+            let safe = position.result != GameResult::InProgress || !Self::can_win(&mut position);
+            position.undo_move(&m, fight_result);
+
+            if won {
+                return m;
+            }
+
+            let centrality = m.target().map_or(0, |c| game.board.neighbours(&c).len());
+            let die_value = match &m {
+                GameMove::Place(die, _) | GameMove::Move(die, _, _) => die.value,
+                _ => 0,
+            };
+            let score = (safe, centrality, Reverse(die_value));
+            if best.as_ref().is_none_or(|(best_score, _)| score > *best_score) {
+                best = Some((score, m));
+            }
         }
+        best.expect("HeuristicAI: no moves").1
+    }
+
+    fn name(&self) -> String {
+        "Heuristic AI".to_string()
     }
 }
 
-fn pp_evaluation(score: i32) -> String {
-    if score == i32::MAX {
-        String::from("inf")
-    } else if score == i32::MIN {
-        String::from("-inf")
+/// Result of the game won by the given player.
+pub(crate) fn won_by(player1: bool) -> GameResult {
+    if player1 {
+        GameResult::FirstPlayerWon
+    } else {
+        GameResult::SecondPlayerWon
+    }
+}
+
+/// Pretty-prints an evaluation of a position on a given ply, like
+/// "win in 3" for decided games.
+pub fn pp_evaluation(score: i32, ply: usize) -> String {
+    if is_decided(score) {
+        // The game is over for plies past the mate.
+        let plies = ((WIN - score.abs()) as usize).saturating_sub(ply);
+        match (score > 0, plies) {
+            (true, 0) => String::from("won"),
+            (false, 0) => String::from("lost"),
+            (true, n) => format!("win in {}", n),
+            (false, n) => format!("loss in {}", n),
+        }
     } else {
         format!("{}", score)
     }
@@ -233,18 +1333,391 @@ mod test {
 
     use crate::board::Layout;
     use crate::card::{Deck, DiceColor, Die};
+    use crate::clock::StepCounter;
     use crate::coord::UserCoord;
     use crate::game::Rules;
     use failure::Fallible;
+    use std::sync::Mutex;
+
+    fn place(color: DiceColor, value: u8, row: u8, card: u8) -> GameMove<UserCoord> {
+        GameMove::Place(Die::new(color, value), UserCoord::new(row, card))
+    }
+
+    fn mov(color: DiceColor, value: u8, from: (u8, u8), to: (u8, u8)) -> GameMove<UserCoord> {
+        GameMove::Move(
+            Die::new(color, value),
+            UserCoord::new(from.0, from.1),
+            UserCoord::new(to.0, to.1),
+        )
+    }
 
     #[test]
     fn test_eval() -> Fallible<()> {
-        let mut game = Game::new(Layout::Bricks7, Deck::ordered("JJJGGGG")?, Rules::default());
-        assert_eq!(evaluate_for_first_player(&game), 0);
-        game.apply_user_move(&GameMove::Place(Die::new(DiceColor::Red, 2), UserCoord::new(1, 2)))?;
-        assert_eq!(evaluate_for_first_player(&game), 1);
-        game.apply_user_move(&GameMove::Place(Die::new(DiceColor::Black, 3), UserCoord::new(2, 2)))?;
-        assert_eq!(evaluate_for_first_player(&game), -1);
+        use DiceColor::*;
+
+        // Positions with the expected sign of the evaluation (from the
+        // first player's perspective).
+        let positions = vec![
+            ("red in the centre", vec![place(Red, 2, 2, 2)], 1),
+            (
+                "black in the centre",
+                vec![place(Red, 2, 1, 1), place(Black, 1, 2, 2)],
+                -1,
+            ),
+            (
+                "red threatens a row",
+                vec![place(Red, 2, 2, 1), place(Black, 1, 1, 3), place(Red, 2, 2, 2)],
+                1,
+            ),
+            (
+                "black threatens a row",
+                vec![
+                    place(Red, 2, 1, 1),
+                    place(Black, 1, 2, 2),
+                    place(Red, 2, 1, 3),
+                    place(Black, 1, 2, 3),
+                ],
+                -1,
+            ),
+            (
+                "black covers red",
+                vec![
+                    place(Red, 2, 2, 2),
+                    place(Black, 1, 1, 2),
+                    place(Red, 2, 2, 4),
+                    mov(Black, 1, (1, 2), (2, 2)),
+                ],
+                -1,
+            ),
+            (
+                "red covers black",
+                vec![place(Red, 2, 1, 1), place(Black, 1, 2, 2), mov(Red, 2, (1, 1), (2, 2))],
+                1,
+            ),
+        ];
+
+        for (name, moves, sign) in positions {
+            let mut game = Game::new(Layout::Bricks7, Deck::ordered("JJJGGGG")?, Rules::new(false, false));
+            for m in &moves {
+                game.apply_user_move(m)?;
+            }
+            let eval = evaluate_for_first_player(&game, &Evaluator::default());
+            assert_eq!(eval.signum(), sign, "{}: {}", name, eval);
+
+            // The breakdown adds up to the evaluation.
+            let breakdown = EvalBreakdown::of(&game);
+            let weights = EvalWeights::default();
+            assert_eq!(breakdown.terms.score(&weights), eval);
+            let weighted: i32 = breakdown
+                .terms
+                .named()
+                .iter()
+                .map(|&(n, v)| weights.get(n).unwrap() * v)
+                .sum();
+            assert_eq!(weighted, eval);
+            let cards_triples: i32 = breakdown.cards.iter().map(|&(_, t, _)| t).sum();
+            let cards_covered: i32 = breakdown.cards.iter().map(|&(_, _, c)| c).sum();
+            assert_eq!(
+                (cards_triples, cards_covered),
+                (breakdown.terms.triples, breakdown.terms.covered)
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_weights_parsing() -> Fallible<()> {
+        let weights: EvalWeights = "triples=3, covered=1".parse()?;
+        assert_eq!(weights.triples, 3);
+        assert_eq!(weights.covered, 1);
+        assert_eq!(weights.stock, EvalWeights::default().stock);
+        assert_eq!(weights.to_string().parse::<EvalWeights>()?, weights);
+
+        let toml = "# Tuned weights\n[eval]\nmobility = 2\nthreats = -4 # why not\n";
+        let weights = EvalWeights::from_toml(toml)?;
+        assert_eq!(weights.mobility, 2);
+        assert_eq!(weights.threats, -4);
+        assert_eq!(EvalWeights::from_toml(&weights.to_toml())?, weights);
+
+        assert!("triples".parse::<EvalWeights>().is_err());
+        assert!("triples=x".parse::<EvalWeights>().is_err());
+        assert!("unknown=1".parse::<EvalWeights>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_win_scores() {
+        assert!(win_score(5) > win_score(7));
+        assert!(-win_score(5) < -win_score(7));
+        assert!(is_decided(win_score(100)) && is_decided(-win_score(100)));
+        assert!(!is_decided(100));
+
+        assert_eq!(pp_evaluation(win_score(10), 7), "win in 3");
+        assert_eq!(pp_evaluation(-win_score(10), 7), "loss in 3");
+        assert_eq!(pp_evaluation(win_score(7), 7), "won");
+        assert_eq!(pp_evaluation(win_score(7), 9), "won");
+        assert_eq!(pp_evaluation(-win_score(7), 9), "lost");
+        assert_eq!(pp_evaluation(-3, 7), "-3");
+    }
+
+    #[test]
+    fn test_nn_eval() -> Fallible<()> {
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("JJJGGGG")?, Rules::new(false, false));
+        let features = nn_features(&game);
+        assert_eq!(features.len(), NN_FEATURES);
+        let jades: f32 = features.iter().step_by(5).take(NN_CARDS).sum();
+        assert_eq!(jades, 3.0);
+        assert_eq!(features[NN_FEATURES - 1], 1.0);
+
+        // Linear network counting the difference in stocks.
+        let mut weights = vec!["0"; NN_FEATURES];
+        weights[NN_CARDS * 5] = "1";
+        weights[NN_CARDS * 5 + 1] = "-1";
+        let net = format!("{} 1\n{}\n0", NN_FEATURES, weights.join(" "));
+        let evaluator = Evaluator::neural_net(net.parse()?)?;
+        assert!(Evaluator::neural_net("2 1\n1 1\n0".parse()?).is_err());
+
+        game.apply_user_move(&place(DiceColor::Red, 2, 2, 2))?;
+        let stocks = game.stock(true).len() as i32 - game.stock(false).len() as i32;
+        assert_eq!(evaluate_for_first_player(&game, &evaluator), stocks * NN_SCALE as i32);
+
+        let mut ai = AlphaBetaAI::with_depth(false, 2);
+        ai.set_evaluator(evaluator);
+        let m = ai.get_move(&game);
+        assert!(game.generate_moves().contains(&m));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_analyze() -> Fallible<()> {
+        use DiceColor::*;
+
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("JGJJJGG")?, Rules::new(false, false));
+        let moves = vec![
+            place(Red, 2, 2, 3),
+            place(Black, 1, 2, 1),
+            place(Red, 2, 2, 4),
+            mov(Black, 1, (2, 1), (2, 3)),
+            place(Red, 2, 1, 2),
+            place(Black, 1, 2, 1),
+            place(Red, 2, 2, 2),
+            mov(Black, 1, (2, 1), (2, 4)),
+            mov(Red, 2, (1, 2), (2, 1)),
+            mov(Black, 1, (2, 4), (2, 2)),
+            mov(Red, 2, (2, 1), (1, 2)),
+        ];
+        for m in &moves {
+            game.apply_user_move(m)?;
+        }
+
+        let mut ai = AlphaBetaAI::with_depth(false, 2);
+        let analysis = ai.analyze(&game);
+        assert_eq!(analysis.len(), game.generate_moves().len());
+        assert!(analysis.windows(2).all(|w| w[0].1 >= w[1].1));
+        assert!(analysis.iter().all(|(m, _, pv)| pv[0] == *m));
+
+        // Black wins right away.
+        let (best, score, _) = &analysis[0];
+        assert_eq!(
+            game.userify_move(best),
+            place(Black, 1, 2, 1).map_coords(|c| c.clone().into())
+        );
+        assert_eq!(*score, win_score(game.ply_to_be_played() + 1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_budget() -> Fallible<()> {
+        let secs = Duration::from_secs;
+        let tm = TimeManager::default();
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("JJJGGGG")?, Rules::new(false, false));
+
+        // 9 moves without fights and surprises.
+        let simple = tm.budget(&game, secs(60), secs(0));
+        assert!(simple > secs(0));
+        assert!(simple < secs(30));
+
+        // Many more moves with fights and surprises.
+        game = Game::new(Layout::Bricks7, Deck::ordered("JJJGGGG")?, Rules::default());
+        let complex = tm.budget(&game, secs(60), secs(0));
+        assert!(complex > simple);
+        assert!(complex <= secs(30));
+
+        // Increment adds up.
+        assert!(tm.budget(&game, secs(60), secs(2)) > complex);
+
+        // Nothing left to spend.
+        assert_eq!(tm.budget(&game, Duration::from_millis(10), secs(0)), secs(0));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_expected_result() {
+        assert_eq!(expected_result(0), 0.5);
+        assert!(expected_result(50) > 0.5 && expected_result(50) < expected_result(100));
+        assert!((expected_result(-100) + expected_result(100) - 1.0).abs() < 1e-9);
+        assert_eq!(expected_result(win_score(10)), 1.0);
+        assert_eq!(expected_result(-win_score(10)), 0.0);
+    }
+
+    #[test]
+    fn test_greedy() -> Fallible<()> {
+        use DiceColor::*;
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("JJJGGGG")?, Rules::new(false, false));
+        for m in &[
+            place(Red, 2, 2, 1),
+            place(Black, 1, 1, 1),
+            place(Red, 2, 2, 2),
+            place(Black, 1, 1, 2),
+        ] {
+            game.apply_user_move(m)?;
+        }
+
+        // The first player completes the row.
+        let m = GreedyAI::default().get_move(&game);
+        game.apply_move(&m)?;
+        assert_eq!(game.result, GameResult::FirstPlayerWon);
+        Ok(())
+    }
+
+    #[test]
+    fn test_heuristic() -> Fallible<()> {
+        use DiceColor::*;
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("JJJGGGG")?, Rules::new(false, false));
+        for m in &[place(Red, 2, 2, 1), place(Black, 1, 1, 1), place(Red, 2, 2, 2)] {
+            game.apply_user_move(m)?;
+        }
+
+        // The second player blocks the row.
+        let m = HeuristicAI.get_move(&game);
+        assert_eq!(game.board.describe_move(&m).to_string(), "place b1 at r2c3");
+
+        // The first move goes to one of the most central cards.
+        let game = Game::new(Layout::Bricks7, Deck::ordered("JJJGGGG")?, Rules::new(false, false));
+        let target = HeuristicAI.get_move(&game).target().unwrap();
+        let most_neighbours = game.board.coords_iter().map(|c| game.board.neighbours(c).len()).max();
+        assert_eq!(Some(game.board.neighbours(&target).len()), most_neighbours);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stepped_search() -> Fallible<()> {
+        use DiceColor::*;
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("JJJGGGG")?, Rules::new(false, false));
+        let mut search = SteppedSearch::new(&game, Evaluator::default(), Some(2));
+        assert!(search.best_move().is_none());
+        search.step();
+        assert_eq!(search.depth(), 1);
+        assert!(search.best_move().is_some() && !search.is_over());
+        search.step();
+        assert!(search.is_over());
+
+        // Nothing to search at depth 0.
+        let search = SteppedSearch::new(&game, Evaluator::default(), Some(0));
+        assert!(search.is_over() && search.best_move().is_none());
+        let evaluator = Evaluator::default();
+        let eval = evaluate_for_first_player(&game, &evaluator);
+        assert_eq!(search_for_first_player(&game, &evaluator, 0), eval);
+
+        // The result is known before the maximal depth.
+        for m in &[
+            place(Red, 2, 2, 1),
+            place(Black, 1, 1, 1),
+            place(Red, 2, 2, 2),
+            place(Black, 1, 1, 2),
+        ] {
+            game.apply_user_move(m)?;
+        }
+        let mut search = SteppedSearch::new(&game, Evaluator::default(), None);
+        while !search.is_over() {
+            search.step();
+        }
+        assert!(search.depth() < 3);
+        let win = win_score(game.ply_to_be_played() + 1);
+        assert_eq!(search_for_first_player(&game, &evaluator, 1), win);
+        game.apply_move(search.best_move().unwrap())?;
+        assert_eq!(game.result, GameResult::FirstPlayerWon);
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_source() -> Fallible<()> {
+        let game = Game::new(Layout::Bricks7, Deck::ordered("JJJGGGG")?, Rules::new(false, false));
+        let mut ai = AlphaBetaAI::with_duration(true, 1);
+        let time = Arc::new(StepCounter::new(10.0));
+        ai.set_time_source(time.clone());
+
+        // The search is over once the time has been asked for a
+        // hundred times (besides setting the deadline).
+        let m = ai.get_move(&game);
+        game.validate_move(&m)?;
+        assert_eq!(time.steps(), 101);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ponder() -> Fallible<()> {
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("JJJGGGG")?, Rules::new(false, false));
+        let mut ai = AlphaBetaAI::with_depth(true, 3);
+
+        let m = ai.get_move(&game);
+        game.apply_move(&m)?;
+        let reply = ai.predicted_reply.clone().unwrap();
+        assert_eq!(ai.principal_variation()[..2], [m, reply.clone()]);
+
+        // Opponent plays the predicted move: the analysis is kept.
+        ai.start_ponder(&game);
+        assert!(ai.ponder.is_some());
+        game.apply_move(&reply)?;
+        ai.stop_ponder(&game);
+        assert!(ai.ponder.is_some());
+        let m = ai.get_move(&game);
+        assert!(ai.ponder.is_none());
+        game.apply_move(&m)?;
+
+        // Opponent plays something else: the analysis is cancelled.
+        let reply = ai.predicted_reply.clone().unwrap();
+        ai.start_ponder(&game);
+        let other = game.generate_moves().into_iter().find(|m| *m != reply).unwrap();
+        game.apply_move(&other)?;
+        ai.stop_ponder(&game);
+        assert!(ai.ponder.is_none());
+        game.apply_move(&ai.get_move(&game))?;
+
+        Ok(())
+    }
+
+    #[derive(Default)]
+    struct CapturedLog(Mutex<Vec<String>>);
+
+    impl AiLog for CapturedLog {
+        fn log(&self, message: fmt::Arguments) {
+            self.0.lock().unwrap().push(message.to_string());
+        }
+    }
+
+    #[test]
+    fn test_log() -> Fallible<()> {
+        let game = Game::new(Layout::Bricks7, Deck::ordered("JJJGGGG")?, Rules::new(false, false));
+        let mut ai = AlphaBetaAI::with_depth(true, 2);
+        let log = Arc::new(CapturedLog::default());
+        ai.set_log(log.clone());
+
+        let m = ai.get_move(&game);
+        let lines = log.0.lock().unwrap().clone();
+        assert!(lines.iter().any(|l| l.starts_with("Running AI with depth")));
+        assert!(lines.iter().any(|l| l == "PV:"));
+        let first = format!("1: {}, eval: ", game.userify_move(&m));
+        assert!(lines.iter().any(|l| l.starts_with(&first)));
+
+        // Nothing goes to the previous log (nor anywhere else).
+        ai.set_log(Arc::new(NoLog));
+        ai.get_move(&game);
+        assert_eq!(log.0.lock().unwrap().len(), lines.len());
 
         Ok(())
     }
@@ -1,12 +1,17 @@
-use failure::{bail, format_err, Fallible};
-use std::fmt;
-use std::str::FromStr;
-
-use itertools::Itertools;
-use std::collections::{BTreeMap, BTreeSet};
+use crate::error::{bail, format_err, Error, Fallible};
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::convert::TryFrom;
+use core::fmt;
+use core::iter;
+use core::str::FromStr;
+
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::sync::Arc;
 
 use crate::card::{Card, Deck, Die};
-use crate::coord::{Coord, UserCoord};
+use crate::coord::{Coord, Direction, EitherCoord, Position, UserCoord};
 use crate::game::GameMove;
 
 #[cfg(feature = "with_serde")]
@@ -36,8 +41,27 @@ pub enum Layout {
     Custom(Grid, BTreeSet<Coord>),
 }
 
+/// One of the standard layouts (custom ones are not generated).
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Layout {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(u.choose(&[Layout::Rectangle6, Layout::Bricks7, Layout::Hex7])?.clone())
+    }
+}
+
+impl Layout {
+    /// Number of cards laid out.
+    pub fn cards_count(&self) -> usize {
+        match self {
+            Layout::Rectangle6 => 6,
+            Layout::Bricks7 | Layout::Hex7 => 7,
+            Layout::Custom(_, coords) => coords.len(),
+        }
+    }
+}
+
 impl FromStr for Layout {
-    type Err = failure::Error;
+    type Err = Error;
     fn from_str(s: &str) -> Fallible<Self> {
         match s {
             "rectangle6" | "r6" => Ok(Layout::Rectangle6),
@@ -49,7 +73,149 @@ impl FromStr for Layout {
     }
 }
 
-type Cards = BTreeMap<Coord, Card>;
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Layout::Rectangle6 => write!(f, "rectangle6"),
+            Layout::Bricks7 => write!(f, "bricks7"),
+            Layout::Hex7 => write!(f, "hex7"),
+            Layout::Custom(..) => write!(f, "custom"),
+        }
+    }
+}
+
+/// Cards on the board with their positions, ordered by coordinates.
+///
+/// Cards are kept in a plain vector next to the (sorted) vector of
+/// their positions: `coords[i]` is where `cards[i]` lies. A board has
+/// just a handful of cards, so looking a position up with a binary
+/// search is as fast as a map, while cloning the board in the search
+/// costs two allocations and walking over it is cache friendly.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cards {
+    coords: Vec<Coord>,
+    cards: Vec<Card>,
+}
+
+impl Cards {
+    fn index(&self, coord: &Coord) -> Option<usize> {
+        self.coords.binary_search(coord).ok()
+    }
+
+    pub fn len(&self) -> usize {
+        self.cards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cards.is_empty()
+    }
+
+    pub fn contains_key(&self, coord: &Coord) -> bool {
+        self.index(coord).is_some()
+    }
+
+    pub fn get(&self, coord: &Coord) -> Option<&Card> {
+        self.index(coord).map(|ix| &self.cards[ix])
+    }
+
+    pub fn get_mut(&mut self, coord: &Coord) -> Option<&mut Card> {
+        self.index(coord).map(move |ix| &mut self.cards[ix])
+    }
+
+    pub fn get_key_value(&self, coord: &Coord) -> Option<(&Coord, &Card)> {
+        self.index(coord).map(|ix| (&self.coords[ix], &self.cards[ix]))
+    }
+
+    /// Puts a card at the position, returning the one which was there.
+    pub fn insert(&mut self, coord: Coord, card: Card) -> Option<Card> {
+        match self.coords.binary_search(&coord) {
+            Ok(ix) => Some(core::mem::replace(&mut self.cards[ix], card)),
+            Err(ix) => {
+                self.coords.insert(ix, coord);
+                self.cards.insert(ix, card);
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, coord: &Coord) -> Option<Card> {
+        let ix = self.index(coord)?;
+        self.coords.remove(ix);
+        Some(self.cards.remove(ix))
+    }
+
+    /// Positions of the cards (ordered).
+    pub fn keys(&self) -> core::slice::Iter<'_, Coord> {
+        self.coords.iter()
+    }
+
+    /// Cards ordered by their positions.
+    pub fn values(&self) -> core::slice::Iter<'_, Card> {
+        self.cards.iter()
+    }
+
+    /// Positions with the cards (ordered by positions).
+    pub fn iter(&self) -> core::iter::Zip<core::slice::Iter<'_, Coord>, core::slice::Iter<'_, Card>> {
+        self.coords.iter().zip(self.cards.iter())
+    }
+}
+
+impl<'a> IntoIterator for &'a Cards {
+    type Item = (&'a Coord, &'a Card);
+    type IntoIter = core::iter::Zip<core::slice::Iter<'a, Coord>, core::slice::Iter<'a, Card>>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+// Hashed and ordered the same way as `BTreeMap<Coord, Card>`.
+impl core::hash::Hash for Cards {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        state.write_usize(self.len());
+        for pair in self {
+            pair.hash(state);
+        }
+    }
+}
+
+impl PartialOrd for Cards {
+    fn partial_cmp(&self, other: &Self) -> Option<core::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Cards {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        self.iter().cmp(other.iter())
+    }
+}
+
+impl core::iter::FromIterator<(Coord, Card)> for Cards {
+    fn from_iter<I: IntoIterator<Item = (Coord, Card)>>(iter: I) -> Self {
+        let mut cards = Cards::default();
+        for (coord, card) in iter {
+            cards.insert(coord, card);
+        }
+        cards
+    }
+}
+
+// Written as a sequence of pairs, as maps with coordinates as keys are
+// (see `serde_coord_map`).
+#[cfg(feature = "with_serde")]
+impl Serialize for Cards {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(self)
+    }
+}
+
+#[cfg(feature = "with_serde")]
+impl<'de> Deserialize<'de> for Cards {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let vec = <Vec<(Coord, Card)>>::deserialize(d)?;
+        Ok(vec.into_iter().collect())
+    }
+}
 
 /// Represents the whole game board: cards at particular positions and
 /// a type of grid used (to make sense of positions).
@@ -57,36 +223,44 @@ type Cards = BTreeMap<Coord, Card>;
 #[derive(Debug, Clone)]
 pub struct Board {
     pub grid: Grid,
-    #[cfg_attr(feature = "with_serde", serde(with = "serde_cards"))]
     pub cards: Cards,
     pub layout: Layout,
     adj_triples: Vec<(Coord, Coord, Coord)>,
+    /// Cards next to each card and to each empty position around them
+    /// (see `neighbours`). Only surprise moves change it, so it's
+    /// shared between the copies of the board made in the search.
+    #[cfg_attr(feature = "with_serde", serde(with = "serde_coord_map"))]
+    neighbours: Arc<BTreeMap<Coord, Vec<Coord>>>,
 }
 
+/// Maps keyed by coordinates are written as sequences of pairs (JSON
+/// only has string keys).
 #[cfg(feature = "with_serde")]
-mod serde_cards {
+mod serde_coord_map {
     use super::*;
 
-    pub fn serialize<S>(cards: &Cards, s: S) -> Result<S::Ok, S::Error>
+    pub fn serialize<S, V>(map: &Arc<BTreeMap<Coord, V>>, s: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
+        V: Serialize,
     {
-        s.collect_seq(cards)
+        s.collect_seq(map.iter())
     }
 
-    pub fn deserialize<'de, D>(d: D) -> Result<Cards, D::Error>
+    pub fn deserialize<'de, D, V>(d: D) -> Result<Arc<BTreeMap<Coord, V>>, D::Error>
     where
         D: Deserializer<'de>,
+        V: Deserialize<'de>,
     {
-        let vec = <Vec<(Coord, Card)>>::deserialize(d)?;
+        let vec = <Vec<(Coord, V)>>::deserialize(d)?;
         let map = vec.into_iter().collect();
-        Ok(map)
+        Ok(Arc::new(map))
     }
 }
 
 impl Board {
     pub fn new(layout: Layout, deck: Deck) -> Self {
-        let mut cards_at_positions = BTreeMap::new();
+        let mut cards_at_positions = Cards::default();
         let mut cards = deck.into_iter();
         let grid;
 
@@ -182,14 +356,21 @@ impl Board {
             }
         }
 
-        let adj_triples = Self::adjacent_triples(&grid, cards_at_positions.keys());
+        Self::with_cards(grid, layout, cards_at_positions)
+    }
 
-        Self {
+    /// Board with the cards already laid out.
+    pub(crate) fn with_cards(grid: Grid, layout: Layout, cards: Cards) -> Self {
+        let adj_triples = Self::adjacent_triples(&grid, cards.keys());
+        let mut board = Self {
             grid,
             layout,
-            cards: cards_at_positions,
+            cards,
             adj_triples,
-        }
+            neighbours: Arc::default(),
+        };
+        board.refresh_neighbours();
+        board
     }
 
     pub fn new_coord(&self, x: i8, y: i8) -> Coord {
@@ -203,6 +384,55 @@ impl Board {
         self.adj_triples = Self::adjacent_triples(&self.grid, self.cards.keys());
     }
 
+    /// Rebuilds the table of neighbours (see `neighbours`).
+    pub fn refresh_neighbours(&mut self) {
+        let mut table: BTreeMap<Coord, Vec<Coord>> = BTreeMap::new();
+        for &card in self.cards.keys() {
+            table.entry(card).or_default();
+            for pos in card.neighbours(self.grid) {
+                table.entry(pos).or_default().push(card);
+            }
+        }
+        self.neighbours = Arc::new(table);
+    }
+
+    /// Moves a card to an empty position (as in a surprise move). Only
+    /// the adjacent triples through the two positions are updated, the
+    /// rest stay as they are.
+    pub fn move_card(&mut self, from: &Coord, to: &Coord) {
+        let card = self.cards.remove(from).expect("move_card: no card to move");
+        self.cards.insert(*to, card);
+        self.refresh_neighbours();
+
+        self.adj_triples
+            .retain(|&(a, b, c)| a != *from && b != *from && c != *from);
+        let added = self.triples_through(*to);
+        self.adj_triples.extend(added);
+        // The same order as in `adjacent_triples`.
+        self.adj_triples.sort();
+    }
+
+    /// Adjacent triples in a single line going through the given card.
+    fn triples_through(&self, c: Coord) -> Vec<(Coord, Coord, Coord)> {
+        let units = Coord::unit_vectors(self.grid);
+        let mut result = vec![];
+        for (ix, &v) in units.iter().enumerate() {
+            let mut lines = vec![[c, c + v, c + v + v]];
+            // Opposite directions give the same line with `c` in the
+            // middle, so only half of them are taken.
+            if ix < units.len() / 2 {
+                lines.push([c - v, c, c + v]);
+            }
+            for mut line in lines {
+                if line.iter().all(|p| self.cards.contains_key(p)) {
+                    line.sort();
+                    result.push((line[0], line[1], line[2]));
+                }
+            }
+        }
+        result
+    }
+
     /// The top-most row (i.e. one with the minimal y-coordinate).
     fn top_row(&self) -> i8 {
         self.cards
@@ -223,10 +453,10 @@ impl Board {
 
     /// Bounding box (left, right, top, bottom), inclusive.
     pub fn bounding_box(&self) -> (i8, i8, i8, i8) {
-        let mut left = std::i8::MAX;
-        let mut right = std::i8::MIN;
-        let mut top = std::i8::MAX;
-        let mut bottom = std::i8::MIN;
+        let mut left = i8::MAX;
+        let mut right = i8::MIN;
+        let mut top = i8::MAX;
+        let mut bottom = i8::MIN;
 
         for c in self.cards.keys() {
             if c.x < left {
@@ -264,6 +494,28 @@ impl Board {
         self.cards.iter()
     }
 
+    /// Kinds of the cards in the order they have been dealt (see
+    /// `Deck::ordered`), like "JJJGGGG".
+    pub fn dealt_cards(&self) -> String {
+        let mut cards: Vec<_> = self.cards.iter().collect();
+        // Custom layouts are dealt in the order of coordinates, the
+        // others row by row.
+        if !matches!(self.layout, Layout::Custom(..)) {
+            cards.sort_by_key(|(c, _)| (c.y, c.x));
+        }
+        cards.into_iter().map(|(_, card)| card.kind.letter()).collect()
+    }
+
+    /// Horizontal position of a card when drawing the board, in
+    /// halves of a card (rows of hex grids are shifted by half a card
+    /// relative to each other).
+    pub fn drawing_column(&self, coord: &Coord) -> i16 {
+        match self.grid {
+            Grid::Hex => 2 * i16::from(coord.x) + i16::from(coord.y),
+            Grid::Square => 2 * i16::from(coord.x),
+        }
+    }
+
     /// Iterator over all coordinates (in arbitrary order).
     pub fn coords_iter(&self) -> impl Iterator<Item = &Coord> {
         self.cards.keys()
@@ -278,10 +530,9 @@ impl Board {
     /// Iterator over positions/cards from given `row` (`y`
     /// coordinate) ordered by `x` coordinate.
     pub fn row_iter(&self, row: i8) -> impl Iterator<Item = (&Coord, &Card)> {
-        self.cards
-            .iter()
-            .filter(move |(coord, _)| coord.y == row)
-            .sorted_by_key(|(coord, _)| coord.x)
+        let mut cards: Vec<_> = self.cards.iter().filter(|(coord, _)| coord.y == row).collect();
+        cards.sort_by_key(|(coord, _)| coord.x);
+        cards.into_iter()
     }
 
     /// Iterator over positions/cards with no dice on them.
@@ -319,48 +570,103 @@ impl Board {
         })
     }
 
+    /// Neighbouring (immediately adjacent) cards of a position, with a
+    /// card or without one. Positions which are not next to a card have
+    /// none.
+    pub fn neighbours(&self, pos: &Coord) -> &[Coord] {
+        self.neighbours.get(pos).map_or(&[], Vec::as_slice)
+    }
+
+    /// Iterator over neighbouring (immediately adjacent) cards for a
+    /// given position.
+    pub fn neighbours_iter(&self, pos: Coord) -> impl Iterator<Item = &Coord> {
+        self.neighbours(&pos).iter()
+    }
+
     /// Iterator over neighbouring (immediately adjacent) cards for a
     /// give position excluding given `exclude` coord.
     pub fn neighbours_iter_without(&self, pos: Coord, exclude: Coord) -> impl Iterator<Item = &Coord> {
-        self.cards
-            .keys()
-            .filter(move |c| Self::distance(&self.grid, c, &pos) == 1 && *c != &exclude)
+        self.neighbours_iter(pos).filter(move |c| **c != exclude)
+    }
+
+    /// Whether the two positions are next to each other (with cards or
+    /// without them).
+    pub fn is_adjacent(&self, a: Coord, b: Coord) -> bool {
+        a.distance(b, self.grid) == 1
+    }
+
+    /// Empty positions next to the cards (ordered by coordinates), the
+    /// only ones where a card can be moved by a surprise move.
+    pub fn empty_positions_iter(&self) -> impl Iterator<Item = &Coord> {
+        self.neighbours.keys().filter(move |c| !self.cards.contains_key(c))
+    }
+
+    /// Empty positions next to at least `min_cards` cards (ordered by
+    /// coordinates).
+    pub fn empty_neighbour_positions(&self, min_cards: usize) -> impl Iterator<Item = Coord> + '_ {
+        self.empty_positions_iter()
+            .filter(move |pos| self.neighbours(pos).len() >= min_cards)
+            .copied()
+    }
+
+    /// Empty positions where the card at `from` can be moved by a
+    /// surprise move: the ones next to at least two other cards.
+    pub fn surprise_destinations(&self, from: Coord) -> impl Iterator<Item = Coord> + '_ {
+        self.empty_positions_iter()
+            .filter(move |&&to| self.neighbours_iter_without(to, from).nth(1).is_some())
+            .copied()
     }
 
     /// Convert from user coordinates to internal coordinates in a GameMove.
-    pub fn convert_move_coords(&self, m: &GameMove<UserCoord>) -> Fallible<GameMove<Coord>> {
-        fn go(board: &Board, uc: &UserCoord) -> Fallible<Coord> {
-            board.convert_coordinates(uc)
+    pub fn convert_move_coords<C, P>(&self, m: &GameMove<C, P>) -> Fallible<GameMove<Coord>>
+    where
+        C: Clone + Into<EitherCoord>,
+        P: Clone + Into<Position>,
+    {
+        fn go<C: Clone + Into<EitherCoord>>(board: &Board, c: &C) -> Fallible<Coord> {
+            board.resolve_coord(&c.clone().into())
         }
 
         // Sadly, there are no functors in Rust so we have to apply
         // a function to "holes" in GameMove by hand.
         use GameMove::*;
         Ok(match m {
-            Place(d, uc) => Place(d.clone(), go(self, uc)?),
-            Move(d, uc_from, uc_to) => Move(d.clone(), go(self, uc_from)?, go(self, uc_to)?),
+            Place(d, uc) => Place(*d, go(self, uc)?),
+            Move(d, uc_from, uc_to) => Move(*d, go(self, uc_from)?, go(self, uc_to)?),
             Fight(uc) => Fight(go(self, uc)?),
-            Surprise(uc_from, to) => Surprise(go(self, uc_from)?, *to),
+            Surprise(uc_from, to) => Surprise(go(self, uc_from)?, self.resolve_position(&to.clone().into())?),
             Submit => Submit,
         })
     }
 
-    /// Convert from internal coordinates to user coordinates in a GameMove.
-    pub fn convert_move_coords_to_user(&self, m: &GameMove<Coord>) -> GameMove<UserCoord> {
-        fn go(board: &Board, c: &Coord) -> UserCoord {
-            board.convert_coordinates_to_user(c)
-        }
+    /// Convert from internal coordinates to user coordinates in a GameMove
+    /// (where possible, see `userify_coord`).
+    pub fn convert_move_coords_to_user(&self, m: &GameMove<Coord>) -> GameMove<EitherCoord> {
+        m.map_coords(|c| self.userify_coord(c))
+    }
 
-        // Sadly, there are no functors in Rust so we have to apply
-        // a function to "holes" in GameMove by hand.
+    /// Same as `convert_move_coords_to_user`, but the destination of a
+    /// surprise move is described as a position next to a card (other
+    /// than the one being moved), see `describe_position`.
+    pub fn describe_move(&self, m: &GameMove<Coord>) -> GameMove<EitherCoord, Position> {
+        let exclude = m.source();
+        self.convert_move_coords_to_user(m)
+            .map_destination(|to| self.describe_position(to, exclude.as_ref()))
+    }
+
+    /// Same as `convert_move_coords_to_user`, but the destination of a
+    /// surprise move is given in user coordinates too. Fails for
+    /// positions which have none (see `convert_coordinates_to_user`).
+    pub fn user_move(&self, m: &GameMove<Coord>) -> Fallible<GameMove<UserCoord, UserCoord>> {
         use GameMove::*;
-        match m {
-            Place(d, uc) => Place(d.clone(), go(self, uc)),
-            Move(d, uc_from, uc_to) => Move(d.clone(), go(self, uc_from), go(self, uc_to)),
-            Fight(uc) => Fight(go(self, uc)),
-            Surprise(uc_from, to) => Surprise(go(self, uc_from), *to),
+        let user = |c: &Coord| self.convert_coordinates_to_user(c);
+        Ok(match m {
+            Place(d, c) => Place(*d, user(c)?),
+            Move(d, from, to) => Move(*d, user(from)?, user(to)?),
+            Fight(c) => Fight(user(c)?),
+            Surprise(from, to) => Surprise(user(from)?, user(to)?),
             Submit => Submit,
-        }
+        })
     }
 
     /// Checks if three positions are adjacent to each other.
@@ -393,11 +699,16 @@ impl Board {
         let mut result = vec![];
 
         // Note: combinations are without repetitions.
-        for tri in coords.combinations(3) {
-            let are_adj = Self::are_three_adjacent(grid, tri[0], tri[1], tri[2]);
-            let are_in_line = Self::are_three_in_line(grid, tri[0], tri[1], tri[2]);
-            if are_adj && are_in_line {
-                result.push((*tri[0], *tri[1], *tri[2]));
+        let coords: Vec<&Coord> = coords.collect();
+        for (i, a) in coords.iter().enumerate() {
+            for (j, b) in coords.iter().enumerate().skip(i + 1) {
+                for c in coords.iter().skip(j + 1) {
+                    let are_adj = Self::are_three_adjacent(grid, a, b, c);
+                    let are_in_line = Self::are_three_in_line(grid, a, b, c);
+                    if are_adj && are_in_line {
+                        result.push((**a, **b, **c));
+                    }
+                }
             }
         }
 
@@ -413,68 +724,281 @@ impl Board {
 
     /// Manhattan distance on hex and square grids.
     pub fn distance(grid: &Grid, a: &Coord, b: &Coord) -> usize {
-        match grid {
-            Grid::Hex => (a.x - b.x).abs().max((a.y - b.y).abs()).max((a.z - b.z).abs()) as usize,
-            Grid::Square => ((a.x - b.x).abs() + (a.y - b.y).abs()) as usize,
-        }
+        a.distance(*b, *grid)
     }
 
     /// Convert between user visible (row + card) and internal
-    /// (hex/square) coordinates in presence of given board.
+    /// (hex/square) coordinates in presence of given board. There
+    /// should be a card at the given coordinates.
     pub fn convert_coordinates(&self, user_coord: &UserCoord) -> Fallible<Coord> {
         if user_coord.row == 0 || user_coord.card == 0 {
             bail!("User coord numeration starts from 1");
         }
 
-        let user_row = user_coord.row as usize - 1;
-        let user_card = user_coord.card as usize - 1;
+        let coord = self.convert_user_position(user_coord)?;
+        if self.cards.contains_key(&coord) {
+            Ok(coord)
+        } else if self.row_positions_iter(coord.y).next().is_some() {
+            bail!("Card is out of bounds: {}", user_coord.card)
+        } else {
+            bail!("Row is out of bounds: {}", user_coord.row)
+        }
+    }
 
-        let mut rows: Vec<i8> = self.cards.keys().map(|c| c.y).collect();
-        rows.as_mut_slice().sort();
-        rows.dedup();
+    /// Internal coordinates of a position given in user coordinates,
+    /// whether there is a card or not (see `convert_coordinates_to_user`).
+    pub fn convert_user_position(&self, user_coord: &UserCoord) -> Fallible<Coord> {
+        let y = i16::from(self.top_row()) + i16::from(user_coord.row) - 1;
+        let y = i8::try_from(y).map_err(|_| format_err!("Row is out of bounds: {}", user_coord.row))?;
+        let x = i16::from(self.first_in_row(y)) + i16::from(user_coord.card) - 1;
+        let x = i8::try_from(x).map_err(|_| format_err!("Card is out of bounds: {}", user_coord.card))?;
+        Ok(self.new_coord(x, y))
+    }
 
-        if user_row <= rows.len() {
-            self.row_positions_iter(rows[user_row])
-                .nth(user_card)
-                .copied()
-                .ok_or_else(|| format_err!("Card is out of bounds: {}", user_coord.card))
+    /// `x` coordinate of `c1` in the given row: the leftmost card of the
+    /// row or, for a row without cards, the leftmost position which is
+    /// not to the left of `c1` of the nearest row with cards above it
+    /// (below it for the row above the top one), as the board is drawn.
+    fn first_in_row(&self, y: i8) -> i8 {
+        if let Some(c) = self.row_positions_iter(y).next() {
+            return c.x;
+        }
+        let top = self.top_row();
+        let nearest = if y < top {
+            top
         } else {
-            bail!("Row is out of bounds: {}", user_coord.row);
+            (top..y)
+                .rev()
+                .find(|row| self.row_positions_iter(*row).next().is_some())
+                .unwrap_or(top)
+        };
+        let above = self.new_coord(self.first_in_row(nearest), nearest);
+        match self.grid {
+            Grid::Hex => {
+                let x = (self.drawing_column(&above) - i16::from(y) + 1).div_euclid(2);
+                i8::try_from(x).unwrap_or(above.x)
+            }
+            Grid::Square => above.x,
         }
     }
 
-    /// Convert internal coordinates (hex/square) to user visible coordinates (row
-    /// + card) in presence of given board.
-    pub fn convert_coordinates_to_user(&self, coord: &Coord) -> UserCoord {
-        let top_row = self.top_row(); // this is considered R1.
-        let user_row = coord.y - top_row + 1;
+    /// Internal coordinates of a card given in either user or internal
+    /// coordinates.
+    pub fn resolve_coord(&self, coord: &EitherCoord) -> Fallible<Coord> {
+        match coord {
+            EitherCoord::User(uc) => self.convert_coordinates(uc),
+            EitherCoord::Internal(c) if self.cards.contains_key(c) => Ok(*c),
+            EitherCoord::Internal(c) => bail!("No card at {}", c),
+        }
+    }
 
-        let leftmost_card = self.row_positions_iter(coord.y).min_by_key(|c| c.x).unwrap().x; // this is considered C1
-        let user_card = coord.x - leftmost_card + 1;
+    /// Position next to the given one in the given direction. Hex grids
+    /// have no positions straight above or below, while square grids
+    /// have no diagonal neighbours.
+    pub fn neighbour(&self, coord: &Coord, dir: Direction) -> Fallible<Coord> {
+        use Direction::*;
+        let (dx, dy) = match (self.grid, dir) {
+            (_, LeftOf) => (-1, 0),
+            (_, RightOf) => (1, 0),
+            (Grid::Hex, AboveLeft) => (0, -1),
+            (Grid::Hex, AboveRight) => (1, -1),
+            (Grid::Hex, BelowLeft) => (-1, 1),
+            (Grid::Hex, BelowRight) => (0, 1),
+            (Grid::Hex, Above) | (Grid::Hex, Below) => {
+                bail!(
+                    "There is no position straight {} a card here, use {}left or {}right",
+                    dir,
+                    dir,
+                    dir
+                )
+            }
+            (Grid::Square, Above) => (0, -1),
+            (Grid::Square, Below) => (0, 1),
+            (Grid::Square, _) => bail!("There are no diagonal positions ({}) on a square grid", dir),
+        };
+        Ok(*coord + self.new_coord(dx, dy))
+    }
 
-        UserCoord {
-            row: user_row as u8,
-            card: user_card as u8,
+    /// Internal coordinates of a position (which may have no card yet),
+    /// for example of the destination of a surprise move.
+    pub fn resolve_position(&self, position: &Position) -> Fallible<Coord> {
+        match position {
+            Position::Internal(c) => Ok(*c),
+            Position::User(uc) => self.convert_user_position(uc),
+            Position::Next(dir, card) => self.neighbour(&self.resolve_coord(card)?, *dir),
+        }
+    }
+
+    /// Describes a position as the one next to a card, preferring cards
+    /// in the same row, or gives its user (or internal) coordinates if
+    /// there is no card around (except `exclude`).
+    pub fn describe_position(&self, coord: &Coord, exclude: Option<&Coord>) -> Position {
+        for &dir in &Direction::ALL {
+            let card = self
+                .cards
+                .keys()
+                .filter(|c| Some(*c) != exclude)
+                .find(|c| self.neighbour(c, dir).ok().as_ref() == Some(coord));
+            if let Some(card) = card {
+                return Position::Next(dir, self.userify_coord(card));
+            }
+        }
+        match self.convert_coordinates_to_user(coord) {
+            Ok(uc) => Position::User(uc),
+            Err(_) => Position::Internal(*coord),
+        }
+    }
+
+    /// Convert internal coordinates (hex/square) to user visible
+    /// coordinates (row + card) in presence of given board. Any position
+    /// next to the cards can be given, even without a card:
+    ///
+    /// - rows are counted from the top one with cards, so `r0` is the
+    ///   row above it, and empty rows count too;
+    /// - `c1` is the leftmost card of the row, so `c0` is the position
+    ///   left of it. In a row without cards `c1` is the first position
+    ///   not to the left of `c1` of the nearest row with cards (half a
+    ///   card to the right of it on hex grids, see `first_in_row`).
+    ///
+    /// Positions further away can't be given in user coordinates.
+    pub fn convert_coordinates_to_user(&self, coord: &Coord) -> Fallible<UserCoord> {
+        let row = i16::from(coord.y) - i16::from(self.top_row()) + 1;
+        let card = i16::from(coord.x) - i16::from(self.first_in_row(coord.y)) + 1;
+        match (u8::try_from(row), u8::try_from(card)) {
+            (Ok(row), Ok(card)) => Ok(UserCoord { row, card }),
+            _ => bail!("Position {} has no user coordinates", coord),
+        }
+    }
+
+    /// User coordinates if the position has them, internal otherwise.
+    pub fn userify_coord(&self, coord: &Coord) -> EitherCoord {
+        match self.convert_coordinates_to_user(coord) {
+            Ok(uc) => EitherCoord::User(uc),
+            Err(_) => EitherCoord::Internal(*coord),
         }
     }
 
     pub fn adj_triples_iter(&self) -> impl Iterator<Item = &(Coord, Coord, Coord)> {
         self.adj_triples.iter()
     }
+
+    /// All maximal straight lines of adjacent cards (of two cards or
+    /// more) as they are laid out now. Every line goes in one of the
+    /// directions of the first half of `Coord::unit_vectors`, and the
+    /// lines are grouped by these directions.
+    pub fn lines(&self) -> Vec<Vec<Coord>> {
+        let units = Coord::unit_vectors(self.grid);
+        let mut lines = vec![];
+        for &v in &units[..units.len() / 2] {
+            // Lines start at the cards with no card before them.
+            for &start in self.cards.keys().filter(|&&c| !self.cards.contains_key(&(c - v))) {
+                let line: Vec<_> = iter::successors(Some(start), |&c| Some(c + v))
+                    .take_while(|c| self.cards.contains_key(c))
+                    .collect();
+                if line.len() > 1 {
+                    lines.push(line);
+                }
+            }
+        }
+        lines
+    }
+
+    /// The maximal line of adjacent cards (see `lines`) going through
+    /// the cards at `a` and `b`, if they are in one.
+    pub fn line_through(&self, a: Coord, b: Coord) -> Option<Vec<Coord>> {
+        if a == b {
+            return None;
+        }
+        self.lines()
+            .into_iter()
+            .find(|line| line.contains(&a) && line.contains(&b))
+    }
+
+    /// Rows of cards from the top as they are drawn by `Display for
+    /// Board` (and the other renderers, which have to lay the cards
+    /// out the same way).
+    pub fn drawing_rows(&self) -> Vec<Vec<DrawnCard<'_>>> {
+        let left = self.cards.keys().map(|c| self.drawing_column(c)).min().unwrap_or(0);
+        (self.top_row()..=self.bottom_row())
+            .map(|y| {
+                self.row_iter(y)
+                    .map(|(coord, card)| DrawnCard {
+                        coord,
+                        card,
+                        indent: (self.drawing_column(coord) - left) as usize * HALF_CARD_WIDTH,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Line with the user coordinates above the cards of a drawn row.
+    pub fn drawing_labels(&self, row: &[DrawnCard]) -> String {
+        row.iter().fold(String::new(), |labels, drawn| {
+            format!(
+                "{:<indent$}{}",
+                labels,
+                self.userify_coord(drawn.coord),
+                indent = drawn.indent
+            )
+        })
+    }
+}
+
+/// Width of half of a card drawn by `Display for Board`.
+const HALF_CARD_WIDTH: usize = 7;
+
+/// Width of the dice in a drawn card.
+const DICE_WIDTH: usize = 9;
+
+/// A card in a row drawn by `Display for Board` (see
+/// `Board::drawing_rows`).
+#[derive(Debug, Clone, Copy)]
+pub struct DrawnCard<'a> {
+    pub coord: &'a Coord,
+    pub card: &'a Card,
+    /// Column of the first character of the card.
+    pub indent: usize,
+}
+
+impl DrawnCard<'_> {
+    /// Number of spaces after the dice, up to the end of the card.
+    pub fn padding(&self) -> usize {
+        let dice = &self.card.dice;
+        let width: usize = dice.iter().map(|d| d.to_string().len()).sum::<usize>() + dice.len().saturating_sub(1);
+        DICE_WIDTH.saturating_sub(width)
+    }
+
+    /// Column right after the card.
+    pub fn end(&self) -> usize {
+        self.indent + 2 * HALF_CARD_WIDTH - 1
+    }
 }
 
+// Every row is drawn as two lines: card coordinates and cards with
+// their kinds and dice stacks (from the bottom up):
+//
+//        r1c1          r1c2          r1c3
+//        [J r2<b1    ] [G          ] [J          ]
+// r2c1          r2c2          r2c3          r2c4
+// [G          ] [J b3       ] [G          ] [G w1       ]
 impl fmt::Display for Board {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let top = self.top_row();
-        let bottom = self.bottom_row();
-
-        for y in top..=bottom {
-            for c in self.row_cards_iter(y) {
-                // TODO: add coordinates as well
-                // Without them we can't distinguish some cases.
-                write!(f, "{} ", c)?;
+        for row in self.drawing_rows() {
+            let mut cards = String::new();
+            for drawn in &row {
+                let dice: Vec<_> = drawn.card.dice.iter().map(|d| d.to_string()).collect();
+                cards = format!(
+                    "{:<indent$}[{} {}{}]",
+                    cards,
+                    drawn.card.kind.letter(),
+                    dice.join("<"),
+                    " ".repeat(drawn.padding()),
+                    indent = drawn.indent
+                );
             }
-            writeln!(f, "")?;
+            writeln!(f, "{}", self.drawing_labels(&row))?;
+            writeln!(f, "{}", cards)?;
         }
 
         Ok(())
@@ -484,6 +1008,44 @@ impl fmt::Display for Board {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::card::DiceColor;
+
+    #[test]
+    fn test_display() -> Fallible<()> {
+        let mut b = Board::new(Layout::Bricks7, Deck::ordered("JGJGJGG")?);
+        b.card_at_mut(&Coord::new_hex(1, -1))
+            .unwrap()
+            .dice
+            .extend(vec![Die::new(DiceColor::Red, 2), Die::new(DiceColor::Black, 1)]);
+        let expected = [
+            "       r1c1          r1c2          r1c3",
+            "       [J r2<b1    ] [G          ] [J          ]",
+            "r2c1          r2c2          r2c3          r2c4",
+            "[G          ] [J          ] [G          ] [G          ]",
+        ];
+        assert_eq!(b.to_string().lines().collect::<Vec<_>>(), expected);
+
+        // Rows of square grids are aligned.
+        let b = Board::new(Layout::Rectangle6, Deck::ordered("JGJGJG")?);
+        let drawing = b.to_string();
+        let lines: Vec<_> = drawing.lines().collect();
+        assert_eq!(lines[0], lines[2].replace("r2", "r1"));
+
+        // The layout shared by the renderers.
+        let mut b = Board::new(Layout::Bricks7, Deck::ordered("JGJGJGG")?);
+        b.card_at_mut(&Coord::new_hex(1, -1))
+            .unwrap()
+            .dice
+            .extend(vec![Die::new(DiceColor::Red, 2), Die::new(DiceColor::Black, 10)]);
+        let rows = b.drawing_rows();
+        let indents: Vec<Vec<usize>> = rows.iter().map(|row| row.iter().map(|d| d.indent).collect()).collect();
+        assert_eq!(indents, [vec![7, 21, 35], vec![0, 14, 28, 42]]);
+        assert_eq!((rows[0][0].padding(), rows[0][0].end()), (3, 20));
+        assert_eq!(rows[1][0].padding(), 9);
+        assert_eq!(b.drawing_labels(&rows[0]), "       r1c1          r1c2          r1c3");
+        assert!(b.to_string().contains("[J r2<b10   ] [G          ]"));
+        Ok(())
+    }
 
     #[test]
     fn test_rectangle() {
@@ -525,4 +1087,191 @@ mod test {
         assert_eq!(Some(&Coord::new_hex(2, 0)), it.next());
         assert_eq!(None, it.next());
     }
+
+    #[test]
+    fn test_cards() -> Fallible<()> {
+        let b = Board::new(Layout::Bricks7, Deck::ordered("jjjjggg")?);
+        let map: BTreeMap<_, _> = b.cards.iter().map(|(c, card)| (*c, card.clone())).collect();
+        assert!(b.cards.keys().eq(map.keys()));
+        let mut cards: Cards = map.clone().into_iter().rev().collect();
+        assert_eq!(cards, b.cards);
+
+        let c = Coord::new_hex;
+        let card = cards.remove(&c(0, 0)).unwrap();
+        assert!(!cards.contains_key(&c(0, 0)));
+        assert!(cards.insert(c(5, 5), card.clone()).is_none());
+        assert_eq!(cards.get(&c(5, 5)), Some(&card));
+        assert_eq!(cards.keys().last(), Some(&c(5, 5)));
+        assert!(cards.keys().zip(cards.keys().skip(1)).all(|(a, b)| a < b));
+        assert_eq!(cards.insert(c(5, 5), card.clone()), Some(card));
+        assert_eq!(cards.len(), 7);
+        Ok(())
+    }
+
+    #[test]
+    fn test_neighbours() -> Fallible<()> {
+        let mut b = Board::new(Layout::Bricks7, Deck::ordered("jjjjggg")?);
+        let c = Coord::new_hex;
+        assert_eq!(b.neighbours(&c(1, -1)), &[c(0, 0), c(1, 0), c(2, -1)]);
+        assert_eq!(b.neighbours(&c(0, -1)), &[c(0, 0), c(1, -1)]);
+        assert!(b.neighbours(&c(5, 5)).is_empty());
+        let brute_force = |b: &Board| {
+            b.empty_positions_iter().all(|p| {
+                let expected: Vec<_> = b
+                    .cards
+                    .keys()
+                    .filter(|c| Board::distance(&b.grid, c, p) == 1)
+                    .copied()
+                    .collect();
+                b.card_at(p).is_none() && b.neighbours(p) == expected.as_slice()
+            })
+        };
+        assert_eq!(b.empty_positions_iter().count(), 13);
+        assert!(brute_force(&b));
+        assert_eq!(b.neighbours_iter(c(1, -1)).count(), 3);
+        assert!(b.is_adjacent(c(1, -1), c(0, 0)) && b.is_adjacent(c(0, -1), c(0, 0)));
+        assert!(!b.is_adjacent(c(0, 0), c(2, 0)));
+        let next_to_two: Vec<_> = b.empty_neighbour_positions(2).collect();
+        assert_eq!(next_to_two.len(), 7);
+        assert!(next_to_two.contains(&c(0, -1)) && !next_to_two.contains(&c(-1, 0)));
+        assert!(next_to_two
+            .iter()
+            .all(|p| b.coords_iter().filter(|c| b.is_adjacent(**c, *p)).count() >= 2));
+        assert_eq!(b.empty_neighbour_positions(1).count(), 13);
+
+        b.move_card(&c(0, 0), &c(0, -1));
+        assert_eq!(b.neighbours(&c(1, -1)), &[c(0, -1), c(1, 0), c(2, -1)]);
+        assert_eq!(b.neighbours(&c(0, 0)), &[c(0, -1), c(1, -1), c(1, 0)]);
+        assert!(brute_force(&b));
+        Ok(())
+    }
+
+    #[test]
+    fn test_lines() -> Fallible<()> {
+        let b = Board::new(Layout::Bricks7, Deck::ordered("jjjjggg")?);
+        let c = Coord::new_hex;
+        assert_eq!(b.lines().len(), 8);
+        assert_eq!(
+            b.line_through(c(2, 0), c(0, 0)),
+            Some(vec![c(0, 0), c(1, 0), c(2, 0), c(3, 0)])
+        );
+        assert_eq!(b.line_through(c(0, 0), c(1, -1)), Some(vec![c(1, -1), c(0, 0)]));
+        assert_eq!(b.line_through(c(0, 0), c(2, -1)), None);
+        assert_eq!(b.line_through(c(0, 0), c(0, 0)), None);
+
+        // The lines make up the adjacent triples.
+        for (layout, cards) in &[
+            (Layout::Bricks7, "jjjjggg"),
+            (Layout::Hex7, "jjjjggg"),
+            (Layout::Rectangle6, "jjjggg"),
+        ] {
+            let b = Board::new(layout.clone(), Deck::ordered(cards)?);
+            let mut triples: Vec<_> = b
+                .lines()
+                .iter()
+                .flat_map(|line| line.windows(3))
+                .map(|w| {
+                    let mut t = [w[0], w[1], w[2]];
+                    t.sort();
+                    (t[0], t[1], t[2])
+                })
+                .collect();
+            triples.sort();
+            assert_eq!(triples, b.adj_triples);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_card() -> Fallible<()> {
+        for (layout, cards) in &[
+            (Layout::Bricks7, "jjjjggg"),
+            (Layout::Hex7, "jjjjggg"),
+            (Layout::Rectangle6, "jjjggg"),
+        ] {
+            let mut b = Board::new(layout.clone(), Deck::ordered(cards)?);
+            let (left, right, top, bottom) = b.bounding_box();
+            let from: Vec<_> = b.coords_iter().copied().collect();
+            for f in from {
+                for x in left - 2..=right + 2 {
+                    for y in top - 2..=bottom + 2 {
+                        let to = b.new_coord(x, y);
+                        if b.card_at(&to).is_some() {
+                            continue;
+                        }
+                        b.move_card(&f, &to);
+                        assert_eq!(b.adj_triples, Board::adjacent_triples(&b.grid, b.cards.keys()));
+                        b.move_card(&to, &f);
+                        assert_eq!(b.adj_triples, Board::adjacent_triples(&b.grid, b.cards.keys()));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_positions() -> Fallible<()> {
+        use Direction::*;
+
+        let next = |dir, row, card| Position::Next(dir, EitherCoord::User(UserCoord::new(row, card)));
+
+        let b = Board::new(Layout::Bricks7, Deck::seven_shuffled());
+        let c = Coord::new_hex;
+        assert_eq!(b.resolve_position(&next(LeftOf, 1, 1))?, c(0, -1));
+        assert_eq!(b.resolve_position(&next(AboveLeft, 2, 1))?, c(0, -1));
+        assert_eq!(b.resolve_position(&next(BelowRight, 1, 3))?, c(3, 0));
+        assert_eq!(b.resolve_position(&next(BelowLeft, 2, 2))?, c(0, 1));
+        assert_eq!(b.resolve_position(&c(4, -1).into())?, c(4, -1));
+        assert!(b.resolve_position(&next(Above, 2, 1)).is_err());
+        assert!(b.resolve_position(&next(LeftOf, 3, 1)).is_err());
+
+        assert_eq!(b.describe_position(&c(0, -1), None), next(LeftOf, 1, 1));
+        assert_eq!(b.describe_position(&c(0, -1), Some(&c(1, -1))), next(AboveLeft, 2, 1));
+        assert_eq!(
+            b.describe_position(&c(5, 5), None),
+            Position::User(UserCoord::new(7, 8))
+        );
+        assert_eq!(b.describe_position(&c(-3, 0), None), c(-3, 0).into());
+
+        // Empty positions around the cards have user coordinates too.
+        let user = |c| b.convert_coordinates_to_user(&c);
+        assert_eq!(user(c(2, -2))?, UserCoord::new(0, 1));
+        assert_eq!(user(c(-1, 0))?, UserCoord::new(2, 0));
+        assert_eq!(user(c(0, 1))?, UserCoord::new(3, 1));
+        assert!(user(c(-2, 0)).is_err());
+        for x in -1..=4 {
+            for y in -2..=1 {
+                if let Ok(uc) = user(c(x, y)) {
+                    assert_eq!(b.convert_user_position(&uc)?, c(x, y));
+                }
+            }
+        }
+        assert!(b.convert_coordinates(&UserCoord::new(3, 1)).is_err());
+
+        let b = Board::new(Layout::Rectangle6, Deck::six_shuffled());
+        let c = Coord::new_square;
+        assert_eq!(b.resolve_position(&next(Above, 1, 1))?, c(0, -2));
+        assert_eq!(b.resolve_position(&next(LeftOf, 2, 1))?, c(-1, 0));
+        assert!(b.resolve_position(&next(AboveLeft, 2, 1)).is_err());
+        assert_eq!(b.describe_position(&c(1, 1), None), next(Below, 2, 2));
+        Ok(())
+    }
+    #[test]
+    fn test_user_move() -> Fallible<()> {
+        let b = Board::new(Layout::Bricks7, Deck::seven_shuffled());
+        let c = Coord::new_hex;
+        let die = Die::new(DiceColor::Red, 2);
+
+        let place = GameMove::Place(die, c(0, 0));
+        assert_eq!(b.user_move(&place)?, GameMove::Place(die, UserCoord::new(2, 1)));
+
+        let surprise = GameMove::Surprise(c(1, -1), c(2, -2));
+        let user = b.user_move(&surprise)?;
+        assert_eq!(user, GameMove::Surprise(UserCoord::new(1, 1), UserCoord::new(0, 1)));
+        assert_eq!(b.convert_move_coords(&user)?, surprise);
+
+        assert!(b.user_move(&GameMove::Surprise(c(1, -1), c(-2, 0))).is_err());
+        Ok(())
+    }
 }
@@ -1,8 +1,20 @@
-use failure::{bail, Fallible};
+use crate::error::{bail, ensure, format_err, Error, Fallible};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+use core::str::FromStr;
+#[cfg(feature = "std")]
+use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
-use std::convert::TryFrom;
-use std::fmt;
-use std::str::FromStr;
+use rand::Rng;
+#[cfg(feature = "std")]
+use rand::SeedableRng;
+
+use smallvec::SmallVec;
+
+use crate::board::Layout;
 
 #[cfg(feature = "with_serde")]
 use serde::{Deserialize, Serialize};
@@ -16,10 +28,36 @@ pub enum DiceColor {
     White,
 }
 
-/// A die in the game. Has a color and value. It's a normal cube die,
-/// so values are from 1 to 6.
+impl DiceColor {
+    pub const ALL: [DiceColor; 3] = [DiceColor::Red, DiceColor::Black, DiceColor::White];
+
+    /// Letter of the color as used in dice, like "r2".
+    pub fn letter(self) -> char {
+        match self {
+            DiceColor::Red => 'r',
+            DiceColor::Black => 'b',
+            DiceColor::White => 'w',
+        }
+    }
+}
+
+impl TryFrom<char> for DiceColor {
+    type Error = Error;
+    fn try_from(c: char) -> Fallible<Self> {
+        match c.to_ascii_lowercase() {
+            'r' => Ok(DiceColor::Red),
+            'b' => Ok(DiceColor::Black),
+            'w' => Ok(DiceColor::White),
+            _ => bail!("unrecognized dice color: {}", c),
+        }
+    }
+}
+
+/// A die in the game. Has a color and value. It's normally a cube
+/// die, so values are from 1 to 6 (see `DieValues` for others). It
+/// takes two bytes, so it's passed around by value.
 #[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
-#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug, Hash)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug, Hash)]
 pub struct Die {
     pub color: DiceColor,
     pub value: u8,
@@ -36,9 +74,15 @@ impl Die {
 
     /// Returns (winner, loser) pair and whether swap happened.
     pub fn compare_dice(d1: Die, d2: Die) -> (Die, Die, bool) {
-        if d1.color == DiceColor::White && d2.value == 6 && d2.color == DiceColor::Red {
+        Die::compare_dice_up_to(d1, d2, DieValues::CUBIC.max)
+    }
+
+    /// Same as `compare_dice`, but with red dice going up to
+    /// `highest_red` (which is beaten by the white die).
+    pub fn compare_dice_up_to(d1: Die, d2: Die, highest_red: u8) -> (Die, Die, bool) {
+        if d1.color == DiceColor::White && d2.value == highest_red && d2.color == DiceColor::Red {
             (d1, d2, false)
-        } else if d2.color == DiceColor::White && d1.value == 6 && d1.color == DiceColor::Red {
+        } else if d2.color == DiceColor::White && d1.value == highest_red && d1.color == DiceColor::Red {
             (d2, d1, true)
         } else if d1.value > d2.value {
             (d1, d2, false)
@@ -51,12 +95,89 @@ impl Die {
 // B3, W1, R6 and so on.
 impl fmt::Display for Die {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let color = match &self.color {
-            DiceColor::Red => "r",
-            DiceColor::White => "w",
-            DiceColor::Black => "b",
+        write!(f, "{}{}", self.color.letter(), self.value)
+    }
+}
+
+// Parsed from the same notation: r2, B3 and so on.
+impl FromStr for Die {
+    type Err = Error;
+    fn from_str(s: &str) -> Fallible<Self> {
+        let mut chars = s.chars();
+        let color = match chars.next().map(DiceColor::try_from) {
+            Some(Ok(color)) => color,
+            _ => bail!("can't parse die: {}", s),
+        };
+        let value: u8 = chars
+            .as_str()
+            .parse()
+            .map_err(|_| format_err!("can't parse die: {}", s))?;
+        ensure!(value > 0, "can't parse die: {}", s);
+        Ok(Die::new(color, value))
+    }
+}
+
+/// Values dice of a color can show: from 1 to 6 for the usual cube
+/// dice, others are for variants (like d8 or doubled values).
+#[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct DieValues {
+    pub min: u8,
+    pub max: u8,
+}
+
+impl DieValues {
+    pub const CUBIC: DieValues = DieValues { min: 1, max: 6 };
+
+    pub fn new(min: u8, max: u8) -> Fallible<Self> {
+        ensure!(0 < min && min <= max, "bad die values: {} to {}", min, max);
+        Ok(DieValues { min, max })
+    }
+
+    pub fn contains(self, value: u8) -> bool {
+        (self.min..=self.max).contains(&value)
+    }
+
+    /// The value in the same place of the range as `value` of a cube
+    /// die (rounded), for dealing the dice of the players: doubled
+    /// for 2-12, and 2, 4 and 6 become 2, 5 and 8 for d8.
+    pub fn from_cubic(self, value: u8) -> u8 {
+        let (cubic, span) = (DieValues::CUBIC, u32::from(self.max - self.min));
+        let steps = u32::from(value - cubic.min) * span;
+        let cubic_span = u32::from(cubic.max - cubic.min);
+        self.min + ((2 * steps + cubic_span) / (2 * cubic_span)) as u8
+    }
+}
+
+impl Default for DieValues {
+    fn default() -> Self {
+        DieValues::CUBIC
+    }
+}
+
+// 1-6, 2-12 and so on.
+impl fmt::Display for DieValues {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.min, self.max)
+    }
+}
+
+// Parsed from the same notation or like d8 (for 1-8).
+impl FromStr for DieValues {
+    type Err = Error;
+    fn from_str(s: &str) -> Fallible<Self> {
+        let bound = |b: &str| {
+            b.trim()
+                .parse()
+                .map_err(|_| format_err!("can't parse die values: {}", s))
         };
-        write!(f, "{}{}", color, self.value)
+        match s.trim().strip_prefix('d') {
+            Some(max) => DieValues::new(1, bound(max)?),
+            None => match s.split_once('-') {
+                Some((min, max)) => DieValues::new(bound(min)?, bound(max)?),
+                None => bail!("can't parse die values (expected like 1-6 or d8): {}", s),
+            },
+        }
     }
 }
 
@@ -70,8 +191,19 @@ pub enum CardKind {
     Fort,
 }
 
+impl CardKind {
+    /// Letter of the kind as used in decks, like "JJJGGGG".
+    pub fn letter(self) -> char {
+        match self {
+            CardKind::Jade => 'J',
+            CardKind::Gold => 'G',
+            CardKind::Fort => 'F',
+        }
+    }
+}
+
 impl TryFrom<char> for CardKind {
-    type Error = failure::Error;
+    type Error = Error;
     fn try_from(c: char) -> Fallible<Self> {
         match c {
             'g' | 'G' | 'w' | 'W' => Ok(CardKind::Gold),
@@ -82,6 +214,16 @@ impl TryFrom<char> for CardKind {
     }
 }
 
+/// Abilities of special cards (for expansions of the game), changing
+/// the rules for the dice on them. The moves are checked against them
+/// in `Game::validate_move`.
+#[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Copy, Clone, Debug)]
+pub enum Ability {
+    /// Dice can't fight on the card.
+    Peaceful,
+}
+
 /// A card in the game. It is of certain kind and may have dice on it.
 #[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
 #[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Debug)]
@@ -90,10 +232,18 @@ pub struct Card {
 
     /// The dice are appended to the end. I.e. the top-most die which
     /// covers everything is the last in the vector.
-    pub dice: Vec<Die>,
+    pub dice: Dice,
+
+    /// Ability of the card, if it's a special one.
+    #[cfg_attr(feature = "with_serde", serde(default))]
+    pub ability: Option<Ability>,
 }
 
-// Jade[], Gold[R6 > W1], Jade[B3] and so on.
+/// Dice stacked on a card. There are at most three of them (a card
+/// with three dice is won), so they are kept inline.
+pub type Dice = SmallVec<[Die; 3]>;
+
+// Jade[], Gold[R6 > W1], Jade Peaceful[B3] and so on.
 impl fmt::Display for Card {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let kind = match &self.kind {
@@ -110,11 +260,29 @@ impl fmt::Display for Card {
             .as_slice()
             .join(" < ");
 
-        write!(f, "{}[{}]", kind, dice_str)
+        match self.ability {
+            Some(ability) => write!(f, "{} {:?}[{}]", kind, ability, dice_str),
+            None => write!(f, "{}[{}]", kind, dice_str),
+        }
     }
 }
 
 impl Card {
+    /// A card without dice.
+    pub fn new(kind: CardKind) -> Self {
+        Card {
+            kind,
+            dice: Dice::new(),
+            ability: None,
+        }
+    }
+
+    /// The same card with the ability.
+    pub fn with_ability(mut self, ability: Ability) -> Self {
+        self.ability = Some(ability);
+        self
+    }
+
     pub fn top_die(&self) -> Option<&Die> {
         self.dice.last()
     }
@@ -125,10 +293,9 @@ impl Card {
 }
 
 impl TryFrom<char> for Card {
-    type Error = failure::Error;
+    type Error = Error;
     fn try_from(c: char) -> Fallible<Self> {
-        let kind = CardKind::try_from(c)?;
-        Ok(Card { kind, dice: vec![] })
+        Ok(Card::new(CardKind::try_from(c)?))
     }
 }
 
@@ -141,7 +308,7 @@ pub struct Deck {
 
 impl IntoIterator for Deck {
     type Item = Card;
-    type IntoIter = std::vec::IntoIter<Self::Item>;
+    type IntoIter = alloc::vec::IntoIter<Self::Item>;
 
     fn into_iter(self) -> Self::IntoIter {
         self.cards.into_iter()
@@ -149,7 +316,7 @@ impl IntoIterator for Deck {
 }
 
 impl FromStr for Deck {
-    type Err = failure::Error;
+    type Err = Error;
     fn from_str(s: &str) -> Fallible<Self> {
         let cards: Fallible<Vec<Card>> = s.chars().map(Card::try_from).collect();
         Ok(Deck { cards: cards? })
@@ -167,6 +334,7 @@ impl Deck {
     }
 
     /// Shuffled deck defined by a specification like 'JJJGGGG'.
+    #[cfg(feature = "std")]
     #[allow(unused)]
     pub fn shuffled(descr: &str) -> Fallible<Self> {
         let mut deck: Deck = descr.parse()?;
@@ -174,28 +342,184 @@ impl Deck {
         Ok(deck)
     }
 
+    /// Shuffled deck defined by a specification like 'JJJGGGG'. The
+    /// same `seed` always gives the same order of cards.
+    #[cfg(feature = "std")]
+    pub fn shuffled_with_seed(descr: &str, seed: u64) -> Fallible<Self> {
+        let mut deck: Deck = descr.parse()?;
+        let mut rng = StdRng::seed_from_u64(seed);
+        deck.cards.as_mut_slice().shuffle(&mut rng);
+        Ok(deck)
+    }
+
     /// A standard deck with 4 Jades and 3 Gold cards which are
     /// randomly shuffled.
+    #[cfg(feature = "std")]
     pub fn seven_shuffled() -> Self {
-        let mut deck = Deck::ordered("jjjjggg").unwrap();
-        deck.shuffle();
-        deck
+        Deck::preset(Act::Four).shuffle(&mut rand::thread_rng()).build()
     }
 
     /// A deck with 6 cards selected out of 4 Jade and 3 Gold cards.
+    #[cfg(feature = "std")]
     pub fn six_shuffled() -> Self {
-        let mut deck = Deck::ordered("jjjjggg").unwrap();
-        deck.shuffle();
-        deck.drop_one_card();
-        deck
+        Deck::preset(Act::One).shuffle(&mut rand::thread_rng()).build()
+    }
+
+    /// An empty deck to add cards to (see `DeckBuilder`).
+    pub fn builder() -> DeckBuilder {
+        DeckBuilder::default()
+    }
+
+    /// The deck of the act, not shuffled yet: 4 Jade and 3 Gold cards,
+    /// 6 of which are dealt in Act 1.
+    pub fn preset(act: Act) -> DeckBuilder {
+        let builder = Deck::builder().jade(4).gold(3);
+        match act {
+            Act::One => builder.draw(6),
+            Act::Two | Act::Three | Act::Four => builder,
+        }
     }
 
+    #[cfg(feature = "std")]
     fn shuffle(&mut self) {
         let mut rng = rand::thread_rng();
         self.cards.as_mut_slice().shuffle(&mut rng);
     }
 
-    fn drop_one_card(&mut self) {
+    pub fn len(&self) -> usize {
+        self.cards.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cards.is_empty()
+    }
+
+    /// Number of cards of the given kind in the deck.
+    pub fn count(&self, kind: CardKind) -> usize {
+        self.cards.iter().filter(|card| card.kind == kind).count()
+    }
+
+    /// Removes the last card, for layouts with fewer cards.
+    pub fn drop_one_card(&mut self) {
         self.cards.truncate(self.cards.len() - 1);
     }
 }
+
+/// Acts of The Rules, introducing the rules of the game one by one.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum Act {
+    One,
+    Two,
+    Three,
+    Four,
+}
+
+impl Act {
+    /// Layout the act is played on.
+    pub fn layout(self) -> Layout {
+        match self {
+            Act::One => Layout::Rectangle6,
+            Act::Two | Act::Three | Act::Four => Layout::Bricks7,
+        }
+    }
+}
+
+/// Deck made card by card, for example:
+///
+/// ```
+/// # use rokumon_core::card::Deck;
+/// let mut rng = rand::thread_rng();
+/// let deck = Deck::builder().jade(4).gold(3).shuffle(&mut rng).draw(6).build();
+/// assert_eq!(deck.len(), 6);
+/// ```
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct DeckBuilder {
+    cards: Vec<Card>,
+    /// Number of cards dealt from the top of the deck, if not all.
+    draw: Option<usize>,
+}
+
+impl DeckBuilder {
+    /// Adds `count` cards of the kind at the bottom of the deck.
+    pub fn add(mut self, kind: CardKind, count: usize) -> Self {
+        self.cards.extend((0..count).map(|_| Card::new(kind)));
+        self
+    }
+
+    pub fn jade(self, count: usize) -> Self {
+        self.add(CardKind::Jade, count)
+    }
+
+    pub fn gold(self, count: usize) -> Self {
+        self.add(CardKind::Gold, count)
+    }
+
+    /// Adds the card (which might be a special one) at the bottom of
+    /// the deck.
+    pub fn card(mut self, card: Card) -> Self {
+        self.cards.push(card);
+        self
+    }
+
+    /// Shuffles the cards added so far.
+    pub fn shuffle<R: Rng + ?Sized>(mut self, rng: &mut R) -> Self {
+        self.cards.as_mut_slice().shuffle(rng);
+        self
+    }
+
+    /// Takes only `count` cards from the top of the deck when it's
+    /// built, leaving the rest out of the game.
+    pub fn draw(mut self, count: usize) -> Self {
+        self.draw = Some(count);
+        self
+    }
+
+    pub fn build(mut self) -> Deck {
+        if let Some(count) = self.draw {
+            self.cards.truncate(count);
+        }
+        Deck { cards: self.cards }
+    }
+}
+
+/// Seven Jade or Gold cards (one is to be dropped for `Rectangle6`).
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Deck {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let cards = (0..7)
+            .map(|_| {
+                let kind = if u.arbitrary()? { CardKind::Jade } else { CardKind::Gold };
+                Ok(Card::new(kind))
+            })
+            .collect::<arbitrary::Result<_>>()?;
+        Ok(Deck { cards })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_deck_builder() -> Fallible<()> {
+        assert_eq!(Deck::builder().gold(2).jade(1).build(), Deck::ordered("ggj")?);
+        assert_eq!(
+            Deck::builder().add(CardKind::Fort, 1).draw(3).build(),
+            Deck::ordered("f")?
+        );
+
+        for &act in &[Act::One, Act::Two, Act::Three, Act::Four] {
+            let deck = Deck::preset(act).shuffle(&mut StdRng::seed_from_u64(7)).build();
+            assert_eq!(deck.len(), act.layout().cards_count());
+            assert!(deck.count(CardKind::Jade) <= 4 && deck.count(CardKind::Gold) <= 3);
+        }
+        // The same seed gives the same deck.
+        let shuffled = |seed| {
+            Deck::preset(Act::Four)
+                .shuffle(&mut StdRng::seed_from_u64(seed))
+                .build()
+        };
+        assert_eq!(shuffled(1), shuffled(1));
+        Ok(())
+    }
+}
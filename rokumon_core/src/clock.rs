@@ -0,0 +1,188 @@
+use failure::{bail, Fallible};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Chess-clock time control: each player starts with `base` time and
+/// gets `increment` added after each of their moves.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct TimeControl {
+    pub base: Duration,
+    pub increment: Duration,
+}
+
+impl TimeControl {
+    pub fn new(base: Duration, increment: Duration) -> Self {
+        TimeControl { base, increment }
+    }
+}
+
+/// Parses time controls like "300+2" (300 seconds of base time plus 2
+/// seconds of increment) or just "300" (no increment).
+impl FromStr for TimeControl {
+    type Err = failure::Error;
+    fn from_str(s: &str) -> Fallible<Self> {
+        let mut parts = s.trim().splitn(2, '+');
+        let base = parts.next().unwrap_or("").trim();
+        let increment = parts.next().unwrap_or("0").trim();
+        match (base.parse::<u64>(), increment.parse::<u64>()) {
+            (Ok(base), Ok(increment)) => Ok(TimeControl::new(
+                Duration::from_secs(base),
+                Duration::from_secs(increment),
+            )),
+            _ => bail!("Can't parse time control, expected something like 300+2, got: {}", s),
+        }
+    }
+}
+
+// 300+2
+impl fmt::Display for TimeControl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}+{}", self.base.as_secs(), self.increment.as_secs())
+    }
+}
+
+/// Game clock keeping track of the time left for both players.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct Clock {
+    player1_left: Duration,
+    player2_left: Duration,
+    pub increment: Duration,
+}
+
+impl Clock {
+    pub fn new(time_control: TimeControl) -> Self {
+        Clock {
+            player1_left: time_control.base,
+            player2_left: time_control.base,
+            increment: time_control.increment,
+        }
+    }
+
+    /// Time left for the given player.
+    pub fn time_left(&self, for_player1: bool) -> Duration {
+        if for_player1 {
+            self.player1_left
+        } else {
+            self.player2_left
+        }
+    }
+
+    /// Registers a move which took `spent` time. Returns false if the
+    /// player has run out of time (their flag fell), in that case the
+    /// increment is not added.
+    pub fn punch(&mut self, for_player1: bool, spent: Duration) -> bool {
+        let left = if for_player1 {
+            &mut self.player1_left
+        } else {
+            &mut self.player2_left
+        };
+
+        match left.checked_sub(spent) {
+            Some(rest) => {
+                *left = rest + self.increment;
+                true
+            }
+            None => {
+                *left = Duration::from_secs(0);
+                false
+            }
+        }
+    }
+}
+
+/// Where searches limited in time get the time from, so that the core
+/// doesn't depend on the way the host measures it (`Instant` is not
+/// available in browsers, for example).
+pub trait TimeSource: Send + Sync {
+    /// Milliseconds elapsed since some fixed moment (only the
+    /// differences matter).
+    fn now_ms(&self) -> f64;
+}
+
+/// Time measured with `std::time::Instant`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StdTime;
+
+impl TimeSource for StdTime {
+    fn now_ms(&self) -> f64 {
+        // Started lazily, so that nothing is measured on hosts without
+        // `Instant` until it's used.
+        static START: OnceLock<Instant> = OnceLock::new();
+        START.get_or_init(Instant::now).elapsed().as_secs_f64() * 1000.0
+    }
+}
+
+/// Time which goes on only when it's asked for: every call of `now_ms`
+/// is a step of `ms_per_step` milliseconds. Searches limited in time
+/// are deterministic with it, which is also handy for hosts without a
+/// clock.
+#[derive(Debug, Default)]
+pub struct StepCounter {
+    steps: AtomicU64,
+    ms_per_step: f64,
+}
+
+impl StepCounter {
+    pub fn new(ms_per_step: f64) -> Self {
+        StepCounter {
+            steps: AtomicU64::new(0),
+            ms_per_step,
+        }
+    }
+
+    /// Number of times the time has been asked for.
+    pub fn steps(&self) -> u64 {
+        self.steps.load(Ordering::Relaxed)
+    }
+}
+
+impl TimeSource for StepCounter {
+    fn now_ms(&self) -> f64 {
+        let steps = self.steps.fetch_add(1, Ordering::Relaxed);
+        steps as f64 * self.ms_per_step
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_time_control_parsing() -> Fallible<()> {
+        let secs = Duration::from_secs;
+        assert_eq!("300+2".parse::<TimeControl>()?, TimeControl::new(secs(300), secs(2)));
+        assert_eq!(" 60 ".parse::<TimeControl>()?, TimeControl::new(secs(60), secs(0)));
+        assert!("60+".parse::<TimeControl>().is_err());
+        assert!("+2".parse::<TimeControl>().is_err());
+        assert!("1m".parse::<TimeControl>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_clock() {
+        let secs = Duration::from_secs;
+        let mut clock = Clock::new(TimeControl::new(secs(10), secs(1)));
+
+        assert!(clock.punch(true, secs(4)));
+        assert_eq!(clock.time_left(true), secs(7));
+        assert_eq!(clock.time_left(false), secs(10));
+
+        assert!(clock.punch(false, secs(10)));
+        assert_eq!(clock.time_left(false), secs(1));
+
+        assert!(!clock.punch(false, secs(2)));
+        assert_eq!(clock.time_left(false), secs(0));
+    }
+
+    #[test]
+    fn test_step_counter() {
+        let time = StepCounter::new(0.5);
+        assert_eq!(time.now_ms(), 0.0);
+        assert_eq!(time.now_ms(), 0.5);
+        assert_eq!(time.now_ms(), 1.0);
+        assert_eq!(time.steps(), 3);
+    }
+}
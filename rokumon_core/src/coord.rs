@@ -1,6 +1,11 @@
-use failure::bail;
-use std::fmt;
-use std::str::FromStr;
+use crate::error::{bail, Error};
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::{Add, Neg, Sub};
+use core::str::FromStr;
+
+use crate::board::Grid;
 
 #[cfg(feature = "with_serde")]
 use serde::{Deserialize, Serialize};
@@ -46,20 +51,105 @@ impl Coord {
     pub fn new_square(x: i8, y: i8) -> Self {
         Coord { x, y, z: 0 }
     }
+
+    /// Offsets of the neighbouring positions, clockwise starting from
+    /// the one to the right (as the board is drawn).
+    pub fn unit_vectors(grid: Grid) -> Vec<Coord> {
+        match grid {
+            Grid::Hex => [(1, 0), (0, 1), (-1, 1), (-1, 0), (0, -1), (1, -1)]
+                .iter()
+                .map(|&(x, y)| Coord::new_hex(x, y))
+                .collect(),
+            Grid::Square => [(1, 0), (0, 1), (-1, 0), (0, -1)]
+                .iter()
+                .map(|&(x, y)| Coord::new_square(x, y))
+                .collect(),
+        }
+    }
+
+    /// Neighbouring positions (whether they have cards or not), in the
+    /// order of `unit_vectors`.
+    pub fn neighbours(self, grid: Grid) -> impl Iterator<Item = Coord> {
+        Coord::unit_vectors(grid).into_iter().map(move |v| self + v)
+    }
+
+    /// Manhattan distance on hex and square grids.
+    pub fn distance(self, other: Coord, grid: Grid) -> usize {
+        let d = self - other;
+        match grid {
+            Grid::Hex => d.x.abs().max(d.y.abs()).max(d.z.abs()) as usize,
+            Grid::Square => (d.x.abs() + d.y.abs()) as usize,
+        }
+    }
+
+    /// Rotates the position clockwise (as the board is drawn) around
+    /// the origin: by 60 degrees on hex grids and by 90 on square ones.
+    pub fn rotate(self, grid: Grid) -> Coord {
+        match grid {
+            Grid::Hex => Coord {
+                x: -self.y,
+                y: -self.z,
+                z: -self.x,
+            },
+            Grid::Square => Coord::new_square(-self.y, self.x),
+        }
+    }
+
+    /// Mirrors the position left to right (as the board is drawn)
+    /// around the vertical line through the origin.
+    pub fn mirror(self, grid: Grid) -> Coord {
+        match grid {
+            Grid::Hex => Coord {
+                x: self.z,
+                y: self.y,
+                z: self.x,
+            },
+            Grid::Square => Coord::new_square(-self.x, self.y),
+        }
+    }
+}
+
+impl Add for Coord {
+    type Output = Coord;
+    fn add(self, other: Coord) -> Coord {
+        Coord {
+            x: self.x + other.x,
+            y: self.y + other.y,
+            z: self.z + other.z,
+        }
+    }
+}
+
+impl Sub for Coord {
+    type Output = Coord;
+    fn sub(self, other: Coord) -> Coord {
+        self + -other
+    }
+}
+
+impl Neg for Coord {
+    type Output = Coord;
+    fn neg(self) -> Coord {
+        Coord {
+            x: -self.x,
+            y: -self.y,
+            z: -self.z,
+        }
+    }
 }
 
 /// More user-friendly coordinates easier to undertand and type. Row
 /// is counted from 1 and start from the top. Card is counted from 1
 /// and starts from the left.
 #[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone)]
 pub struct UserCoord {
     pub row: u8,
     pub card: u8,
 }
 
 impl FromStr for UserCoord {
-    type Err = failure::Error;
+    type Err = Error;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let trimmed = s.trim();
         let chars: Vec<char> = trimmed.chars().collect();
@@ -91,6 +181,125 @@ impl UserCoord {
     }
 }
 
+/// Coordinates entered by users: either user coordinates or the
+/// internal ones (see `Board::resolve_coord`).
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone)]
+pub enum EitherCoord {
+    User(UserCoord),
+    Internal(Coord),
+}
+
+impl From<UserCoord> for EitherCoord {
+    fn from(c: UserCoord) -> Self {
+        EitherCoord::User(c)
+    }
+}
+
+impl From<Coord> for EitherCoord {
+    fn from(c: Coord) -> Self {
+        EitherCoord::Internal(c)
+    }
+}
+
+impl fmt::Display for EitherCoord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EitherCoord::User(c) => write!(f, "{}", c),
+            EitherCoord::Internal(c) => write!(f, "{}", c),
+        }
+    }
+}
+
+/// Directions to the neighbouring positions, as the board is drawn.
+/// Hex grids have diagonal neighbours above and below, while square
+/// grids have straight ones.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Copy, Clone)]
+pub enum Direction {
+    LeftOf,
+    RightOf,
+    Above,
+    Below,
+    AboveLeft,
+    AboveRight,
+    BelowLeft,
+    BelowRight,
+}
+
+impl Direction {
+    pub const ALL: [Direction; 8] = [
+        Direction::LeftOf,
+        Direction::RightOf,
+        Direction::Above,
+        Direction::Below,
+        Direction::AboveLeft,
+        Direction::AboveRight,
+        Direction::BelowLeft,
+        Direction::BelowRight,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Direction::LeftOf => "leftof",
+            Direction::RightOf => "rightof",
+            Direction::Above => "above",
+            Direction::Below => "below",
+            Direction::AboveLeft => "aboveleft",
+            Direction::AboveRight => "aboveright",
+            Direction::BelowLeft => "belowleft",
+            Direction::BelowRight => "belowright",
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Direction {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match Direction::ALL.iter().find(|d| d.name().eq_ignore_ascii_case(s.trim())) {
+            Some(d) => Ok(*d),
+            None => bail!("Can't parse direction: {}", s),
+        }
+    }
+}
+
+/// Destination of a surprise move, where there is no card yet: either
+/// internal coordinates, user coordinates (see
+/// `Board::convert_coordinates_to_user`) or the position next to a
+/// card, like `leftof r1c1` (see `Board::resolve_position`).
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone)]
+pub enum Position {
+    Internal(Coord),
+    User(UserCoord),
+    Next(Direction, EitherCoord),
+}
+
+impl From<Coord> for Position {
+    fn from(c: Coord) -> Self {
+        Position::Internal(c)
+    }
+}
+
+impl From<UserCoord> for Position {
+    fn from(c: UserCoord) -> Self {
+        Position::User(c)
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Position::Internal(c) => write!(f, "{}", c),
+            Position::User(c) => write!(f, "{}", c),
+            Position::Next(dir, card) => write!(f, "{} {}", dir, card),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -141,9 +350,48 @@ mod test {
         test_failure!(1, 0);
         test_failure!(20, 1);
 
+        let resolve = |c: EitherCoord| board.resolve_coord(&c);
+        assert_eq!(resolve(UserCoord::new(2, 2).into())?, Coord::new_hex(1, 0));
+        assert_eq!(resolve(Coord::new_hex(1, 0).into())?, Coord::new_hex(1, 0));
+        assert!(resolve(Coord::new_hex(5, 0).into()).is_err());
+
         Ok(())
     }
 
+    #[test]
+    fn test_coord_arithmetic() {
+        let h = Coord::new_hex;
+        let s = Coord::new_square;
+
+        assert_eq!(h(1, -1) + h(2, 0), h(3, -1));
+        assert_eq!(h(1, -1) - h(2, 0), h(-1, -1));
+        assert_eq!(-s(1, 2), s(-1, -2));
+
+        let hex: Vec<_> = h(1, 0).neighbours(Grid::Hex).collect();
+        assert_eq!(hex, vec![h(2, 0), h(1, 1), h(0, 1), h(0, 0), h(1, -1), h(2, -1)]);
+        assert!(hex.iter().all(|c| c.distance(h(1, 0), Grid::Hex) == 1));
+        let square: Vec<_> = s(0, 0).neighbours(Grid::Square).collect();
+        assert_eq!(square, vec![s(1, 0), s(0, 1), s(-1, 0), s(0, -1)]);
+
+        // Rotating takes each neighbour to the next one, the full turn
+        // brings it back.
+        for (grid, ns) in &[(Grid::Hex, &hex), (Grid::Square, &square)] {
+            let units = Coord::unit_vectors(*grid);
+            for (ix, v) in units.iter().enumerate() {
+                assert_eq!(v.rotate(*grid), units[(ix + 1) % units.len()]);
+            }
+            let c = ns[0] + ns[1];
+            let full = (0..units.len()).fold(c, |c, _| c.rotate(*grid));
+            assert_eq!(full, c);
+            assert_eq!(c.mirror(*grid).mirror(*grid), c);
+        }
+
+        // Mirroring swaps left and right, but keeps the rows.
+        assert_eq!(h(1, 0).mirror(Grid::Hex), h(-1, 0));
+        assert_eq!(h(0, 1).mirror(Grid::Hex), h(-1, 1));
+        assert_eq!(s(2, 1).mirror(Grid::Square), s(-2, 1));
+    }
+
     #[test]
     fn test_user_coord_parsing() -> Fallible<()> {
         macro_rules! test {
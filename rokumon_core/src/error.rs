@@ -0,0 +1,75 @@
+//! Errors of the rules (parsing, illegal moves and setups). With the
+//! `std` feature they are `failure` errors, like in the rest of the
+//! crate; without it they are just messages.
+
+#[cfg(feature = "std")]
+pub use failure::{bail, ensure, format_err, Error, Fallible};
+
+#[cfg(not(feature = "std"))]
+pub use self::message::{Error, Fallible};
+
+#[cfg(not(feature = "std"))]
+pub(crate) use self::message::{bail, ensure, format_err};
+
+#[cfg(not(feature = "std"))]
+mod message {
+    use alloc::string::{String, ToString};
+    use core::fmt;
+    use core::num::ParseIntError;
+
+    /// An error message.
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Error(String);
+
+    pub type Fallible<T> = Result<T, Error>;
+
+    impl Error {
+        pub fn msg<D: fmt::Display>(message: D) -> Self {
+            Error(message.to_string())
+        }
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str(&self.0)
+        }
+    }
+
+    impl From<ParseIntError> for Error {
+        fn from(e: ParseIntError) -> Self {
+            Error::msg(e)
+        }
+    }
+
+    // The same as the macros of `failure`.
+
+    macro_rules! format_err {
+        ($($arg:tt)*) => {
+            $crate::error::Error::msg(alloc::format!($($arg)*))
+        };
+    }
+
+    macro_rules! bail {
+        ($e:expr) => {
+            return Err($crate::error::Error::msg($e))
+        };
+        ($fmt:expr, $($arg:tt)*) => {
+            return Err($crate::error::format_err!($fmt, $($arg)*))
+        };
+    }
+
+    macro_rules! ensure {
+        ($cond:expr, $e:expr) => {
+            if !($cond) {
+                $crate::error::bail!($e);
+            }
+        };
+        ($cond:expr, $fmt:expr, $($arg:tt)*) => {
+            if !($cond) {
+                $crate::error::bail!($fmt, $($arg)*);
+            }
+        };
+    }
+
+    pub(crate) use {bail, ensure, format_err};
+}
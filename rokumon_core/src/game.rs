@@ -1,13 +1,25 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use alloc::{format, vec};
+use core::fmt;
+use core::hash::{Hash, Hasher};
+use core::str::FromStr;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use rand::rngs::StdRng;
+#[cfg(feature = "std")]
 use rand::seq::SliceRandom;
-use std::collections::BTreeMap;
-use std::collections::BTreeSet;
-use std::fmt;
+#[cfg(feature = "std")]
+use rand::{Rng, SeedableRng};
 
-use failure::{bail, ensure, format_err, Fallible};
+use crate::error::{bail, ensure, format_err, Error, Fallible};
+use smallvec::{smallvec, SmallVec};
 
 use crate::board::{Board, Layout};
-use crate::card::{Card, Deck, DiceColor, Die};
-use crate::coord::{Coord, UserCoord};
+use crate::card::{Ability, CardKind, Deck, DiceColor, Die, DieValues};
+use crate::coord::{Coord, EitherCoord, Position};
+#[cfg(feature = "std")]
+use crate::play::GameObserver;
 
 #[cfg(feature = "with_serde")]
 use serde::{Deserialize, Serialize};
@@ -16,20 +28,24 @@ use serde::{Deserialize, Serialize};
 #[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
 #[derive(Debug, Clone)]
 pub struct Player {
-    name: String,
-    dice: Vec<Die>,
+    pub(crate) name: String,
+    pub(crate) dice: Stock,
 }
 
+/// Dice in the stock of a player: there are five of them at most, so
+/// they are kept inline.
+type Stock = SmallVec<[Die; 5]>;
+
 impl Player {
     fn first(rules: &Rules) -> Self {
         use DiceColor::*;
-        let d = Die::new;
+        let d = |color, value| rules.cubic_die(color, value);
 
         let dice = if rules.enable_fight_move {
-            vec![d(Red, 2), d(Red, 2), d(Red, 4), d(Red, 6)]
+            smallvec![d(Red, 2), d(Red, 2), d(Red, 4), d(Red, 6)]
         } else {
             // No difference then, just generate 4 equal dice.
-            vec![d(Red, 2); 4]
+            smallvec![d(Red, 2); 4]
         };
 
         Player {
@@ -40,12 +56,12 @@ impl Player {
 
     fn second(rules: &Rules) -> Self {
         use DiceColor::*;
-        let d = Die::new;
+        let d = |color, value| rules.cubic_die(color, value);
         let dice = if rules.enable_fight_move {
-            vec![d(Black, 1), d(Black, 3), d(Black, 3), d(Black, 5), d(White, 1)]
+            smallvec![d(Black, 1), d(Black, 3), d(Black, 3), d(Black, 5), d(White, 1)]
         } else {
             // No difference then, just generate 5 equal dice.
-            vec![d(Black, 1); 5]
+            smallvec![d(Black, 1); 5]
         };
 
         Player {
@@ -63,6 +79,14 @@ impl Player {
         self.dice.remove(die_ix);
         Ok(())
     }
+
+    /// Different dice in the stock (ordered).
+    fn distinct_dice(&self) -> Stock {
+        let mut dice = self.dice.clone();
+        dice.sort();
+        dice.dedup();
+        dice
+    }
 }
 
 impl fmt::Display for Player {
@@ -80,18 +104,20 @@ impl fmt::Display for Player {
 }
 
 /// Representation of a possible game move. Parametrised by a type of
-/// coordinates used (user coordinates or internal ones).
+/// coordinates used for cards (user coordinates or internal ones) and
+/// for the destination of a surprise move, where there is no card yet
+/// (internal coordinates unless entered by users, see `Position`).
 #[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
 #[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Hash)]
-pub enum GameMove<C> {
+pub enum GameMove<C, P = Coord> {
     Place(Die, C),
     Move(Die, C, C),
     Fight(C),
-    Surprise(C, Coord),
+    Surprise(C, P),
     Submit,
 }
 
-impl<T: fmt::Display> fmt::Display for GameMove<T> {
+impl<T: fmt::Display, P: fmt::Display> fmt::Display for GameMove<T, P> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         use GameMove::*;
         match self {
@@ -104,18 +130,105 @@ impl<T: fmt::Display> fmt::Display for GameMove<T> {
     }
 }
 
+impl<C, P: Clone> GameMove<C, P> {
+    /// The same move with the cards given in other coordinates (the
+    /// destination of a surprise move stays as it is).
+    pub fn map_coords<D>(&self, mut f: impl FnMut(&C) -> D) -> GameMove<D, P> {
+        use GameMove::*;
+        match self {
+            Place(d, c) => Place(*d, f(c)),
+            Move(d, from, to) => Move(*d, f(from), f(to)),
+            Fight(c) => Fight(f(c)),
+            Surprise(from, to) => Surprise(f(from), to.clone()),
+            Submit => Submit,
+        }
+    }
+
+    /// The same move with the destination of a surprise move given in
+    /// other coordinates.
+    pub fn map_destination<Q>(&self, f: impl FnOnce(&P) -> Q) -> GameMove<C, Q>
+    where
+        C: Clone,
+    {
+        use GameMove::*;
+        match self {
+            Place(d, c) => Place(*d, c.clone()),
+            Move(d, from, to) => Move(*d, from.clone(), to.clone()),
+            Fight(c) => Fight(c.clone()),
+            Surprise(from, to) => Surprise(from.clone(), f(to)),
+            Submit => Submit,
+        }
+    }
+}
+
+impl GameMove<Coord> {
+    /// Card the move takes a die or a card from.
+    pub fn source(&self) -> Option<Coord> {
+        match self {
+            GameMove::Move(_, from, _) | GameMove::Surprise(from, _) => Some(*from),
+            _ => None,
+        }
+    }
+
+    /// Card the move ends on: where the die goes, where the fight is or
+    /// where the card is moved to.
+    pub fn target(&self) -> Option<Coord> {
+        match self {
+            GameMove::Place(_, to) | GameMove::Move(_, _, to) | GameMove::Surprise(_, to) => Some(*to),
+            GameMove::Fight(place) => Some(*place),
+            GameMove::Submit => None,
+        }
+    }
+}
+
+#[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum ZIndex {
     Top,
     Bottom,
 }
 
+#[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct FightResult {
     pub losing_die: Die,
     pub losing_position: ZIndex,
 }
 
+/// Move played in the game together with what is known about how it
+/// has been played.
+#[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct HistoryEntry {
+    pub game_move: GameMove<Coord>,
+    /// Set for fight moves (needed to take them back).
+    pub fight_result: Option<FightResult>,
+    /// Time the player has spent on the move. Only known for the
+    /// games played with `play::play_game_with`.
+    pub elapsed: Option<Duration>,
+}
+
+/// What has happened when a move has been applied.
+#[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct MoveOutcome {
+    /// Set for fight moves.
+    pub fight_result: Option<FightResult>,
+    /// Whether the game has been won while the moved die was in
+    /// flight, i.e. lifting it has uncovered a row. The result stands
+    /// no matter where the die lands.
+    pub won_in_flight: bool,
+    /// Game result after the move.
+    pub result: GameResult,
+}
+
+impl MoveOutcome {
+    /// Whether the move has finished the game.
+    pub fn ends_game(&self) -> bool {
+        self.result != GameResult::InProgress
+    }
+}
+
 /// Current game result, can be either won by one of the player or
 /// still in progress.
 #[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
@@ -126,13 +239,105 @@ pub enum GameResult {
     SecondPlayerWon,
 }
 
-/// Variations in game rules. Currently, it's whether we allow certain
-/// moves or not.
+/// Why the game has been won.
+#[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum WinReason {
+    /// Winner's dice on top of three cards in a row.
+    ThreeInARow { coords: [Coord; 3] },
+    /// Three dice stacked on a card.
+    ThreeInStack { coord: Coord },
+    /// The loser had no moves to make.
+    NoMoves,
+    /// The loser has given up.
+    Submitted,
+}
+
+/// Cards the decks for the standard layouts are made of: 4 Jade and 3
+/// Gold ones (Act 1 uses 6 of them).
+const STANDARD_CARDS: [(CardKind, usize); 3] = [(CardKind::Jade, 4), (CardKind::Gold, 3), (CardKind::Fort, 0)];
+
+/// Number of plies in the setup phase of the `first_placements`
+/// handicap: the player places two dice in a row.
+const SETUP_PLIES: usize = 2;
+
+/// Handicap evening out a game between players of different strength,
+/// written like `extra:r4,placements:2` (see `FromStr`).
+#[derive(PartialEq, Eq, Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
+pub struct Handicap {
+    /// Die added to the stock of its owner (by its color).
+    pub extra_die: Option<Die>,
+    /// Die taken away from the stock of its owner.
+    pub removed_die: Option<Die>,
+    /// Player (the first one if true) who starts the game with a setup
+    /// phase: they place their first two dice in a row before the
+    /// players start to alternate.
+    pub first_placements: Option<bool>,
+}
+
+impl Handicap {
+    pub fn is_none(&self) -> bool {
+        *self == Handicap::default()
+    }
+}
+
+// extra:r4,remove:b5,placements:1 (or none).
+impl fmt::Display for Handicap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = vec![];
+        if let Some(die) = self.extra_die {
+            parts.push(format!("extra:{}", die));
+        }
+        if let Some(die) = self.removed_die {
+            parts.push(format!("remove:{}", die));
+        }
+        if let Some(player1) = self.first_placements {
+            parts.push(format!("placements:{}", if player1 { 1 } else { 2 }));
+        }
+        if parts.is_empty() {
+            parts.push(String::from("none"));
+        }
+        write!(f, "{}", parts.join(","))
+    }
+}
+
+impl FromStr for Handicap {
+    type Err = Error;
+    fn from_str(s: &str) -> Fallible<Self> {
+        let mut handicap = Handicap::default();
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty() && *p != "none") {
+            match part.split_once(':') {
+                Some(("extra", die)) => handicap.extra_die = Some(die.parse()?),
+                Some(("remove", die)) => handicap.removed_die = Some(die.parse()?),
+                Some(("placements", "1")) => handicap.first_placements = Some(true),
+                Some(("placements", "2")) => handicap.first_placements = Some(false),
+                _ => bail!(
+                    "can't parse handicap (expected extra:<die>, remove:<die> or placements:<1|2>): {}",
+                    part
+                ),
+            }
+        }
+        Ok(handicap)
+    }
+}
+
+/// Variations in game rules: whether we allow certain moves or not,
+/// which player moves first and the handicap.
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 #[cfg_attr(feature = "with_serde", derive(Serialize, Deserialize))]
 pub struct Rules {
     enable_fight_move: bool,
     enable_surprise_move: bool,
+    /// The second player (with black and white dice) makes the first
+    /// move instead of the first one (with red dice).
+    #[cfg_attr(feature = "with_serde", serde(default))]
+    pub(crate) player2_starts: bool,
+    #[cfg_attr(feature = "with_serde", serde(default))]
+    handicap: Handicap,
+    /// Values of the dice by their colors (see `DieValues`).
+    #[cfg_attr(feature = "with_serde", serde(default))]
+    die_values: [DieValues; 3],
 }
 
 impl Default for Rules {
@@ -140,6 +345,9 @@ impl Default for Rules {
         Rules {
             enable_fight_move: true,
             enable_surprise_move: true,
+            player2_starts: false,
+            handicap: Handicap::default(),
+            die_values: Default::default(),
         }
     }
 }
@@ -149,8 +357,105 @@ impl Rules {
         Rules {
             enable_fight_move,
             enable_surprise_move,
+            player2_starts: false,
+            handicap: Handicap::default(),
+            die_values: Default::default(),
         }
     }
+
+    /// The same rules with the first or the second player making the
+    /// first move.
+    pub fn with_player1_starting(mut self, player1_starts: bool) -> Self {
+        self.player2_starts = !player1_starts;
+        self
+    }
+
+    /// The same rules with a random player making the first move (the
+    /// same one for the same `seed`).
+    #[cfg(feature = "std")]
+    pub fn with_random_starting_player(self, seed: Option<u64>) -> Self {
+        let player1_starts = match seed {
+            Some(seed) => StdRng::seed_from_u64(seed).gen(),
+            None => rand::thread_rng().gen(),
+        };
+        self.with_player1_starting(player1_starts)
+    }
+
+    /// The same rules with the handicap (the player with the setup
+    /// phase starts). Fails if the removed die is not in the stock of
+    /// its owner.
+    pub fn with_handicap(mut self, handicap: Handicap) -> Fallible<Self> {
+        if let Some(die) = handicap.removed_die {
+            let stock = if die.belongs_to_player1() {
+                Player::first(&self).dice
+            } else {
+                Player::second(&self).dice
+            };
+            ensure!(stock.contains(&die), "handicap: there is no {} to remove", die);
+        }
+        if let Some(player1) = handicap.first_placements {
+            self.player2_starts = !player1;
+        }
+        self.handicap = handicap;
+        Ok(self)
+    }
+
+    pub fn handicap(&self) -> Handicap {
+        self.handicap
+    }
+
+    /// The same rules with the dice of the color showing the values,
+    /// for variants with other dice than the usual cubes. The players
+    /// start with the dice in the same places of the range as the
+    /// usual ones (see `DieValues::from_cubic`), so the handicap has
+    /// to be set after this.
+    pub fn with_die_values(mut self, color: DiceColor, values: DieValues) -> Self {
+        self.die_values[color as usize] = values;
+        self
+    }
+
+    pub fn die_values(&self, color: DiceColor) -> DieValues {
+        self.die_values[color as usize]
+    }
+
+    /// The die of the color in place of the cube die showing `value`.
+    fn cubic_die(&self, color: DiceColor, value: u8) -> Die {
+        Die::new(color, self.die_values(color).from_cubic(value))
+    }
+
+    /// Checks that the die shows one of the values of its color.
+    pub fn check_die(&self, die: Die) -> Fallible<()> {
+        let values = self.die_values(die.color);
+        ensure!(
+            values.contains(die.value),
+            "{} doesn't fit the values {} of {:?} dice",
+            die,
+            values,
+            die.color
+        );
+        Ok(())
+    }
+
+    /// Whether the first player makes the first move (the player with
+    /// the setup phase of the handicap does, if there is one).
+    pub fn player1_starts(&self) -> bool {
+        self.handicap.first_placements.unwrap_or(!self.player2_starts)
+    }
+
+    pub fn fight_enabled(&self) -> bool {
+        self.enable_fight_move
+    }
+
+    pub fn surprise_enabled(&self) -> bool {
+        self.enable_surprise_move
+    }
+}
+
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Rules {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(Rules::new(u.arbitrary()?, u.arbitrary()?).with_player1_starting(u.arbitrary()?))
+    }
 }
 
 /// Represents the whole game state with board, players and additional
@@ -160,14 +465,14 @@ impl Rules {
 #[derive(Debug, Clone)]
 pub struct Game {
     pub board: Board,
-    rules: Rules,
-    player1: Player,
-    player2: Player,
+    pub(crate) rules: Rules,
+    pub(crate) player1: Player,
+    pub(crate) player2: Player,
     pub player1_moves: bool,
-    player1_surprises: u8,
-    player2_surprises: u8,
+    pub(crate) player1_surprises: u8,
+    pub(crate) player2_surprises: u8,
     pub result: GameResult,
-    pub history: Vec<GameMove<Coord>>,
+    pub history: Vec<HistoryEntry>,
 }
 
 impl fmt::Display for Game {
@@ -185,14 +490,26 @@ impl fmt::Display for Game {
 }
 
 impl Game {
-    /// Create a new game (with position from Act 4 for now).
+    /// Create a new game (with position from Act 4 for now). Panics if
+    /// the deck doesn't fit the layout, see `try_new`.
     pub fn new(layout: Layout, deck: Deck, rules: Rules) -> Self {
+        let mut players = [Player::first(&rules), Player::second(&rules)];
+        let owner = |die: Die| usize::from(!die.belongs_to_player1());
+        if let Some(die) = rules.handicap.extra_die {
+            players[owner(die)].dice.push(die);
+        }
+        if let Some(die) = rules.handicap.removed_die {
+            // It's there, see `Rules::with_handicap`.
+            players[owner(die)].remove_die(&die).ok();
+        }
+        let [player1, player2] = players;
+
         Game {
             board: Board::new(layout, deck),
             rules,
-            player1: Player::first(&rules),
-            player2: Player::second(&rules),
-            player1_moves: true,
+            player1,
+            player2,
+            player1_moves: rules.player1_starts(),
             player1_surprises: 0,
             player2_surprises: 0,
             result: GameResult::InProgress,
@@ -200,6 +517,90 @@ impl Game {
         }
     }
 
+    /// Same as `new`, but fails if the deck doesn't fit the layout: it
+    /// needs a card for every position and, unless the layout is
+    /// custom, has to be made of the cards of the game (see
+    /// `STANDARD_CARDS`). Dice of the players have to fit the rules
+    /// too (see `Rules::check_die`).
+    pub fn try_new(layout: Layout, deck: Deck, rules: Rules) -> Fallible<Self> {
+        ensure!(
+            deck.len() == layout.cards_count(),
+            "layout {} needs {} cards, but there are {} in the deck",
+            layout,
+            layout.cards_count(),
+            deck.len()
+        );
+        if !matches!(layout, Layout::Custom(..)) {
+            for &(kind, available) in &STANDARD_CARDS {
+                ensure!(
+                    deck.count(kind) <= available,
+                    "there are {} {:?} cards in the deck, but only {} in the game",
+                    deck.count(kind),
+                    kind,
+                    available
+                );
+            }
+        }
+        // The removed die may be missing if the die values have been
+        // changed after the handicap.
+        rules.with_handicap(rules.handicap)?;
+        let game = Game::new(layout, deck, rules);
+        for die in game.player1.dice.iter().chain(&game.player2.dice) {
+            rules.check_die(*die)?;
+        }
+        Ok(game)
+    }
+
+    /// Creates a new game and plays the given moves in it (checking
+    /// that they are legal).
+    pub fn from_moves(layout: Layout, deck: Deck, rules: Rules, moves: &[GameMove<Coord>]) -> Fallible<Self> {
+        let mut game = Game::try_new(layout, deck, rules)?;
+        for (ix, m) in moves.iter().enumerate() {
+            if let Err(err) = game.apply_move(m) {
+                bail!("Can't play move {} ({}): {}", ix + 1, game.userify_move(m), err);
+            }
+        }
+        Ok(game)
+    }
+
+    /// Same as `from_moves`, but with moves in user coordinates (as in
+    /// transcripts).
+    pub fn from_user_moves<C, P>(layout: Layout, deck: Deck, rules: Rules, moves: &[GameMove<C, P>]) -> Fallible<Self>
+    where
+        C: Clone + Into<EitherCoord> + fmt::Display,
+        P: Clone + Into<Position> + fmt::Display,
+    {
+        let mut game = Game::try_new(layout, deck, rules)?;
+        for (ix, m) in moves.iter().enumerate() {
+            if let Err(err) = game.apply_user_move(m) {
+                bail!("Can't play move {} ({}): {}", ix + 1, m, err);
+            }
+        }
+        Ok(game)
+    }
+
+    pub fn rules(&self) -> Rules {
+        self.rules
+    }
+
+    /// Number of surprise moves made by a given player.
+    pub fn surprises_used(&self, for_player1: bool) -> u8 {
+        if for_player1 {
+            self.player1_surprises
+        } else {
+            self.player2_surprises
+        }
+    }
+
+    /// Dice in the stock of a given player.
+    pub fn stock(&self, for_player1: bool) -> &[Die] {
+        if for_player1 {
+            &self.player1.dice
+        } else {
+            &self.player2.dice
+        }
+    }
+
     fn current_player(&self) -> &Player {
         if self.player1_moves {
             &self.player1
@@ -216,6 +617,22 @@ impl Game {
         }
     }
 
+    /// Checks the move against the abilities of the cards it's made at
+    /// (see `Ability`).
+    fn check_abilities(&self, game_move: &GameMove<Coord>) -> Fallible<()> {
+        for coord in game_move.source().into_iter().chain(game_move.target()) {
+            match self.board.card_at(&coord).and_then(|card| card.ability) {
+                Some(Ability::Peaceful) => ensure!(
+                    !matches!(game_move, GameMove::Fight(..)),
+                    "fight: there are no fights at the peaceful card at {}",
+                    coord
+                ),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
     fn current_player_surprises(&self) -> u8 {
         if self.player1_moves {
             self.player1_surprises
@@ -233,6 +650,11 @@ impl Game {
             self.result == GameResult::InProgress,
             "can't apply move to finished game",
         );
+        ensure!(
+            !self.in_setup() || matches!(game_move, Place(..) | Submit),
+            "only dice can be placed in the setup phase of the handicap",
+        );
+        self.check_abilities(game_move)?;
 
         match game_move {
             Place(die, coord) => {
@@ -347,29 +769,72 @@ impl Game {
         }
     }
 
+    /// Moves played so far (the history without the annotations).
+    pub fn moves(&self) -> Vec<GameMove<Coord>> {
+        self.history.iter().map(|e| e.game_move.clone()).collect()
+    }
+
+    /// The last move played, if any.
+    pub fn last_move(&self) -> Option<&GameMove<Coord>> {
+        self.history.last().map(|e| &e.game_move)
+    }
+
     /// Number of ply (semi-move) to be played. Starts from zero.
     pub fn ply_to_be_played(&self) -> usize {
         self.history.len()
     }
 
     /// Applies a move to the current game state.
-    pub fn apply_move(&mut self, game_move: &GameMove<Coord>) -> Fallible<Option<FightResult>> {
+    pub fn apply_move(&mut self, game_move: &GameMove<Coord>) -> Fallible<MoveOutcome> {
         self.validate_move(game_move)?;
 
-        Ok(self.apply_move_unchecked(game_move))
+        Ok(self.apply_move_with_outcome(game_move))
+    }
+
+    /// Applies a move to the current game state and notifies
+    /// `observers` about it (and about the fight outcome, if any).
+    #[cfg(feature = "std")]
+    pub fn apply_move_observed(
+        &mut self,
+        game_move: &GameMove<Coord>,
+        observers: &mut [&mut dyn GameObserver],
+    ) -> Fallible<MoveOutcome> {
+        let outcome = self.apply_move(game_move)?;
+
+        for o in observers.iter_mut() {
+            o.on_move_applied(self, game_move);
+            if let (GameMove::Fight(place), Some(fight_result)) = (game_move, &outcome.fight_result) {
+                o.on_fight_resolved(self, *place, fight_result);
+            }
+        }
+
+        Ok(outcome)
     }
 
     /// Applies a move in the user coordinates to the current game state.
-    pub fn apply_user_move(&mut self, user_move: &GameMove<UserCoord>) -> Fallible<Option<FightResult>> {
+    pub fn apply_user_move<C, P>(&mut self, user_move: &GameMove<C, P>) -> Fallible<MoveOutcome>
+    where
+        C: Clone + Into<EitherCoord>,
+        P: Clone + Into<Position>,
+    {
         let game_move = self.convert_move_coords(user_move)?;
         self.apply_move(&game_move)
     }
 
     /// Applies a move to the current game state (without validation).
+    /// Returns the fight result needed to undo the move.
     pub fn apply_move_unchecked(&mut self, game_move: &GameMove<Coord>) -> Option<FightResult> {
+        self.apply_move_with_outcome(game_move).fight_result
+    }
+
+    fn apply_move_with_outcome(&mut self, game_move: &GameMove<Coord>) -> MoveOutcome {
         use GameMove::*;
 
+        #[cfg(feature = "strict-checks")]
+        let before = self.clone();
+
         let mut fight_result = None;
+        let mut won_in_flight = false;
 
         // Here we consider all moves validated, so we use unwrap
         // freely, even though there might be still be failures
@@ -381,7 +846,7 @@ impl Game {
 
                 // Add the die to the card.
                 let card = self.board.card_at_mut(coord).unwrap();
-                card.dice.push(die.clone());
+                card.dice.push(*die);
 
                 self.result = self.result();
             }
@@ -399,6 +864,7 @@ impl Game {
                 if intermediate_result != GameResult::InProgress {
                     // Set the game result.
                     self.result = intermediate_result;
+                    won_in_flight = true;
 
                     // Continue with the rest of the move (this won't
                     // affect game result).
@@ -418,11 +884,12 @@ impl Game {
                 let top_die = battle_card.dice.pop().unwrap();
                 let bottom_die = battle_card.dice.pop().unwrap();
 
-                let (winner, loser, swapped) = Die::compare_dice(top_die, bottom_die);
+                let highest_red = self.rules.die_values(DiceColor::Red).max;
+                let (winner, loser, swapped) = Die::compare_dice_up_to(top_die, bottom_die, highest_red);
                 battle_card.dice.push(winner);
 
                 let losing_position = if swapped { ZIndex::Top } else { ZIndex::Bottom };
-                let losing_die = loser.clone();
+                let losing_die = loser;
                 fight_result = Some(FightResult {
                     losing_die,
                     losing_position,
@@ -438,9 +905,7 @@ impl Game {
             }
 
             Surprise(from, to) => {
-                let card = self.board.cards.remove(&from).unwrap();
-                self.board.cards.insert(*to, card);
-                self.board.refresh_adj_triples();
+                self.board.move_card(from, to);
 
                 if self.player1_moves {
                     self.player1_surprises += 1;
@@ -460,17 +925,35 @@ impl Game {
             }
         };
 
-        self.player1_moves = !self.player1_moves;
-        self.history.push(game_move.clone());
-
-        fight_result
+        if !self.keeps_turn(self.history.len()) {
+            self.player1_moves = !self.player1_moves;
+        }
+        self.history.push(HistoryEntry {
+            game_move: game_move.clone(),
+            fight_result: fight_result.clone(),
+            elapsed: None,
+        });
+
+        #[cfg(feature = "strict-checks")]
+        self.check_applied(&before, game_move, fight_result.clone());
+
+        MoveOutcome {
+            fight_result,
+            won_in_flight,
+            result: self.result,
+        }
     }
 
     pub fn undo_move(&mut self, game_move: &GameMove<Coord>, fight_result: Option<FightResult>) {
         use GameMove::*;
 
+        #[cfg(feature = "strict-checks")]
+        let (dice_before, result_before) = (self.all_dice(), self.result);
+
         self.history.pop();
-        self.player1_moves = !self.player1_moves;
+        if !self.keeps_turn(self.history.len()) {
+            self.player1_moves = !self.player1_moves;
+        }
 
         // Here we consider the move which was made validated, so we
         // use unwrap freely, even though there might be still be
@@ -522,9 +1005,7 @@ impl Game {
             }
 
             Surprise(from, to) => {
-                let card = self.board.cards.remove(&to).unwrap();
-                self.board.cards.insert(*from, card);
-                self.board.refresh_adj_triples();
+                self.board.move_card(to, from);
 
                 if self.player1_moves {
                     self.player1_surprises -= 1;
@@ -539,6 +1020,62 @@ impl Game {
                 self.result = GameResult::InProgress;
             }
         };
+
+        #[cfg(feature = "strict-checks")]
+        {
+            assert_eq!(
+                self.all_dice(),
+                dice_before,
+                "Dice have changed after undoing {}",
+                game_move
+            );
+            assert!(
+                self.result == GameResult::InProgress || result_before != GameResult::InProgress,
+                "Undoing {} has finished the game",
+                game_move
+            );
+        }
+    }
+
+    /// Checks the invariants of the rules after `game_move` has turned
+    /// `before` into this game (with the `strict-checks` feature): no
+    /// dice appear or disappear, a finished game stays finished with
+    /// the same result and undoing the move gives the same position.
+    #[cfg(feature = "strict-checks")]
+    fn check_applied(&self, before: &Game, game_move: &GameMove<Coord>, fight_result: Option<FightResult>) {
+        assert_eq!(
+            self.all_dice(),
+            before.all_dice(),
+            "Dice have changed after {}",
+            game_move
+        );
+        assert!(
+            before.result == GameResult::InProgress || self.result == before.result,
+            "{} has changed the result of a finished game",
+            game_move
+        );
+
+        let mut undone = self.clone();
+        undone.undo_move(game_move, fight_result);
+        assert!(
+            undone.position_key() == before.position_key(),
+            "Undoing {} hasn't restored the position:{}",
+            game_move,
+            before
+        );
+    }
+
+    /// All dice of the game (on the cards and in the stocks), sorted.
+    #[cfg(feature = "strict-checks")]
+    fn all_dice(&self) -> Vec<Die> {
+        let on_cards = self.board.coord_cards_iter().flat_map(|(_, card)| card.dice.iter());
+        let mut dice: Vec<Die> = on_cards
+            .chain(self.player1.dice.iter())
+            .chain(self.player2.dice.iter())
+            .copied()
+            .collect();
+        dice.sort();
+        dice
     }
 
     /// Caclulates the game result of a particular game state by
@@ -583,66 +1120,8 @@ impl Game {
         GameResult::InProgress
     }
 
-    // TODO: this is a copy of generate_moves with `moves.append()`
-    // replace with `return true` to signalize that there is at least
-    // one move. It's done like this for efficiency reasons and
-    // because it's not trivial to rewrite generate_moves() as
-    // iterator. But we should do so, then we can just lazily check
-    // for this first element.
     fn has_moves(&self) -> bool {
-        if self.rules.enable_fight_move {
-            let dice: BTreeSet<Die> = self.current_player().dice.iter().cloned().collect();
-            for _ in self.board.empty_cards_iter() {
-                for _ in dice.iter() {
-                    return true;
-                }
-            }
-
-            for (&pos, _) in self.board.cards.iter().filter(|(_, card)| card.dice.len() > 1) {
-                let candidate = GameMove::Fight(pos);
-                if self.validate_move(&candidate).is_ok() {
-                    return true;
-                }
-            }
-        } else {
-            match self.current_player().dice.first() {
-                None => {}
-                Some(_) => {
-                    for _ in self.board.empty_cards_iter() {
-                        return true;
-                    }
-                }
-            }
-        }
-
-        let active_dice = self.board.active_dice_iter(self.player1_moves);
-        let all_positions: Vec<_> = self.board.cards.keys().collect();
-        for (from, die) in active_dice {
-            for to in &all_positions {
-                let candidate = GameMove::Move(die.clone(), *from, **to);
-                if self.validate_move(&candidate).is_ok() {
-                    return true;
-                }
-            }
-        }
-
-        if self.rules.enable_surprise_move {
-            if self.current_player_surprises() == 0 {
-                let (left, right, top, bottom) = self.board.bounding_box();
-                for &from in self.board.cards.keys() {
-                    for x in left - 1..=right + 1 {
-                        for y in top - 1..=bottom + 1 {
-                            let candidate = GameMove::Surprise(from, self.board.new_coord(x, y));
-                            if self.validate_move(&candidate).is_ok() {
-                                return true;
-                            }
-                        }
-                    }
-                }
-            }
-        }
-
-        false
+        self.candidate_moves_iter().next().is_some()
     }
 
     fn no_moves(&self) -> GameResult {
@@ -692,155 +1171,474 @@ impl Game {
         GameResult::InProgress
     }
 
-    /// Convert move coordinates from `UserCoord` to `Coord`.
-    pub fn convert_move_coords(&self, m: &GameMove<UserCoord>) -> Fallible<GameMove<Coord>> {
+    /// Cards with the dice of the given player on top forming a row.
+    fn winning_row(&self, for_player1: bool) -> Option<[Coord; 3]> {
+        self.board.adj_triples_iter().find_map(|&(c1, c2, c3)| {
+            let owned = |c: &Coord| {
+                self.board
+                    .card_at(c)
+                    .and_then(|card| card.top_die())
+                    .is_some_and(|die| die.belongs_to_player1() == for_player1)
+            };
+            if owned(&c1) && owned(&c2) && owned(&c3) {
+                Some([c1, c2, c3])
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns why the game has been won or None if it's still in
+    /// progress. If several conditions hold at once, the one checked
+    /// first by `result()` is reported.
+    pub fn win_reason(&self) -> Option<WinReason> {
+        let for_player1 = match self.result {
+            GameResult::InProgress => return None,
+            GameResult::FirstPlayerWon => true,
+            GameResult::SecondPlayerWon => false,
+        };
+
+        match self.last_move() {
+            Some(GameMove::Submit) => return Some(WinReason::Submitted),
+            Some(GameMove::Move(_, _, to)) => {
+                // The row might have been uncovered while the die was
+                // in flight.
+                let mut game = self.clone();
+                game.board.card_at_mut(to)?.dice.pop();
+                if let Some(coords) = game.winning_row(for_player1) {
+                    return Some(WinReason::ThreeInARow { coords });
+                }
+            }
+            _ => {}
+        }
+
+        if let Some((&coord, _)) = self.board.cards.iter().find(|(_, card)| card.dice.len() > 2) {
+            return Some(WinReason::ThreeInStack { coord });
+        }
+        if let Some(coords) = self.winning_row(for_player1) {
+            return Some(WinReason::ThreeInARow { coords });
+        }
+        Some(WinReason::NoMoves)
+    }
+
+    /// Convert move coordinates from `UserCoord` (or `EitherCoord`) to
+    /// `Coord`, and the destination of a surprise move from `Position`.
+    pub fn convert_move_coords<C, P>(&self, m: &GameMove<C, P>) -> Fallible<GameMove<Coord>>
+    where
+        C: Clone + Into<EitherCoord>,
+        P: Clone + Into<Position>,
+    {
         self.board.convert_move_coords(m)
     }
 
-    /// Convert move coordinates from `Coord` to `UserCoord`.
-    pub fn userify_move(&self, m: &GameMove<Coord>) -> GameMove<UserCoord> {
+    /// Convert move coordinates from `Coord` to `UserCoord` (where
+    /// possible, see `Board::convert_coordinates_to_user`).
+    pub fn userify_move(&self, m: &GameMove<Coord>) -> GameMove<EitherCoord> {
         self.board.convert_move_coords_to_user(m)
     }
 
+    /// Same as `userify_move`, but the destination of a surprise move
+    /// is given next to a card, like `leftof r1c1`.
+    pub fn describe_move(&self, m: &GameMove<Coord>) -> GameMove<EitherCoord, Position> {
+        self.board.describe_move(m)
+    }
+
+    /// Moves played so far, each described (see `describe_move`) as
+    /// it was seen when it was played: Surprise moves change the user
+    /// coordinates of the cards.
+    pub fn described_moves(&self) -> Vec<GameMove<EitherCoord, Position>> {
+        let mut game = self.clone();
+        for entry in self.history.iter().rev() {
+            game.undo_move(&entry.game_move, entry.fight_result.clone());
+        }
+        self.history
+            .iter()
+            .map(|entry| {
+                let described = game.describe_move(&entry.game_move);
+                game.apply_move_unchecked(&entry.game_move);
+                described
+            })
+            .collect()
+    }
+
     /// Returns a random move (uniform distribution).
+    #[cfg(feature = "std")]
     pub fn random_move(&self) -> GameMove<Coord> {
         let moves = self.generate_moves();
         let mut rng = rand::thread_rng();
         moves.choose(&mut rng).unwrap().clone()
     }
 
-    // Note: The move generator is supposed to be fast, but now I'm
-    // generating moves in a rather naive way. This is an obvious
-    // candidate for optimization, if we need any.
-    //
-    // WARNING: please update `has_moves()` if any serious changes are
-    // made to `generate_moves()`.
-    //
     /// Generate all legal moves for current game position.
     pub fn generate_moves(&self) -> Vec<GameMove<Coord>> {
-        if self.result != GameResult::InProgress {
+        let mut moves = Vec::with_capacity(32);
+        moves.extend(self.moves_iter());
+        moves
+    }
+
+    /// Legal moves for current game position, generated lazily (in
+    /// the same order as by `generate_moves`): places, fights, moves
+    /// and then surprises.
+    pub fn moves_iter(&self) -> impl Iterator<Item = GameMove<Coord>> + '_ {
+        let in_progress = self.result == GameResult::InProgress;
+        in_progress.then(|| self.candidate_moves_iter()).into_iter().flatten()
+    }
+
+    /// Same as `moves_iter`, but doesn't check if the game is over.
+    fn candidate_moves_iter(&self) -> impl Iterator<Item = GameMove<Coord>> + '_ {
+        let fight_enabled = self.rules.enable_fight_move;
+        let surprise_enabled =
+            self.rules.enable_surprise_move && self.current_player_surprises() == 0 && !self.in_setup();
+        let valid = move |m: &GameMove<Coord>| self.validate_move(m).is_ok();
+
+        // Without fights all dice are equal, so we only place the
+        // first one.
+        let dice = if fight_enabled {
+            self.current_player().distinct_dice()
+        } else {
+            self.current_player().dice.iter().copied().take(1).collect()
+        };
+        let places = self
+            .board
+            .empty_cards_iter()
+            .flat_map(move |(&coord, _)| dice.clone().into_iter().map(move |d| GameMove::Place(d, coord)));
+
+        let fights = fight_enabled
+            .then(|| {
+                self.board
+                    .cards
+                    .iter()
+                    .filter(|(_, card)| card.dice.len() > 1)
+                    .map(|(&pos, _)| GameMove::Fight(pos))
+                    .filter(valid)
+            })
+            .into_iter()
+            .flatten();
+
+        let moves = self
+            .board
+            .active_dice_iter(self.player1_moves)
+            .flat_map(move |(&from, &die)| self.board.cards.keys().map(move |&to| GameMove::Move(die, from, to)))
+            .filter(valid);
+
+        // Surprise destinations are generated directly from the
+        // neighbour tables, no need to validate them.
+        let surprises = surprise_enabled
+            .then(|| {
+                self.board.cards.keys().flat_map(move |&from| {
+                    self.board
+                        .surprise_destinations(from)
+                        .map(move |to| GameMove::Surprise(from, to))
+                })
+            })
+            .into_iter()
+            .flatten();
+
+        // We don't include Submit as a candidate move ;)
+        places.chain(fights).chain(moves).chain(surprises)
+    }
+
+    /// Whether the player to move is placing their dice in the setup
+    /// phase of the handicap (see `Handicap::first_placements`).
+    pub fn in_setup(&self) -> bool {
+        self.rules.handicap.first_placements.is_some() && self.history.len() < SETUP_PLIES
+    }
+
+    /// Whether the player making the move at `ply` (counted from zero)
+    /// makes the next one too, as in the setup phase of the handicap.
+    fn keeps_turn(&self, ply: usize) -> bool {
+        self.rules.handicap.first_placements.is_some() && ply + 1 < SETUP_PLIES
+    }
+
+    /// Whether the first player makes the move at `ply` (counted from
+    /// zero).
+    pub fn player1_plays_ply(&self, ply: usize) -> bool {
+        // The starting player makes all the moves of the setup phase.
+        let turns = (0..ply).filter(|&p| !self.keeps_turn(p)).count();
+        (turns % 2 == 0) == self.rules.player1_starts()
+    }
+
+    /// Returns if the game is over.
+    pub fn is_game_over(&self) -> bool {
+        self.result != GameResult::InProgress
+    }
+
+    /// Moves of the given player winning the game on the spot (by
+    /// completing a row or a stack, usually), as if it were their turn.
+    pub fn immediate_wins(&self, for_player1: bool) -> Vec<GameMove<Coord>> {
+        if self.is_game_over() {
             return vec![];
         }
 
-        let mut moves = Vec::with_capacity(32);
-
-        if self.rules.enable_fight_move {
-            let dice: BTreeSet<Die> = self.current_player().dice.iter().cloned().collect();
-            for (&coord, _card) in self.board.empty_cards_iter() {
-                for d in dice.iter() {
-                    moves.push(GameMove::Place(d.clone(), coord));
-                }
+        let mut game = self.clone();
+        game.player1_moves = for_player1;
+        let mut wins = vec![];
+        for m in game.generate_moves() {
+            let fight_result = game.apply_move_unchecked(&m);
+            if game.has_won(for_player1) {
+                wins.push(m.clone());
             }
+            game.undo_move(&m, fight_result);
+        }
+        wins
+    }
 
-            for (&pos, _) in self.board.cards.iter().filter(|(_, card)| card.dice.len() > 1) {
-                let candidate = GameMove::Fight(pos);
-                if self.validate_move(&candidate).is_ok() {
-                    moves.push(candidate);
-                }
+    /// Whether the opponent of the player to move would have a winning
+    /// move if it were their turn, i.e. whether there is a threat to
+    /// be parried.
+    pub fn is_threatened(&self) -> bool {
+        !self.immediate_wins(!self.player1_moves).is_empty()
+    }
+
+    /// Looks for a forced win of the player to move within `max_plies`
+    /// plies (counting the moves of both players). Returns the
+    /// shortest winning line (with the longest defence) if there is
+    /// one.
+    pub fn find_forced_win(&self, max_plies: usize) -> Option<Vec<GameMove<Coord>>> {
+        let mut game = self.clone();
+        let winner = self.player1_moves;
+        (1..=max_plies).find_map(|plies| game.attack(winner, plies))
+    }
+
+    /// Walks the tree of moves `depth` plies deep and checks that taking
+    /// back every move restores the position exactly (its key with the
+    /// hash and the result). Returns the number of moves checked,
+    /// or the line of moves after which undo went wrong.
+    pub fn check_undo_consistency(&self, depth: usize) -> Fallible<u64> {
+        let mut game = self.clone();
+        let mut line = vec![];
+        game.check_undo(depth, &mut line)
+    }
+
+    fn check_undo(&mut self, depth: usize, line: &mut Vec<GameMove<Coord>>) -> Fallible<u64> {
+        if depth == 0 || self.is_game_over() {
+            return Ok(0);
+        }
+
+        let key = self.position_key();
+        let result = self.result;
+        let mut checked = 0;
+        for m in self.generate_moves() {
+            let fight_result = self.apply_move_unchecked(&m);
+            line.push(m.clone());
+            checked += 1 + self.check_undo(depth - 1, line)?;
+            self.undo_move(&m, fight_result);
+
+            if self.position_key() != key || self.result != result {
+                let moves: Vec<String> = line.iter().map(|m| m.to_string()).collect();
+                bail!("Undo hasn't restored the position after {}:{}", moves.join(", "), self);
             }
+            line.pop();
+        }
+        Ok(checked)
+    }
+
+    fn has_won(&self, player1: bool) -> bool {
+        match self.result {
+            GameResult::FirstPlayerWon => player1,
+            GameResult::SecondPlayerWon => !player1,
+            GameResult::InProgress => false,
+        }
+    }
+
+    /// The line of `attack` or `defend`, depending on who is to move
+    /// (the same player moves again in the setup phase of a handicap).
+    fn forced_win_line(&mut self, winner: bool, plies: usize) -> Option<Vec<GameMove<Coord>>> {
+        if self.player1_moves == winner {
+            self.attack(winner, plies)
         } else {
-            match self.current_player().dice.first() {
-                None => {}
-                Some(d) => {
-                    for (&coord, _card) in self.board.empty_cards_iter() {
-                        moves.push(GameMove::Place(d.clone(), coord));
-                    }
-                }
+            self.defend(winner, plies)
+        }
+    }
+
+    /// A move of `winner` winning within `plies`.
+    fn attack(&mut self, winner: bool, plies: usize) -> Option<Vec<GameMove<Coord>>> {
+        for m in self.generate_moves() {
+            let fight_result = self.apply_move_unchecked(&m);
+            let line = if self.has_won(winner) {
+                Some(vec![])
+            } else if self.is_game_over() || plies == 1 {
+                None
+            } else {
+                self.forced_win_line(winner, plies - 1)
+            };
+            self.undo_move(&m, fight_result);
+
+            if let Some(mut line) = line {
+                line.insert(0, m);
+                return Some(line);
             }
         }
+        None
+    }
 
-        let active_dice = self.board.active_dice_iter(self.player1_moves);
-        let all_positions: Vec<_> = self.board.cards.keys().collect();
-        for (from, die) in active_dice {
-            for to in &all_positions {
-                let candidate = GameMove::Move(die.clone(), *from, **to);
-                if self.validate_move(&candidate).is_ok() {
-                    moves.push(candidate);
-                }
+    /// The longest defence against `winner`, if all the moves lose
+    /// within `plies`.
+    fn defend(&mut self, winner: bool, plies: usize) -> Option<Vec<GameMove<Coord>>> {
+        let mut longest: Option<Vec<GameMove<Coord>>> = None;
+        for m in self.generate_moves() {
+            let fight_result = self.apply_move_unchecked(&m);
+            let line = if self.has_won(winner) {
+                Some(vec![])
+            } else if self.is_game_over() || plies == 1 {
+                None
+            } else {
+                self.forced_win_line(winner, plies - 1)
+            };
+            self.undo_move(&m, fight_result);
+
+            let mut line = line?;
+            if longest.as_ref().is_none_or(|l| l.len() < line.len() + 1) {
+                line.insert(0, m);
+                longest = Some(line);
             }
         }
+        longest
+    }
 
-        if self.rules.enable_surprise_move {
-            if self.current_player_surprises() == 0 {
-                let (left, right, top, bottom) = self.board.bounding_box();
-                for &from in self.board.cards.keys() {
-                    for x in left - 1..=right + 1 {
-                        for y in top - 1..=bottom + 1 {
-                            let candidate = GameMove::Surprise(from, self.board.new_coord(x, y));
-                            if self.validate_move(&candidate).is_ok() {
-                                moves.push(candidate);
-                            }
-                        }
-                    }
-                }
+    /// Returns the key of the position, which can be used to check if
+    /// the same position repeats again and again. Order of the dice in
+    /// players' stocks doesn't matter.
+    pub fn position_key(&self) -> PositionKey {
+        let mut bytes = SmallVec::new();
+        for (coord, card) in &self.board.cards {
+            let ability = card.ability.map_or(0, |ability| ability as u8 + 1);
+            bytes.extend_from_slice(&[coord.x as u8, coord.y as u8, coord.z as u8, card.kind as u8, ability]);
+            bytes.push(card.dice.len() as u8);
+            for die in &card.dice {
+                bytes.extend_from_slice(&[die.color as u8, die.value]);
             }
         }
+        for player in &[&self.player1, &self.player2] {
+            let mut dice = player.dice.clone();
+            dice.sort();
+            bytes.push(dice.len() as u8);
+            for die in &dice {
+                bytes.extend_from_slice(&[die.color as u8, die.value]);
+            }
+        }
+        bytes.extend_from_slice(&[
+            self.player1_moves as u8,
+            self.player1_surprises,
+            self.player2_surprises,
+            self.in_setup() as u8,
+        ]);
+        PositionKey::new(bytes)
+    }
 
-        // We don't include Submit as a candidate move ;)
-        moves
+    /// Returns a hash of the position to be used as a key in
+    /// transposition tables (the hash of its `position_key`).
+    pub fn position_hash(&self) -> u64 {
+        self.position_key().hash
+    }
+}
+
+/// A game of random rules, layout and deck after a few random moves.
+#[cfg(feature = "arbitrary")]
+impl<'a> arbitrary::Arbitrary<'a> for Game {
+    fn arbitrary(u: &mut arbitrary::Unstructured<'a>) -> arbitrary::Result<Self> {
+        let layout: Layout = u.arbitrary()?;
+        let mut deck: Deck = u.arbitrary()?;
+        if layout == Layout::Rectangle6 {
+            deck.drop_one_card();
+        }
+        let mut game = Game::new(layout, deck, u.arbitrary()?);
+        for _ in 0..u.int_in_range(0..=30)? {
+            if game.is_game_over() {
+                break;
+            }
+            let moves = game.generate_moves();
+            let mov = u.choose(&moves)?;
+            game.apply_move(mov).expect("Generated moves are legal");
+        }
+        Ok(game)
     }
+}
 
-    /// Returns if the game is over.
-    pub fn is_game_over(&self) -> bool {
-        self.result != GameResult::InProgress
+/// Compact binary encoding of games (with the history and the rules),
+/// much smaller and faster than JSON, to send them over the network
+/// or to store lots of them.
+#[cfg(feature = "with_bincode")]
+impl Game {
+    pub fn to_bytes(&self) -> Fallible<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
     }
 
-    /// Returns "defining features" of the game which can be used to
-    /// check if the same position repeats again and again.
-    pub fn defining_features(&self) -> GameFeatures {
-        GameFeatures {
-            cards: self.board.cards.clone(),
-            player1_dice: self.player1.dice.clone(),
-            player2_dice: self.player2.dice.clone(),
-            player1_moves: self.player1_moves,
-            player1_surprises: self.player1_surprises,
-            player2_surprises: self.player2_surprises,
+    pub fn from_bytes(bytes: &[u8]) -> Fallible<Game> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
+
+/// Compact key of a position (see `Game::position_key`): the cards with
+/// their dice, the stocks, the player to move and the surprises used,
+/// encoded in bytes, together with their hash. Comparing two keys
+/// compares the hashes first, so they are cheap to look up.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct PositionKey {
+    hash: u64,
+    bytes: SmallVec<[u8; 64]>,
+}
+
+impl PositionKey {
+    fn new(bytes: SmallVec<[u8; 64]>) -> Self {
+        // FNV-1a, with the bits mixed in the end (as in splitmix64) as
+        // transposition tables use the lowest ones.
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for &b in &bytes {
+            hash = (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3);
         }
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        hash ^= hash >> 31;
+        PositionKey { hash, bytes }
+    }
+
+    pub fn hash(&self) -> u64 {
+        self.hash
     }
 }
 
-#[derive(PartialOrd, Ord, PartialEq, Eq, Hash, Debug)]
-pub struct GameFeatures {
-    cards: BTreeMap<Coord, Card>,
-    player1_dice: Vec<Die>,
-    player2_dice: Vec<Die>,
-    player1_moves: bool,
-    player1_surprises: u8,
-    player2_surprises: u8,
+impl Hash for PositionKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::board::Grid;
+    use crate::card::Card;
+    use crate::coord::UserCoord;
     use failure::Fallible;
 
     macro_rules! submit {
         () => {
-            &GameMove::Submit
+            &GameMove::<UserCoord>::Submit
         };
     }
 
     macro_rules! place {
         (r, $val:literal => $row:literal, $card:literal) => {
-            &GameMove::Place(Die::new(DiceColor::Red, $val), UserCoord::new($row, $card))
+            &GameMove::<UserCoord>::Place(Die::new(DiceColor::Red, $val), UserCoord::new($row, $card))
         };
         (b, $val:literal => $row:literal, $card:literal) => {
-            &GameMove::Place(Die::new(DiceColor::Black, $val), UserCoord::new($row, $card))
+            &GameMove::<UserCoord>::Place(Die::new(DiceColor::Black, $val), UserCoord::new($row, $card))
         };
         (w, $val:literal => $row:literal, $card:literal) => {
-            &GameMove::Place(Die::new(DiceColor::White, $val), UserCoord::new($row, $card))
+            &GameMove::<UserCoord>::Place(Die::new(DiceColor::White, $val), UserCoord::new($row, $card))
         };
     }
 
     macro_rules! fight {
         ($row:literal, $card:literal) => {
-            &GameMove::Fight(UserCoord::new($row, $card))
+            &GameMove::<UserCoord>::Fight(UserCoord::new($row, $card))
         };
     }
 
     macro_rules! mov {
         (r, $val:literal => $from_row:literal, $from_card:literal => $to_row:literal, $to_card:literal) => {
-            &GameMove::Move(
+            &GameMove::<UserCoord>::Move(
                 Die::new(DiceColor::Red, $val),
                 UserCoord::new($from_row, $from_card),
                 UserCoord::new($to_row, $to_card),
@@ -848,7 +1646,7 @@ mod test {
         };
 
         (b, $val:literal => $from_row:literal, $from_card:literal => $to_row:literal, $to_card:literal) => {
-            &GameMove::Move(
+            &GameMove::<UserCoord>::Move(
                 Die::new(DiceColor::Black, $val),
                 UserCoord::new($from_row, $from_card),
                 UserCoord::new($to_row, $to_card),
@@ -856,7 +1654,7 @@ mod test {
         };
 
         (w, $val:literal => $from_row:literal, $from_card:literal => $to_row:literal, $to_card:literal) => {
-            &GameMove::Move(
+            &GameMove::<UserCoord>::Move(
                 Die::new(DiceColor::White, $val),
                 UserCoord::new($from_row, $from_card),
                 UserCoord::new($to_row, $to_card),
@@ -866,7 +1664,7 @@ mod test {
 
     macro_rules! surprise {
         ($from_row:literal, $from_card:literal => $to_x:literal, $to_y:literal) => {
-            &GameMove::Surprise(
+            &GameMove::<UserCoord>::Surprise(
                 UserCoord::new($from_row, $from_card),
                 Coord::new_hex($to_x, $to_y),
             )
@@ -926,6 +1724,38 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_surprise_destinations() -> Fallible<()> {
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("jgjjgjg")?, Rules::new(true, true));
+        for _ in 0..8 {
+            // All the surprise moves found by brute force over the
+            // bounding box are generated.
+            let (left, right, top, bottom) = game.board.bounding_box();
+            let mut expected = vec![];
+            for &from in game.board.cards.keys() {
+                for x in left - 1..=right + 1 {
+                    for y in top - 1..=bottom + 1 {
+                        let m = GameMove::Surprise(from, game.board.new_coord(x, y));
+                        if game.validate_move(&m).is_ok() {
+                            expected.push(m);
+                        }
+                    }
+                }
+            }
+            expected.sort();
+            let mut generated: Vec<_> = game
+                .moves_iter()
+                .filter(|m| matches!(m, GameMove::Surprise(..)))
+                .collect();
+            generated.sort();
+            assert_eq!(generated, expected);
+
+            let m = game.generate_moves().into_iter().last().unwrap();
+            game.apply_move(&m)?;
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_validity_of_surprise() -> Fallible<()> {
         // TODO: this test is somewhat limited since now we are only
@@ -1031,8 +1861,10 @@ mod test {
         // Submit leads to loss.
         let mut game = Game::new(Layout::Bricks7, Deck::seven_shuffled(), Default::default());
         assert_eq!(game.result, GameResult::InProgress);
+        assert_eq!(game.win_reason(), None);
         apply_moves!(game, submit!());
         assert_eq!(game.result, GameResult::SecondPlayerWon);
+        assert_eq!(game.win_reason(), Some(WinReason::Submitted));
 
         // Submit leads to loss #2.
         let mut game = Game::new(Layout::Bricks7, Deck::seven_shuffled(), Default::default());
@@ -1054,6 +1886,10 @@ mod test {
         let card = game.board.card_at(&c(0, 0)).unwrap();
         assert_eq!(card.dice.len(), 1);
         assert_eq!(card.dice[0].value, 2);
+        let history = game.moves();
+        assert_eq!(history[2].source(), history[0].target());
+        assert_eq!(history[2].target(), history[3].target());
+        assert_eq!(history[3].source(), None);
 
         // Fight reduces number of dice on cards.
         // White 1 beats red 6.
@@ -1062,12 +1898,18 @@ mod test {
             game,
             place!(r, 6 => 1, 1),
             place!(w, 1 => 2, 1),
-            mov!(r, 6 => 1, 1 => 2, 1),
-            fight!(2, 1)
+            mov!(r, 6 => 1, 1 => 2, 1)
         );
+        let outcome = game.apply_user_move(fight!(2, 1))?;
         let card = game.board.card_at(&c(0, 0)).unwrap();
         assert_eq!(card.dice.len(), 1);
         assert_eq!(card.dice[0].value, 1);
+        assert!(!outcome.ends_game());
+        let fight_result = outcome.fight_result.unwrap();
+        assert_eq!(fight_result.losing_die, Die::new(DiceColor::Red, 6));
+        assert_eq!(fight_result.losing_position, ZIndex::Top);
+        assert_eq!(game.history[3].fight_result, Some(fight_result));
+        assert_eq!(game.history[2].fight_result, None);
 
         // Simplest 3-in-a-row win.
         let mut game = Game::new(layout.clone(), deck.clone(), Default::default());
@@ -1119,8 +1961,12 @@ mod test {
             place!(r, 2 => 2, 3)
         );
         assert_eq!(game.result, GameResult::InProgress);
-        game.apply_user_move(mov!(w, 1 => 2, 2 => 1, 1))?;
+        let outcome = game.apply_user_move(mov!(w, 1 => 2, 2 => 1, 1))?;
         assert_eq!(game.result, GameResult::FirstPlayerWon);
+        assert!(outcome.won_in_flight && outcome.ends_game());
+        let user = |row, card| game.board.convert_coordinates(&UserCoord::new(row, card));
+        let coords = [user(2, 1)?, user(2, 2)?, user(2, 3)?];
+        assert_eq!(game.win_reason(), Some(WinReason::ThreeInARow { coords }));
 
         // No move between the same card kinds is allowed.
         let mut game = Game::new(layout.clone(), deck.clone(), Default::default());
@@ -1141,8 +1987,11 @@ mod test {
             place!(b, 3 => 1, 2)
         );
         assert_eq!(game.result, GameResult::InProgress);
-        game.apply_user_move(mov!(r, 2 => 1, 1 => 2, 1))?;
+        let outcome = game.apply_user_move(mov!(r, 2 => 1, 1 => 2, 1))?;
         assert_eq!(game.result, GameResult::FirstPlayerWon);
+        assert!(!outcome.won_in_flight && outcome.ends_game());
+        let coord = game.board.convert_coordinates(&UserCoord::new(2, 1))?;
+        assert_eq!(game.win_reason(), Some(WinReason::ThreeInStack { coord }));
 
         // Simultaneous 3-in-a-row for both players.
         // (plus a stack and actually 4-in-a-row)
@@ -1187,8 +2036,53 @@ mod test {
         );
 
         assert_eq!(game.result, GameResult::InProgress);
+        let win = game.convert_move_coords(place!(b, 1 => 2, 1))?;
+        assert!(game.immediate_wins(false).contains(&win));
         apply_moves!(game, place!(b, 1 => 2, 1));
         assert_eq!(game.result, GameResult::SecondPlayerWon);
+        assert!(game.immediate_wins(false).is_empty());
+        assert!(matches!(game.win_reason(), Some(WinReason::ThreeInARow { .. })));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn test_find_forced_win() -> Fallible<()> {
+        let deck = Deck::ordered("jgjjjgg")?;
+        let mut game = Game::new(Layout::Bricks7, deck, Rules::new(false, false));
+        apply_moves!(
+            game,
+            place!(r, 2 => 2, 3),
+            place!(b, 1 => 2, 1),
+            place!(r, 2 => 2, 4),
+            mov!(b, 1 => 2, 1 => 2, 3),
+            place!(r, 2 => 1, 2),
+            place!(b, 1 => 2, 1),
+            place!(r, 2 => 2, 2),
+            mov!(b, 1 => 2, 1 => 2, 4),
+            mov!(r, 2 => 1, 2 => 2, 1),
+            mov!(b, 1 => 2, 4 => 2, 2),
+            mov!(r, 2 => 2, 1 => 1, 2),
+        );
+
+        let line = game.find_forced_win(3).unwrap();
+        assert_eq!(line.len(), 1);
+        game.apply_move(&line[0])?;
+        assert_eq!(game.result, GameResult::SecondPlayerWon);
+
+        // Three cards in a row: the first player wins in 6 plies.
+        let layout = Layout::Custom(Grid::Square, (0..3).map(|x| Coord::new_square(x, 0)).collect());
+        let mut game = Game::new(layout, Deck::ordered("jgg")?, Rules::new(false, false));
+        assert!(game.find_forced_win(5).is_none());
+        let line = game.find_forced_win(6).unwrap();
+        assert_eq!(line.len(), 6);
+        for (ix, m) in line.iter().enumerate() {
+            game.apply_move(m)?;
+            // After the fourth ply Black threatens to leave Red without
+            // moves.
+            assert_eq!(game.is_threatened(), ix == 3);
+        }
+        assert_eq!(game.result, GameResult::FirstPlayerWon);
 
         Ok(())
     }
@@ -1202,6 +2096,12 @@ mod test {
         assert_eq!(game.generate_moves().len(), 59);
         apply_moves!(game, place!(b, 1 => 1, 1));
         assert_eq!(game.generate_moves().len(), 53);
+
+        // The lazy generator gives the same moves and can stop early.
+        assert!(game.moves_iter().eq(game.generate_moves()));
+        assert!(game.has_moves());
+        game.result = GameResult::FirstPlayerWon;
+        assert!(game.moves_iter().next().is_none());
         Ok(())
     }
 
@@ -1256,6 +2156,54 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn test_from_moves() -> Fallible<()> {
+        let deck = Deck::ordered("gggjjjj")?;
+        let mut game = Game::new(Layout::Bricks7, deck.clone(), Default::default());
+        apply_moves!(
+            game,
+            place!(r, 2 => 1, 1),
+            place!(b, 1 => 2, 1),
+            mov!(r, 2 => 1, 1 => 2, 1)
+        );
+
+        let replayed = Game::from_moves(Layout::Bricks7, deck.clone(), Default::default(), &game.moves())?;
+        assert_eq!(replayed.position_key(), game.position_key());
+        assert_eq!(replayed.moves(), game.moves());
+
+        let user_moves: Vec<_> = game.moves().iter().map(|m| game.userify_move(m)).collect();
+        let replayed = Game::from_user_moves(Layout::Bricks7, deck.clone(), Default::default(), &user_moves)?;
+        assert_eq!(replayed.position_key(), game.position_key());
+
+        // The same die can't be placed twice.
+        let moves = [
+            game.moves()[0].clone(),
+            game.moves()[1].clone(),
+            game.moves()[0].clone(),
+        ];
+        let err = Game::from_moves(Layout::Bricks7, deck, Default::default(), &moves).unwrap_err();
+        assert!(err.to_string().starts_with("Can't play move 3"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_described_moves() -> Fallible<()> {
+        let c = Coord::new_hex;
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("gggjjjj")?, Rules::new(false, true));
+        apply_moves!(game, place!(r, 2 => 1, 2));
+        let place = game.last_move().cloned().unwrap();
+
+        // The card moves above the first row, which becomes the second one.
+        game.apply_move(&GameMove::Surprise(c(0, 0), c(2, -2)))?;
+        assert_eq!(game.describe_move(&place).to_string(), "place r2 at r2c2");
+
+        let described: Vec<_> = game.described_moves().iter().map(|m| m.to_string()).collect();
+        assert_eq!(described[0], "place r2 at r1c2");
+        assert_eq!(described.len(), 2);
+        Ok(())
+    }
+
     #[cfg(feature = "with_serde")]
     #[test]
     fn game_serde() -> Fallible<()> {
@@ -1265,8 +2213,228 @@ mod test {
         eprintln!("serialised = {}", ser);
         let de: Game = serde_json::from_str(&ser)?;
         eprintln!("deserialised = {:?}", de);
-        assert_eq!(game.defining_features(), de.defining_features());
+        assert_eq!(game.position_key(), de.position_key());
 
         Ok(())
     }
+
+    #[cfg(feature = "with_bincode")]
+    #[test]
+    fn game_bincode() -> Fallible<()> {
+        let deck = Deck::ordered("jggjgjj")?;
+        let mut game = Game::new(Layout::Bricks7, deck, Rules::new(true, true));
+        for _ in 0..6 {
+            let m = game.generate_moves().into_iter().last().unwrap();
+            game.apply_move(&m)?;
+        }
+        let bytes = game.to_bytes()?;
+        let de = Game::from_bytes(&bytes)?;
+        assert_eq!(game.position_key(), de.position_key());
+        assert_eq!(game.history, de.history);
+        assert!(bytes.len() < serde_json::to_string(&game)?.len() / 2);
+        assert!(Game::from_bytes(&bytes[..bytes.len() / 2]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_player2_starting() -> Fallible<()> {
+        let rules = Rules::new(true, false).with_player1_starting(false);
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("jggjgjj")?, rules);
+        assert!(!game.player1_moves);
+        assert!(game
+            .generate_moves()
+            .iter()
+            .all(|m| matches!(m, GameMove::Place(d, _) if !d.belongs_to_player1())));
+        game.apply_user_move(place!(b, 1 => 2, 1))?;
+        assert!(game.player1_moves);
+
+        let seeded = |seed| {
+            Rules::default()
+                .with_random_starting_player(Some(seed))
+                .player1_starts()
+        };
+        assert!((0..20).all(|seed| seeded(seed) == seeded(seed)));
+        assert!((0..20).any(seeded) && !(0..20).all(seeded));
+        Ok(())
+    }
+
+    #[test]
+    fn test_abilities() -> Fallible<()> {
+        let peaceful = Card::new(CardKind::Jade).with_ability(Ability::Peaceful);
+        assert_eq!(peaceful.to_string(), "Jade Peaceful[]");
+        let deck = Deck::builder().gold(3).card(peaceful).jade(3).build();
+        let mut game = Game::try_new(Layout::Bricks7, deck, Rules::default())?;
+        apply_moves!(
+            game,
+            place!(r, 6 => 1, 1),
+            place!(w, 1 => 2, 1),
+            mov!(r, 6 => 1, 1 => 2, 1)
+        );
+        let err = game.apply_user_move(fight!(2, 1)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "fight: there are no fights at the peaceful card at <0, 0, 0>"
+        );
+        assert!(game.generate_moves().iter().all(|m| !matches!(m, GameMove::Fight(..))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_die_values() -> Fallible<()> {
+        assert_eq!("d8".parse::<DieValues>()?, DieValues::new(1, 8)?);
+        assert_eq!("2-12".parse::<DieValues>()?.to_string(), "2-12");
+        assert!("6-1".parse::<DieValues>().is_err());
+        assert!("d0".parse::<DieValues>().is_err());
+        assert_eq!("r12".parse::<Die>()?, Die::new(DiceColor::Red, 12));
+
+        let d8 = DieValues::new(1, 8)?;
+        let cubic: Vec<u8> = (1..=6).map(|v| d8.from_cubic(v)).collect();
+        assert_eq!(cubic, [1, 2, 4, 5, 7, 8]);
+        assert!((1..=6).all(|v| DieValues::CUBIC.from_cubic(v) == v));
+
+        // With red d8 dice the white die beats red 8 instead of red 6,
+        // and the red dice are dealt as 2, 2, 5 and 8.
+        let rules = Rules::default()
+            .with_die_values(DiceColor::Red, d8)
+            .with_handicap("extra:r6".parse()?)?;
+        let deck = Deck::ordered("gggjjjj")?;
+        let mut game = Game::try_new(Layout::Bricks7, deck.clone(), rules)?;
+        let red: Vec<String> = game.stock(true).iter().map(|d| d.to_string()).collect();
+        assert_eq!(red, ["r2", "r2", "r5", "r8", "r6"]);
+        apply_moves!(
+            game,
+            place!(r, 6 => 1, 1),
+            place!(w, 1 => 2, 1),
+            mov!(r, 6 => 1, 1 => 2, 1),
+            fight!(2, 1)
+        );
+        assert_eq!(
+            game.board.card_at(&Coord::new_hex(0, 0)).unwrap().dice[..],
+            [Die::new(DiceColor::Red, 6)]
+        );
+
+        // Doubled values are playable.
+        let doubled = Rules::default().with_die_values(DiceColor::Black, "2-12".parse()?);
+        let game = Game::try_new(Layout::Bricks7, deck.clone(), doubled)?;
+        let black: Vec<String> = game.stock(false).iter().map(|d| d.to_string()).collect();
+        assert_eq!(black, ["b2", "b6", "b6", "b10", "w1"]);
+
+        // Dice of the handicap have to fit too, and the removed one has
+        // to be dealt.
+        let rules = Rules::default().with_handicap("extra:w7".parse()?)?;
+        assert!(Game::try_new(Layout::Bricks7, deck.clone(), rules).is_err());
+        let rules = Rules::default()
+            .with_handicap("remove:b3".parse()?)?
+            .with_die_values(DiceColor::Black, "2-12".parse()?);
+        let err = Game::try_new(Layout::Bricks7, deck, rules).unwrap_err();
+        assert_eq!(err.to_string(), "handicap: there is no b3 to remove");
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_new() -> Fallible<()> {
+        let rules = Rules::default();
+        assert!(Game::try_new(Layout::Bricks7, Deck::ordered("jjjjggg")?, rules).is_ok());
+        assert!(Game::try_new(Layout::Rectangle6, Deck::ordered("jjjggg")?, rules).is_ok());
+
+        let err = Game::try_new(Layout::Rectangle6, Deck::ordered("jjjjggg")?, rules).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "layout rectangle6 needs 6 cards, but there are 7 in the deck"
+        );
+        assert!(Game::try_new(Layout::Hex7, Deck::ordered("jjjggg")?, rules).is_err());
+        let err = Game::try_new(Layout::Bricks7, Deck::ordered("jjjgggg")?, rules).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "there are 4 Gold cards in the deck, but only 3 in the game"
+        );
+        assert!(Game::try_new(Layout::Hex7, Deck::ordered("jjjfggg")?, rules).is_err());
+
+        // Any cards go for custom layouts.
+        let coords = (0..3).map(|x| Coord::new_square(x, 0)).collect();
+        assert!(Game::try_new(Layout::Custom(Grid::Square, coords), Deck::ordered("ggg")?, rules).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_handicap() -> Fallible<()> {
+        let handicap: Handicap = "extra:b6, remove:r2,placements:2".parse()?;
+        assert_eq!(handicap.to_string(), "extra:b6,remove:r2,placements:2");
+        assert_eq!(handicap.to_string().parse::<Handicap>()?, handicap);
+        assert!("none".parse::<Handicap>()?.is_none());
+        assert!("extra:g1".parse::<Handicap>().is_err());
+        assert!(Rules::new(true, false).with_handicap("remove:r5".parse()?).is_err());
+
+        let rules = Rules::new(true, true).with_handicap(handicap)?;
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("jggjgjj")?, rules);
+        assert_eq!(game.stock(true).len(), 3);
+        assert_eq!(game.stock(false).len(), 6);
+
+        // The second player places two dice (surprises are allowed only
+        // afterwards) and then the players alternate.
+        assert!(!game.player1_moves && game.in_setup());
+        for m in game.generate_moves() {
+            assert!(matches!(m, GameMove::Place(..)));
+            let mut next = game.clone();
+            next.apply_move(&m)?;
+            assert!(!next.player1_moves);
+            assert!(next.generate_moves().iter().all(|m| matches!(m, GameMove::Place(..))));
+        }
+        // Placements on empty cards only: any of the 5 different dice
+        // first and then (on the 6 cards left) the same 5 after one
+        // of the two threes or 4 after any other one.
+        assert_eq!(game.check_undo_consistency(2)?, 5 * 7 + 7 * (5 * 6) + 4 * 7 * (4 * 6));
+        game.apply_user_move(place!(b, 6 => 2, 1))?;
+        assert!(!game.player1_moves && game.in_setup());
+        assert!(game.generate_moves().iter().all(|m| matches!(m, GameMove::Place(..))));
+        game.apply_user_move(place!(b, 1 => 2, 2))?;
+        assert!(game.player1_moves && !game.in_setup());
+        let plies: Vec<bool> = (0..4).map(|ply| game.player1_plays_ply(ply)).collect();
+        assert_eq!(plies, [false, false, true, false]);
+
+        let entry = game.history.last().cloned().unwrap();
+        game.undo_move(&entry.game_move, entry.fight_result);
+        assert!(!game.player1_moves && game.in_setup());
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_consistency() -> Fallible<()> {
+        let deck = Deck::ordered("jggjgjj")?;
+        let mut game = Game::new(Layout::Bricks7, deck, Rules::new(true, true));
+        for _ in 0..6 {
+            let m = game.generate_moves().into_iter().last().unwrap();
+            game.apply_move(&m)?;
+        }
+        assert!(game.check_undo_consistency(3)? > 1000);
+        assert_eq!(game.check_undo_consistency(0)?, 0);
+        Ok(())
+    }
+
+    #[cfg(feature = "arbitrary")]
+    #[test]
+    fn arbitrary_games() -> Fallible<()> {
+        use arbitrary::{Arbitrary, Unstructured};
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut bytes = [0u8; 256];
+        for _ in 0..200 {
+            rng.fill(&mut bytes[..]);
+            let mut game = Game::arbitrary(&mut Unstructured::new(&bytes))?;
+            if game.is_game_over() {
+                continue;
+            }
+            let hash = game.position_hash();
+            for m in game.generate_moves() {
+                game.validate_move(&m)?;
+                let fight_result = game.apply_move_unchecked(&m);
+                game.undo_move(&m, fight_result);
+                assert_eq!(game.position_hash(), hash);
+            }
+        }
+        Ok(())
+    }
 }
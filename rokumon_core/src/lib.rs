@@ -1,6 +1,37 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "std")]
 pub mod ai;
 pub mod board;
 pub mod card;
+#[cfg(feature = "std")]
+pub mod clock;
 pub mod coord;
+pub mod error;
 pub mod game;
+#[cfg(feature = "std")]
+pub mod match_runner;
+#[cfg(feature = "std")]
+pub mod nn;
+#[cfg(feature = "std")]
 pub mod play;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+#[cfg(feature = "std")]
+pub mod pns;
+#[cfg(feature = "with_proto")]
+pub mod proto;
+#[cfg(feature = "with_serde")]
+pub mod schema;
+#[cfg(feature = "with_rhai")]
+pub mod script;
+#[cfg(feature = "std")]
+pub mod search;
+#[cfg(feature = "std")]
+pub mod tablebase;
+#[cfg(feature = "std")]
+pub mod tree;
+#[cfg(feature = "std")]
+pub mod tune;
@@ -0,0 +1,364 @@
+//! Matches between two engines, run without any user interface (by
+//! the `match` mode of the console application, the tuner, or any
+//! other program which needs engines to play each other).
+
+use std::fmt;
+
+use crate::coord::{EitherCoord, Position};
+use crate::game::{Game, GameMove};
+use crate::play::{play_game_with, GameObserver, PlaySettings, Strategy};
+
+/// Settings for a match of several games between two engines.
+#[derive(Debug, Clone)]
+pub struct MatchConfig {
+    /// Number of games to play.
+    pub games: u32,
+    /// Whether the engines should swap sides after every game.
+    pub alternate_colors: bool,
+    /// Whether every deck should be played by a pair of games with the
+    /// engines on both sides, so that the luck of the deal cancels
+    /// out. Then game number `n` gets `seed + n / 2`.
+    pub paired: bool,
+    /// Base seed for the games. Game number `n` (counted from zero)
+    /// gets `seed + n`. If not set, the games are not reproducible.
+    pub seed: Option<u64>,
+    /// Settings used for every game of the match.
+    pub settings: PlaySettings,
+    /// Moves (in user coordinates) the games start with, so that they
+    /// differ from each other. The games are paired (see `paired`)
+    /// and every opening is played by a pair, the openings are
+    /// repeated if there are more games than that.
+    pub openings: Vec<Vec<GameMove<EitherCoord, Position>>>,
+}
+
+impl Default for MatchConfig {
+    fn default() -> Self {
+        MatchConfig {
+            games: 10,
+            alternate_colors: true,
+            paired: false,
+            seed: None,
+            settings: PlaySettings::default(),
+            openings: vec![],
+        }
+    }
+}
+
+impl MatchConfig {
+    /// Whether the games are played in pairs (see `paired`).
+    pub fn is_paired(&self) -> bool {
+        self.paired || !self.openings.is_empty()
+    }
+}
+
+/// Outcome of a single game played in a match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchGame {
+    /// Seed the game was created with (if any).
+    pub seed: Option<u64>,
+    /// Whether the first engine played as the first player.
+    pub engine1_first: bool,
+    /// Index of the opening the game started with (see
+    /// `MatchConfig::openings`).
+    pub opening: Option<usize>,
+    /// Game result from the first player's perspective: 1 for a win,
+    /// 0 for a draw and -1 for a loss.
+    pub result: i8,
+}
+
+impl MatchGame {
+    /// Game result from the first engine's perspective.
+    pub fn engine1_score(&self) -> i8 {
+        if self.engine1_first {
+            self.result
+        } else {
+            -self.result
+        }
+    }
+}
+
+/// Results of all games in a match.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MatchReport {
+    pub games: Vec<MatchGame>,
+}
+
+impl MatchReport {
+    /// Number of games won by the first engine.
+    pub fn wins(&self) -> usize {
+        self.games.iter().filter(|g| g.engine1_score() > 0).count()
+    }
+
+    /// Number of drawn games.
+    pub fn draws(&self) -> usize {
+        self.games.iter().filter(|g| g.engine1_score() == 0).count()
+    }
+
+    /// Number of games lost by the first engine.
+    pub fn losses(&self) -> usize {
+        self.games.iter().filter(|g| g.engine1_score() < 0).count()
+    }
+
+    /// Summed scores of the first engine in the pairs of games (see
+    /// `MatchConfig::paired`), from -2 to 2. An unfinished pair at
+    /// the end is left out.
+    pub fn pair_scores(&self) -> Vec<i8> {
+        self.games
+            .chunks_exact(2)
+            .map(|pair| pair[0].engine1_score() + pair[1].engine1_score())
+            .collect()
+    }
+}
+
+// 5 : 2 : 3 (wins, draws and losses of the first engine).
+impl fmt::Display for MatchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} : {} : {}", self.wins(), self.draws(), self.losses())
+    }
+}
+
+/// Observer of the match progress (on top of the progress of its
+/// games, see `GameObserver`). All the methods have empty default
+/// implementations.
+pub trait MatchObserver {
+    /// Called before game number `ix` (counted from zero) starts.
+    fn on_match_game_start(&mut self, _ix: u32, _config: &MatchConfig) {}
+
+    /// Called when the opening can't be played in the game, which then
+    /// starts without it.
+    fn on_opening_failed(&mut self, _opening: usize, _error: &failure::Error) {}
+
+    /// Called after every game with the results so far.
+    fn on_match_game_over(&mut self, _report: &MatchReport) {}
+
+    /// Called when all the games have been played.
+    fn on_match_over(&mut self, _config: &MatchConfig, _report: &MatchReport) {}
+}
+
+/// Doesn't observe anything, for running matches silently.
+impl MatchObserver for () {}
+
+/// Prints the match progress to stdout.
+pub struct PrintingMatchObserver;
+
+impl MatchObserver for PrintingMatchObserver {
+    fn on_match_game_start(&mut self, ix: u32, config: &MatchConfig) {
+        if ix == 0 {
+            println!("Starting a match of {} games", config.games);
+        }
+        println!();
+        println!("Starting game {}", ix + 1);
+    }
+
+    fn on_opening_failed(&mut self, opening: usize, error: &failure::Error) {
+        println!("[ERR] Can't play opening {}: {}", opening + 1, error);
+    }
+
+    fn on_match_game_over(&mut self, report: &MatchReport) {
+        println!("Match status so far: played {} games: {}", report.games.len(), report);
+        println!();
+    }
+
+    fn on_match_over(&mut self, config: &MatchConfig, report: &MatchReport) {
+        println!("Played {} games in total: {}", config.games, report);
+        if config.is_paired() {
+            let pairs = report.pair_scores();
+            println!(
+                "Pairs won, even and lost by the first engine: {} : {} : {}",
+                pairs.iter().filter(|&&s| s > 0).count(),
+                pairs.iter().filter(|&&s| s == 0).count(),
+                pairs.iter().filter(|&&s| s < 0).count()
+            );
+        }
+    }
+}
+
+/// Plays a match between two engines. `new_game` creates a game from
+/// a seed (see `MatchConfig::seed`), `engine1` and `engine2` create
+/// strategies given whether they are going to play as the first
+/// player. `observers` are notified about the progress of every game
+/// and `match_observer` about the progress of the match (pass `&mut ()`
+/// to run it silently).
+pub fn run_match<S1, S2>(
+    config: &MatchConfig,
+    mut new_game: impl FnMut(Option<u64>) -> Game,
+    mut engine1: impl FnMut(bool) -> S1,
+    mut engine2: impl FnMut(bool) -> S2,
+    observers: &mut [&mut dyn GameObserver],
+    match_observer: &mut dyn MatchObserver,
+) -> MatchReport
+where
+    S1: Strategy,
+    S2: Strategy,
+{
+    let mut report = MatchReport::default();
+    let paired = config.is_paired();
+
+    for ix in 0..config.games {
+        match_observer.on_match_game_start(ix, config);
+
+        let (seed, mut game, engine1_first) = if paired {
+            let seed = config.seed.map(|s| s.wrapping_add(u64::from(ix / 2)));
+            (seed, new_game(seed), ix % 2 == 0)
+        } else {
+            let seed = config.seed.map(|s| s.wrapping_add(u64::from(ix)));
+            (seed, new_game(seed), !config.alternate_colors || ix % 2 == 0)
+        };
+
+        let opening = if config.openings.is_empty() {
+            None
+        } else {
+            Some((ix / 2) as usize % config.openings.len())
+        };
+        if let Some(opening) = opening {
+            let mut with_opening = game.clone();
+            match config.openings[opening]
+                .iter()
+                .try_for_each(|m| with_opening.apply_user_move(m).map(|_| ()))
+            {
+                Ok(()) => game = with_opening,
+                Err(err) => match_observer.on_opening_failed(opening, &err),
+            }
+        }
+
+        let game_report = if engine1_first {
+            play_game_with(game, engine1(true), engine2(false), &config.settings, observers)
+        } else {
+            play_game_with(game, engine2(true), engine1(false), &config.settings, observers)
+        };
+
+        report.games.push(MatchGame {
+            seed,
+            engine1_first,
+            opening,
+            result: game_report.score(),
+        });
+        match_observer.on_match_game_over(&report);
+    }
+
+    match_observer.on_match_over(config, &report);
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::board::Layout;
+    use crate::card::{Deck, DiceColor, Die};
+    use crate::coord::UserCoord;
+    use crate::game::Rules;
+    use crate::play::test::MoveCounter;
+    use crate::play::RandomAI;
+
+    /// Remembers the starting positions of the games.
+    struct StartRecorder(Vec<Game>);
+
+    impl GameObserver for StartRecorder {
+        fn on_game_start(&mut self, game: &Game) {
+            self.0.push(game.clone());
+        }
+    }
+
+    #[test]
+    fn test_match_alternates_colors() {
+        let config = MatchConfig {
+            games: 4,
+            seed: Some(42),
+            settings: PlaySettings {
+                max_plies: Some(50),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let new_game = |seed: Option<u64>| {
+            let deck = Deck::shuffled_with_seed("gggjjjj", seed.unwrap()).unwrap();
+            Game::new(Layout::Bricks7, deck, Rules::new(true, false))
+        };
+
+        let mut counter = MoveCounter::default();
+        let report = run_match(
+            &config,
+            new_game,
+            |_| RandomAI,
+            |_| RandomAI,
+            &mut [&mut counter],
+            &mut (),
+        );
+        assert_eq!(report.pair_scores().len(), 2);
+
+        assert_eq!(report.games.len(), 4);
+        assert_eq!(report.wins() + report.draws() + report.losses(), 4);
+        let sides: Vec<_> = report.games.iter().map(|g| g.engine1_first).collect();
+        assert_eq!(sides, vec![true, false, true, false]);
+        let seeds: Vec<_> = report.games.iter().map(|g| g.seed).collect();
+        assert_eq!(seeds, vec![Some(42), Some(43), Some(44), Some(45)]);
+        assert!(counter.moves >= 4);
+        assert!(counter.rejected.is_empty());
+
+        // Paired games share the decks.
+        let config = MatchConfig {
+            games: 3,
+            paired: true,
+            ..config
+        };
+        let mut starts = StartRecorder(vec![]);
+        let report = run_match(
+            &config,
+            new_game,
+            |_| RandomAI,
+            |_| RandomAI,
+            &mut [&mut starts],
+            &mut (),
+        );
+        let seeds: Vec<_> = report.games.iter().map(|g| g.seed).collect();
+        assert_eq!(seeds, vec![Some(42), Some(42), Some(43)]);
+        assert_eq!(starts.0[0].board.cards, starts.0[1].board.cards);
+        let sides: Vec<_> = report.games.iter().map(|g| g.engine1_first).collect();
+        assert_eq!(sides, vec![true, false, true]);
+        assert_eq!(report.pair_scores().len(), 1);
+    }
+
+    #[test]
+    fn test_match_openings() {
+        let place = |die, row, card| GameMove::Place(die, EitherCoord::User(UserCoord::new(row, card)));
+        let (red, black) = (Die::new(DiceColor::Red, 2), Die::new(DiceColor::Black, 1));
+        let config = MatchConfig {
+            games: 5,
+            seed: Some(42),
+            alternate_colors: false,
+            settings: PlaySettings {
+                max_plies: Some(20),
+                ..Default::default()
+            },
+            openings: vec![vec![place(red, 2, 1), place(black, 1, 1)], vec![place(red, 2, 4)]],
+            ..Default::default()
+        };
+        let new_game = |seed: Option<u64>| {
+            let deck = Deck::shuffled_with_seed("gggjjjj", seed.unwrap()).unwrap();
+            Game::new(Layout::Bricks7, deck, Rules::default())
+        };
+
+        let mut starts = StartRecorder(vec![]);
+        let report = run_match(
+            &config,
+            new_game,
+            |_| RandomAI,
+            |_| RandomAI,
+            &mut [&mut starts],
+            &mut (),
+        );
+
+        let openings: Vec<_> = report.games.iter().map(|g| g.opening).collect();
+        assert_eq!(openings, vec![Some(0), Some(0), Some(1), Some(1), Some(0)]);
+        let seeds: Vec<_> = report.games.iter().map(|g| g.seed).collect();
+        assert_eq!(seeds, vec![Some(42), Some(42), Some(43), Some(43), Some(44)]);
+        // Engines swap sides within a pair even without alternating colors.
+        let sides: Vec<_> = report.games.iter().map(|g| g.engine1_first).collect();
+        assert_eq!(sides, vec![true, false, true, false, true]);
+        let lengths: Vec<_> = starts.0.iter().map(|game| game.history.len()).collect();
+        assert_eq!(lengths, vec![2, 2, 1, 1, 2]);
+        assert_eq!(starts.0[0].moves(), starts.0[1].moves());
+    }
+}
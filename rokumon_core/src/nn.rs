@@ -0,0 +1,125 @@
+//! Tiny multi-layer perceptron used as an alternative evaluation
+//! function (see `ai::Evaluator`). Networks are trained outside of
+//! this crate and loaded from text files.
+
+use failure::{bail, ensure, format_err, Fallible};
+use std::str::FromStr;
+
+/// Fully connected layer: `output = weights * input + biases`.
+#[derive(Debug, Clone, PartialEq)]
+struct Layer {
+    inputs: usize,
+    /// Row-major, one row per output.
+    weights: Vec<f32>,
+    biases: Vec<f32>,
+}
+
+/// Network of fully connected layers with ReLU activations between
+/// them (the output layer is linear).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mlp {
+    layers: Vec<Layer>,
+}
+
+impl Mlp {
+    pub fn inputs(&self) -> usize {
+        self.layers[0].inputs
+    }
+
+    pub fn outputs(&self) -> usize {
+        self.layers.last().unwrap().biases.len()
+    }
+
+    pub fn forward(&self, input: &[f32]) -> Vec<f32> {
+        let mut values = input.to_vec();
+        for (ix, layer) in self.layers.iter().enumerate() {
+            let last = ix + 1 == self.layers.len();
+            values = layer
+                .weights
+                .chunks(layer.inputs)
+                .zip(&layer.biases)
+                .map(|(row, bias)| {
+                    let sum = row.iter().zip(&values).map(|(w, v)| w * v).sum::<f32>() + bias;
+                    if last {
+                        sum
+                    } else {
+                        sum.max(0.0)
+                    }
+                })
+                .collect();
+        }
+        values
+    }
+}
+
+/// Parses a network in the following text format: the first line
+/// lists sizes of the layers (starting with the number of inputs),
+/// then for every layer there are its weights (row by row, one row
+/// per output) followed by its biases. Numbers are separated by any
+/// whitespace, lines starting with `#` are ignored.
+///
+/// For example, "2 1 \n 0.5 -0.5 \n 0.1" is a network computing
+/// `0.5 * x - 0.5 * y + 0.1`.
+impl FromStr for Mlp {
+    type Err = failure::Error;
+    fn from_str(s: &str) -> Fallible<Self> {
+        let mut lines = s.lines().filter(|l| !l.trim_start().starts_with('#'));
+        let sizes = lines
+            .next()
+            .ok_or_else(|| format_err!("Network description is empty"))?
+            .split_whitespace()
+            .map(|n| n.parse::<usize>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format_err!("Can't parse layer sizes: {}", e))?;
+        ensure!(sizes.len() >= 2, "Network should have at least inputs and outputs");
+
+        let mut numbers = lines.flat_map(|l| l.split_whitespace()).map(|n| {
+            n.parse::<f32>()
+                .map_err(|_| format_err!("Can't parse network parameter: {}", n))
+        });
+        let mut take = |count: usize| -> Fallible<Vec<f32>> {
+            let values = numbers.by_ref().take(count).collect::<Fallible<Vec<_>>>()?;
+            ensure!(values.len() == count, "Not enough network parameters");
+            Ok(values)
+        };
+
+        let mut layers = vec![];
+        for pair in sizes.windows(2) {
+            let (inputs, outputs) = (pair[0], pair[1]);
+            layers.push(Layer {
+                inputs,
+                weights: take(inputs * outputs)?,
+                biases: take(outputs)?,
+            });
+        }
+
+        if numbers.next().is_some() {
+            bail!("Too many network parameters");
+        }
+        Ok(Mlp { layers })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mlp() -> Fallible<()> {
+        let linear: Mlp = "2 1 \n 0.5 -0.5 \n 0.1".parse()?;
+        assert_eq!(linear.inputs(), 2);
+        assert_eq!(linear.outputs(), 1);
+        assert_eq!(linear.forward(&[2.0, 1.0]), vec![0.6]);
+
+        // Hidden layer with ReLU: max(x, 0) + max(-x, 0) = |x|.
+        let abs: Mlp = "# abs\n1 2 1\n1 -1\n0 0\n1 1\n0".parse()?;
+        assert_eq!(abs.forward(&[-3.0]), vec![3.0]);
+        assert_eq!(abs.forward(&[2.0]), vec![2.0]);
+
+        assert!("2 1\n0.5".parse::<Mlp>().is_err());
+        assert!("2 1\n0.5 0.5 0 1".parse::<Mlp>().is_err());
+        assert!("2\n".parse::<Mlp>().is_err());
+        assert!("2 1\n0.5 x 0".parse::<Mlp>().is_err());
+        Ok(())
+    }
+}
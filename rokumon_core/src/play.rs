@@ -1,10 +1,242 @@
+use crate::clock::{Clock, TimeControl};
 use crate::coord::Coord;
-use crate::game::{Game, GameFeatures, GameMove, GameResult};
+use crate::game::{FightResult, Game, GameMove, GameResult, PositionKey, WinReason};
+use crate::match_runner::{MatchGame, MatchReport};
+
+use failure::{bail, format_err, Fallible};
 
 use std::collections::HashMap;
+use std::fmt;
+use std::future::{self, Future};
+use std::pin::{pin, Pin};
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
 pub trait Strategy {
     fn get_move(&mut self, game: &Game) -> GameMove<Coord>;
+
+    /// Name of the player shown in the game reports.
+    fn name(&self) -> String {
+        "Anonymous".to_string()
+    }
+
+    /// Same as `get_move`, but called in games played with a time
+    /// control, so that strategies can budget their thinking time
+    /// according to the `clock`.
+    fn get_move_with_clock(&mut self, game: &Game, _clock: &Clock) -> GameMove<Coord> {
+        self.get_move(game)
+    }
+
+    /// Called after the strategy has made its move (`game` is the
+    /// position after it), so that it can think while the opponent is
+    /// thinking.
+    fn start_ponder(&mut self, _game: &Game) {}
+
+    /// Called after the opponent has made its move (`game` is the
+    /// position after it). The strategy should stop pondering, but
+    /// can keep the analysis for the next `get_move` if it has been
+    /// pondering on the right position.
+    fn stop_ponder(&mut self, _game: &Game) {}
+
+    /// Called after the opponent has made a move (but not for the
+    /// moves replayed after taking them back).
+    fn on_opponent_move(&mut self, _mov: &GameMove<Coord>) {}
+
+    /// Called when the game is over (the result is `InProgress` if
+    /// it has been drawn).
+    fn on_game_over(&mut self, _result: &GameResult) {}
+
+    /// Evaluation of the position after the strategy's last move from
+    /// its own perspective (like `SearchResult::score`), if it has
+    /// one. Used for adjudicating games.
+    fn evaluation(&self) -> Option<i32> {
+        None
+    }
+
+    /// Decides what to do on the strategy's turn. Only interactive
+    /// strategies need to do anything but play a move.
+    fn decide(&mut self, game: &Game, clock: Option<&Clock>) -> Decision {
+        Decision::Play(match clock {
+            Some(clock) => self.get_move_with_clock(game, clock),
+            None => self.get_move(game),
+        })
+    }
+}
+
+// Strategies behind pointers are strategies too.
+macro_rules! forward_strategy {
+    ($pointer:ty) => {
+        impl<S: Strategy + ?Sized> Strategy for $pointer {
+            fn get_move(&mut self, game: &Game) -> GameMove<Coord> {
+                (**self).get_move(game)
+            }
+
+            fn name(&self) -> String {
+                (**self).name()
+            }
+
+            fn get_move_with_clock(&mut self, game: &Game, clock: &Clock) -> GameMove<Coord> {
+                (**self).get_move_with_clock(game, clock)
+            }
+
+            fn start_ponder(&mut self, game: &Game) {
+                (**self).start_ponder(game)
+            }
+
+            fn stop_ponder(&mut self, game: &Game) {
+                (**self).stop_ponder(game)
+            }
+
+            fn on_opponent_move(&mut self, mov: &GameMove<Coord>) {
+                (**self).on_opponent_move(mov)
+            }
+
+            fn on_game_over(&mut self, result: &GameResult) {
+                (**self).on_game_over(result)
+            }
+
+            fn evaluation(&self) -> Option<i32> {
+                (**self).evaluation()
+            }
+
+            fn decide(&mut self, game: &Game, clock: Option<&Clock>) -> Decision {
+                (**self).decide(game, clock)
+            }
+        }
+    };
+}
+
+// Lets the players be chosen at runtime (`Box<dyn Strategy>`).
+forward_strategy!(Box<S>);
+// Lets the players be borrowed for a game, e.g. from a list of them.
+forward_strategy!(&mut S);
+
+/// What a player has decided to do on their turn.
+#[derive(Debug, Clone)]
+pub enum Decision {
+    Play(GameMove<Coord>),
+    /// Take back the given number of plies.
+    TakeBack(usize),
+    /// Play again the given number of plies taken back before.
+    Replay(usize),
+    /// Continue a different game: the one starting with the `start`
+    /// position after `moves`.
+    Load {
+        start: Box<Game>,
+        moves: Vec<GameMove<Coord>>,
+    },
+}
+
+/// Strategy which may have to wait for its decisions, e.g. for the
+/// moves of a remote human, to play in `play_game_async`. The hooks
+/// are the same as in `Strategy`, only deciding is asynchronous. Any
+/// `Strategy` can be used as one with `Blocking`.
+pub trait AsyncStrategy {
+    /// Decides what to do on the strategy's turn (see
+    /// `Strategy::decide`).
+    fn decide<'a>(
+        &'a mut self,
+        game: &'a Game,
+        clock: Option<&'a Clock>,
+    ) -> Pin<Box<dyn Future<Output = Decision> + 'a>>;
+
+    fn name(&self) -> String {
+        "Anonymous".to_string()
+    }
+
+    fn start_ponder(&mut self, _game: &Game) {}
+
+    fn stop_ponder(&mut self, _game: &Game) {}
+
+    fn on_opponent_move(&mut self, _mov: &GameMove<Coord>) {}
+
+    fn on_game_over(&mut self, _result: &GameResult) {}
+
+    fn evaluation(&self) -> Option<i32> {
+        None
+    }
+}
+
+// Asynchronous strategies behind pointers, like the blocking ones.
+macro_rules! forward_async_strategy {
+    ($pointer:ty) => {
+        impl<S: AsyncStrategy + ?Sized> AsyncStrategy for $pointer {
+            fn decide<'a>(
+                &'a mut self,
+                game: &'a Game,
+                clock: Option<&'a Clock>,
+            ) -> Pin<Box<dyn Future<Output = Decision> + 'a>> {
+                (**self).decide(game, clock)
+            }
+
+            fn name(&self) -> String {
+                (**self).name()
+            }
+
+            fn start_ponder(&mut self, game: &Game) {
+                (**self).start_ponder(game)
+            }
+
+            fn stop_ponder(&mut self, game: &Game) {
+                (**self).stop_ponder(game)
+            }
+
+            fn on_opponent_move(&mut self, mov: &GameMove<Coord>) {
+                (**self).on_opponent_move(mov)
+            }
+
+            fn on_game_over(&mut self, result: &GameResult) {
+                (**self).on_game_over(result)
+            }
+
+            fn evaluation(&self) -> Option<i32> {
+                (**self).evaluation()
+            }
+        }
+    };
+}
+
+forward_async_strategy!(Box<S>);
+forward_async_strategy!(&mut S);
+
+/// Lets a `Strategy` play in `play_game_async`. It blocks while it's
+/// deciding, so its decisions are ready once they are asked for.
+pub struct Blocking<S>(pub S);
+
+impl<S: Strategy> AsyncStrategy for Blocking<S> {
+    fn decide<'a>(
+        &'a mut self,
+        game: &'a Game,
+        clock: Option<&'a Clock>,
+    ) -> Pin<Box<dyn Future<Output = Decision> + 'a>> {
+        Box::pin(future::ready(self.0.decide(game, clock)))
+    }
+
+    fn name(&self) -> String {
+        self.0.name()
+    }
+
+    fn start_ponder(&mut self, game: &Game) {
+        self.0.start_ponder(game)
+    }
+
+    fn stop_ponder(&mut self, game: &Game) {
+        self.0.stop_ponder(game)
+    }
+
+    fn on_opponent_move(&mut self, mov: &GameMove<Coord>) {
+        self.0.on_opponent_move(mov)
+    }
+
+    fn on_game_over(&mut self, result: &GameResult) {
+        self.0.on_game_over(result)
+    }
+
+    fn evaluation(&self) -> Option<i32> {
+        self.0.evaluation()
+    }
 }
 
 pub struct RandomAI;
@@ -13,64 +245,1018 @@ impl Strategy for RandomAI {
     fn get_move(&mut self, game: &Game) -> GameMove<Coord> {
         game.random_move()
     }
+
+    fn name(&self) -> String {
+        "Random AI".to_string()
+    }
+}
+
+/// Player whose decisions are made elsewhere (by a server handler, a
+/// GUI or another thread) and sent through a channel with the handle
+/// made by `ChannelStrategy::new`. A player whose handle has been
+/// dropped submits.
+pub struct ChannelStrategy {
+    name: String,
+    turns: mpsc::Sender<Game>,
+    decisions: mpsc::Receiver<Decision>,
+}
+
+/// The other end of a `ChannelStrategy`.
+pub struct ChannelHandle {
+    turns: mpsc::Receiver<Game>,
+    decisions: mpsc::Sender<Decision>,
+}
+
+impl ChannelStrategy {
+    pub fn new(name: &str) -> (Self, ChannelHandle) {
+        let (turns_tx, turns_rx) = mpsc::channel();
+        let (decisions_tx, decisions_rx) = mpsc::channel();
+        let strategy = ChannelStrategy {
+            name: name.to_string(),
+            turns: turns_tx,
+            decisions: decisions_rx,
+        };
+        let handle = ChannelHandle {
+            turns: turns_rx,
+            decisions: decisions_tx,
+        };
+        (strategy, handle)
+    }
+}
+
+impl Strategy for ChannelStrategy {
+    fn get_move(&mut self, game: &Game) -> GameMove<Coord> {
+        // Only moves can be played here.
+        loop {
+            if let Decision::Play(mov) = self.decide(game, None) {
+                return mov;
+            }
+        }
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn decide(&mut self, game: &Game, _clock: Option<&Clock>) -> Decision {
+        // Nobody may be waiting for the turns, it's fine.
+        let _ = self.turns.send(game.clone());
+        self.decisions.recv().unwrap_or(Decision::Play(GameMove::Submit))
+    }
+}
+
+impl ChannelHandle {
+    /// Waits for the player's turn and returns the position, or `None`
+    /// once the strategy has been dropped (the game is over). Every
+    /// time a decision is asked for, there is a turn, so an illegal
+    /// move is followed by another turn in the same position.
+    pub fn next_turn(&self) -> Option<Game> {
+        self.turns.recv().ok()
+    }
+
+    /// Sends the player's decision, failing if the strategy has been
+    /// dropped.
+    pub fn send(&self, decision: Decision) -> Fallible<()> {
+        self.decisions
+            .send(decision)
+            .map_err(|_| format_err!("The player has left the game"))
+    }
+
+    pub fn send_move(&self, mov: GameMove<Coord>) -> Fallible<()> {
+        self.send(Decision::Play(mov))
+    }
+
+    /// Sender of the decisions, for other threads to use.
+    pub fn sender(&self) -> mpsc::Sender<Decision> {
+        self.decisions.clone()
+    }
+}
+
+/// Additional settings for playing a single game.
+#[derive(Debug, Clone, Default)]
+pub struct PlaySettings {
+    /// Adjudicate the game as a draw after this number of plies.
+    pub max_plies: Option<usize>,
+    /// Play with a chess clock. A player who runs out of time loses.
+    pub time_control: Option<TimeControl>,
+    /// Let the players think during the opponent's time.
+    pub ponder: bool,
+    /// Adjudicate the game as lost by a player whose evaluation has
+    /// been at most `-margin` for that many of their moves in a row.
+    pub resign: Option<Adjudication>,
+    /// Adjudicate the game as a draw once the evaluations of both
+    /// players have been within `margin` of zero for that many of
+    /// their moves in a row.
+    pub draw: Option<Adjudication>,
+}
+
+/// Rule for ending a game early based on the players' own
+/// evaluations (see `Strategy::evaluation`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Adjudication {
+    pub margin: i32,
+    pub moves: usize,
+}
+
+/// Parses rules like "300/3" (margin of 300 for 3 moves in a row).
+impl FromStr for Adjudication {
+    type Err = failure::Error;
+    fn from_str(s: &str) -> Fallible<Self> {
+        let mut parts = s.trim().splitn(2, '/');
+        let margin = parts.next().unwrap_or("").trim();
+        let moves = parts.next().unwrap_or("").trim();
+        match (margin.parse::<i32>(), moves.parse::<usize>()) {
+            (Ok(margin), Ok(moves)) if margin >= 0 && moves > 0 => Ok(Adjudication { margin, moves }),
+            _ => bail!(
+                "Can't parse adjudication rule, expected something like 300/3, got: {}",
+                s
+            ),
+        }
+    }
+}
+
+// 300/3
+impl fmt::Display for Adjudication {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.margin, self.moves)
+    }
+}
+
+/// Why a game has been drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrawReason {
+    /// The same position has occurred three times.
+    Repetition,
+    /// The game has reached `PlaySettings::max_plies`.
+    MovesLimit,
+    /// Both players have evaluated the game as even for long enough
+    /// (see `PlaySettings::draw`).
+    Adjudication,
+}
+
+/// Everything we know about a finished game.
+#[derive(Debug, Clone)]
+pub struct GameReport {
+    /// Result of the game. It stays `InProgress` if the game has been
+    /// drawn and differs from `final_position.result` if the game has
+    /// been lost on time.
+    pub result: GameResult,
+    /// All the moves played in the game.
+    pub moves: Vec<GameMove<Coord>>,
+    /// Time spent by the players on each of the moves.
+    pub per_move_times: Vec<Duration>,
+    /// Set if the game has been drawn.
+    pub draw_reason: Option<DrawReason>,
+    /// Whether the game has been lost on time.
+    pub time_forfeit: bool,
+    /// Whether the game has been lost by resignation (see
+    /// `PlaySettings::resign`).
+    pub resigned: bool,
+    /// Set if the game has been won on the board.
+    pub win_reason: Option<WinReason>,
+    /// Names of the players (see `Strategy::name`).
+    pub player1: String,
+    pub player2: String,
+    /// The position at the end of the game.
+    pub final_position: Game,
+}
+
+impl GameReport {
+    /// Game result from the first player's perspective: 1 for a win,
+    /// 0 for a draw and -1 for a loss.
+    pub fn score(&self) -> i8 {
+        match self.result {
+            GameResult::FirstPlayerWon => 1,
+            GameResult::SecondPlayerWon => -1,
+            GameResult::InProgress => 0,
+        }
+    }
+
+    pub fn is_draw(&self) -> bool {
+        self.draw_reason.is_some()
+    }
+}
+
+/// Observer of game progress. All the methods have empty default
+/// implementations, so observers only need to implement the events
+/// they are interested in.
+pub trait GameObserver {
+    /// Called before the first move of the game is made.
+    fn on_game_start(&mut self, _game: &Game) {}
+
+    /// Called after a move has been applied. `game` is the position
+    /// after the move.
+    fn on_move_applied(&mut self, _game: &Game, _mov: &GameMove<Coord>) {}
+
+    /// Called after a fight at `place` has been resolved (right after
+    /// `on_move_applied` for the fight move).
+    fn on_fight_resolved(&mut self, _game: &Game, _place: Coord, _fight_result: &FightResult) {}
+
+    /// Called after a move has been taken back. `game` is the position
+    /// before the move.
+    fn on_move_taken_back(&mut self, _game: &Game, _mov: &GameMove<Coord>) {}
+
+    /// Called when a player's decision can't be carried out (an illegal
+    /// move, too many moves to take back, a broken game to load). The
+    /// player is asked to decide again.
+    fn on_decision_rejected(&mut self, _game: &Game, _error: &failure::Error) {}
+
+    /// Called when the game is over.
+    fn on_game_over(&mut self, _report: &GameReport) {}
+}
+
+/// Prints the game progress to stdout.
+pub struct PrintingObserver;
+
+impl GameObserver for PrintingObserver {
+    fn on_game_start(&mut self, game: &Game) {
+        println!("Starting position: {}", game);
+    }
+
+    fn on_move_applied(&mut self, game: &Game, mov: &GameMove<Coord>) {
+        println!("Played move: {}", game.userify_move(mov));
+    }
+
+    fn on_move_taken_back(&mut self, game: &Game, mov: &GameMove<Coord>) {
+        println!("Took back move: {}", game.userify_move(mov));
+    }
+
+    fn on_decision_rejected(&mut self, _game: &Game, error: &failure::Error) {
+        println!("[ERR] {}", error);
+    }
+
+    fn on_game_over(&mut self, report: &GameReport) {
+        let game = &report.final_position;
+        match report.draw_reason {
+            Some(DrawReason::Repetition) => println!("Game over! Drawn by repetition in {} moves.", report.moves.len()),
+            Some(DrawReason::MovesLimit) => {
+                println!("Game over! Drawn by moves limit in {} moves.", report.moves.len())
+            }
+            Some(DrawReason::Adjudication) => {
+                println!("Game over! Drawn by adjudication in {} moves.", report.moves.len())
+            }
+            None => println!(
+                "Game over! {} won{} in {} moves.",
+                if report.result == GameResult::FirstPlayerWon {
+                    format!("{} (first player)", report.player1)
+                } else {
+                    format!("{} (second player)", report.player2)
+                },
+                if report.time_forfeit {
+                    " on time".to_string()
+                } else if report.resigned {
+                    " by resignation".to_string()
+                } else {
+                    report.win_reason.map_or(String::new(), |r| pp_win_reason(game, r))
+                },
+                report.moves.len()
+            ),
+        }
+
+        println!("Moves history:");
+        for (ix, m) in report.moves.iter().enumerate() {
+            println!("{}: {}", ix + 1, game.userify_move(m));
+        }
+    }
 }
 
-pub fn play_game(mut game: Game, mut player1: impl Strategy, mut player2: impl Strategy) -> i8 {
-    println!("Starting position: {}", &game);
+/// Describes the win reason, e.g. " by a row at r1c1–r1c3".
+fn pp_win_reason(game: &Game, reason: WinReason) -> String {
+    let user = |c: &Coord| game.board.userify_coord(c);
+    match reason {
+        WinReason::ThreeInARow { coords } => {
+            format!(" by a row at {}–{}", user(&coords[0]), user(&coords[2]))
+        }
+        WinReason::ThreeInStack { coord } => format!(" by a stack at {}", user(&coord)),
+        WinReason::NoMoves => " as the opponent had no moves".to_string(),
+        WinReason::Submitted => " as the opponent submitted".to_string(),
+    }
+}
 
-    fn step(player: &mut impl Strategy, game: &mut Game) -> GameMove<Coord> {
+/// Plays a game printing its progress to stdout.
+pub fn play_game(game: Game, player1: impl Strategy, player2: impl Strategy) -> GameReport {
+    play_game_with(
+        game,
+        player1,
+        player2,
+        &PlaySettings::default(),
+        &mut [&mut PrintingObserver],
+    )
+}
+
+/// Plays a game notifying `observers` about its progress.
+pub fn play_game_with(
+    game: Game,
+    player1: impl Strategy,
+    player2: impl Strategy,
+    settings: &PlaySettings,
+    observers: &mut [&mut dyn GameObserver],
+) -> GameReport {
+    let game = pin!(play_game_async(
+        game,
+        Blocking(player1),
+        Blocking(player2),
+        settings,
+        observers
+    ));
+    // Blocking strategies have their decisions ready right away, so the
+    // game never waits.
+    match game.poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(report) => report,
+        Poll::Pending => unreachable!("play_game_with: blocking strategies never wait"),
+    }
+}
+
+/// Same as `play_game_with`, but for the strategies which may wait for
+/// their moves (like remote humans), so that a server can play many
+/// games without a thread for each. The future is not `Send` (neither
+/// are the observers), so it has to be run on a local executor.
+pub async fn play_game_async(
+    mut game: Game,
+    mut player1: impl AsyncStrategy,
+    mut player2: impl AsyncStrategy,
+    settings: &PlaySettings,
+    observers: &mut [&mut dyn GameObserver],
+) -> GameReport {
+    for o in observers.iter_mut() {
+        o.on_game_start(&game);
+    }
+
+    enum Turn {
+        /// Time spent on the move (including the invalid attempts).
+        Played(Duration),
+        TakeBack(usize),
+        Replay(usize),
+        /// Number of moves of the loaded game.
+        Loaded(usize),
+    }
+
+    // Asks the player until they make a valid decision: a legal move
+    // or taking back (replaying) no more plies than possible.
+    async fn step(
+        player: &mut impl AsyncStrategy,
+        game: &mut Game,
+        clock: Option<&Clock>,
+        observers: &mut [&mut dyn GameObserver],
+        (can_take_back, can_replay): (usize, usize),
+    ) -> Turn {
+        let start = Instant::now();
         loop {
-            let mov = player.get_move(&game);
-            match game.apply_move(&mov) {
-                Ok(_) => break mov,
-                Err(msg) => println!("[ERR] Can't apply move: {}", msg),
+            let error = match player.decide(game, clock).await {
+                Decision::Play(mov) => match game.apply_move_observed(&mov, observers) {
+                    Ok(_) => break Turn::Played(start.elapsed()),
+                    Err(msg) => format_err!("Can't apply move: {}", msg),
+                },
+                Decision::TakeBack(plies) if (1..=can_take_back).contains(&plies) => break Turn::TakeBack(plies),
+                Decision::Replay(plies) if (1..=can_replay).contains(&plies) => break Turn::Replay(plies),
+                Decision::TakeBack(plies) => format_err!("Can't take back {} moves", plies),
+                Decision::Replay(plies) => format_err!("Can't replay {} moves", plies),
+                Decision::Load { start, moves } => {
+                    let mut loaded = start.clone();
+                    match moves.iter().try_for_each(|m| loaded.apply_move(m).map(|_| ())) {
+                        Ok(()) => {
+                            for o in observers.iter_mut() {
+                                o.on_game_start(&start);
+                            }
+                            *game = *start;
+                            for m in &moves {
+                                game.apply_move_observed(m, observers).unwrap();
+                            }
+                            break Turn::Loaded(moves.len());
+                        }
+                        Err(msg) => format_err!("Can't load game: {}", msg),
+                    }
+                }
+            };
+            for o in observers.iter_mut() {
+                o.on_decision_rejected(game, &error);
             }
         }
     }
 
-    let mut positions: HashMap<GameFeatures, u8> = HashMap::new();
-    let mut draw = false;
+    // Counts the position, returns whether it has occurred three times.
+    fn is_repeated(positions: &mut HashMap<PositionKey, u8>, game: &Game) -> bool {
+        let counter = positions.entry(game.position_key()).or_insert(0);
+        *counter += 1;
+        *counter == 3
+    }
+
+    let mut positions: HashMap<PositionKey, u8> = HashMap::new();
+    let mut per_move_times = vec![];
+    let mut draw_reason = None;
+    let mut clock = settings.time_control.map(Clock::new);
+    let mut result = GameResult::InProgress;
+    let mut time_forfeit = false;
+    let mut resigned = false;
+    // Numbers of the last moves of each player (the first one goes
+    // first) with lost and with even evaluations.
+    let mut lost_streaks = [0, 0];
+    let mut even_streaks = [0, 0];
+    // Moves played in this game can be taken back and then replayed.
+    let mut played = 0;
+    let mut taken_back = vec![];
 
-    while !game.is_game_over() && !draw {
-        let mov = if game.player1_moves {
-            step(&mut player1, &mut game)
+    while !game.is_game_over() && draw_reason.is_none() {
+        let player1_moves = game.player1_moves;
+        let limits = (played, taken_back.len());
+        let turn = if player1_moves {
+            step(&mut player1, &mut game, clock.as_ref(), observers, limits).await
         } else {
-            step(&mut player2, &mut game)
+            step(&mut player2, &mut game, clock.as_ref(), observers, limits).await
         };
+        // Evaluations are only known for the moves just played.
+        if !matches!(turn, Turn::Played(_)) {
+            lost_streaks = [0, 0];
+            even_streaks = [0, 0];
+        }
 
-        println!("Played move: {}", game.userify_move(&mov));
+        match turn {
+            Turn::Played(time) => {
+                let entry = game.history.last_mut().unwrap();
+                entry.elapsed = Some(time);
+                if player1_moves {
+                    player2.on_opponent_move(&entry.game_move);
+                } else {
+                    player1.on_opponent_move(&entry.game_move);
+                }
+                per_move_times.push(time);
+                played += 1;
+                taken_back.clear();
 
-        let counter = positions.entry(game.defining_features()).or_insert(0);
-        *counter += 1;
-        if *counter == 3 {
-            draw = true;
+                if let Some(clock) = clock.as_mut() {
+                    if !clock.punch(player1_moves, time) {
+                        time_forfeit = true;
+                        result = if player1_moves {
+                            GameResult::SecondPlayerWon
+                        } else {
+                            GameResult::FirstPlayerWon
+                        };
+                        break;
+                    }
+                }
+
+                if is_repeated(&mut positions, &game) {
+                    draw_reason = Some(DrawReason::Repetition);
+                }
+
+                let evaluation = if player1_moves {
+                    player1.evaluation()
+                } else {
+                    player2.evaluation()
+                };
+                let side = if player1_moves { 0 } else { 1 };
+                let streak = |rule: Option<Adjudication>, streak: &mut usize, counts: fn(i32, i32) -> bool| {
+                    match (rule, evaluation) {
+                        (Some(rule), Some(eval)) if counts(eval, rule.margin) => *streak += 1,
+                        _ => *streak = 0,
+                    }
+                    rule.is_some_and(|rule| *streak >= rule.moves)
+                };
+                if streak(settings.resign, &mut lost_streaks[side], |eval, margin| eval <= -margin)
+                    && !game.is_game_over()
+                {
+                    resigned = true;
+                    result = if player1_moves {
+                        GameResult::SecondPlayerWon
+                    } else {
+                        GameResult::FirstPlayerWon
+                    };
+                    break;
+                }
+                if streak(settings.draw, &mut even_streaks[side], |eval, margin| {
+                    eval.abs() <= margin
+                }) && even_streaks[1 - side] >= settings.draw.unwrap().moves
+                    && !game.is_game_over()
+                {
+                    draw_reason = Some(DrawReason::Adjudication);
+                }
+            }
+            Turn::TakeBack(plies) => {
+                for _ in 0..plies {
+                    if let Some(counter) = positions.get_mut(&game.position_key()) {
+                        *counter -= 1;
+                    }
+                    let entry = game.history.last().cloned().unwrap();
+                    let mov = entry.game_move;
+                    game.undo_move(&mov, entry.fight_result);
+                    per_move_times.pop();
+                    played -= 1;
+                    for o in observers.iter_mut() {
+                        o.on_move_taken_back(&game, &mov);
+                    }
+                    taken_back.push(mov);
+                }
+                continue;
+            }
+            Turn::Loaded(moves) => {
+                per_move_times = vec![Duration::default(); moves];
+                played = moves;
+                taken_back.clear();
+                positions.clear();
+                is_repeated(&mut positions, &game);
+                result = game.result;
+                continue;
+            }
+            Turn::Replay(plies) => {
+                for _ in 0..plies {
+                    // Replayed moves have been legal before, so they
+                    // still are.
+                    let mov = taken_back.pop().unwrap();
+                    game.apply_move_observed(&mov, observers).unwrap();
+                    per_move_times.push(Duration::default());
+                    played += 1;
+                    if is_repeated(&mut positions, &game) {
+                        draw_reason = Some(DrawReason::Repetition);
+                        break;
+                    }
+                }
+            }
+        }
+        result = game.result;
+
+        if let Some(max_plies) = settings.max_plies {
+            if !game.is_game_over() && game.history.len() >= max_plies {
+                draw_reason = Some(DrawReason::MovesLimit);
+            }
+        }
+
+        if settings.ponder && !game.is_game_over() && draw_reason.is_none() {
+            if player1_moves {
+                player2.stop_ponder(&game);
+                player1.start_ponder(&game);
+            } else {
+                player1.stop_ponder(&game);
+                player2.start_ponder(&game);
+            }
         }
     }
 
-    if draw {
-        println!("Game over! Drawn in {} moves.", game.history.len());
+    player1.on_game_over(&result);
+    player2.on_game_over(&result);
+
+    let win_reason = if time_forfeit || resigned {
+        None
     } else {
-        println!(
-            "Game over! {} player won in {} moves.",
-            if game.result == GameResult::FirstPlayerWon {
-                "First"
-            } else {
-                "Second"
+        game.win_reason()
+    };
+    let report = GameReport {
+        result,
+        moves: game.moves(),
+        per_move_times,
+        draw_reason,
+        time_forfeit,
+        resigned,
+        win_reason,
+        player1: player1.name(),
+        player2: player2.name(),
+        final_position: game,
+    };
+
+    for o in observers.iter_mut() {
+        o.on_game_over(&report);
+    }
+
+    report
+}
+
+/// Plays games between two engines for as long as `rematch` wants
+/// more, swapping sides after every game. Unlike `run_match`, the
+/// number of games is not known in advance: `rematch` is called with
+/// the report of the game just played and the score so far.
+pub fn play_session<S1, S2>(
+    settings: &PlaySettings,
+    mut new_game: impl FnMut() -> Game,
+    mut engine1: impl FnMut(bool) -> S1,
+    mut engine2: impl FnMut(bool) -> S2,
+    observers: &mut [&mut dyn GameObserver],
+    mut rematch: impl FnMut(&GameReport, &MatchReport) -> bool,
+) -> MatchReport
+where
+    S1: Strategy,
+    S2: Strategy,
+{
+    let mut report = MatchReport::default();
+
+    loop {
+        let engine1_first = report.games.len() % 2 == 0;
+        let game = new_game();
+        let game_report = if engine1_first {
+            play_game_with(game, engine1(true), engine2(false), settings, observers)
+        } else {
+            play_game_with(game, engine2(true), engine1(false), settings, observers)
+        };
+
+        report.games.push(MatchGame {
+            seed: None,
+            engine1_first,
+            opening: None,
+            result: game_report.score(),
+        });
+        println!("Session score: {}", report);
+
+        if !rematch(&game_report, &report) {
+            break;
+        }
+        println!();
+    }
+
+    println!("Played {} games in the session: {}", report.games.len(), report);
+    report
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    use crate::board::Layout;
+    use crate::card::Deck;
+    use crate::game::Rules;
+
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    /// Counts the applied moves and remembers the rejected decisions.
+    #[derive(Default)]
+    pub(crate) struct MoveCounter {
+        pub(crate) moves: usize,
+        pub(crate) rejected: Vec<String>,
+    }
+
+    impl GameObserver for MoveCounter {
+        fn on_move_applied(&mut self, _game: &Game, _mov: &GameMove<Coord>) {
+            self.moves += 1;
+        }
+
+        fn on_decision_rejected(&mut self, _game: &Game, error: &failure::Error) {
+            self.rejected.push(error.to_string());
+        }
+    }
+
+    /// Random player which makes the game wait once before each of its
+    /// moves, like a remote human.
+    struct WaitingRandomAI {
+        waits: usize,
+    }
+
+    impl AsyncStrategy for WaitingRandomAI {
+        fn decide<'a>(
+            &'a mut self,
+            game: &'a Game,
+            _: Option<&'a Clock>,
+        ) -> Pin<Box<dyn Future<Output = Decision> + 'a>> {
+            let mov = game.random_move();
+            let mut waited = false;
+            Box::pin(future::poll_fn(move |cx| {
+                if waited {
+                    return Poll::Ready(Decision::Play(mov.clone()));
+                }
+                waited = true;
+                self.waits += 1;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }))
+        }
+    }
+
+    #[test]
+    fn test_async_game() {
+        let game = Game::new(Layout::Bricks7, Deck::seven_shuffled(), Rules::default());
+        let settings = PlaySettings {
+            max_plies: Some(40),
+            ..Default::default()
+        };
+        let mut waiting = WaitingRandomAI { waits: 0 };
+        let mut counter = MoveCounter::default();
+        let mut polls = 1;
+        let report = {
+            let mut observers: [&mut dyn GameObserver; 1] = [&mut counter];
+            let mut game = pin!(play_game_async(
+                game,
+                &mut waiting,
+                Blocking(RandomAI),
+                &settings,
+                &mut observers
+            ));
+            let mut cx = Context::from_waker(Waker::noop());
+            loop {
+                match game.as_mut().poll(&mut cx) {
+                    Poll::Ready(report) => break report,
+                    Poll::Pending => polls += 1,
+                }
+            }
+        };
+
+        // The first player waits before every move.
+        assert_eq!(waiting.waits, report.moves.len().div_ceil(2));
+        assert_eq!(polls, waiting.waits + 1);
+        assert_eq!(counter.moves, report.moves.len());
+        assert_eq!(report.player2, "Random AI");
+    }
+
+    #[test]
+    fn test_channel_strategy() {
+        let game = Game::new(Layout::Bricks7, Deck::seven_shuffled(), Rules::default());
+        let (remote, handle) = ChannelStrategy::new("Remote");
+        let player = std::thread::spawn(move || {
+            let mut turns = 0;
+            while let Some(game) = handle.next_turn() {
+                turns += 1;
+                // An illegal move first, it's asked for again.
+                if turns == 1 {
+                    handle.send_move(GameMove::Fight(Coord::new_hex(9, 9))).unwrap();
+                } else {
+                    handle.send_move(game.random_move()).unwrap();
+                }
+            }
+            turns
+        });
+
+        let settings = PlaySettings {
+            max_plies: Some(30),
+            ..Default::default()
+        };
+        let report = play_game_with(game, remote, RandomAI, &settings, &mut []);
+        assert_eq!(report.player1, "Remote");
+        assert_eq!(player.join().unwrap(), report.moves.len().div_ceil(2) + 1);
+
+        // Players without handles submit.
+        let (remote, handle) = ChannelStrategy::new("Gone");
+        drop(handle);
+        let game = Game::new(Layout::Bricks7, Deck::seven_shuffled(), Rules::default());
+        let report = play_game_with(game, remote, RandomAI, &settings, &mut []);
+        assert_eq!(report.result, GameResult::SecondPlayerWon);
+        assert_eq!(report.moves, vec![GameMove::Submit]);
+    }
+
+    #[test]
+    fn test_session_swaps_sides() {
+        let settings = PlaySettings {
+            max_plies: Some(50),
+            ..Default::default()
+        };
+        let new_game = || Game::new(Layout::Bricks7, Deck::seven_shuffled(), Rules::default());
+
+        let mut last_moves = vec![];
+        let report = play_session(
+            &settings,
+            new_game,
+            |_| RandomAI,
+            |_| RandomAI,
+            &mut [],
+            |game_report, report| {
+                last_moves.push(game_report.moves.last().cloned());
+                report.games.len() < 3
             },
-            game.history.len()
         );
+
+        assert_eq!(report.games.len(), 3);
+        assert_eq!(last_moves.len(), 3);
+        assert_eq!(report.wins() + report.draws() + report.losses(), 3);
+        let sides: Vec<_> = report.games.iter().map(|g| g.engine1_first).collect();
+        assert_eq!(sides, vec![true, false, true]);
     }
 
-    println!("Moves history:");
-    for (ix, m) in game.history.iter().enumerate() {
-        println!("{}: {}", ix + 1, game.userify_move(&m));
+    #[test]
+    fn test_game_report() {
+        let deck = Deck::ordered("gggjjjj").unwrap();
+        let game = Game::new(Layout::Bricks7, deck, Rules::new(true, true));
+        let settings = PlaySettings {
+            max_plies: Some(6),
+            ..Default::default()
+        };
+
+        let report = play_game_with(game, RandomAI, RandomAI, &settings, &mut []);
+
+        assert!(report.moves.len() <= 6);
+        assert_eq!(report.moves.len(), report.per_move_times.len());
+        assert_eq!(report.moves, report.final_position.moves());
+        assert!(report.final_position.history.iter().all(|e| e.elapsed.is_some()));
+        if report.is_draw() {
+            assert_eq!(report.score(), 0);
+            assert_eq!(report.result, GameResult::InProgress);
+        } else {
+            assert!(report.final_position.is_game_over());
+        }
     }
 
-    if draw {
-        0
-    } else if game.result == GameResult::FirstPlayerWon {
-        1
-    } else {
-        -1
+    /// Plays the last legal move, evaluating every position the same.
+    struct Evaluating(i32);
+
+    impl Strategy for Evaluating {
+        fn get_move(&mut self, game: &Game) -> GameMove<Coord> {
+            game.generate_moves().pop().unwrap()
+        }
+
+        fn evaluation(&self) -> Option<i32> {
+            Some(self.0)
+        }
+    }
+
+    #[test]
+    fn test_adjudication() -> Fallible<()> {
+        let game = || Game::new(Layout::Bricks7, Deck::ordered("gggjjjj").unwrap(), Rules::default());
+        let settings = PlaySettings {
+            resign: Some("300/3".parse()?),
+            draw: Some("10/2".parse()?),
+            max_plies: Some(10),
+            ..Default::default()
+        };
+
+        // The second player resigns after their third move.
+        let report = play_game_with(game(), Evaluating(0), Evaluating(-500), &settings, &mut []);
+        assert!(report.resigned);
+        assert_eq!(report.result, GameResult::FirstPlayerWon);
+        assert_eq!(report.moves.len(), 6);
+        assert_eq!(report.win_reason, None);
+
+        // Both players think the game is even.
+        let report = play_game_with(game(), Evaluating(5), Evaluating(-10), &settings, &mut []);
+        assert_eq!(report.draw_reason, Some(DrawReason::Adjudication));
+        assert_eq!(report.moves.len(), 4);
+
+        // Only one of them does.
+        let report = play_game_with(game(), Evaluating(5), Evaluating(100), &settings, &mut []);
+        assert_ne!(report.draw_reason, Some(DrawReason::Adjudication));
+        assert!(!report.resigned);
+
+        assert_eq!("300/3".parse::<Adjudication>()?.to_string(), "300/3");
+        assert!("300".parse::<Adjudication>().is_err());
+        assert!("300/0".parse::<Adjudication>().is_err());
+        Ok(())
+    }
+
+    /// Plays random moves and counts the opponent's ones.
+    struct Hooked {
+        name: &'static str,
+        opponent_moves: Rc<Cell<usize>>,
+        result: Rc<Cell<Option<GameResult>>>,
+    }
+
+    impl Strategy for Hooked {
+        fn get_move(&mut self, game: &Game) -> GameMove<Coord> {
+            game.random_move()
+        }
+
+        fn name(&self) -> String {
+            self.name.to_string()
+        }
+
+        fn on_opponent_move(&mut self, _mov: &GameMove<Coord>) {
+            self.opponent_moves.set(self.opponent_moves.get() + 1);
+        }
+
+        fn on_game_over(&mut self, result: &GameResult) {
+            self.result.set(Some(*result));
+        }
+    }
+
+    #[test]
+    fn test_strategy_hooks() {
+        let game = Game::new(Layout::Bricks7, Deck::ordered("gggjjjj").unwrap(), Rules::default());
+        let settings = PlaySettings {
+            max_plies: Some(9),
+            ..Default::default()
+        };
+        let hooked = |name| Hooked {
+            name,
+            opponent_moves: Rc::new(Cell::new(0)),
+            result: Rc::new(Cell::new(None)),
+        };
+        let (alice, bob) = (hooked("Alice"), hooked("Bob"));
+        let (seen_by_alice, seen_by_bob) = (alice.opponent_moves.clone(), bob.opponent_moves.clone());
+        let (result1, result2) = (alice.result.clone(), bob.result.clone());
+
+        let report = play_game_with(game, alice, Box::new(bob), &settings, &mut []);
+        assert_eq!((report.player1.as_str(), report.player2.as_str()), ("Alice", "Bob"));
+        let plies = report.moves.len();
+        assert_eq!(seen_by_alice.get(), plies / 2);
+        assert_eq!(seen_by_bob.get(), plies - plies / 2);
+        assert_eq!(result1.get(), Some(report.result));
+        assert_eq!(result2.get(), Some(report.result));
+        assert_eq!(RandomAI.name(), "Random AI");
+    }
+
+    #[test]
+    fn test_strategy_list() {
+        let mut players: Vec<Box<dyn Strategy>> = vec![Box::new(RandomAI), Box::new(Evaluating(0)), Box::new(SlowAI)];
+        let settings = PlaySettings {
+            max_plies: Some(4),
+            ..Default::default()
+        };
+
+        // Every player plays every other one.
+        let mut games = 0;
+        for i in 0..players.len() {
+            for j in i + 1..players.len() {
+                let (left, right) = players.split_at_mut(j);
+                let game = Game::new(Layout::Bricks7, Deck::seven_shuffled(), Rules::default());
+                let report = play_game_with(game, &mut left[i], &mut right[0], &settings, &mut []);
+                assert!(report.moves.len() <= 4);
+                games += 1;
+            }
+        }
+        assert_eq!(games, 3);
+        assert_eq!(players[0].name(), "Random AI");
+    }
+
+    struct SlowAI;
+
+    impl Strategy for SlowAI {
+        fn get_move(&mut self, game: &Game) -> GameMove<Coord> {
+            std::thread::sleep(Duration::from_millis(5));
+            game.random_move()
+        }
+    }
+
+    #[test]
+    fn test_time_forfeit() {
+        let deck = Deck::ordered("gggjjjj").unwrap();
+        let game = Game::new(Layout::Bricks7, deck, Rules::new(true, true));
+        let settings = PlaySettings {
+            max_plies: Some(10),
+            time_control: Some(TimeControl::new(Duration::from_secs(10), Duration::from_secs(0))),
+            ..Default::default()
+        };
+
+        let report = play_game_with(game.clone(), SlowAI, SlowAI, &settings, &mut []);
+        assert!(!report.time_forfeit);
+
+        let settings = PlaySettings {
+            time_control: Some(TimeControl::new(Duration::from_millis(1), Duration::from_secs(0))),
+            ..Default::default()
+        };
+
+        let report = play_game_with(game, SlowAI, SlowAI, &settings, &mut []);
+        assert!(report.time_forfeit);
+        assert_eq!(report.result, GameResult::SecondPlayerWon);
+        assert_eq!(report.moves.len(), 1);
+    }
+
+    /// Follows the script of decisions and then plays the first legal
+    /// move.
+    struct Scripted(Vec<Decision>);
+
+    impl Strategy for Scripted {
+        fn get_move(&mut self, game: &Game) -> GameMove<Coord> {
+            game.generate_moves()[0].clone()
+        }
+
+        fn decide(&mut self, game: &Game, _clock: Option<&Clock>) -> Decision {
+            if self.0.is_empty() {
+                Decision::Play(self.get_move(game))
+            } else {
+                self.0.remove(0)
+            }
+        }
+    }
+
+    #[test]
+    fn test_take_back() {
+        let deck = Deck::ordered("gggjjjj").unwrap();
+        let game = Game::new(Layout::Bricks7, deck, Rules::new(true, true));
+        let settings = PlaySettings {
+            max_plies: Some(6),
+            ..Default::default()
+        };
+        let expected = play_game_with(game.clone(), Scripted(vec![]), Scripted(vec![]), &settings, &mut []);
+
+        // The first player takes back both moves after the first one
+        // and then replays them (impossible requests are repeated).
+        let m = game.generate_moves()[0].clone();
+        let script = vec![
+            Decision::Play(m),
+            Decision::TakeBack(3),
+            Decision::TakeBack(2),
+            Decision::Replay(3),
+            Decision::Replay(2),
+        ];
+        let mut counter = MoveCounter::default();
+        let report = play_game_with(
+            game.clone(),
+            Scripted(script),
+            Scripted(vec![]),
+            &settings,
+            &mut [&mut counter],
+        );
+        assert_eq!(report.moves, expected.moves);
+        assert_eq!(report.per_move_times.len(), report.moves.len());
+        assert_eq!(counter.moves, report.moves.len() + 2);
+        assert_eq!(
+            counter.rejected,
+            vec!["Can't take back 3 moves", "Can't replay 3 moves"]
+        );
+
+        // Continuing the same game loaded after three moves (loaded
+        // moves can be taken back too).
+        let script = vec![
+            Decision::Load {
+                start: Box::new(game.clone()),
+                moves: expected.moves[..3].to_vec(),
+            },
+            Decision::TakeBack(1),
+        ];
+        let report = play_game_with(game, Scripted(vec![]), Scripted(script), &settings, &mut []);
+        assert_eq!(report.moves, expected.moves);
+        assert_eq!(report.per_move_times.len(), report.moves.len());
     }
 }
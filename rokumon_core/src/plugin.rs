@@ -0,0 +1,220 @@
+//! Strategies loaded from WebAssembly modules (with the `plugins`
+//! feature), so that bots written in any language can play against
+//! the built-in ones. Modules are run in a sandbox: they can't import
+//! anything (so they can't reach files, network or clock) and the
+//! number of instructions they run per move is limited.
+//!
+//! Interface of the modules (pointers and lengths are in bytes of the
+//! module's own memory, pairs of them are returned packed in an `i64`
+//! as `ptr << 32 | len`):
+//!
+//! - `memory`: the exported memory,
+//! - `alloc(len: i32) -> i32`: returns a buffer of `len` bytes for the
+//!   host to write the game to,
+//! - `choose_move(ptr: i32, len: i32) -> i64`: gets the game in the
+//!   buffer as JSON (as saved by `schema::to_json`, i.e. `{"version":
+//!   ..., "game": ...}`) and returns the move as JSON, like
+//!   `{"Place":[{"color":"Red","value":2},{"x":0,"y":0,"z":0}]}` or
+//!   `"Submit"`,
+//! - `name() -> i64` (optional): the name of the bot in UTF-8.
+
+use failure::{bail, format_err, Fallible};
+use std::convert::TryFrom;
+use std::fs;
+use std::path::Path;
+use wasmi::{Config, Engine, Instance, Linker, Memory, Module, Store, TypedFunc};
+
+use crate::coord::Coord;
+use crate::game::{Game, GameMove};
+use crate::play::Strategy;
+use crate::schema;
+
+type Move = GameMove<Coord>;
+
+/// Default number of instructions a module can run to choose a move
+/// (a few seconds of interpretation).
+const FUEL_PER_MOVE: u64 = 1_000_000_000;
+
+pub struct WasmStrategy {
+    name: String,
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    choose: TypedFunc<(i32, i32), i64>,
+    fuel: u64,
+}
+
+impl WasmStrategy {
+    pub fn new(name: &str, wasm: &[u8]) -> Fallible<Self> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(|err| format_err!("Can't load plugin {}: {}", name, err))?;
+        let mut store = Store::new(&engine, ());
+        store.set_fuel(FUEL_PER_MOVE).map_err(|err| format_err!("{}", err))?;
+
+        // No host functions: modules which import anything can't be
+        // instantiated.
+        let instance = Linker::new(&engine)
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(|err| format_err!("Can't instantiate plugin {}: {}", name, err))?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| format_err!("Plugin {} doesn't export its memory", name))?;
+        let export = |func: &str| format_err!("Plugin {} doesn't export {}", name, func);
+        let alloc = instance
+            .get_typed_func(&store, "alloc")
+            .map_err(|_| export("alloc(i32) -> i32"))?;
+        let choose = instance
+            .get_typed_func(&store, "choose_move")
+            .map_err(|_| export("choose_move(i32, i32) -> i64"))?;
+
+        let mut strategy = WasmStrategy {
+            name: name.to_string(),
+            store,
+            memory,
+            alloc,
+            choose,
+            fuel: FUEL_PER_MOVE,
+        };
+        if let Some(name) = strategy.exported_name(&instance)? {
+            strategy.name = name;
+        }
+        Ok(strategy)
+    }
+
+    /// Loads the module from a file, named after the file unless the
+    /// module has a name.
+    pub fn from_file(path: &str) -> Fallible<Self> {
+        let name = Path::new(path)
+            .file_stem()
+            .map_or_else(|| path.to_string(), |stem| stem.to_string_lossy().into_owned());
+        Self::new(&name, &fs::read(path)?)
+    }
+
+    /// Sets the number of instructions the module can run per move.
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = fuel;
+    }
+
+    fn exported_name(&mut self, instance: &Instance) -> Fallible<Option<String>> {
+        let name = match instance.get_typed_func::<(), i64>(&self.store, "name") {
+            Ok(name) => name,
+            Err(_) => return Ok(None),
+        };
+        let packed = name
+            .call(&mut self.store, ())
+            .map_err(|err| format_err!("name failed: {}", err))?;
+        Ok(Some(String::from_utf8(self.read(packed)?)?))
+    }
+
+    fn choose_move(&mut self, game: &Game) -> Fallible<Move> {
+        self.store.set_fuel(self.fuel).map_err(|err| format_err!("{}", err))?;
+
+        let input = schema::to_json(game)?;
+        let len = i32::try_from(input.len())?;
+        let ptr = self
+            .alloc
+            .call(&mut self.store, len)
+            .map_err(|err| format_err!("alloc failed: {}", err))?;
+        self.memory
+            .write(&mut self.store, ptr as u32 as usize, input.as_bytes())
+            .map_err(|err| format_err!("alloc returned a bad buffer: {}", err))?;
+        let packed = self
+            .choose
+            .call(&mut self.store, (ptr, len))
+            .map_err(|err| format_err!("choose_move failed: {}", err))?;
+
+        let output = self.read(packed)?;
+        let mov: Move = serde_json::from_slice(&output)
+            .map_err(|err| format_err!("choose_move returned something else than a move: {}", err))?;
+        if !game.generate_moves().contains(&mov) {
+            bail!("choose_move returned an illegal move: {}", mov);
+        }
+        Ok(mov)
+    }
+
+    /// Reads the bytes at the packed pointer and length.
+    fn read(&self, packed: i64) -> Fallible<Vec<u8>> {
+        let (ptr, len) = ((packed as u64 >> 32) as usize, packed as u32 as usize);
+        let mut bytes = vec![0; len];
+        self.memory
+            .read(&self.store, ptr, &mut bytes)
+            .map_err(|err| format_err!("Plugin returned a bad buffer: {}", err))?;
+        Ok(bytes)
+    }
+}
+
+impl Strategy for WasmStrategy {
+    fn get_move(&mut self, game: &Game) -> Move {
+        self.choose_move(game).unwrap_or_else(|err| {
+            // Don't let a broken plugin stop the game.
+            println!("[ERR] Plugin {}: {}, playing a random move", self.name, err);
+            game.random_move()
+        })
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::board::Layout;
+    use crate::card::Deck;
+    use crate::game::Rules;
+
+    fn plugin(wat: &str) -> Fallible<WasmStrategy> {
+        WasmStrategy::new("test", &wat::parse_str(wat)?)
+    }
+
+    /// Module returning `result` from `choose_move`, given the input at
+    /// `$ptr` with its length `$len` and `mov` (at 64) with its length.
+    fn returning(result: &str, mov: &Move) -> Fallible<String> {
+        let mov = serde_json::to_string(mov)?;
+        Ok(format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (data (i32.const 0) "Player")
+                (data (i32.const 64) "{}")
+                (func (export "alloc") (param i32) (result i32) i32.const 1024)
+                (func (export "name") (result i64) i64.const 6)
+                (func (export "choose_move") (param $ptr i32) (param $len i32) (result i64) {}))"#,
+            mov.replace('"', "\\\""),
+            result.replace("$mov", &format!("i64.const {}", (64 << 32) + mov.len()))
+        ))
+    }
+
+    #[test]
+    fn test_plugins() -> Fallible<()> {
+        let game = Game::new(Layout::Bricks7, Deck::ordered("jggjgjj")?, Rules::default());
+        let first = game.generate_moves()[0].clone();
+
+        let mut player = plugin(&returning("$mov", &first)?)?;
+        assert_eq!(player.name(), "Player");
+        assert_eq!(player.choose_move(&game)?, first);
+
+        let mut submitter = plugin(&returning("$mov", &GameMove::Submit)?)?;
+        let err = submitter.choose_move(&game).unwrap_err().to_string();
+        assert!(err.contains("illegal move"), "{}", err);
+
+        // Echoes the game back, which is not a move.
+        let echo = "local.get $ptr i64.extend_i32_u i64.const 32 i64.shl local.get $len i64.extend_i32_u i64.or";
+        let mut echo = plugin(&returning(echo, &first)?)?;
+        let err = echo.choose_move(&game).unwrap_err().to_string();
+        assert!(err.contains("something else than a move"), "{}", err);
+        assert!(game.generate_moves().contains(&echo.get_move(&game)));
+
+        let mut endless = plugin(&returning("(loop br 0) $mov", &first)?)?;
+        endless.set_fuel(10_000);
+        assert!(endless.choose_move(&game).is_err());
+
+        let importing = r#"(module (import "env" "now" (func)) (memory (export "memory") 1))"#;
+        assert!(plugin(importing).is_err());
+        assert!(plugin("(module)").is_err());
+        Ok(())
+    }
+}
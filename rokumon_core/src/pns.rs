@@ -0,0 +1,334 @@
+//! Proof-number search: proves the game-theoretic value of a position
+//! (win, loss or draw for the player to move).
+//!
+//! Positions repeating one of their ancestors are treated as not
+//! winning for the player trying to prove the win, so a "draw" means
+//! that neither player can force a win without letting the game go on
+//! forever.
+
+use crate::coord::Coord;
+use crate::game::{Game, GameMove, GameResult};
+
+use std::collections::HashSet;
+use std::fmt;
+
+const INFINITY: u32 = u32::MAX;
+
+/// Game-theoretic value of a position for the player to move.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Value {
+    Win,
+    Loss,
+    Draw,
+}
+
+/// Moves which force a win: one move for the winning player and all
+/// the possible replies for their opponent. Positions which have been
+/// proven elsewhere in the tree are not expanded again.
+#[derive(Debug, Clone, Default)]
+pub struct ProofTree {
+    pub moves: Vec<(GameMove<Coord>, ProofTree)>,
+}
+
+impl ProofTree {
+    /// Number of positions in the tree.
+    pub fn size(&self) -> usize {
+        1 + self.moves.iter().map(|(_, tree)| tree.size()).sum::<usize>()
+    }
+
+    /// Length of the longest line in the tree (in plies).
+    pub fn depth(&self) -> usize {
+        self.moves.iter().map(|(_, tree)| tree.depth() + 1).max().unwrap_or(0)
+    }
+
+    /// Prints the tree up to `max_depth` plies, one move per line.
+    pub fn pretty_print(&self, game: &Game, max_depth: usize) -> String {
+        let mut out = String::new();
+        self.print_to(&mut out, game, 0, max_depth);
+        out
+    }
+
+    fn print_to(&self, out: &mut String, game: &Game, depth: usize, max_depth: usize) {
+        if depth == max_depth {
+            if !self.moves.is_empty() {
+                out.push_str(&format!("{}...\n", "  ".repeat(depth)));
+            }
+            return;
+        }
+        for (m, tree) in &self.moves {
+            out.push_str(&format!("{}{}\n", "  ".repeat(depth), game.userify_move(m)));
+            let mut next = game.clone();
+            next.apply_move_unchecked(m);
+            tree.print_to(out, &next, depth + 1, max_depth);
+        }
+    }
+}
+
+/// Result of `solve`.
+#[derive(Debug, Clone)]
+pub struct Solution {
+    /// None if the search has run out of nodes.
+    pub value: Option<Value>,
+    /// Proof of the win (for the winning player), if any.
+    pub proof: Option<ProofTree>,
+    /// Number of nodes created in all the searches.
+    pub nodes: usize,
+}
+
+// value: win, nodes: 12345, proof: 67 positions, 9 plies deep
+impl fmt::Display for Solution {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value {
+            Some(Value::Win) => write!(f, "value: win")?,
+            Some(Value::Loss) => write!(f, "value: loss")?,
+            Some(Value::Draw) => write!(f, "value: draw")?,
+            None => write!(f, "value: unknown")?,
+        }
+        write!(f, ", nodes: {}", self.nodes)?;
+        if let Some(proof) = &self.proof {
+            write!(f, ", proof: {} positions, {} plies deep", proof.size(), proof.depth())?;
+        }
+        Ok(())
+    }
+}
+
+/// Solves the position creating at most `max_nodes` nodes in each of
+/// the searches: first we try to prove that the player to move wins
+/// and then that their opponent does.
+pub fn solve(game: &Game, max_nodes: usize) -> Solution {
+    let mut search = Search::new(game, game.player1_moves);
+    let first = search.run(max_nodes);
+    let mut nodes = search.nodes.len();
+
+    let (value, proof) = match first {
+        Some(true) => (Some(Value::Win), Some(search.proof_tree(0))),
+        None => (None, None),
+        Some(false) => {
+            let mut search = Search::new(game, !game.player1_moves);
+            let second = search.run(max_nodes);
+            nodes += search.nodes.len();
+            match second {
+                Some(true) => (Some(Value::Loss), Some(search.proof_tree(0))),
+                Some(false) => (Some(Value::Draw), None),
+                None => (None, None),
+            }
+        }
+    };
+
+    Solution { value, proof, nodes }
+}
+
+struct Node {
+    /// Move leading to this node (None for the root).
+    game_move: Option<GameMove<Coord>>,
+    /// Proof and disproof numbers: how many leaves should be proven
+    /// (or disproven) to prove (disprove) this node.
+    pn: u32,
+    dn: u32,
+    /// Whether it's the attacker to move.
+    or_node: bool,
+    /// Children are stored contiguously.
+    first_child: usize,
+    num_children: usize,
+}
+
+impl Node {
+    fn is_solved(&self) -> bool {
+        self.pn == 0 || self.dn == 0
+    }
+}
+
+/// Search proving that `attacker` wins.
+struct Search {
+    root: Game,
+    attacker: bool,
+    nodes: Vec<Node>,
+    /// Positions known to be won by the attacker. Wins never depend
+    /// on repetitions, so they don't depend on the path to the
+    /// position either.
+    proven: HashSet<u64>,
+}
+
+impl Search {
+    fn new(root: &Game, attacker: bool) -> Self {
+        let mut search = Search {
+            root: root.clone(),
+            attacker,
+            nodes: vec![],
+            proven: HashSet::new(),
+        };
+        let (pn, dn) = search.initial_numbers(root, &HashSet::new());
+        search.nodes.push(Node {
+            game_move: None,
+            pn,
+            dn,
+            or_node: root.player1_moves == attacker,
+            first_child: 0,
+            num_children: 0,
+        });
+        search
+    }
+
+    /// Returns whether the attacker wins or None if the search has
+    /// run out of nodes.
+    fn run(&mut self, max_nodes: usize) -> Option<bool> {
+        while !self.nodes[0].is_solved() {
+            if self.nodes.len() >= max_nodes {
+                return None;
+            }
+
+            // Descend to the most proving node.
+            let mut game = self.root.clone();
+            let mut path = vec![(0, game.position_hash())];
+            let mut ancestors = HashSet::new();
+            ancestors.insert(path[0].1);
+            let mut n = 0;
+            while self.nodes[n].num_children > 0 {
+                let children = self.children(n);
+                n = if self.nodes[n].or_node {
+                    children.min_by_key(|&c| self.nodes[c].pn).unwrap()
+                } else {
+                    children.min_by_key(|&c| self.nodes[c].dn).unwrap()
+                };
+                game.apply_move_unchecked(self.nodes[n].game_move.as_ref().unwrap());
+                let key = game.position_hash();
+                ancestors.insert(key);
+                path.push((n, key));
+            }
+
+            self.expand(n, &mut game, &ancestors);
+
+            // Update the ancestors.
+            for &(n, key) in path.iter().rev() {
+                self.update(n);
+                if self.nodes[n].pn == 0 {
+                    self.proven.insert(key);
+                }
+            }
+        }
+        Some(self.nodes[0].pn == 0)
+    }
+
+    fn children(&self, n: usize) -> std::ops::Range<usize> {
+        let node = &self.nodes[n];
+        node.first_child..node.first_child + node.num_children
+    }
+
+    fn initial_numbers(&self, game: &Game, ancestors: &HashSet<u64>) -> (u32, u32) {
+        let attacker_won = if self.attacker {
+            GameResult::FirstPlayerWon
+        } else {
+            GameResult::SecondPlayerWon
+        };
+        let key = game.position_hash();
+        if game.result == attacker_won || self.proven.contains(&key) {
+            (0, INFINITY)
+        } else if game.is_game_over() || ancestors.contains(&key) {
+            (INFINITY, 0)
+        } else {
+            (1, 1)
+        }
+    }
+
+    fn expand(&mut self, n: usize, game: &mut Game, ancestors: &HashSet<u64>) {
+        let moves = game.generate_moves();
+        if moves.is_empty() {
+            // No moves: the player to move has lost.
+            let lost = self.nodes[n].or_node;
+            let node = &mut self.nodes[n];
+            node.pn = if lost { INFINITY } else { 0 };
+            node.dn = if lost { 0 } else { INFINITY };
+            return;
+        }
+
+        let first_child = self.nodes.len();
+        for m in moves {
+            let fight_result = game.apply_move_unchecked(&m);
+            let (pn, dn) = self.initial_numbers(game, ancestors);
+            let or_node = game.player1_moves == self.attacker;
+            game.undo_move(&m, fight_result);
+            self.nodes.push(Node {
+                game_move: Some(m),
+                pn,
+                dn,
+                or_node,
+                first_child: 0,
+                num_children: 0,
+            });
+        }
+        self.nodes[n].first_child = first_child;
+        self.nodes[n].num_children = self.nodes.len() - first_child;
+    }
+
+    fn update(&mut self, n: usize) {
+        if self.nodes[n].num_children == 0 {
+            return;
+        }
+        let or_node = self.nodes[n].or_node;
+        let children = self.children(n);
+        let min_pn = children.clone().map(|c| self.nodes[c].pn).min().unwrap();
+        let min_dn = children.clone().map(|c| self.nodes[c].dn).min().unwrap();
+        let sum_pn = children.clone().fold(0u32, |s, c| s.saturating_add(self.nodes[c].pn));
+        let sum_dn = children.fold(0u32, |s, c| s.saturating_add(self.nodes[c].dn));
+        let (pn, dn) = if or_node { (min_pn, sum_dn) } else { (sum_pn, min_dn) };
+        self.nodes[n].pn = pn;
+        self.nodes[n].dn = dn;
+    }
+
+    /// Extracts the proof tree of a proven node.
+    fn proof_tree(&self, n: usize) -> ProofTree {
+        let proven: Vec<usize> = self.children(n).filter(|&c| self.nodes[c].pn == 0).collect();
+        // One winning move of the attacker is enough, but all the
+        // replies of the defender are needed.
+        let moves = if self.nodes[n].or_node {
+            &proven[..proven.len().min(1)]
+        } else {
+            &proven[..]
+        };
+
+        ProofTree {
+            moves: moves
+                .iter()
+                .map(|&c| (self.nodes[c].game_move.clone().unwrap(), self.proof_tree(c)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::board::{Grid, Layout};
+    use crate::card::Deck;
+    use crate::game::Rules;
+    use crate::tablebase::{Outcome, Tablebase};
+    use failure::Fallible;
+
+    #[test]
+    fn test_solve() -> Fallible<()> {
+        // Three cards in a row, small enough to be solved exactly by
+        // the tablebase as well.
+        let coords = (0..3).map(|x| Coord::new_square(x, 0)).collect();
+        let layout = Layout::Custom(Grid::Square, coords);
+        let game = Game::new(layout, Deck::ordered("JGG")?, Rules::new(false, false));
+
+        let solution = solve(&game, 100_000);
+        let tb = Tablebase::generate(&game, 1000)?;
+        match tb.probe(&game).unwrap() {
+            Outcome::Win(_) => assert_eq!(solution.value, Some(Value::Win)),
+            Outcome::Loss(_) => assert_eq!(solution.value, Some(Value::Loss)),
+            Outcome::Draw => assert_eq!(solution.value, Some(Value::Draw)),
+        }
+
+        // The proof starts with a single winning move.
+        let proof = solution.proof.unwrap();
+        assert_eq!(proof.moves.len(), 1);
+        assert!(proof.depth() > 1);
+        assert!(!proof.pretty_print(&game, 2).is_empty());
+
+        // Not enough nodes.
+        assert_eq!(solve(&game, 3).value, None);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,752 @@
+//! Protocol Buffers encoding of games for the clients which are not
+//! written in Rust. The messages below are the ones described by
+//! `proto/rokumon.proto` (written by hand to not require `protoc` for
+//! building, so the two have to be kept in sync). Any protobuf library
+//! can generate the code for other languages from the `.proto` file.
+
+use failure::{bail, format_err, Fallible};
+use prost::Message;
+use std::convert::TryFrom;
+use std::time;
+
+use crate::{board, card, coord, game};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum DiceColor {
+    Red = 0,
+    Black = 1,
+    White = 2,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Die {
+    #[prost(enumeration = "DiceColor", tag = "1")]
+    pub color: i32,
+    #[prost(uint32, tag = "2")]
+    pub value: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum CardKind {
+    Jade = 0,
+    Gold = 1,
+    Fort = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Ability {
+    None = 0,
+    Peaceful = 1,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Coord {
+    #[prost(sint32, tag = "1")]
+    pub x: i32,
+    #[prost(sint32, tag = "2")]
+    pub y: i32,
+    #[prost(sint32, tag = "3")]
+    pub z: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Card {
+    #[prost(message, optional, tag = "1")]
+    pub coord: Option<Coord>,
+    #[prost(enumeration = "CardKind", tag = "2")]
+    pub kind: i32,
+    #[prost(message, repeated, tag = "3")]
+    pub dice: Vec<Die>,
+    #[prost(enumeration = "Ability", tag = "4")]
+    pub ability: i32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Grid {
+    Hex = 0,
+    Square = 1,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum LayoutKind {
+    Rectangle6 = 0,
+    Bricks7 = 1,
+    Hex7 = 2,
+    Custom = 3,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Layout {
+    #[prost(enumeration = "LayoutKind", tag = "1")]
+    pub kind: i32,
+    /// Only for custom layouts.
+    #[prost(enumeration = "Grid", tag = "2")]
+    pub grid: i32,
+    /// Only for custom layouts.
+    #[prost(message, repeated, tag = "3")]
+    pub coords: Vec<Coord>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Rules {
+    #[prost(bool, tag = "1")]
+    pub enable_fight_move: bool,
+    #[prost(bool, tag = "2")]
+    pub enable_surprise_move: bool,
+    #[prost(bool, tag = "3")]
+    pub player2_starts: bool,
+    #[prost(message, optional, tag = "4")]
+    pub handicap: Option<Handicap>,
+    /// Only for the colors with other values than 1 to 6.
+    #[prost(message, repeated, tag = "5")]
+    pub die_values: Vec<DieValues>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DieValues {
+    #[prost(enumeration = "DiceColor", tag = "1")]
+    pub color: i32,
+    #[prost(uint32, tag = "2")]
+    pub min: u32,
+    #[prost(uint32, tag = "3")]
+    pub max: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Handicap {
+    #[prost(message, optional, tag = "1")]
+    pub extra_die: Option<Die>,
+    #[prost(message, optional, tag = "2")]
+    pub removed_die: Option<Die>,
+    /// Player with the setup phase: 1 or 2 (0 if none).
+    #[prost(uint32, tag = "3")]
+    pub first_placements: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Board {
+    #[prost(enumeration = "Grid", tag = "1")]
+    pub grid: i32,
+    #[prost(message, optional, tag = "2")]
+    pub layout: Option<Layout>,
+    #[prost(message, repeated, tag = "3")]
+    pub cards: Vec<Card>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GameMove {
+    #[prost(oneof = "game_move::Kind", tags = "1, 2, 3, 4, 5")]
+    pub kind: Option<game_move::Kind>,
+}
+
+pub mod game_move {
+    use super::{Coord, Die};
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Place {
+        #[prost(message, optional, tag = "1")]
+        pub die: Option<Die>,
+        #[prost(message, optional, tag = "2")]
+        pub to: Option<Coord>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Move {
+        #[prost(message, optional, tag = "1")]
+        pub die: Option<Die>,
+        #[prost(message, optional, tag = "2")]
+        pub from: Option<Coord>,
+        #[prost(message, optional, tag = "3")]
+        pub to: Option<Coord>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Fight {
+        #[prost(message, optional, tag = "1")]
+        pub at: Option<Coord>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Surprise {
+        #[prost(message, optional, tag = "1")]
+        pub from: Option<Coord>,
+        #[prost(message, optional, tag = "2")]
+        pub to: Option<Coord>,
+    }
+
+    #[derive(Clone, PartialEq, prost::Message)]
+    pub struct Submit {}
+
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Kind {
+        #[prost(message, tag = "1")]
+        Place(Place),
+        #[prost(message, tag = "2")]
+        Move(Move),
+        #[prost(message, tag = "3")]
+        Fight(Fight),
+        #[prost(message, tag = "4")]
+        Surprise(Surprise),
+        #[prost(message, tag = "5")]
+        Submit(Submit),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum ZIndex {
+    Top = 0,
+    Bottom = 1,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct FightResult {
+    #[prost(message, optional, tag = "1")]
+    pub losing_die: Option<Die>,
+    #[prost(enumeration = "ZIndex", tag = "2")]
+    pub losing_position: i32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Duration {
+    #[prost(uint64, tag = "1")]
+    pub seconds: u64,
+    #[prost(uint32, tag = "2")]
+    pub nanos: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct HistoryEntry {
+    #[prost(message, optional, tag = "1")]
+    pub game_move: Option<GameMove>,
+    #[prost(message, optional, tag = "2")]
+    pub fight_result: Option<FightResult>,
+    #[prost(message, optional, tag = "3")]
+    pub elapsed: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum GameResult {
+    InProgress = 0,
+    FirstPlayerWon = 1,
+    SecondPlayerWon = 2,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Player {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(message, repeated, tag = "2")]
+    pub dice: Vec<Die>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Game {
+    #[prost(message, optional, tag = "1")]
+    pub board: Option<Board>,
+    #[prost(message, optional, tag = "2")]
+    pub rules: Option<Rules>,
+    #[prost(message, optional, tag = "3")]
+    pub player1: Option<Player>,
+    #[prost(message, optional, tag = "4")]
+    pub player2: Option<Player>,
+    #[prost(bool, tag = "5")]
+    pub player1_moves: bool,
+    #[prost(uint32, tag = "6")]
+    pub player1_surprises: u32,
+    #[prost(uint32, tag = "7")]
+    pub player2_surprises: u32,
+    #[prost(enumeration = "GameResult", tag = "8")]
+    pub result: i32,
+    #[prost(message, repeated, tag = "9")]
+    pub history: Vec<HistoryEntry>,
+}
+
+impl game::Game {
+    pub fn to_proto(&self) -> Vec<u8> {
+        Game::from(self).encode_to_vec()
+    }
+
+    pub fn from_proto(bytes: &[u8]) -> Fallible<Self> {
+        game::Game::try_from(Game::decode(bytes)?)
+    }
+}
+
+impl game::GameMove<coord::Coord> {
+    pub fn to_proto(&self) -> Vec<u8> {
+        GameMove::from(self).encode_to_vec()
+    }
+
+    pub fn from_proto(bytes: &[u8]) -> Fallible<Self> {
+        game::GameMove::try_from(GameMove::decode(bytes)?)
+    }
+}
+
+impl game::Rules {
+    pub fn to_proto(&self) -> Vec<u8> {
+        Rules::from(self).encode_to_vec()
+    }
+
+    pub fn from_proto(bytes: &[u8]) -> Fallible<Self> {
+        game::Rules::try_from(Rules::decode(bytes)?)
+    }
+}
+
+/// Field which has to be set in the message.
+fn required<T>(field: Option<T>, name: &str) -> Fallible<T> {
+    field.ok_or_else(|| format_err!("Missing {}", name))
+}
+
+fn enumeration<E: TryFrom<i32>>(value: i32, name: &str) -> Fallible<E> {
+    E::try_from(value).map_err(|_| format_err!("Unknown {}: {}", name, value))
+}
+
+fn dice_color(color: card::DiceColor) -> DiceColor {
+    match color {
+        card::DiceColor::Red => DiceColor::Red,
+        card::DiceColor::Black => DiceColor::Black,
+        card::DiceColor::White => DiceColor::White,
+    }
+}
+
+fn from_dice_color(color: i32) -> Fallible<card::DiceColor> {
+    Ok(match enumeration(color, "dice color")? {
+        DiceColor::Red => card::DiceColor::Red,
+        DiceColor::Black => card::DiceColor::Black,
+        DiceColor::White => card::DiceColor::White,
+    })
+}
+
+impl From<&card::Die> for Die {
+    fn from(die: &card::Die) -> Self {
+        Die {
+            color: dice_color(die.color) as i32,
+            value: u32::from(die.value),
+        }
+    }
+}
+
+impl TryFrom<Die> for card::Die {
+    type Error = failure::Error;
+
+    fn try_from(die: Die) -> Fallible<Self> {
+        let color = from_dice_color(die.color)?;
+        // Values beyond the usual 1 to 6 are checked against the rules
+        // of the game.
+        match u8::try_from(die.value) {
+            Ok(value) if value > 0 => Ok(card::Die::new(color, value)),
+            _ => bail!("Invalid die value: {}", die.value),
+        }
+    }
+}
+
+impl From<&coord::Coord> for Coord {
+    fn from(c: &coord::Coord) -> Self {
+        Coord {
+            x: i32::from(c.x),
+            y: i32::from(c.y),
+            z: i32::from(c.z),
+        }
+    }
+}
+
+impl TryFrom<Coord> for coord::Coord {
+    type Error = failure::Error;
+
+    fn try_from(c: Coord) -> Fallible<Self> {
+        let component = |v: i32| i8::try_from(v).map_err(|_| format_err!("Coordinate out of range: {}", v));
+        Ok(coord::Coord {
+            x: component(c.x)?,
+            y: component(c.y)?,
+            z: component(c.z)?,
+        })
+    }
+}
+
+fn coord(c: Option<Coord>) -> Fallible<coord::Coord> {
+    coord::Coord::try_from(required(c, "coordinate")?)
+}
+
+fn die(d: Option<Die>) -> Fallible<card::Die> {
+    card::Die::try_from(required(d, "die")?)
+}
+
+fn grid(grid: board::Grid) -> Grid {
+    match grid {
+        board::Grid::Hex => Grid::Hex,
+        board::Grid::Square => Grid::Square,
+    }
+}
+
+fn from_grid(value: i32) -> Fallible<board::Grid> {
+    Ok(match enumeration(value, "grid")? {
+        Grid::Hex => board::Grid::Hex,
+        Grid::Square => board::Grid::Square,
+    })
+}
+
+impl From<&board::Layout> for Layout {
+    fn from(layout: &board::Layout) -> Self {
+        let (kind, custom) = match layout {
+            board::Layout::Rectangle6 => (LayoutKind::Rectangle6, None),
+            board::Layout::Bricks7 => (LayoutKind::Bricks7, None),
+            board::Layout::Hex7 => (LayoutKind::Hex7, None),
+            board::Layout::Custom(g, coords) => (LayoutKind::Custom, Some((g, coords))),
+        };
+        Layout {
+            kind: kind as i32,
+            grid: custom.map_or(Grid::Hex, |(&g, _)| grid(g)) as i32,
+            coords: custom.map_or_else(Vec::new, |(_, coords)| coords.iter().map(Coord::from).collect()),
+        }
+    }
+}
+
+impl TryFrom<Layout> for board::Layout {
+    type Error = failure::Error;
+
+    fn try_from(layout: Layout) -> Fallible<Self> {
+        Ok(match enumeration(layout.kind, "layout")? {
+            LayoutKind::Rectangle6 => board::Layout::Rectangle6,
+            LayoutKind::Bricks7 => board::Layout::Bricks7,
+            LayoutKind::Hex7 => board::Layout::Hex7,
+            LayoutKind::Custom => {
+                let coords = layout
+                    .coords
+                    .into_iter()
+                    .map(coord::Coord::try_from)
+                    .collect::<Fallible<_>>()?;
+                board::Layout::Custom(from_grid(layout.grid)?, coords)
+            }
+        })
+    }
+}
+
+impl From<&game::Rules> for Rules {
+    fn from(rules: &game::Rules) -> Self {
+        Rules {
+            enable_fight_move: rules.fight_enabled(),
+            enable_surprise_move: rules.surprise_enabled(),
+            player2_starts: rules.player2_starts,
+            handicap: (!rules.handicap().is_none()).then(|| Handicap::from(&rules.handicap())),
+            die_values: card::DiceColor::ALL
+                .iter()
+                .map(|&color| (color, rules.die_values(color)))
+                .filter(|(_, values)| *values != card::DieValues::CUBIC)
+                .map(|(color, values)| DieValues {
+                    color: dice_color(color) as i32,
+                    min: u32::from(values.min),
+                    max: u32::from(values.max),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<Rules> for game::Rules {
+    type Error = failure::Error;
+    fn try_from(rules: Rules) -> Fallible<Self> {
+        let handicap = match rules.handicap {
+            Some(handicap) => game::Handicap::try_from(handicap)?,
+            None => game::Handicap::default(),
+        };
+        let mut result = game::Rules::new(rules.enable_fight_move, rules.enable_surprise_move)
+            .with_player1_starting(!rules.player2_starts);
+        // Before the handicap, since the stocks depend on them.
+        for values in rules.die_values {
+            let bound = |b: u32| u8::try_from(b).map_err(|_| format_err!("Invalid die value: {}", b));
+            let range = card::DieValues::new(bound(values.min)?, bound(values.max)?)?;
+            result = result.with_die_values(from_dice_color(values.color)?, range);
+        }
+        result.with_handicap(handicap)
+    }
+}
+
+impl From<&game::Handicap> for Handicap {
+    fn from(handicap: &game::Handicap) -> Self {
+        Handicap {
+            extra_die: handicap.extra_die.as_ref().map(Die::from),
+            removed_die: handicap.removed_die.as_ref().map(Die::from),
+            first_placements: match handicap.first_placements {
+                None => 0,
+                Some(true) => 1,
+                Some(false) => 2,
+            },
+        }
+    }
+}
+
+impl TryFrom<Handicap> for game::Handicap {
+    type Error = failure::Error;
+    fn try_from(handicap: Handicap) -> Fallible<Self> {
+        Ok(game::Handicap {
+            extra_die: handicap.extra_die.map(card::Die::try_from).transpose()?,
+            removed_die: handicap.removed_die.map(card::Die::try_from).transpose()?,
+            first_placements: match handicap.first_placements {
+                0 => None,
+                1 => Some(true),
+                2 => Some(false),
+                n => bail!("Unexpected player with the setup phase: {}", n),
+            },
+        })
+    }
+}
+
+impl From<&board::Board> for Board {
+    fn from(board: &board::Board) -> Self {
+        Board {
+            grid: grid(board.grid) as i32,
+            layout: Some(Layout::from(&board.layout)),
+            cards: board
+                .coord_cards_iter()
+                .map(|(c, card)| Card {
+                    coord: Some(Coord::from(c)),
+                    kind: match card.kind {
+                        card::CardKind::Jade => CardKind::Jade,
+                        card::CardKind::Gold => CardKind::Gold,
+                        card::CardKind::Fort => CardKind::Fort,
+                    } as i32,
+                    dice: card.dice.iter().map(Die::from).collect(),
+                    ability: match card.ability {
+                        None => Ability::None,
+                        Some(card::Ability::Peaceful) => Ability::Peaceful,
+                    } as i32,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<Board> for board::Board {
+    type Error = failure::Error;
+
+    fn try_from(b: Board) -> Fallible<Self> {
+        let mut cards = board::Cards::default();
+        for card in b.cards {
+            let c = coord(card.coord)?;
+            let kind = match enumeration(card.kind, "card kind")? {
+                CardKind::Jade => card::CardKind::Jade,
+                CardKind::Gold => card::CardKind::Gold,
+                CardKind::Fort => card::CardKind::Fort,
+            };
+            let dice = card
+                .dice
+                .into_iter()
+                .map(card::Die::try_from)
+                .collect::<Fallible<_>>()?;
+            let ability = match enumeration(card.ability, "ability")? {
+                Ability::None => None,
+                Ability::Peaceful => Some(card::Ability::Peaceful),
+            };
+            if cards.insert(c, card::Card { kind, dice, ability }).is_some() {
+                bail!("Two cards at {}", c);
+            }
+        }
+        let layout = board::Layout::try_from(required(b.layout, "layout")?)?;
+        Ok(board::Board::with_cards(from_grid(b.grid)?, layout, cards))
+    }
+}
+
+impl From<&game::GameMove<coord::Coord>> for GameMove {
+    fn from(m: &game::GameMove<coord::Coord>) -> Self {
+        use game_move::*;
+        let kind = match m {
+            game::GameMove::Place(d, to) => Kind::Place(Place {
+                die: Some(d.into()),
+                to: Some(to.into()),
+            }),
+            game::GameMove::Move(d, from, to) => Kind::Move(Move {
+                die: Some(d.into()),
+                from: Some(from.into()),
+                to: Some(to.into()),
+            }),
+            game::GameMove::Fight(at) => Kind::Fight(Fight { at: Some(at.into()) }),
+            game::GameMove::Surprise(from, to) => Kind::Surprise(Surprise {
+                from: Some(from.into()),
+                to: Some(to.into()),
+            }),
+            game::GameMove::Submit => Kind::Submit(Submit {}),
+        };
+        GameMove { kind: Some(kind) }
+    }
+}
+
+impl TryFrom<GameMove> for game::GameMove<coord::Coord> {
+    type Error = failure::Error;
+
+    fn try_from(m: GameMove) -> Fallible<Self> {
+        use game_move::Kind;
+        Ok(match required(m.kind, "kind of move")? {
+            Kind::Place(m) => game::GameMove::Place(die(m.die)?, coord(m.to)?),
+            Kind::Move(m) => game::GameMove::Move(die(m.die)?, coord(m.from)?, coord(m.to)?),
+            Kind::Fight(m) => game::GameMove::Fight(coord(m.at)?),
+            Kind::Surprise(m) => game::GameMove::Surprise(coord(m.from)?, coord(m.to)?),
+            Kind::Submit(_) => game::GameMove::Submit,
+        })
+    }
+}
+
+impl From<&game::HistoryEntry> for HistoryEntry {
+    fn from(entry: &game::HistoryEntry) -> Self {
+        HistoryEntry {
+            game_move: Some(GameMove::from(&entry.game_move)),
+            fight_result: entry.fight_result.as_ref().map(|fr| FightResult {
+                losing_die: Some(Die::from(&fr.losing_die)),
+                losing_position: match fr.losing_position {
+                    game::ZIndex::Top => ZIndex::Top,
+                    game::ZIndex::Bottom => ZIndex::Bottom,
+                } as i32,
+            }),
+            elapsed: entry.elapsed.map(|e| Duration {
+                seconds: e.as_secs(),
+                nanos: e.subsec_nanos(),
+            }),
+        }
+    }
+}
+
+impl TryFrom<HistoryEntry> for game::HistoryEntry {
+    type Error = failure::Error;
+
+    fn try_from(entry: HistoryEntry) -> Fallible<Self> {
+        let fight_result = match entry.fight_result {
+            Some(fr) => Some(game::FightResult {
+                losing_die: die(fr.losing_die)?,
+                losing_position: match enumeration(fr.losing_position, "z-index")? {
+                    ZIndex::Top => game::ZIndex::Top,
+                    ZIndex::Bottom => game::ZIndex::Bottom,
+                },
+            }),
+            None => None,
+        };
+        Ok(game::HistoryEntry {
+            game_move: game::GameMove::try_from(required(entry.game_move, "move")?)?,
+            fight_result,
+            elapsed: entry.elapsed.map(|e| time::Duration::new(e.seconds, e.nanos)),
+        })
+    }
+}
+
+impl From<&game::Player> for Player {
+    fn from(player: &game::Player) -> Self {
+        Player {
+            name: player.name.clone(),
+            dice: player.dice.iter().map(Die::from).collect(),
+        }
+    }
+}
+
+impl TryFrom<Player> for game::Player {
+    type Error = failure::Error;
+
+    fn try_from(player: Player) -> Fallible<Self> {
+        Ok(game::Player {
+            name: player.name,
+            dice: player
+                .dice
+                .into_iter()
+                .map(card::Die::try_from)
+                .collect::<Fallible<_>>()?,
+        })
+    }
+}
+
+impl From<&game::Game> for Game {
+    fn from(game: &game::Game) -> Self {
+        Game {
+            board: Some(Board::from(&game.board)),
+            rules: Some(Rules::from(&game.rules)),
+            player1: Some(Player::from(&game.player1)),
+            player2: Some(Player::from(&game.player2)),
+            player1_moves: game.player1_moves,
+            player1_surprises: u32::from(game.player1_surprises),
+            player2_surprises: u32::from(game.player2_surprises),
+            result: match game.result {
+                game::GameResult::InProgress => GameResult::InProgress,
+                game::GameResult::FirstPlayerWon => GameResult::FirstPlayerWon,
+                game::GameResult::SecondPlayerWon => GameResult::SecondPlayerWon,
+            } as i32,
+            history: game.history.iter().map(HistoryEntry::from).collect(),
+        }
+    }
+}
+
+impl TryFrom<Game> for game::Game {
+    type Error = failure::Error;
+
+    fn try_from(game: Game) -> Fallible<Self> {
+        let surprises = |n: u32| u8::try_from(n).map_err(|_| format_err!("Too many surprise moves: {}", n));
+        Ok(game::Game {
+            board: board::Board::try_from(required(game.board, "board")?)?,
+            rules: game::Rules::try_from(required(game.rules, "rules")?)?,
+            player1: game::Player::try_from(required(game.player1, "first player")?)?,
+            player2: game::Player::try_from(required(game.player2, "second player")?)?,
+            player1_moves: game.player1_moves,
+            player1_surprises: surprises(game.player1_surprises)?,
+            player2_surprises: surprises(game.player2_surprises)?,
+            result: match enumeration(game.result, "game result")? {
+                GameResult::InProgress => game::GameResult::InProgress,
+                GameResult::FirstPlayerWon => game::GameResult::FirstPlayerWon,
+                GameResult::SecondPlayerWon => game::GameResult::SecondPlayerWon,
+            },
+            history: game
+                .history
+                .into_iter()
+                .map(game::HistoryEntry::try_from)
+                .collect::<Fallible<_>>()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::card::Deck;
+
+    #[test]
+    fn test_round_trip() -> Fallible<()> {
+        let peaceful = card::Card::new(card::CardKind::Jade).with_ability(card::Ability::Peaceful);
+        let deck = Deck::builder()
+            .jade(1)
+            .gold(2)
+            .jade(1)
+            .gold(1)
+            .jade(1)
+            .card(peaceful)
+            .build();
+        let mut game = game::Game::new(
+            board::Layout::Bricks7,
+            deck,
+            game::Rules::new(true, true).with_player1_starting(false),
+        );
+        for _ in 0..8 {
+            let m = game.generate_moves().into_iter().last().unwrap();
+            assert_eq!(game::GameMove::from_proto(&m.to_proto())?, m);
+            game.apply_move(&m)?;
+        }
+        assert!(game.history.iter().any(|entry| entry.fight_result.is_some()));
+
+        let bytes = game.to_proto();
+        let decoded = game::Game::from_proto(&bytes)?;
+        assert_eq!(decoded.position_key(), game.position_key());
+        assert_eq!(decoded.history, game.history);
+        assert_eq!(decoded.board.layout, game.board.layout);
+        assert_eq!(decoded.generate_moves(), game.generate_moves());
+        assert_eq!(game::Rules::from_proto(&game.rules().to_proto())?, game.rules());
+        let handicapped = game::Rules::default().with_handicap("extra:w1,remove:r6,placements:2".parse()?)?;
+        assert_eq!(game::Rules::from_proto(&handicapped.to_proto())?, handicapped);
+        let doubled = game::Rules::default()
+            .with_die_values(card::DiceColor::Black, "2-12".parse()?)
+            .with_handicap("remove:b10".parse()?)?;
+        assert_eq!(game::Rules::from_proto(&doubled.to_proto())?, doubled);
+
+        assert!(game::Game::from_proto(&bytes[..bytes.len() / 2]).is_err());
+        assert!(game::GameMove::from_proto(&[]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+//! Versioned serialization of games, so that saved games keep loading
+//! when the fields of `Game` change. Games are saved as `{ "version":
+//! 2, "game": { ... } }` and the ones saved with older versions of the
+//! schema are migrated to the current one when loaded.
+//!
+//! Changing the serialized shape of `Game` (or anything inside it)
+//! means bumping `VERSION` and adding a migration from the previous
+//! version to `MIGRATIONS`.
+
+use failure::{bail, format_err, Fallible};
+use serde_json::{json, Value};
+
+use crate::game::Game;
+
+/// Current version of the schema.
+pub const VERSION: u64 = 2;
+
+/// Migrations from every version to the next one: the first one
+/// migrates from version 1 to version 2 and so on.
+const MIGRATIONS: &[fn(&mut Value) -> Fallible<()>] = &[v1_to_v2];
+
+/// The game with the version of the schema.
+pub fn to_value(game: &Game) -> Fallible<Value> {
+    Ok(json!({
+        "version": VERSION,
+        "game": serde_json::to_value(game)?,
+    }))
+}
+
+pub fn to_json(game: &Game) -> Fallible<String> {
+    Ok(serde_json::to_string(&to_value(game)?)?)
+}
+
+/// Loads the game saved with any version of the schema. Games saved
+/// without the version are either from version 1 or, if their board
+/// has the neighbours of the cards, from version 2.
+pub fn from_value(value: Value) -> Fallible<Game> {
+    let (version, mut game) = match value.get("version") {
+        Some(version) => {
+            let version = version
+                .as_u64()
+                .ok_or_else(|| format_err!("Unexpected schema version: {}", version))?;
+            let game = value
+                .get("game")
+                .cloned()
+                .ok_or_else(|| format_err!("No game in the saved game"))?;
+            (version, game)
+        }
+        None if value.pointer("/board/neighbours").is_some() => (2, value),
+        None => (1, value),
+    };
+    if version == 0 || version > VERSION {
+        bail!(
+            "Unsupported schema version: {} (the latest one is {})",
+            version,
+            VERSION
+        );
+    }
+
+    for migrate in &MIGRATIONS[version as usize - 1..] {
+        migrate(&mut game)?;
+    }
+    let mut game: Game = serde_json::from_value(game)?;
+    if version < VERSION {
+        // Derived data dropped by the migrations.
+        game.board.refresh_neighbours();
+    }
+    Ok(game)
+}
+
+pub fn from_json(s: &str) -> Fallible<Game> {
+    from_value(serde_json::from_str(s)?)
+}
+
+/// Version 2 keeps the neighbours of the cards in the board and how the
+/// moves have been played in the history. Neighbours are refreshed once
+/// the game is loaded, but the results of the fights are lost, so the
+/// fights of migrated games can't be taken back.
+fn v1_to_v2(game: &mut Value) -> Fallible<()> {
+    let board = game
+        .get_mut("board")
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| format_err!("No board in the saved game"))?;
+    board.insert("neighbours".to_string(), json!([]));
+
+    let history = game
+        .get_mut("history")
+        .and_then(Value::as_array_mut)
+        .ok_or_else(|| format_err!("No history in the saved game"))?;
+    for entry in history.iter_mut() {
+        *entry = json!({
+            "game_move": entry.take(),
+            "fight_result": null,
+            "elapsed": null,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::board::Layout;
+    use crate::card::Deck;
+    use crate::game::Rules;
+
+    /// Game saved before the versions were introduced: five moves of
+    /// a Bricks7 game with the cards "JGGJGJJ", including a fight.
+    const GAME_V1: &str = r#"{"board":{"grid":"Hex","cards":[[{"x":0,"y":0,"z":0},{"kind":"Jade","dice":[]}],[{"x":1,"y":-1,"z":0},{"kind":"Jade","dice":[]}],[{"x":1,"y":0,"z":-1},{"kind":"Gold","dice":[]}],[{"x":2,"y":-1,"z":-1},{"kind":"Gold","dice":[]}],[{"x":2,"y":0,"z":-2},{"kind":"Jade","dice":[]}],[{"x":3,"y":-1,"z":-2},{"kind":"Gold","dice":[{"color":"White","value":1}]}],[{"x":3,"y":0,"z":-3},{"kind":"Jade","dice":[{"color":"Red","value":6}]}]],"layout":"Bricks7","adj_triples":[[{"x":0,"y":0,"z":0},{"x":1,"y":0,"z":-1},{"x":2,"y":0,"z":-2}],[{"x":1,"y":-1,"z":0},{"x":2,"y":-1,"z":-1},{"x":3,"y":-1,"z":-2}],[{"x":1,"y":0,"z":-1},{"x":2,"y":0,"z":-2},{"x":3,"y":0,"z":-3}]]},"rules":{"enable_fight_move":true,"enable_surprise_move":false},"player1":{"name":"Player 1","dice":[{"color":"Red","value":2},{"color":"Red","value":2},{"color":"Red","value":4}]},"player2":{"name":"Player 2","dice":[{"color":"Black","value":1},{"color":"Black","value":3},{"color":"Black","value":3},{"color":"Black","value":5}]},"player1_moves":false,"player1_surprises":0,"player2_surprises":0,"result":"InProgress","history":[{"Place":[{"color":"Red","value":6},{"x":3,"y":0,"z":-3}]},{"Place":[{"color":"White","value":1},{"x":3,"y":-1,"z":-2}]},{"Move":[{"color":"Red","value":6},{"x":3,"y":0,"z":-3},{"x":3,"y":-1,"z":-2}]},{"Fight":{"x":3,"y":-1,"z":-2}},{"Place":[{"color":"Red","value":6},{"x":3,"y":0,"z":-3}]}]}"#;
+
+    #[test]
+    fn test_migration_from_v1() -> Fallible<()> {
+        let game = from_json(GAME_V1)?;
+        let deck = Deck::ordered("JGGJGJJ")?;
+        let replayed = Game::from_moves(Layout::Bricks7, deck, Rules::new(true, false), &game.moves())?;
+        assert_eq!(game.position_key(), replayed.position_key());
+        assert_eq!(game.generate_moves(), replayed.generate_moves());
+        assert_eq!(game.history.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn test_versions() -> Fallible<()> {
+        let mut game = Game::new(Layout::Hex7, Deck::ordered("JJGGJGJ")?, Rules::default());
+        let m = game.generate_moves()[0].clone();
+        game.apply_move(&m)?;
+
+        let saved = to_value(&game)?;
+        assert_eq!(saved["version"], VERSION);
+        assert_eq!(from_json(&saved.to_string())?.position_key(), game.position_key());
+
+        // Games of the current version saved without it.
+        let unversioned = serde_json::to_string(&game)?;
+        assert_eq!(from_json(&unversioned)?.history, game.history);
+
+        assert!(from_json(r#"{"version":3,"game":{}}"#).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,283 @@
+//! Strategies written as [Rhai](https://rhai.rs) scripts, to try ideas
+//! for bots and evaluation functions without recompiling anything. A
+//! script defines one of:
+//!
+//! - `fn choose_move(game)` returning one of `game.moves()`,
+//! - `fn evaluate(game)` returning the score of the position for the
+//!   first player (positive when they are better). Then the AI plays
+//!   the move after which the score is the best for itself.
+//!
+//! For example:
+//!
+//! ```text
+//! fn choose_move(game) {
+//!     let moves = game.moves();
+//!     for m in moves {
+//!         if game.play(m).is_over { return m; }
+//!     }
+//!     moves[random(moves.len())]
+//! }
+//! ```
+//!
+//! Scripts can't do anything but look at the game through the API
+//! below, and the number of operations they make per move is limited.
+//!
+//! - `game.moves()`: legal moves (none once the game is over),
+//! - `game.play(move)`: the game after the move,
+//! - `game.first_player_moves`, `game.is_over`, `game.ply` and
+//!   `game.result` (`"InProgress"`, `"FirstPlayerWon"` or
+//!   `"SecondPlayerWon"`),
+//! - `game.cards()`: cards like `#{x, y, z, kind: "Jade", dice: [#{color:
+//!   "Red", value: 2}]}` with the top die last,
+//! - `game.stock(first_player)`: dice left in the stock of a player,
+//! - `game.heuristic()`: the built-in evaluation for the first player,
+//! - `move.kind` (`"Place"`, `"Move"`, `"Fight"`, `"Surprise"` or
+//!   `"Submit"`), `move.die`, `move.from` and `move.to` (coordinates
+//!   like `#{x, y, z}`, or `()` if the move has none),
+//! - `random(n)`: a random number from 0 to `n - 1`.
+
+use failure::{bail, format_err, Fallible};
+use rand::Rng;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use std::fs;
+use std::path::Path;
+
+use crate::ai::{evaluate_for_first_player, Evaluator};
+use crate::card::Die;
+use crate::coord::Coord;
+use crate::game::{Game, GameMove};
+use crate::play::Strategy;
+
+/// Maximal number of operations a script can make to choose a move.
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+type Move = GameMove<Coord>;
+
+/// Function of the script which decides on the moves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Entry {
+    ChooseMove,
+    Evaluate,
+}
+
+pub struct ScriptedAI {
+    name: String,
+    engine: Engine,
+    ast: AST,
+    entry: Entry,
+}
+
+impl ScriptedAI {
+    pub fn new(name: &str, script: &str) -> Fallible<Self> {
+        let engine = engine();
+        let ast = engine
+            .compile(script)
+            .map_err(|err| format_err!("Can't compile script {}: {}", name, err))?;
+        let defines = |fn_name: &str| ast.iter_functions().any(|f| f.name == fn_name && f.params.len() == 1);
+        let entry = if defines("choose_move") {
+            Entry::ChooseMove
+        } else if defines("evaluate") {
+            Entry::Evaluate
+        } else {
+            bail!("Script {} defines neither choose_move(game) nor evaluate(game)", name);
+        };
+        Ok(ScriptedAI {
+            name: name.to_string(),
+            engine,
+            ast,
+            entry,
+        })
+    }
+
+    /// Loads the script from a file, named after the file.
+    pub fn from_file(path: &str) -> Fallible<Self> {
+        let name = Path::new(path)
+            .file_stem()
+            .map_or_else(|| path.to_string(), |stem| stem.to_string_lossy().into_owned());
+        Self::new(&name, &fs::read_to_string(path)?)
+    }
+
+    fn choose_move(&self, game: &Game) -> Fallible<Move> {
+        let moves = game.generate_moves();
+        match self.entry {
+            Entry::ChooseMove => {
+                let mov: Move = self.call("choose_move", game.clone())?;
+                if !moves.contains(&mov) {
+                    bail!("choose_move returned an illegal move: {}", mov);
+                }
+                Ok(mov)
+            }
+            Entry::Evaluate => {
+                let mut best = None;
+                for mov in moves {
+                    let mut next = game.clone();
+                    next.apply_move(&mov)?;
+                    let score: i64 = self.call("evaluate", next)?;
+                    let score = if game.player1_moves { score } else { -score };
+                    if best.as_ref().is_none_or(|&(_, best_score)| score > best_score) {
+                        best = Some((mov, score));
+                    }
+                }
+                best.map(|(mov, _)| mov).ok_or_else(|| format_err!("No moves to make"))
+            }
+        }
+    }
+
+    fn call<T: Clone + 'static>(&self, fn_name: &str, game: Game) -> Fallible<T> {
+        self.engine
+            .call_fn(&mut Scope::new(), &self.ast, fn_name, (game,))
+            .map_err(|err| format_err!("{} failed: {}", fn_name, err))
+    }
+}
+
+impl Strategy for ScriptedAI {
+    fn get_move(&mut self, game: &Game) -> Move {
+        self.choose_move(game).unwrap_or_else(|err| {
+            // Don't let a broken script stop the game.
+            println!("[ERR] Script {}: {}, playing a random move", self.name, err);
+            game.random_move()
+        })
+    }
+
+    fn name(&self) -> String {
+        format!("Script {}", self.name)
+    }
+}
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.set_max_operations(MAX_OPERATIONS);
+
+    engine
+        .register_type_with_name::<Game>("Game")
+        .register_fn("moves", |game: &mut Game| -> Array {
+            if game.is_game_over() {
+                return vec![];
+            }
+            game.generate_moves().into_iter().map(Dynamic::from).collect()
+        })
+        .register_fn(
+            "play",
+            |game: &mut Game, mov: Move| -> Result<Game, Box<EvalAltResult>> {
+                let mut next = game.clone();
+                next.apply_move(&mov).map_err(|err| err.to_string())?;
+                Ok(next)
+            },
+        )
+        .register_get("first_player_moves", |game: &mut Game| game.player1_moves)
+        .register_get("is_over", |game: &mut Game| game.is_game_over())
+        .register_get("ply", |game: &mut Game| game.ply_to_be_played() as i64)
+        .register_get("result", |game: &mut Game| format!("{:?}", game.result))
+        .register_fn("cards", |game: &mut Game| -> Array {
+            game.board
+                .coord_cards_iter()
+                .map(|(coord, card)| {
+                    let mut map = coord_map(coord);
+                    map.insert("kind".into(), format!("{:?}", card.kind).into());
+                    let dice: Array = card.dice.iter().map(die).collect();
+                    map.insert("dice".into(), dice.into());
+                    map.into()
+                })
+                .collect()
+        })
+        .register_fn("stock", |game: &mut Game, first_player: bool| -> Array {
+            game.stock(first_player).iter().map(die).collect()
+        })
+        .register_fn("heuristic", |game: &mut Game| {
+            i64::from(evaluate_for_first_player(game, &Evaluator::default()))
+        });
+
+    engine
+        .register_type_with_name::<Move>("Move")
+        .register_fn("to_string", |mov: &mut Move| mov.to_string())
+        .register_fn("==", |a: &mut Move, b: Move| *a == b)
+        .register_get("kind", |mov: &mut Move| {
+            match mov {
+                GameMove::Place(..) => "Place",
+                GameMove::Move(..) => "Move",
+                GameMove::Fight(..) => "Fight",
+                GameMove::Surprise(..) => "Surprise",
+                GameMove::Submit => "Submit",
+            }
+            .to_string()
+        })
+        .register_get("die", |mov: &mut Move| match mov {
+            GameMove::Place(d, _) | GameMove::Move(d, _, _) => die(d),
+            _ => Dynamic::UNIT,
+        })
+        .register_get("from", |mov: &mut Move| {
+            mov.source().map_or(Dynamic::UNIT, |c| coord_map(&c).into())
+        })
+        .register_get("to", |mov: &mut Move| {
+            mov.target().map_or(Dynamic::UNIT, |c| coord_map(&c).into())
+        });
+
+    engine.register_fn("random", |n: i64| -> Result<i64, Box<EvalAltResult>> {
+        if n <= 0 {
+            return Err(format!("random({}): the bound has to be positive", n).into());
+        }
+        Ok(rand::thread_rng().gen_range(0, n))
+    });
+
+    engine
+}
+
+fn coord_map(coord: &Coord) -> Map {
+    let mut map = Map::new();
+    map.insert("x".into(), i64::from(coord.x).into());
+    map.insert("y".into(), i64::from(coord.y).into());
+    map.insert("z".into(), i64::from(coord.z).into());
+    map
+}
+
+fn die(die: &Die) -> Dynamic {
+    let mut map = Map::new();
+    map.insert("color".into(), format!("{:?}", die.color).into());
+    map.insert("value".into(), i64::from(die.value).into());
+    map.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::board::Layout;
+    use crate::card::Deck;
+    use crate::game::Rules;
+
+    #[test]
+    fn test_scripts() -> Fallible<()> {
+        let game = Game::new(Layout::Bricks7, Deck::ordered("jggjgjj")?, Rules::default());
+
+        // Places the highest die on the first card.
+        let mut chooser = ScriptedAI::new(
+            "first",
+            r#"
+            fn choose_move(game) {
+                let best = ();
+                for m in game.moves() {
+                    if m.kind == "Place" && (best == () || m.die.value > best.die.value) {
+                        best = m;
+                    }
+                }
+                best
+            }
+            "#,
+        )?;
+        let mov = chooser.get_move(&game);
+        assert!(matches!(mov, GameMove::Place(d, _) if d.value == 6));
+        assert_eq!(chooser.name(), "Script first");
+
+        // Follows the built-in evaluation.
+        let mut evaluator = ScriptedAI::new("eval", "fn evaluate(game) { game.heuristic() + game.cards().len() }")?;
+        let mov = evaluator.get_move(&game);
+        assert!(game.generate_moves().contains(&mov));
+
+        assert!(ScriptedAI::new("nothing", "fn play(game) { 1 }").is_err());
+        assert!(ScriptedAI::new("broken", "fn evaluate(game) {").is_err());
+
+        // Endless scripts are stopped.
+        let endless = ScriptedAI::new("endless", "fn choose_move(game) { loop {} }")?;
+        assert!(endless.choose_move(&game).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,1119 @@
+//! Parallel alpha-beta search ("lazy SMP"). Several threads search
+//! the same position independently, sharing a lock-free
+//! transposition table, so that they benefit from each other's
+//! results.
+
+use crate::ai::{evaluate_for_player, is_decided, won_by, Evaluator};
+use crate::clock::{StdTime, TimeSource};
+use crate::coord::Coord;
+use crate::game::{Game, GameMove};
+use crate::tablebase::Tablebase;
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::AddAssign;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Bound for the scores (all of them are in `-INFINITY..=INFINITY`).
+const INFINITY: i32 = i32::MAX;
+
+/// Maximal depth of the iterative deepening.
+const MAX_DEPTH: u32 = 64;
+
+/// How many plies of forcing moves (fights and wins) the quiescence
+/// search plays beyond the horizon.
+const QUIESCENCE_DEPTH: u32 = 4;
+
+/// How often (in nodes) the search threads check the clock.
+const CHECK_TIME_EVERY: u64 = 64;
+
+/// Cutoffs are counted separately for this many first moves in the
+/// search order (the last counter is for all the later moves).
+pub const CUTOFF_MOVES: usize = 4;
+
+// Move ordering priorities: the move from the transposition table,
+// moves winning immediately, fights, killer moves and then the rest
+// ordered by their history score (which is capped below killers).
+const TT_MOVE_PRIORITY: u32 = u32::MAX;
+const WINNING_MOVE_PRIORITY: u32 = u32::MAX - 1;
+const FIGHT_PRIORITY: u32 = u32::MAX - 2;
+const KILLER_PRIORITY: u32 = u32::MAX - 3;
+
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+enum Bound {
+    Exact,
+    Lower,
+    Upper,
+}
+
+/// Transposition table entry. Best move is stored as an index in the
+/// list returned by `Game::generate_moves`.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+struct Entry {
+    score: i32,
+    depth: u8,
+    bound: Bound,
+    best_move: Option<u16>,
+}
+
+impl Entry {
+    fn pack(self) -> u64 {
+        let bound = match self.bound {
+            Bound::Exact => 0,
+            Bound::Lower => 1,
+            Bound::Upper => 2,
+        };
+        let best_move = self.best_move.map_or(0, |ix| u64::from(ix) + 1);
+        u64::from(self.score as u32) | u64::from(self.depth) << 32 | bound << 40 | best_move << 48
+    }
+
+    fn unpack(data: u64) -> Self {
+        let bound = match (data >> 40) & 0xff {
+            0 => Bound::Exact,
+            1 => Bound::Lower,
+            _ => Bound::Upper,
+        };
+        let best_move = (data >> 48) as u16;
+        Entry {
+            score: data as u32 as i32,
+            depth: (data >> 32) as u8,
+            bound,
+            best_move: if best_move == 0 { None } else { Some(best_move - 1) },
+        }
+    }
+}
+
+/// Slot of the transposition table. The key is stored xor-ed with
+/// the data, so that a slot torn by concurrent writes is detected and
+/// ignored on probe (no locks needed).
+#[derive(Default)]
+struct Slot {
+    key: AtomicU64,
+    data: AtomicU64,
+}
+
+/// Transposition table shared between the search threads.
+pub struct TranspositionTable {
+    slots: Vec<Slot>,
+    mask: u64,
+}
+
+impl TranspositionTable {
+    /// Creates a table with `2^bits` entries (16 bytes each).
+    pub fn new(bits: u32) -> Self {
+        let size = 1usize << bits;
+        let mut slots = Vec::with_capacity(size);
+        slots.resize_with(size, Slot::default);
+        TranspositionTable {
+            slots,
+            mask: size as u64 - 1,
+        }
+    }
+
+    pub fn clear(&self) {
+        for slot in &self.slots {
+            slot.key.store(0, Ordering::Relaxed);
+            slot.data.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn slot(&self, key: u64) -> &Slot {
+        &self.slots[(key & self.mask) as usize]
+    }
+
+    fn probe(&self, key: u64) -> Option<Entry> {
+        let slot = self.slot(key);
+        let data = slot.data.load(Ordering::Relaxed);
+        if slot.key.load(Ordering::Relaxed) ^ data == key && data != 0 {
+            Some(Entry::unpack(data))
+        } else {
+            None
+        }
+    }
+
+    fn store(&self, key: u64, entry: Entry) {
+        // Deeper results for the same position are more valuable,
+        // anything else is replaced.
+        if let Some(old) = self.probe(key) {
+            if old.depth > entry.depth {
+                return;
+            }
+        }
+
+        let slot = self.slot(key);
+        let data = entry.pack();
+        slot.key.store(key ^ data, Ordering::Relaxed);
+        slot.data.store(data, Ordering::Relaxed);
+    }
+}
+
+/// When to stop the search. With no limits it runs until the game
+/// result is known (or until `MAX_DEPTH`).
+#[derive(Clone, Default)]
+pub struct SearchLimits {
+    pub depth: Option<u32>,
+    pub duration: Option<Duration>,
+    /// How much the player to move at the root dislikes draws by
+    /// repetition: they are scored as `-contempt` for them. Positive
+    /// values make the AI avoid draws (against weaker opponents),
+    /// negative ones make it seek them.
+    pub contempt: i32,
+    /// Where the time of the search is measured (`StdTime` if none).
+    pub time: Option<Arc<dyn TimeSource>>,
+}
+
+impl fmt::Debug for SearchLimits {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SearchLimits")
+            .field("depth", &self.depth)
+            .field("duration", &self.duration)
+            .field("contempt", &self.contempt)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+    /// Score from the perspective of the player to move (see
+    /// `ai::win_score` for the scores of decided games).
+    pub score: i32,
+    /// Principal variation, starting with the best move.
+    pub pv: Vec<GameMove<Coord>>,
+    /// Depth of the last completed iteration.
+    pub depth: u32,
+    /// Counters summed over all the threads.
+    pub stats: SearchStats,
+    /// Time the search has taken.
+    pub elapsed: Duration,
+}
+
+/// Search counters, useful for measuring the effect of search
+/// improvements (like move ordering).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SearchStats {
+    /// Number of visited positions.
+    pub nodes: u64,
+    /// Number of visited positions at the horizon of the search and
+    /// beyond it, in the quiescence search (included in `nodes`).
+    pub qnodes: u64,
+    /// Number of lookups in the transposition table.
+    pub tt_probes: u64,
+    /// Number of positions found in the transposition table.
+    pub tt_hits: u64,
+    /// Number of beta cutoffs.
+    pub cutoffs: u64,
+    /// Number of beta cutoffs by the index of the move causing it in
+    /// the search order (see `CUTOFF_MOVES`).
+    pub cutoffs_by_move: [u64; CUTOFF_MOVES],
+    /// Number of positions found in the tablebase.
+    pub tablebase_hits: u64,
+    /// Number of positions scored as draws since they repeat earlier
+    /// ones.
+    pub repetitions: u64,
+}
+
+fn rate(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+impl SearchStats {
+    /// Share of the cutoffs caused by the first searched move. The
+    /// better the move ordering, the closer it is to 1.
+    pub fn first_move_cutoff_rate(&self) -> f64 {
+        rate(self.cutoffs_by_move[0], self.cutoffs)
+    }
+
+    /// Share of the transposition table lookups which have found the
+    /// position.
+    pub fn tt_hit_rate(&self) -> f64 {
+        rate(self.tt_hits, self.tt_probes)
+    }
+
+    /// Counts a cutoff caused by the `n`-th searched move.
+    fn count_cutoff(&mut self, n: usize) {
+        self.cutoffs += 1;
+        self.cutoffs_by_move[n.min(CUTOFF_MOVES - 1)] += 1;
+    }
+}
+
+impl AddAssign for SearchStats {
+    fn add_assign(&mut self, other: Self) {
+        self.nodes += other.nodes;
+        self.qnodes += other.qnodes;
+        self.tt_probes += other.tt_probes;
+        self.tt_hits += other.tt_hits;
+        self.cutoffs += other.cutoffs;
+        for (c, o) in self.cutoffs_by_move.iter_mut().zip(other.cutoffs_by_move.iter()) {
+            *c += o;
+        }
+        self.tablebase_hits += other.tablebase_hits;
+        self.repetitions += other.repetitions;
+    }
+}
+
+// nodes: 1234 (qnodes: 800), tt hits: 56 of 100 (56%), cutoffs: 78
+// (by move: 90% 5% 3% 2%)
+impl fmt::Display for SearchStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "nodes: {} (qnodes: {}), tt hits: {} of {} ({:.0}%), cutoffs: {} (by move:",
+            self.nodes,
+            self.qnodes,
+            self.tt_hits,
+            self.tt_probes,
+            self.tt_hit_rate() * 100.0,
+            self.cutoffs,
+        )?;
+        for &c in self.cutoffs_by_move.iter() {
+            write!(f, " {:.0}%", rate(c, self.cutoffs) * 100.0)?;
+        }
+        write!(f, ")")?;
+        if self.tablebase_hits > 0 {
+            write!(f, ", tablebase hits: {}", self.tablebase_hits)?;
+        }
+        if self.repetitions > 0 {
+            write!(f, ", repetitions: {}", self.repetitions)?;
+        }
+        Ok(())
+    }
+}
+
+impl SearchResult {
+    pub fn best_move(&self) -> &GameMove<Coord> {
+        &self.pv[0]
+    }
+
+    /// Nodes searched per second.
+    pub fn nps(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs > 0.0 {
+            self.stats.nodes as f64 / secs
+        } else {
+            0.0
+        }
+    }
+}
+
+/// Searches `game` position in `threads` threads. Returns None if
+/// there are no moves.
+pub fn search(
+    game: &Game,
+    threads: usize,
+    limits: SearchLimits,
+    evaluator: &Evaluator,
+    tt: &TranspositionTable,
+    tablebase: Option<&Tablebase>,
+) -> Option<SearchResult> {
+    run_search(game, threads, limits, evaluator, tt, tablebase, None).map(|(result, _)| result)
+}
+
+/// Same as `search`, but also records the tree explored by the main
+/// thread down to `max_ply` (for debugging).
+pub fn search_with_tree(
+    game: &Game,
+    threads: usize,
+    limits: SearchLimits,
+    evaluator: &Evaluator,
+    tt: &TranspositionTable,
+    tablebase: Option<&Tablebase>,
+    max_ply: u32,
+) -> Option<(SearchResult, SearchTree)> {
+    run_search(game, threads, limits, evaluator, tt, tablebase, Some(max_ply))
+        .map(|(result, tree)| (result, tree.unwrap_or_default()))
+}
+
+fn run_search(
+    game: &Game,
+    threads: usize,
+    limits: SearchLimits,
+    evaluator: &Evaluator,
+    tt: &TranspositionTable,
+    tablebase: Option<&Tablebase>,
+    tree_max_ply: Option<u32>,
+) -> Option<(SearchResult, Option<SearchTree>)> {
+    let moves = game.generate_moves();
+    if moves.is_empty() {
+        return None;
+    }
+
+    let time = limits.time.clone().unwrap_or_else(|| Arc::new(StdTime));
+    let start = time.now_ms();
+    let stop = AtomicBool::new(false);
+    let stats = Mutex::new(SearchStats::default());
+    let best: Mutex<Option<SearchResult>> = Mutex::new(None);
+    let tree: Mutex<Option<SearchTree>> = Mutex::new(None);
+    let deadline = limits.duration.map(|d| start + d.as_secs_f64() * 1000.0);
+    let max_depth = limits.depth.unwrap_or(MAX_DEPTH).clamp(1, MAX_DEPTH);
+    let played = played_positions(game);
+
+    thread::scope(|s| {
+        for id in 0..threads.max(1) {
+            let mut worker = Worker {
+                id,
+                game: game.clone(),
+                evaluator,
+                tt,
+                tablebase,
+                stop: &stop,
+                time: &*time,
+                deadline,
+                stats: SearchStats::default(),
+                root_best: None,
+                killers: vec![],
+                history: HashMap::new(),
+                tree: tree_max_ply.filter(|_| id == 0).map(SearchTree::new),
+                contempt: limits.contempt,
+                path: played.clone(),
+            };
+            let (best, stats, tree) = (&best, &stats, &tree);
+            s.spawn(move || {
+                worker.iterative_deepening(max_depth, best);
+                *stats.lock().unwrap() += worker.stats;
+                if worker.tree.is_some() {
+                    *tree.lock().unwrap() = worker.tree;
+                }
+            });
+        }
+    });
+
+    // If not even the first iteration has been completed, just take
+    // any move.
+    let mut result = best.into_inner().unwrap().unwrap_or_else(|| SearchResult {
+        score: 0,
+        pv: vec![moves[0].clone()],
+        depth: 0,
+        stats: SearchStats::default(),
+        elapsed: Duration::default(),
+    });
+    result.stats = stats.into_inner().unwrap();
+    result.elapsed = Duration::from_secs_f64((time.now_ms() - start).max(0.0) / 1000.0);
+    Some((result, tree.into_inner().unwrap()))
+}
+
+/// Hashes of the positions which have occurred in `game` before the
+/// current one.
+fn played_positions(game: &Game) -> Vec<u64> {
+    let mut game = game.clone();
+    let mut hashes = vec![];
+    while let Some(entry) = game.history.last().cloned() {
+        game.undo_move(&entry.game_move, entry.fight_result);
+        hashes.push(game.position_hash());
+    }
+    hashes
+}
+
+/// Tree explored by the search, down to a limited ply. Every iteration
+/// of the iterative deepening has its own root.
+#[derive(Debug, Clone, Default)]
+pub struct SearchTree {
+    pub nodes: Vec<TreeNode>,
+    max_ply: u32,
+    /// Nodes on the way to the one being searched.
+    path: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TreeNode {
+    pub parent: Option<usize>,
+    /// Move leading to the node (None for the roots).
+    pub game_move: Option<GameMove<Coord>>,
+    pub ply: u32,
+    /// Remaining depth of the search.
+    pub depth: u32,
+    /// Search window.
+    pub alpha: i32,
+    pub beta: i32,
+    /// Score from the perspective of the player to move. None if the
+    /// search has been stopped.
+    pub score: Option<i32>,
+}
+
+fn pp_bound(score: i32) -> String {
+    match score {
+        INFINITY => "inf".to_string(),
+        s if s == -INFINITY => "-inf".to_string(),
+        s => s.to_string(),
+    }
+}
+
+impl SearchTree {
+    fn new(max_ply: u32) -> Self {
+        SearchTree {
+            max_ply,
+            ..Default::default()
+        }
+    }
+
+    fn open(&mut self, game_move: Option<GameMove<Coord>>, ply: u32, depth: u32, alpha: i32, beta: i32) {
+        self.nodes.push(TreeNode {
+            parent: self.path.last().copied(),
+            game_move,
+            ply,
+            depth,
+            alpha,
+            beta,
+            score: None,
+        });
+        self.path.push(self.nodes.len() - 1);
+    }
+
+    fn close(&mut self, score: Option<i32>) {
+        let ix = self.path.pop().expect("SearchTree::close: no open node");
+        self.nodes[ix].score = score;
+    }
+
+    /// The tree in graphviz DOT format. Nodes failing high are red,
+    /// failing low are grey.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph search {\n    node [shape=box, fontname=monospace];\n");
+        for (ix, node) in self.nodes.iter().enumerate() {
+            let score = node.score.map_or("stopped".to_string(), |s| format!("score {}", s));
+            let color = match node.score {
+                Some(s) if s >= node.beta => "red",
+                Some(s) if s <= node.alpha => "grey",
+                _ => "black",
+            };
+            let title = match node.parent {
+                None => format!("iteration {}", node.depth),
+                Some(_) => format!("depth {}", node.depth),
+            };
+            dot.push_str(&format!(
+                "    n{} [label=\"{}\\n[{}, {}]\\n{}\", color={}];\n",
+                ix,
+                title,
+                pp_bound(node.alpha),
+                pp_bound(node.beta),
+                score,
+                color
+            ));
+            if let (Some(parent), Some(m)) = (node.parent, &node.game_move) {
+                dot.push_str(&format!("    n{} -> n{} [label=\"{}\"];\n", parent, ix, m));
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// The tree in JSON: a list of nodes referring to their parents
+    /// by index.
+    pub fn to_json(&self) -> String {
+        let nodes: Vec<_> = self
+            .nodes
+            .iter()
+            .map(|node| {
+                let opt = |v: Option<String>| v.unwrap_or_else(|| "null".to_string());
+                format!(
+                    "{{\"parent\":{},\"move\":{},\"ply\":{},\"depth\":{},\"alpha\":{},\"beta\":{},\"score\":{}}}",
+                    opt(node.parent.map(|p| p.to_string())),
+                    opt(node.game_move.as_ref().map(|m| format!("\"{}\"", m))),
+                    node.ply,
+                    node.depth,
+                    node.alpha,
+                    node.beta,
+                    opt(node.score.map(|s| s.to_string()))
+                )
+            })
+            .collect();
+        format!("{{\"nodes\":[\n{}\n]}}\n", nodes.join(",\n"))
+    }
+}
+
+/// Scores of decided games depend on the ply the game is decided on
+/// (see `ai::win_score`), so in the transposition table we store them
+/// relative to the ply of the position (as positions can be reached
+/// on different plies).
+fn score_to_tt(score: i32, ply: usize) -> i32 {
+    if !is_decided(score) {
+        score
+    } else if score > 0 {
+        score + ply as i32
+    } else {
+        score - ply as i32
+    }
+}
+
+fn score_from_tt(score: i32, ply: usize) -> i32 {
+    if !is_decided(score) {
+        score
+    } else if score > 0 {
+        score - ply as i32
+    } else {
+        score + ply as i32
+    }
+}
+
+struct Worker<'a> {
+    id: usize,
+    game: Game,
+    evaluator: &'a Evaluator,
+    tt: &'a TranspositionTable,
+    tablebase: Option<&'a Tablebase>,
+    stop: &'a AtomicBool,
+    time: &'a dyn TimeSource,
+    /// When the time is up (in the milliseconds of `time`).
+    deadline: Option<f64>,
+    stats: SearchStats,
+    root_best: Option<usize>,
+    /// Two last moves which caused a cutoff at each ply.
+    killers: Vec<[Option<GameMove<Coord>>; 2]>,
+    /// How often the moves caused cutoffs (weighted by depth).
+    history: HashMap<GameMove<Coord>, u32>,
+    /// Explored tree, if it's recorded.
+    tree: Option<SearchTree>,
+    /// See `SearchLimits::contempt`.
+    contempt: i32,
+    /// Hashes of the positions played in the game and searched on the
+    /// way to the current one, to detect repetitions.
+    path: Vec<u64>,
+}
+
+impl<'a> Worker<'a> {
+    fn iterative_deepening(&mut self, max_depth: u32, best: &Mutex<Option<SearchResult>>) {
+        // Helper threads with odd ids search one ply deeper, which
+        // makes them diverge from the main thread.
+        let start_depth = (1 + self.id as u32 % 2).min(max_depth);
+
+        for depth in start_depth..=max_depth {
+            let score = match self.negamax(depth, 0, -INFINITY, INFINITY) {
+                Some(score) => score,
+                None => break,
+            };
+
+            let moves = self.game.generate_moves();
+            let best_move = moves[self.root_best.unwrap()].clone();
+            let pv = self.principal_variation(best_move, depth);
+
+            let mut best = best.lock().unwrap();
+            if best.as_ref().is_none_or(|b| b.depth < depth) {
+                *best = Some(SearchResult {
+                    score,
+                    pv,
+                    depth,
+                    stats: SearchStats::default(),
+                    elapsed: Duration::default(),
+                });
+            }
+
+            // No point in searching deeper if the game is decided.
+            if is_decided(score) {
+                self.stop.store(true, Ordering::Relaxed);
+                break;
+            }
+        }
+
+        // Main thread decides when the search is over.
+        if self.id == 0 {
+            self.stop.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns None if the search has been stopped.
+    fn negamax(&mut self, depth: u32, ply: u32, alpha: i32, beta: i32) -> Option<i32> {
+        let recorded = match &mut self.tree {
+            Some(tree) if ply <= tree.max_ply => {
+                let m = if ply == 0 { None } else { self.game.last_move().cloned() };
+                tree.open(m, ply, depth, alpha, beta);
+                true
+            }
+            _ => false,
+        };
+
+        let score = self.search_node(depth, ply, alpha, beta);
+        if recorded {
+            self.tree.as_mut().unwrap().close(score);
+        }
+        score
+    }
+
+    /// Counts a visited node and checks whether the search has been
+    /// stopped (or the time is up).
+    fn visit(&mut self) -> bool {
+        self.stats.nodes += 1;
+        if self.stats.nodes.is_multiple_of(CHECK_TIME_EVERY) {
+            if let Some(deadline) = self.deadline {
+                if self.time.now_ms() >= deadline {
+                    self.stop.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+        self.stop.load(Ordering::Relaxed)
+    }
+
+    fn search_node(&mut self, depth: u32, ply: u32, mut alpha: i32, beta: i32) -> Option<i32> {
+        if self.visit() {
+            return None;
+        }
+
+        if self.game.is_game_over() {
+            return Some(self.evaluate());
+        }
+
+        // We need a move at the root, so we search it anyway.
+        if ply > 0 {
+            if let Some(outcome) = self.tablebase.and_then(|tb| tb.probe(&self.game)) {
+                self.stats.tablebase_hits += 1;
+                return Some(outcome.score(self.game.ply_to_be_played()));
+            }
+        }
+
+        if depth == 0 {
+            return self.quiesce(QUIESCENCE_DEPTH, alpha, beta);
+        }
+
+        let key = self.game.position_hash();
+        // Repeating a position is as good as a draw: if it's worth
+        // playing for, it can be repeated again.
+        if ply > 0 && self.path.contains(&key) {
+            self.stats.repetitions += 1;
+            return Some(self.draw_score(ply));
+        }
+
+        let mut tt_move = None;
+        self.stats.tt_probes += 1;
+        if let Some(mut entry) = self.tt.probe(key) {
+            self.stats.tt_hits += 1;
+            entry.score = score_from_tt(entry.score, self.game.ply_to_be_played());
+            tt_move = entry.best_move.map(usize::from);
+            // We need a move at the root, so we search it anyway.
+            if ply > 0 && u32::from(entry.depth) >= depth {
+                match entry.bound {
+                    Bound::Exact => return Some(entry.score),
+                    Bound::Lower if entry.score >= beta => return Some(entry.score),
+                    Bound::Upper if entry.score <= alpha => return Some(entry.score),
+                    _ => {}
+                }
+            }
+        }
+
+        let moves = self.game.generate_moves();
+        if moves.is_empty() {
+            return Some(self.evaluate());
+        }
+
+        let order = self.order_moves(&moves, tt_move, ply);
+
+        let alpha_orig = alpha;
+        let mut best_score = -INFINITY;
+        let mut best_ix = order[0];
+        for (n, ix) in order.into_iter().enumerate() {
+            let m = &moves[ix];
+            let mover = self.game.player1_moves;
+            let fight_result = self.game.apply_move_unchecked(m);
+            self.path.push(key);
+            let score = if self.game.player1_moves == mover {
+                // Moves of the setup phase of a handicap keep the turn.
+                self.negamax(depth - 1, ply + 1, alpha, beta)
+            } else {
+                self.negamax(depth - 1, ply + 1, -beta, -alpha).map(|s| -s)
+            };
+            self.path.pop();
+            self.game.undo_move(m, fight_result);
+            let score = score?;
+
+            if score > best_score {
+                best_score = score;
+                best_ix = ix;
+            }
+            alpha = alpha.max(score);
+            if alpha >= beta {
+                self.stats.count_cutoff(n);
+                self.remember_cutoff(m, depth, ply);
+                break;
+            }
+        }
+
+        let bound = if best_score <= alpha_orig {
+            Bound::Upper
+        } else if best_score >= beta {
+            Bound::Lower
+        } else {
+            Bound::Exact
+        };
+        self.tt.store(
+            key,
+            Entry {
+                score: score_to_tt(best_score, self.game.ply_to_be_played()),
+                depth: depth as u8,
+                bound,
+                best_move: Some(best_ix as u16),
+            },
+        );
+
+        if ply == 0 {
+            self.root_best = Some(best_ix);
+        }
+        Some(best_score)
+    }
+
+    /// Searches fights and winning moves beyond the horizon, so that
+    /// positions where they are hanging are not evaluated statically.
+    /// The player to move can "stand pat" and rely on the static
+    /// evaluation instead.
+    fn quiesce(&mut self, depth: u32, mut alpha: i32, beta: i32) -> Option<i32> {
+        self.stats.qnodes += 1;
+        let stand_pat = self.evaluate();
+        if depth == 0 || self.game.is_game_over() || stand_pat >= beta {
+            return Some(stand_pat);
+        }
+        alpha = alpha.max(stand_pat);
+
+        let win = won_by(self.game.player1_moves);
+        let mut best_score = stand_pat;
+        for m in self.game.generate_moves() {
+            let fight_result = self.game.apply_move_unchecked(&m);
+            if fight_result.is_none() && self.game.result != win {
+                self.game.undo_move(&m, fight_result);
+                continue;
+            }
+            let score = if self.visit() {
+                None
+            } else {
+                self.quiesce(depth - 1, -beta, -alpha).map(|s| -s)
+            };
+            self.game.undo_move(&m, fight_result);
+            let score = score?;
+
+            best_score = best_score.max(score);
+            alpha = alpha.max(score);
+            if alpha >= beta {
+                break;
+            }
+        }
+        Some(best_score)
+    }
+
+    /// Returns indices of `moves` in the order they should be
+    /// searched.
+    fn order_moves(&mut self, moves: &[GameMove<Coord>], tt_move: Option<usize>, ply: u32) -> Vec<usize> {
+        let no_killers = [None, None];
+        let killers = self.killers.get(ply as usize).unwrap_or(&no_killers).clone();
+
+        let mut prioritized: Vec<(u32, usize)> = moves
+            .iter()
+            .enumerate()
+            .map(|(ix, m)| {
+                let priority = if tt_move == Some(ix) {
+                    TT_MOVE_PRIORITY
+                } else if self.wins_immediately(m) {
+                    WINNING_MOVE_PRIORITY
+                } else if let GameMove::Fight(_) = m {
+                    FIGHT_PRIORITY
+                } else if killers.iter().any(|k| k.as_ref() == Some(m)) {
+                    KILLER_PRIORITY
+                } else {
+                    self.history.get(m).map_or(0, |&h| h.min(KILLER_PRIORITY - 1))
+                };
+                (priority, ix)
+            })
+            .collect();
+
+        // Stable, so equal moves are kept in generation order.
+        prioritized.sort_by_key(|&(priority, _)| Reverse(priority));
+        prioritized.into_iter().map(|(_, ix)| ix).collect()
+    }
+
+    fn wins_immediately(&mut self, m: &GameMove<Coord>) -> bool {
+        let win = won_by(self.game.player1_moves);
+        let fight_result = self.game.apply_move_unchecked(m);
+        let result = self.game.result;
+        self.game.undo_move(m, fight_result);
+        result == win
+    }
+
+    /// Updates killer moves and history scores after `m` has caused
+    /// a cutoff.
+    fn remember_cutoff(&mut self, m: &GameMove<Coord>, depth: u32, ply: u32) {
+        if let GameMove::Fight(_) = m {
+            return;
+        }
+
+        let ply = ply as usize;
+        if self.killers.len() <= ply {
+            self.killers.resize(ply + 1, [None, None]);
+        }
+        let killers = &mut self.killers[ply];
+        if killers[0].as_ref() != Some(m) {
+            killers[1] = killers[0].take();
+            killers[0] = Some(m.clone());
+        }
+
+        let history = self.history.entry(m.clone()).or_insert(0);
+        *history = history.saturating_add(depth * depth);
+    }
+
+    /// Score of a draw for the player to move at `ply`.
+    fn draw_score(&self, ply: u32) -> i32 {
+        if ply.is_multiple_of(2) {
+            -self.contempt
+        } else {
+            self.contempt
+        }
+    }
+
+    /// Static evaluation from the perspective of the player to move.
+    fn evaluate(&self) -> i32 {
+        evaluate_for_player(&self.game, self.game.player1_moves, self.evaluator)
+    }
+
+    /// Follows the best moves stored in the transposition table.
+    fn principal_variation(&self, best_move: GameMove<Coord>, depth: u32) -> Vec<GameMove<Coord>> {
+        let mut game = self.game.clone();
+        game.apply_move_unchecked(&best_move);
+        let mut pv = vec![best_move];
+
+        while pv.len() < depth as usize && !game.is_game_over() {
+            let entry = match self.tt.probe(game.position_hash()) {
+                Some(entry) => entry,
+                None => break,
+            };
+            let moves = game.generate_moves();
+            match entry.best_move.and_then(|ix| moves.get(usize::from(ix))) {
+                Some(m) => {
+                    game.apply_move_unchecked(m);
+                    pv.push(m.clone());
+                }
+                None => break,
+            }
+        }
+
+        pv
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::ai::win_score;
+    use crate::board::Layout;
+    use crate::card::{Deck, DiceColor, Die};
+    use crate::clock::StepCounter;
+    use crate::coord::UserCoord;
+    use crate::game::Rules;
+    use failure::Fallible;
+
+    #[test]
+    fn test_search_tree() -> Fallible<()> {
+        let game = Game::new(Layout::Bricks7, Deck::ordered("jjjjggg")?, Rules::new(true, false));
+        let tt = TranspositionTable::new(16);
+        let limits = SearchLimits {
+            depth: Some(3),
+            ..Default::default()
+        };
+        let (result, tree) = search_with_tree(&game, 1, limits, &Evaluator::default(), &tt, None, 1).unwrap();
+
+        // One root per iteration, with all the moves of the root
+        // searched below it.
+        let roots: Vec<_> = tree.nodes.iter().filter(|n| n.parent.is_none()).collect();
+        assert_eq!(roots.len(), 3);
+        assert_eq!(roots[2].score, Some(result.score));
+        let moves = game.generate_moves().len();
+        assert_eq!(tree.nodes.iter().filter(|n| n.parent == Some(0)).count(), moves);
+        assert!(tree.nodes.iter().all(|n| n.ply <= 1 && n.score.is_some()));
+
+        assert!(tree.to_dot().starts_with("digraph search {"));
+        assert_eq!(tree.to_dot().matches(" -> ").count(), tree.nodes.len() - 3);
+        assert_eq!(tree.to_json().matches("\"parent\"").count(), tree.nodes.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quiescence() -> Fallible<()> {
+        let red = |row, card| GameMove::<UserCoord>::Place(Die::new(DiceColor::Red, 2), UserCoord::new(row, card));
+        let black = |row, card| GameMove::Place(Die::new(DiceColor::Black, 1), UserCoord::new(row, card));
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("ggggggg")?, Rules::new(false, false));
+        for m in &[red(2, 2), black(1, 1), red(2, 3)] {
+            game.apply_user_move(m)?;
+        }
+
+        let tt = TranspositionTable::new(16);
+        let limits = SearchLimits {
+            depth: Some(1),
+            ..Default::default()
+        };
+        // Black can't block both rows, which is seen beyond the horizon.
+        let result = search(&game, 1, limits, &Evaluator::default(), &tt, None).unwrap();
+        assert_eq!(result.depth, 1);
+        assert_eq!(result.score, -win_score(game.ply_to_be_played() + 2));
+        assert!(result.stats.qnodes > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_tt_entry() {
+        let tt = TranspositionTable::new(4);
+        let entry = Entry {
+            score: -INFINITY,
+            depth: 7,
+            bound: Bound::Upper,
+            best_move: Some(42),
+        };
+        tt.store(12345, entry);
+        assert_eq!(tt.probe(12345), Some(entry));
+        assert_eq!(tt.probe(12346), None);
+
+        // Shallower results don't replace deeper ones.
+        tt.store(12345, Entry { depth: 3, ..entry });
+        assert_eq!(tt.probe(12345), Some(entry));
+
+        tt.clear();
+        assert_eq!(tt.probe(12345), None);
+    }
+
+    #[test]
+    fn test_contempt() -> Fallible<()> {
+        let place =
+            |color, val, row, card| GameMove::<UserCoord>::Place(Die::new(color, val), UserCoord::new(row, card));
+        let mov = |color, val, from: (u8, u8), to: (u8, u8)| {
+            GameMove::Move(
+                Die::new(color, val),
+                UserCoord::new(from.0, from.1),
+                UserCoord::new(to.0, to.1),
+            )
+        };
+
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("jgjjjgg")?, Rules::new(false, false));
+        for m in &[
+            place(DiceColor::Red, 2, 2, 1),
+            place(DiceColor::Black, 1, 1, 1),
+            place(DiceColor::Red, 2, 2, 2),
+            place(DiceColor::Black, 1, 1, 2),
+            mov(DiceColor::Red, 2, (2, 1), (1, 2)),
+            mov(DiceColor::Black, 1, (1, 1), (2, 3)),
+            mov(DiceColor::Red, 2, (1, 2), (2, 1)),
+        ] {
+            game.apply_user_move(m)?;
+        }
+        // Moving the black die back repeats the position.
+        let repeating = game.convert_move_coords(&mov(DiceColor::Black, 1, (2, 3), (1, 1)))?;
+
+        let search_with = |contempt| {
+            let limits = SearchLimits {
+                depth: Some(2),
+                contempt,
+                ..Default::default()
+            };
+            search(
+                &game,
+                1,
+                limits,
+                &Evaluator::default(),
+                &TranspositionTable::new(16),
+                None,
+            )
+            .unwrap()
+        };
+
+        // Draw is better than anything else if we think we are weaker.
+        let result = search_with(-1000);
+        assert_eq!(result.score, 1000);
+        assert_eq!(result.best_move(), &repeating);
+        assert!(result.stats.repetitions > 0);
+
+        // And worse than anything if we think we are stronger.
+        let result = search_with(1000);
+        assert!(result.score > -1000);
+        assert_ne!(result.best_move(), &repeating);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_search_finds_win() -> Fallible<()> {
+        let red = |row, card| GameMove::<UserCoord>::Place(Die::new(DiceColor::Red, 2), UserCoord::new(row, card));
+        let black = |row, card| GameMove::Place(Die::new(DiceColor::Black, 1), UserCoord::new(row, card));
+        let mov = |color, val, from: (u8, u8), to: (u8, u8)| {
+            GameMove::Move(
+                Die::new(color, val),
+                UserCoord::new(from.0, from.1),
+                UserCoord::new(to.0, to.1),
+            )
+        };
+
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("jgjjjgg")?, Rules::new(false, false));
+        for m in &[
+            red(2, 3),
+            black(2, 1),
+            red(2, 4),
+            mov(DiceColor::Black, 1, (2, 1), (2, 3)),
+            red(1, 2),
+            black(2, 1),
+            red(2, 2),
+            mov(DiceColor::Black, 1, (2, 1), (2, 4)),
+            mov(DiceColor::Red, 2, (1, 2), (2, 1)),
+            mov(DiceColor::Black, 1, (2, 4), (2, 2)),
+            mov(DiceColor::Red, 2, (2, 1), (1, 2)),
+        ] {
+            game.apply_user_move(m)?;
+        }
+
+        let tt = TranspositionTable::new(16);
+        let limits = SearchLimits {
+            depth: Some(3),
+            ..Default::default()
+        };
+        let result = search(&game, 4, limits, &Evaluator::default(), &tt, None).unwrap();
+        assert_eq!(result.score, win_score(game.ply_to_be_played() + 1));
+        assert!(result.stats.nodes > 0);
+
+        game.apply_move(result.best_move())?;
+        assert!(game.is_game_over());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_source() -> Fallible<()> {
+        let game = Game::new(Layout::Bricks7, Deck::ordered("jjjjggg")?, Rules::default());
+        let tt = TranspositionTable::new(16);
+        let time = Arc::new(StepCounter::new(100.0));
+        let limits = SearchLimits {
+            duration: Some(Duration::from_secs(1)),
+            time: Some(time.clone()),
+            ..Default::default()
+        };
+
+        // The clock is read at the start, every `CHECK_TIME_EVERY`
+        // nodes until the time is up and at the end.
+        let result = search(&game, 1, limits, &Evaluator::default(), &tt, None).unwrap();
+        assert_eq!(time.steps(), 12);
+        assert_eq!(result.stats.nodes, 10 * CHECK_TIME_EVERY);
+        assert_eq!(result.elapsed, Duration::from_millis(1100));
+        Ok(())
+    }
+
+    #[test]
+    fn test_move_ordering() -> Fallible<()> {
+        let mut game = Game::new(Layout::Bricks7, Deck::ordered("gggjjjj")?, Rules::default());
+        game.apply_user_move(&GameMove::<UserCoord>::Place(
+            Die::new(DiceColor::Red, 2),
+            UserCoord::new(2, 2),
+        ))?;
+
+        let tt = TranspositionTable::new(16);
+        let limits = SearchLimits {
+            depth: Some(2),
+            ..Default::default()
+        };
+        let stats = search(&game, 1, limits, &Evaluator::default(), &tt, None)
+            .unwrap()
+            .stats;
+        assert!(stats.nodes > 0);
+        assert!(stats.tt_hits > 0);
+        assert!(stats.tt_probes >= stats.tt_hits);
+        assert!(stats.qnodes > 0 && stats.qnodes < stats.nodes);
+        assert_eq!(stats.cutoffs_by_move.iter().sum::<u64>(), stats.cutoffs);
+        assert!(stats.cutoffs > 0);
+        assert!(stats.first_move_cutoff_rate() > 0.5);
+
+        Ok(())
+    }
+}
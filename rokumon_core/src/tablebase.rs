@@ -0,0 +1,255 @@
+//! Endgame tablebases: all the positions reachable from a given one
+//! (usually with few dice left in the stocks) solved exactly with
+//! retrograde analysis.
+
+use crate::ai::win_score;
+use crate::game::{Game, GameResult};
+
+use failure::{bail, Fallible};
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+
+/// Exact value of a position for the player to move.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Win in the given number of plies.
+    Win(u16),
+    /// Loss in the given number of plies.
+    Loss(u16),
+    /// Neither player can force a win, the game goes on forever.
+    Draw,
+}
+
+/// Outcomes are packed in 16 bits: 2 bits for the kind and the rest
+/// for the distance.
+const DISTANCE_BITS: u32 = 14;
+const MAX_DISTANCE: u16 = (1 << DISTANCE_BITS) - 1;
+const WIN_TAG: u16 = 1 << DISTANCE_BITS;
+const LOSS_TAG: u16 = 2 << DISTANCE_BITS;
+
+impl Outcome {
+    /// Score compatible with the AI evaluation (see `ai::win_score`)
+    /// for the player to move in a position on a given ply.
+    pub fn score(self, ply: usize) -> i32 {
+        match self {
+            Outcome::Win(distance) => win_score(ply + usize::from(distance)),
+            Outcome::Loss(distance) => -win_score(ply + usize::from(distance)),
+            Outcome::Draw => 0,
+        }
+    }
+
+    fn pack(self) -> u16 {
+        match self {
+            Outcome::Win(distance) => WIN_TAG | distance,
+            Outcome::Loss(distance) => LOSS_TAG | distance,
+            Outcome::Draw => 0,
+        }
+    }
+
+    fn unpack(data: u16) -> Self {
+        let distance = data & MAX_DISTANCE;
+        match data & !MAX_DISTANCE {
+            WIN_TAG => Outcome::Win(distance),
+            LOSS_TAG => Outcome::Loss(distance),
+            _ => Outcome::Draw,
+        }
+    }
+}
+
+/// Solved positions, stored as sorted position hashes (see
+/// `Game::position_hash`) with packed outcomes.
+#[derive(Debug, Clone, Default)]
+pub struct Tablebase {
+    keys: Vec<u64>,
+    outcomes: Vec<u16>,
+}
+
+impl Tablebase {
+    /// Enumerates all the positions reachable from `root` and solves
+    /// them. Fails if there are more than `max_positions` of them.
+    pub fn generate(root: &Game, max_positions: usize) -> Fallible<Self> {
+        // Graph of positions: successors of position `i` are
+        // `children[start[i]..start[i + 1]]`.
+        let mut index: HashMap<u64, u32> = HashMap::new();
+        let mut keys = vec![root.position_hash()];
+        let mut start = vec![];
+        let mut children = vec![];
+        let mut values: Vec<Option<Outcome>> = vec![];
+
+        index.insert(keys[0], 0);
+        let mut queue = VecDeque::new();
+        queue.push_back(root.clone());
+
+        // Positions are expanded in the order of their indices.
+        while let Some(mut game) = queue.pop_front() {
+            start.push(children.len());
+            let moves = game.generate_moves();
+            values.push(match game.result {
+                GameResult::InProgress if !moves.is_empty() => None,
+                GameResult::FirstPlayerWon if game.player1_moves => Some(Outcome::Win(0)),
+                GameResult::SecondPlayerWon if !game.player1_moves => Some(Outcome::Win(0)),
+                // The player to move has lost or has no moves.
+                _ => Some(Outcome::Loss(0)),
+            });
+
+            for m in &moves {
+                let fight_result = game.apply_move_unchecked(m);
+                let key = game.position_hash();
+                let ix = match index.entry(key) {
+                    Entry::Occupied(e) => *e.get(),
+                    Entry::Vacant(e) => {
+                        if keys.len() >= max_positions {
+                            bail!("Tablebase would have more than {} positions", max_positions);
+                        }
+                        let ix = keys.len() as u32;
+                        keys.push(key);
+                        e.insert(ix);
+                        queue.push_back(game.clone());
+                        ix
+                    }
+                };
+                game.undo_move(m, fight_result);
+                children.push(ix);
+            }
+        }
+        start.push(children.len());
+
+        // Retrograde analysis: going back from the solved positions
+        // in the order of their distances. A position is won if there
+        // is a move to a lost one and lost if all the moves lead to
+        // won ones. Everything else is a draw.
+        let n = values.len();
+        let mut parents_start = vec![0; n + 1];
+        for &child in &children {
+            parents_start[child as usize + 1] += 1;
+        }
+        for i in 0..n {
+            parents_start[i + 1] += parents_start[i];
+        }
+        let mut parents = vec![0; children.len()];
+        let mut next_parent = parents_start.clone();
+        for parent in 0..n {
+            for &child in &children[start[parent]..start[parent + 1]] {
+                parents[next_parent[child as usize]] = parent as u32;
+                next_parent[child as usize] += 1;
+            }
+        }
+
+        let mut unsolved_children: Vec<usize> = (0..n).map(|i| start[i + 1] - start[i]).collect();
+        let mut queue: VecDeque<usize> = (0..n).filter(|&i| values[i].is_some()).collect();
+        while let Some(child) = queue.pop_front() {
+            for &parent in &parents[parents_start[child]..parents_start[child + 1]] {
+                let parent = parent as usize;
+                if values[parent].is_some() {
+                    continue;
+                }
+                match values[child] {
+                    Some(Outcome::Loss(d)) if d < MAX_DISTANCE => {
+                        values[parent] = Some(Outcome::Win(d + 1));
+                        queue.push_back(parent);
+                    }
+                    Some(Outcome::Win(d)) if d < MAX_DISTANCE => {
+                        unsolved_children[parent] -= 1;
+                        // Children come in the order of distances, so
+                        // this is the longest way to lose.
+                        if unsolved_children[parent] == 0 {
+                            values[parent] = Some(Outcome::Loss(d + 1));
+                            queue.push_back(parent);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut entries: Vec<(u64, u16)> = keys
+            .into_iter()
+            .zip(values)
+            .map(|(key, value)| (key, value.unwrap_or(Outcome::Draw).pack()))
+            .collect();
+        entries.sort_unstable();
+
+        Ok(Tablebase {
+            keys: entries.iter().map(|&(key, _)| key).collect(),
+            outcomes: entries.iter().map(|&(_, outcome)| outcome).collect(),
+        })
+    }
+
+    /// Number of positions in the tablebase.
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Outcome of the position if it's in the tablebase.
+    pub fn probe(&self, game: &Game) -> Option<Outcome> {
+        let ix = self.keys.binary_search(&game.position_hash()).ok()?;
+        Some(Outcome::unpack(self.outcomes[ix]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ai::Evaluator;
+    use crate::board::{Grid, Layout};
+    use crate::card::Deck;
+    use crate::coord::Coord;
+    use crate::game::Rules;
+    use crate::search::{self, SearchLimits, TranspositionTable};
+
+    #[test]
+    fn test_outcome_packing() {
+        for &outcome in &[
+            Outcome::Win(0),
+            Outcome::Win(17),
+            Outcome::Loss(MAX_DISTANCE),
+            Outcome::Draw,
+        ] {
+            assert_eq!(Outcome::unpack(outcome.pack()), outcome);
+        }
+        assert!(Outcome::Win(3).score(10) > Outcome::Win(5).score(10));
+        assert!(Outcome::Loss(3).score(10) < Outcome::Draw.score(10));
+    }
+
+    #[test]
+    fn test_tablebase() -> Fallible<()> {
+        // Three cards in a row, so that the game is small enough.
+        let coords = (0..3).map(|x| Coord::new_square(x, 0)).collect();
+        let layout = Layout::Custom(Grid::Square, coords);
+        let game = Game::new(layout, Deck::ordered("JGG")?, Rules::new(false, false));
+
+        assert!(Tablebase::generate(&game, 10).is_err());
+        let tb = Tablebase::generate(&game, 1000)?;
+        let outcome = tb.probe(&game).unwrap();
+        let d = match outcome {
+            Outcome::Win(d) => d,
+            _ => panic!("First player should win, got {:?}", outcome),
+        };
+        let score = outcome.score(game.ply_to_be_played());
+
+        // Search agrees with the tablebase...
+        let tt = TranspositionTable::new(16);
+        let limits = SearchLimits {
+            depth: Some(u32::from(d)),
+            ..Default::default()
+        };
+        let result = search::search(&game, 1, limits, &Evaluator::default(), &tt, None).unwrap();
+        assert_eq!(result.score, score);
+
+        // ... and doesn't need to look deep with it.
+        tt.clear();
+        let limits = SearchLimits {
+            depth: Some(1),
+            ..Default::default()
+        };
+        let result = search::search(&game, 1, limits, &Evaluator::default(), &tt, Some(&tb)).unwrap();
+        assert_eq!(result.score, score);
+        assert!(result.stats.tablebase_hits > 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+//! Game tree for analysis: the mainline of the game together with
+//! the variations branching off it at any ply.
+//!
+//! The tree keeps a single `Game` for the current node, moving
+//! between the nodes with `apply_move` and `undo_move`.
+
+use crate::coord::Coord;
+use crate::game::{FightResult, Game, GameMove, MoveOutcome};
+
+use failure::{ensure, Fallible};
+
+struct Node {
+    /// Move leading to this node (None for the root).
+    game_move: Option<GameMove<Coord>>,
+    /// Needed to take the move back.
+    fight_result: Option<FightResult>,
+    parent: Option<usize>,
+    /// The first child continues the main line of the node, the rest
+    /// are variations.
+    children: Vec<usize>,
+}
+
+pub struct GameTree {
+    game: Game,
+    nodes: Vec<Node>,
+    current: usize,
+}
+
+impl GameTree {
+    /// Creates a tree starting with the given position.
+    pub fn new(game: Game) -> Self {
+        GameTree {
+            game,
+            nodes: vec![Node {
+                game_move: None,
+                fight_result: None,
+                parent: None,
+                children: vec![],
+            }],
+            current: 0,
+        }
+    }
+
+    /// Position at the current node.
+    pub fn game(&self) -> &Game {
+        &self.game
+    }
+
+    /// Whether the current node is the root of the tree.
+    pub fn at_root(&self) -> bool {
+        self.current == 0
+    }
+
+    /// Plays a move from the current node. If it has been played there
+    /// before, we just follow the existing line, otherwise a new line
+    /// is started (it becomes the main line if it's the first one).
+    pub fn play(&mut self, game_move: &GameMove<Coord>) -> Fallible<MoveOutcome> {
+        let outcome = self.game.apply_move(game_move)?;
+        let existing = self.nodes[self.current]
+            .children
+            .iter()
+            .copied()
+            .find(|&c| self.nodes[c].game_move.as_ref() == Some(game_move));
+
+        self.current = match existing {
+            Some(child) => child,
+            None => {
+                let child = self.nodes.len();
+                self.nodes.push(Node {
+                    game_move: Some(game_move.clone()),
+                    fight_result: outcome.fight_result.clone(),
+                    parent: Some(self.current),
+                    children: vec![],
+                });
+                self.nodes[self.current].children.push(child);
+                child
+            }
+        };
+        Ok(outcome)
+    }
+
+    /// Goes one ply back. Returns false at the root.
+    pub fn back(&mut self) -> bool {
+        let node = &self.nodes[self.current];
+        match (node.parent, &node.game_move) {
+            (Some(parent), Some(m)) => {
+                self.game.undo_move(m, node.fight_result.clone());
+                self.current = parent;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Goes one ply forward along the main line of the current node.
+    /// Returns false at the end of the line.
+    pub fn forward(&mut self) -> bool {
+        self.enter_variation(0).is_ok()
+    }
+
+    /// Goes one ply forward along the given continuation (0 is the
+    /// main line, see `variations`).
+    pub fn enter_variation(&mut self, ix: usize) -> Fallible<()> {
+        let children = &self.nodes[self.current].children;
+        ensure!(ix < children.len(), "No variation #{} here", ix);
+        let child = children[ix];
+        self.game
+            .apply_move_unchecked(self.nodes[child].game_move.as_ref().unwrap());
+        self.current = child;
+        Ok(())
+    }
+
+    /// Goes back to the starting position.
+    pub fn to_root(&mut self) {
+        while self.back() {}
+    }
+
+    /// Goes forward to the end of the main line of the current node.
+    pub fn to_end(&mut self) {
+        while self.forward() {}
+    }
+
+    /// Moves continuing the current node, main line first.
+    pub fn variations(&self) -> Vec<&GameMove<Coord>> {
+        self.nodes[self.current]
+            .children
+            .iter()
+            .map(|&c| self.nodes[c].game_move.as_ref().unwrap())
+            .collect()
+    }
+
+    /// Moves leading from the root to the current node.
+    pub fn current_line(&self) -> Vec<GameMove<Coord>> {
+        let mut line = vec![];
+        let mut n = self.current;
+        while let (Some(parent), Some(m)) = (self.nodes[n].parent, &self.nodes[n].game_move) {
+            line.push(m.clone());
+            n = parent;
+        }
+        line.reverse();
+        line
+    }
+
+    /// Moves of the main line of the whole game.
+    pub fn mainline(&self) -> Vec<GameMove<Coord>> {
+        let mut line = vec![];
+        let mut n = 0;
+        while let Some(&child) = self.nodes[n].children.first() {
+            line.push(self.nodes[child].game_move.clone().unwrap());
+            n = child;
+        }
+        line
+    }
+
+    /// Makes the line leading to the current node the main line at
+    /// every fork on the way.
+    pub fn promote(&mut self) {
+        let mut n = self.current;
+        while let Some(parent) = self.nodes[n].parent {
+            let children = &mut self.nodes[parent].children;
+            let ix = children.iter().position(|&c| c == n).unwrap();
+            children[..=ix].rotate_right(1);
+            n = parent;
+        }
+    }
+
+    /// Removes the current node with all its continuations and goes
+    /// one ply back. Does nothing at the root.
+    pub fn delete_line(&mut self) {
+        let n = self.current;
+        if self.back() {
+            self.nodes[self.current].children.retain(|&c| c != n);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::board::Layout;
+    use crate::card::Deck;
+    use crate::game::Rules;
+
+    #[test]
+    fn test_game_tree() -> Fallible<()> {
+        let game = Game::new(Layout::Bricks7, Deck::ordered("jgjjjgg")?, Rules::default());
+        let mut tree = GameTree::new(game.clone());
+
+        let play_last = |tree: &mut GameTree| -> Fallible<()> {
+            let m = tree.game().generate_moves().into_iter().last().unwrap();
+            tree.play(&m)?;
+            Ok(())
+        };
+        for _ in 0..4 {
+            play_last(&mut tree)?;
+        }
+        let mainline = tree.mainline();
+        assert_eq!(mainline.len(), 4);
+        assert_eq!(tree.game().moves(), mainline);
+
+        // Branch off after the second ply.
+        tree.back();
+        tree.back();
+        let first = tree.game().generate_moves()[0].clone();
+        tree.play(&first)?;
+        play_last(&mut tree)?;
+        assert_eq!(tree.mainline(), mainline);
+        assert_eq!(tree.current_line()[..2], mainline[..2]);
+        assert_eq!(tree.current_line()[2], first);
+
+        // Replaying a known move follows the existing line.
+        let nodes = tree.nodes.len();
+        tree.back();
+        tree.back();
+        tree.play(&first)?;
+        assert_eq!(tree.nodes.len(), nodes);
+        tree.back();
+        assert_eq!(tree.variations(), vec![&mainline[2], &first]);
+
+        // Promoting the variation makes it the main line.
+        tree.enter_variation(1)?;
+        tree.to_end();
+        let variation = tree.current_line();
+        tree.promote();
+        assert_eq!(tree.mainline(), variation);
+
+        // Positions are restored when going back.
+        tree.to_root();
+        assert!(tree.at_root());
+        assert!(tree.game().history.is_empty());
+        assert_eq!(tree.game().board.cards, game.board.cards);
+        assert!(tree.enter_variation(1).is_err());
+
+        // Deleting the line brings back the old main line.
+        tree.to_end();
+        tree.back();
+        tree.delete_line();
+        assert_eq!(tree.mainline(), mainline);
+        assert_eq!(tree.game().moves(), mainline[..2]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,238 @@
+//! Automatic tuning of the evaluation weights (Texel's method): we
+//! play a number of fast self-play games, label every position with
+//! the result of its game and look for the weights which predict
+//! these results best.
+
+use crate::ai::{EvalTerms, EvalWeights, Evaluator};
+use crate::coord::Coord;
+use crate::game::{Game, GameMove};
+use crate::match_runner::{self, MatchConfig, PrintingMatchObserver};
+use crate::play::{GameObserver, GameReport, PlaySettings, Strategy};
+use crate::search::{self, SearchLimits, TranspositionTable};
+
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::cell::Cell;
+
+/// Settings of the tuning.
+#[derive(Debug, Clone)]
+pub struct TuneConfig {
+    /// Number of self-play games to generate positions from.
+    pub games: u32,
+    /// Seed for the games and the random moves in them.
+    pub seed: u64,
+    /// Search depth of the players.
+    pub depth: u32,
+    /// Games are adjudicated as draws after this number of plies.
+    pub max_plies: usize,
+    /// Probability of a random move (so that the games differ).
+    pub random_move_probability: f64,
+    /// Maximal number of passes over all the weights.
+    pub max_iterations: u32,
+}
+
+impl Default for TuneConfig {
+    fn default() -> Self {
+        TuneConfig {
+            games: 100,
+            seed: 0,
+            depth: 2,
+            max_plies: 200,
+            random_move_probability: 0.1,
+            max_iterations: 50,
+        }
+    }
+}
+
+/// Position from a self-play game along with the game result from
+/// the first player's perspective (1 for a win, 0.5 for a draw and 0
+/// for a loss).
+#[derive(Debug, Clone, Copy)]
+pub struct Sample {
+    pub terms: EvalTerms,
+    pub result: f64,
+}
+
+/// Quiet player for self-play games: searches to a fixed depth, but
+/// sometimes plays a random move.
+struct SelfPlayAI {
+    depth: u32,
+    evaluator: Evaluator,
+    random_move_probability: f64,
+    rng: StdRng,
+    tt: TranspositionTable,
+}
+
+impl Strategy for SelfPlayAI {
+    fn get_move(&mut self, game: &Game) -> GameMove<Coord> {
+        if self.rng.gen_bool(self.random_move_probability) {
+            return game.generate_moves().choose(&mut self.rng).unwrap().clone();
+        }
+
+        let limits = SearchLimits {
+            depth: Some(self.depth),
+            ..Default::default()
+        };
+        let result = search::search(game, 1, limits, &self.evaluator, &self.tt, None).unwrap();
+        result.best_move().clone()
+    }
+}
+
+/// Collects positions of the games with their results.
+#[derive(Default)]
+struct SampleCollector {
+    positions: Vec<EvalTerms>,
+    samples: Vec<Sample>,
+}
+
+impl GameObserver for SampleCollector {
+    fn on_move_applied(&mut self, game: &Game, _mov: &GameMove<Coord>) {
+        // Decided positions don't need evaluation.
+        if !game.is_game_over() {
+            self.positions.push(EvalTerms::of(game));
+        }
+    }
+
+    fn on_game_over(&mut self, report: &GameReport) {
+        let result = (f64::from(report.score()) + 1.0) / 2.0;
+        self.samples
+            .extend(self.positions.drain(..).map(|terms| Sample { terms, result }));
+    }
+}
+
+/// Plays self-play games with `weights` and returns all their
+/// positions. `new_game` creates a game from a seed.
+pub fn generate_samples(
+    config: &TuneConfig,
+    new_game: impl FnMut(Option<u64>) -> Game,
+    weights: &EvalWeights,
+) -> Vec<Sample> {
+    let match_config = MatchConfig {
+        games: config.games,
+        seed: Some(config.seed),
+        settings: PlaySettings {
+            max_plies: Some(config.max_plies),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    let next_seed = Cell::new(config.seed);
+    let new_player = |_| {
+        next_seed.set(next_seed.get().wrapping_add(1));
+        SelfPlayAI {
+            depth: config.depth,
+            evaluator: Evaluator::Terms(*weights),
+            random_move_probability: config.random_move_probability,
+            rng: StdRng::seed_from_u64(next_seed.get()),
+            tt: TranspositionTable::new(16),
+        }
+    };
+
+    let mut collector = SampleCollector::default();
+    match_runner::run_match(
+        &match_config,
+        new_game,
+        new_player,
+        new_player,
+        &mut [&mut collector],
+        &mut PrintingMatchObserver,
+    );
+    collector.samples
+}
+
+/// Mean squared error of the game results predicted from the
+/// evaluations. Evaluation is converted to the expected result with a
+/// logistic function, `k` is its scale.
+pub fn prediction_error(samples: &[Sample], weights: &EvalWeights, k: f64) -> f64 {
+    let error: f64 = samples
+        .iter()
+        .map(|s| {
+            let predicted = 1.0 / (1.0 + (-f64::from(s.terms.score(weights)) / k).exp());
+            (s.result - predicted).powi(2)
+        })
+        .sum();
+    error / samples.len().max(1) as f64
+}
+
+/// Finds the scale for the logistic function which fits the current
+/// weights best.
+fn best_scale(samples: &[Sample], weights: &EvalWeights) -> f64 {
+    let mut best = (f64::INFINITY, 1.0);
+    let mut k = 1.0;
+    while k <= 1000.0 {
+        let error = prediction_error(samples, weights, k);
+        if error < best.0 {
+            best = (error, k);
+        }
+        k *= 1.25;
+    }
+    best.1
+}
+
+/// Tunes the weights with a local search: tries changing every weight
+/// by one up and down and keeps the changes which reduce the
+/// prediction error, until nothing helps. Returns the tuned weights
+/// and their prediction error.
+pub fn tune(samples: &[Sample], initial: &EvalWeights, max_iterations: u32) -> (EvalWeights, f64) {
+    let k = best_scale(samples, initial);
+    let mut weights = *initial;
+    let mut best_error = prediction_error(samples, &weights, k);
+
+    for iteration in 1..=max_iterations {
+        let mut improved = false;
+        for name in EvalWeights::NAMES.iter() {
+            let value = weights.get(name).unwrap();
+            for &delta in &[1, -1] {
+                let mut candidate = weights;
+                candidate.set(name, value + delta).unwrap();
+                let error = prediction_error(samples, &candidate, k);
+                if error < best_error {
+                    best_error = error;
+                    weights = candidate;
+                    improved = true;
+                    break;
+                }
+            }
+        }
+
+        println!("Iteration {}: error {:.6}, weights: {}", iteration, best_error, weights);
+        if !improved {
+            break;
+        }
+    }
+
+    (weights, best_error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample(triples: i32, result: f64) -> Sample {
+        Sample {
+            terms: EvalTerms {
+                triples,
+                ..Default::default()
+            },
+            result,
+        }
+    }
+
+    #[test]
+    fn test_tune() {
+        // Results are perfectly predicted by the `triples` term, so
+        // its weight should grow.
+        let samples: Vec<_> = (1..10).flat_map(|t| vec![sample(t, 1.0), sample(-t, 0.0)]).collect();
+        let initial = EvalWeights {
+            triples: 1,
+            ..Default::default()
+        };
+
+        let k = best_scale(&samples, &initial);
+        let (tuned, error) = tune(&samples, &initial, 10);
+        assert!(error < prediction_error(&samples, &initial, k));
+        assert!(tuned.triples > initial.triples);
+    }
+}
@@ -0,0 +1,79 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_wasm_bindgen::Serializer;
+use std::fmt;
+use wasm_bindgen::prelude::*;
+
+/// What has gone wrong, so that the frontend can react to errors
+/// without parsing the messages.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub enum ErrorKind {
+    /// Options the playground has been created with are not supported.
+    InvalidOptions,
+    /// Value sent from JS (or to it) can't be (de)serialized.
+    Serialization,
+    /// Position is not on the board or can't be given in user
+    /// coordinates.
+    InvalidCoord,
+    /// Move is not legal in the current position.
+    InvalidMove,
+    /// There are no moves to make since the game is over.
+    GameOver,
+    /// Bot can't move for a human.
+    NotBotsTurn,
+    /// Search has to be started before it's continued (and has to
+    /// find a move to be finished).
+    NoSearch,
+    /// Search depth is zero.
+    InvalidDepth,
+    /// Puzzle has no solution, or the solver can't find it.
+    InvalidPuzzle,
+    /// Puzzle methods are called for a game which is not a puzzle.
+    NotAPuzzle,
+    /// There is no playground with the given id.
+    UnknownPlayground,
+}
+
+/// Error thrown to JS as an `{ kind, message }` object.
+#[derive(Debug, Serialize)]
+struct Error {
+    kind: ErrorKind,
+    message: String,
+}
+
+pub fn js_error(kind: ErrorKind, message: impl fmt::Display) -> JsValue {
+    let message = message.to_string();
+    let error = Error {
+        kind,
+        message: message.clone(),
+    };
+    error
+        .serialize(&Serializer::json_compatible())
+        .unwrap_or_else(|_| JsValue::from_str(&message))
+}
+
+/// Converts errors of any kind to JS errors.
+pub trait OrJsError<T> {
+    fn or_js_error(self, kind: ErrorKind) -> Result<T, JsValue>;
+}
+
+impl<T, E: fmt::Display> OrJsError<T> for Result<T, E> {
+    fn or_js_error(self, kind: ErrorKind) -> Result<T, JsValue> {
+        self.map_err(|e| js_error(kind, e))
+    }
+}
+
+/// Serializes a value to be sent to JS as the given (TypeScript) type.
+/// Values are sent as they would be in JSON (options as nulls, maps as
+/// objects), which is what the types in `types.rs` describe.
+pub fn to_js<T: Serialize, J: JsCast>(value: &T) -> Result<J, JsValue> {
+    value
+        .serialize(&Serializer::json_compatible())
+        .map(JsCast::unchecked_into)
+        .or_js_error(ErrorKind::Serialization)
+}
+
+/// Deserializes a value sent from JS.
+pub fn from_js<T: DeserializeOwned>(value: &JsValue) -> Result<T, JsValue> {
+    serde_wasm_bindgen::from_value(value.clone()).or_js_error(ErrorKind::Serialization)
+}
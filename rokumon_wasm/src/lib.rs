@@ -1,20 +1,95 @@
+mod error;
+mod puzzle;
+mod types;
 mod utils;
+mod view;
 
+use failure::{bail, Fallible};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
 use wasm_bindgen::prelude::*;
 
-use rokumon_core::ai::AlphaBetaAI;
+use rokumon_core::ai::{
+    expected_result, pp_evaluation, search_for_first_player, AlphaBetaAI, Evaluator, HeuristicAI, NoLog, SteppedSearch,
+};
 use rokumon_core::board::Layout;
-use rokumon_core::card::Deck;
-use rokumon_core::coord::Coord;
-use rokumon_core::game::{Game, GameMove, Rules};
+use rokumon_core::card::{Deck, Die};
+use rokumon_core::clock::TimeSource;
+use rokumon_core::coord::{Coord, UserCoord};
+use rokumon_core::game::{Game, GameMove, GameResult, Rules, WinReason};
 use rokumon_core::play::Strategy;
+use rokumon_core::schema;
 
+use crate::error::{from_js, js_error, to_js, ErrorKind, OrJsError};
+use crate::puzzle::Puzzle;
+use crate::types::*;
+use crate::view::GameView;
+
+/// Cards dealt when no other ones are given (Rectangle6 uses six of
+/// them).
+const DEFAULT_CARDS: &str = "jjjjggg";
+
+/// Who plays the game: the first player is named first.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Mode {
+    HumanVsBot,
+    BotVsHuman,
+    HumanVsHuman,
+    /// Both bots are set up by the same options, unless the ones for
+    /// the second bot are given (see `Opts::with_second_bot`).
+    BotVsBot,
+}
+
+impl Mode {
+    fn is_bot(self, player1: bool) -> bool {
+        match self {
+            Mode::HumanVsBot => !player1,
+            Mode::BotVsHuman => player1,
+            Mode::HumanVsHuman => false,
+            Mode::BotVsBot => true,
+        }
+    }
+}
+
+/// Which player makes the first move, whoever plays them (see `Mode`).
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum FirstPlayer {
+    #[default]
+    Player1,
+    Player2,
+    /// Chosen by the seed, if it's given.
+    Random,
+}
+
+/// Game and bot settings. Beyond the ones passed to `new`, they can be
+/// changed with the `with_*` builder methods, mirroring the options of
+/// the console version.
 #[wasm_bindgen]
+#[derive(Serialize, Deserialize)]
 pub struct Opts {
     enable_fight: bool,
+    enable_surprise: bool,
     grid: String,
-    bot_goes_first: bool,
+    cards: Option<String>,
+    shuffle: bool,
+    seed: Option<u32>,
+    mode: Mode,
+    #[serde(default)]
+    first_player: FirstPlayer,
+    /// Handicap like `extra:r4,placements:2` (see `Handicap`).
+    #[serde(default)]
+    handicap: Option<String>,
     duration: u8,
+    depth: Option<u32>,
+    to_completion: bool,
+    bot: String,
+    /// Bot settings of the second player in `BotVsBot` games.
+    second_bot: Option<Box<Opts>>,
 }
 
 #[wasm_bindgen]
@@ -22,64 +97,729 @@ impl Opts {
     pub fn new(enable_fight: bool, grid: String, bot_goes_first: bool, duration: u8) -> Self {
         Self {
             enable_fight,
+            enable_surprise: false,
             grid,
-            bot_goes_first,
+            cards: None,
+            shuffle: true,
+            seed: None,
+            mode: if bot_goes_first {
+                Mode::BotVsHuman
+            } else {
+                Mode::HumanVsBot
+            },
+            first_player: FirstPlayer::Player1,
+            handicap: None,
             duration,
+            depth: None,
+            to_completion: false,
+            bot: "ai".to_string(),
+            second_bot: None,
         }
     }
+
+    /// Makes the given player move first (the first one by default, so
+    /// with `bot_goes_first` the bot plays the first player and moves
+    /// first).
+    pub fn with_first_player(mut self, first_player: FirstPlayer) -> Self {
+        self.first_player = first_player;
+        self
+    }
+
+    /// Gives a handicap to one of the players, like
+    /// `extra:r4,remove:b5,placements:2` (see `Handicap`).
+    pub fn with_handicap(mut self, handicap: String) -> Self {
+        self.handicap = Some(handicap);
+        self
+    }
+
+    /// Sets who plays the game (overriding `bot_goes_first`).
+    pub fn with_mode(mut self, mode: Mode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets up the bot of the second player in `BotVsBot` games with
+    /// the bot settings (the kind, duration and depth) of `opts`.
+    pub fn with_second_bot(mut self, opts: Opts) -> Self {
+        self.second_bot = Some(Box::new(opts));
+        self
+    }
+
+    /// Picks the bot to play against: "ai" (the default) or the
+    /// friendlier "heuristic" one.
+    pub fn with_bot(mut self, bot: String) -> Self {
+        self.bot = bot;
+        self
+    }
+
+    /// Allows the Surprise move.
+    pub fn with_surprise(mut self, enable_surprise: bool) -> Self {
+        self.enable_surprise = enable_surprise;
+        self
+    }
+
+    /// Cards to deal, like "gggjjjj" (g - gold, j - jade).
+    pub fn with_cards(mut self, cards: String) -> Self {
+        self.cards = Some(cards);
+        self
+    }
+
+    /// Deals the cards in the given order.
+    pub fn without_shuffle(mut self) -> Self {
+        self.shuffle = false;
+        self
+    }
+
+    /// Shuffles the cards in the same way for the same seed.
+    pub fn with_seed(mut self, seed: u32) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Makes the bot search to the given depth (in plies) instead of
+    /// thinking for the given duration.
+    pub fn with_depth(mut self, depth: u32) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    /// Makes the bot search until the result of the game is known.
+    pub fn to_completion(mut self) -> Self {
+        self.to_completion = true;
+        self
+    }
 }
 
+impl Opts {
+    fn game(&self) -> Fallible<Game> {
+        let layout: Layout = self.grid.to_lowercase().parse()?;
+        let cards = self.cards.as_deref().unwrap_or(DEFAULT_CARDS);
+        let mut deck = if !self.shuffle {
+            Deck::ordered(cards)?
+        } else if let Some(seed) = self.seed {
+            Deck::shuffled_with_seed(cards, u64::from(seed))?
+        } else {
+            Deck::shuffled(cards)?
+        };
+        if self.cards.is_none() && layout == Layout::Rectangle6 {
+            deck.drop_one_card();
+        }
+        let rules = Rules::new(self.enable_fight, self.enable_surprise);
+        let rules = match self.first_player {
+            FirstPlayer::Player1 => rules,
+            FirstPlayer::Player2 => rules.with_player1_starting(false),
+            FirstPlayer::Random => rules.with_random_starting_player(self.seed.map(u64::from)),
+        };
+        let rules = match &self.handicap {
+            Some(handicap) => rules.with_handicap(handicap.parse()?)?,
+            None => rules,
+        };
+        Game::try_new(layout, deck, rules)
+    }
+
+    /// Bots of the first and the second player (if they are bots).
+    fn bots(&self) -> Fallible<[Option<Box<dyn Strategy>>; 2]> {
+        let second = match &self.second_bot {
+            Some(opts) if self.mode == Mode::BotVsBot => opts,
+            _ => self,
+        };
+        let bot = |opts: &Opts, first| {
+            if self.mode.is_bot(first) {
+                opts.bot(first).map(Some)
+            } else {
+                Ok(None)
+            }
+        };
+        Ok([bot(self, true)?, bot(second, false)?])
+    }
+
+    fn bot(&self, for_first_player: bool) -> Fallible<Box<dyn Strategy>> {
+        Ok(match self.bot.as_str() {
+            "ai" => {
+                let ai = if self.to_completion {
+                    AlphaBetaAI::to_completion(for_first_player)
+                } else if let Some(depth) = self.depth {
+                    AlphaBetaAI::with_depth(for_first_player, depth)
+                } else {
+                    AlphaBetaAI::with_duration(for_first_player, u64::from(self.duration))
+                };
+                Box::new(for_browser(ai))
+            }
+            "heuristic" => Box::new(HeuristicAI),
+            bot => bail!("Unexpected bot type: {}", bot),
+        })
+    }
+}
+
+/// A game with its players. JS objects share it, so that it can be
+/// kept in `Playgrounds` and used from JS at the same time.
 #[wasm_bindgen]
+#[derive(Clone)]
 pub struct Playground {
-    ai: AlphaBetaAI,
+    state: Rc<RefCell<PlaygroundState>>,
+}
+
+struct PlaygroundState {
+    /// Bots of the first and the second player (None for humans).
+    bots: [Option<Box<dyn Strategy>>; 2],
+    opts: Opts,
     game: Game,
+    /// Search started with `start_search` (dropped once a move is
+    /// made or taken back).
+    search: Option<SteppedSearch>,
+    /// Set for playgrounds created with `load_puzzle`.
+    puzzle: Option<Puzzle>,
+}
+
+/// Move suggested by the AI.
+#[derive(Serialize)]
+struct Hint {
+    #[serde(rename = "move")]
+    mov: GameMove<Coord>,
+    /// Score from the perspective of the player to move.
+    score: i32,
+    /// Human-readable score, like "win in 3".
+    evaluation: String,
+    pv: Vec<GameMove<Coord>>,
+}
+
+#[derive(Serialize)]
+struct Outcome {
+    result: GameResult,
+    reason: Option<WinReason>,
+    winning_coords: Vec<Coord>,
+}
+
+#[derive(Serialize)]
+struct PlayedMove {
+    #[serde(rename = "move")]
+    mov: GameMove<Coord>,
+    text: String,
+}
+
+/// Everything needed to restore a playground (see
+/// `Playground::serialize`). The game is saved with the version of its
+/// schema, so that it can be migrated (see `rokumon_core::schema`).
+#[derive(Serialize, Deserialize)]
+struct SavedPlayground<O = Opts> {
+    opts: O,
+    game: serde_json::Value,
+}
+
+impl SavedPlayground {
+    fn load(state: &str) -> Result<(Opts, Game), JsValue> {
+        let saved: SavedPlayground = serde_json::from_str(state).or_js_error(ErrorKind::Serialization)?;
+        let game = schema::from_value(saved.game).or_js_error(ErrorKind::Serialization)?;
+        Ok((saved.opts, game))
+    }
+}
+
+#[derive(Serialize)]
+struct Evaluation {
+    score: i32,
+    advantage: f64,
+    /// Human-readable score, like "win in 3".
+    evaluation: String,
+}
+
+#[derive(Serialize)]
+struct SearchProgress {
+    depth: u32,
+    best_move: Option<GameMove<Coord>>,
+    /// Score from the perspective of the player to move.
+    score: Option<i32>,
+    done: bool,
 }
 
-// TODO: handle errors better by changing return types to Result<T, JSValue> and automating conversion
-// of errors to JSValue (instead of just calling JsValue::from_str(&e.to_string())) all the time.
+#[wasm_bindgen]
+extern "C" {
+    /// Milliseconds since the page has been loaded.
+    #[wasm_bindgen(js_namespace = performance)]
+    fn now() -> f64;
+}
+
+/// Time of the page, for the AI (`Instant` is not available in
+/// browsers).
+struct PerformanceTime;
+
+impl TimeSource for PerformanceTime {
+    fn now_ms(&self) -> f64 {
+        now()
+    }
+}
+
+/// The AI with the time of the page and without the log (there is no
+/// console to print to).
+fn for_browser(mut ai: AlphaBetaAI) -> AlphaBetaAI {
+    ai.set_time_source(Arc::new(PerformanceTime));
+    ai.set_log(Arc::new(NoLog));
+    ai
+}
+
+/// All the methods throw `{ kind, message }` objects on errors (see
+/// `ErrorKind` for the kinds).
 #[wasm_bindgen]
 impl Playground {
-    pub fn new(opts: Opts) -> Self {
+    pub fn new(opts: Opts) -> Result<Playground, JsValue> {
         utils::set_panic_hook();
-        let game = match opts.grid.as_str() {
-            "Bricks7" => {
-                let deck = Deck::seven_shuffled();
-                Game::new(Layout::Bricks7, deck, Rules::new(opts.enable_fight, false))
-            }
-            "Rectangle6" => {
-                let deck = Deck::six_shuffled();
-                Game::new(Layout::Rectangle6, deck, Rules::new(opts.enable_fight, false))
+        let game = opts.game().or_js_error(ErrorKind::InvalidOptions)?;
+        Self::with_game(opts, game)
+    }
+
+    /// Restores the playground saved with `serialize`.
+    pub fn restore(state: &str) -> Result<Playground, JsValue> {
+        utils::set_panic_hook();
+        let (opts, game) = SavedPlayground::load(state)?;
+        Self::with_game(opts, game)
+    }
+
+    /// Loads a puzzle: the position is saved with `serialize` and the
+    /// solution starts with the move of the player to move, followed
+    /// by the replies of their opponent (it's found by the solver if
+    /// it's empty). The opponent's replies are made by `try_move`, so
+    /// there are no bots in puzzles.
+    pub fn load_puzzle(position: &str, solution_value: &JsGameMoves) -> Result<Playground, JsValue> {
+        utils::set_panic_hook();
+        let (opts, game) = SavedPlayground::load(position)?;
+        let solution: Vec<GameMove<Coord>> = from_js(solution_value)?;
+        let puzzle = Puzzle::new(&game, solution).or_js_error(ErrorKind::InvalidPuzzle)?;
+        let playground = Self::with_game(opts.with_mode(Mode::HumanVsHuman), game)?;
+        playground.state.borrow_mut().puzzle = Some(puzzle);
+        Ok(playground)
+    }
+
+    /// Saves the game with the options of the playground as a JSON
+    /// string (to be put in the local storage, for example).
+    pub fn serialize(&self) -> Result<String, JsValue> {
+        let state = self.state.borrow();
+        let saved = SavedPlayground {
+            opts: &state.opts,
+            game: schema::to_value(&state.game).or_js_error(ErrorKind::Serialization)?,
+        };
+        serde_json::to_string(&saved).or_js_error(ErrorKind::Serialization)
+    }
+
+    pub fn get_game(&self) -> Result<JsGame, JsValue> {
+        let state = self.state.borrow();
+        to_js(&state.game)
+    }
+
+    /// The game as it's drawn: cards with their positions and dice,
+    /// the stocks of the players and whose turn it is.
+    pub fn view(&self) -> Result<JsGameView, JsValue> {
+        let state = self.state.borrow();
+        to_js(&GameView::new(&state.game))
+    }
+
+    /// Why the game has been won (null while it's in progress), so
+    /// that the winning cards can be highlighted.
+    pub fn get_win_reason(&self) -> Result<JsOptionalWinReason, JsValue> {
+        let state = self.state.borrow();
+        to_js(&state.game.win_reason())
+    }
+
+    pub fn is_game_over(&self) -> bool {
+        let state = self.state.borrow();
+        state.game.is_game_over()
+    }
+
+    /// Result of the game as `{ result, reason, winning_coords }`, where
+    /// the reason is null while the game is in progress and the
+    /// winning coordinates are the cards to highlight (if any).
+    pub fn result(&self) -> Result<JsOutcome, JsValue> {
+        let state = self.state.borrow();
+        let reason = state.game.win_reason();
+        let winning_coords = match reason {
+            Some(WinReason::ThreeInARow { coords }) => coords.to_vec(),
+            Some(WinReason::ThreeInStack { coord }) => vec![coord],
+            _ => vec![],
+        };
+        to_js(&Outcome {
+            result: state.game.result,
+            reason,
+            winning_coords,
+        })
+    }
+
+    /// Player to move: 1 for the first player, 2 for the second one.
+    pub fn side_to_move(&self) -> u8 {
+        let state = self.state.borrow();
+        if state.game.player1_moves {
+            1
+        } else {
+            2
+        }
+    }
+
+    /// Moves played so far as `{ move, text }`, where the text is in
+    /// user coordinates, like "place r2 at r1c1".
+    pub fn history(&self) -> Result<JsPlayedMoves, JsValue> {
+        let state = self.state.borrow();
+        let history: Vec<_> = state
+            .game
+            .moves()
+            .into_iter()
+            .zip(state.game.described_moves())
+            .map(|(mov, described)| PlayedMove {
+                mov,
+                text: described.to_string(),
+            })
+            .collect();
+        to_js(&history)
+    }
+
+    /// Makes the move of the bot whose turn it is and returns it.
+    pub fn get_move(&self) -> Result<JsGameMove, JsValue> {
+        let state = &mut *self.state.borrow_mut();
+        if state.game.is_game_over() {
+            return Err(js_error(ErrorKind::GameOver, "The game is over"));
+        }
+        let bot = state.bots[usize::from(!state.game.player1_moves)]
+            .as_mut()
+            .ok_or_else(|| js_error(ErrorKind::NotBotsTurn, "It's a human's turn"))?;
+        let mov = bot.get_move(&state.game);
+        state.game.apply_move(&mov).or_js_error(ErrorKind::InvalidMove)?;
+        state.search = None;
+        to_js(&mov)
+    }
+
+    /// Makes the move of the bot if it's its turn and returns it (null
+    /// if it's a human's turn or the game is over). Call it repeatedly
+    /// to play bots against each other.
+    pub fn tick(&self) -> Result<JsOptionalGameMove, JsValue> {
+        let bots_turn = {
+            let state = self.state.borrow();
+            !state.game.is_game_over() && state.opts.mode.is_bot(state.game.player1_moves)
+        };
+        if !bots_turn {
+            return Ok(JsValue::NULL.unchecked_into());
+        }
+        self.get_move().map(JsCast::unchecked_into)
+    }
+
+    /// Starts searching for the move of the player to move in steps
+    /// (see `search_step`), up to `max_depth` plies if given (at least
+    /// one).
+    pub fn start_search(&self, max_depth: Option<u32>) -> Result<(), JsValue> {
+        let state = &mut *self.state.borrow_mut();
+        if state.game.is_game_over() {
+            return Err(js_error(ErrorKind::GameOver, "The game is over"));
+        }
+        if max_depth == Some(0) {
+            return Err(js_error(
+                ErrorKind::InvalidDepth,
+                "The search has to be at least a ply deep",
+            ));
+        }
+        state.search = Some(SteppedSearch::new(&state.game, Evaluator::default(), max_depth));
+        Ok(())
+    }
+
+    /// Continues the search for about `ms` milliseconds (it's
+    /// deepened a ply at a time, so the last ply may take longer) and
+    /// returns the progress as `{ depth, best_move, score, done }`.
+    pub fn search_step(&self, ms: u32) -> Result<JsSearchProgress, JsValue> {
+        let state = &mut *self.state.borrow_mut();
+        let search = state
+            .search
+            .as_mut()
+            .ok_or_else(|| js_error(ErrorKind::NoSearch, "The search hasn't been started"))?;
+        let deadline = now() + f64::from(ms);
+        loop {
+            search.step();
+            if search.is_over() || now() >= deadline {
+                break;
             }
-            "Hex7" => {
-                let deck = Deck::seven_shuffled();
-                Game::new(Layout::Hex7, deck, Rules::new(opts.enable_fight, false))
+        }
+        to_js(&SearchProgress {
+            depth: search.depth(),
+            best_move: search.best_move().cloned(),
+            score: search.score(),
+            done: search.is_over(),
+        })
+    }
+
+    /// Makes the best move found by the search and returns it.
+    pub fn finish_search(&self) -> Result<JsGameMove, JsValue> {
+        let state = &mut *self.state.borrow_mut();
+        let mut search = state
+            .search
+            .take()
+            .ok_or_else(|| js_error(ErrorKind::NoSearch, "The search hasn't been started"))?;
+        if search.best_move().is_none() {
+            search.step();
+        }
+        let mov = search
+            .best_move()
+            .cloned()
+            .ok_or_else(|| js_error(ErrorKind::NoSearch, "The search has found no move"))?;
+        state.game.apply_move(&mov).or_js_error(ErrorKind::InvalidMove)?;
+        to_js(&mov)
+    }
+
+    pub fn validate_move(&self, mov_value: &JsGameMove) -> Result<(), JsValue> {
+        let state = self.state.borrow();
+        let mov: GameMove<Coord> = from_js(mov_value)?;
+        state.game.validate_move(&mov).or_js_error(ErrorKind::InvalidMove)
+    }
+
+    /// Runs the AI for the player to move for the given number of
+    /// seconds and returns its suggestion as `{ move, score,
+    /// evaluation, pv }`, without making the move.
+    pub fn hint(&self, seconds: u32) -> Result<JsHint, JsValue> {
+        let state = self.state.borrow();
+        if state.game.is_game_over() {
+            return Err(js_error(ErrorKind::GameOver, "The game is over"));
+        }
+        let mut ai = for_browser(AlphaBetaAI::with_duration(state.game.player1_moves, u64::from(seconds)));
+        let mov = ai.get_move(&state.game);
+        let score = ai.evaluation().unwrap_or_default();
+        to_js(&Hint {
+            mov,
+            score,
+            evaluation: pp_evaluation(score, state.game.ply_to_be_played()),
+            pv: ai.principal_variation().to_vec(),
+        })
+    }
+
+    /// Takes back the last ply and returns the move taken back (null
+    /// if there are no moves to take back).
+    pub fn undo_ply(&self) -> Result<JsOptionalGameMove, JsValue> {
+        let state = &mut *self.state.borrow_mut();
+        to_js(&state.take_back())
+    }
+
+    /// Takes back the last move of a human together with the bot's
+    /// reply to it (if it has been made), so that it's the human's
+    /// turn again. Returns the moves taken back, the last one first.
+    pub fn undo_full_move(&self) -> Result<JsGameMoves, JsValue> {
+        let state = &mut *self.state.borrow_mut();
+        let human_ply = (0..state.game.ply_to_be_played())
+            .rev()
+            .find(|&ply| !state.opts.mode.is_bot(state.game.player1_plays_ply(ply)));
+        let mut moves = vec![];
+        if let Some(ply) = human_ply {
+            while state.game.ply_to_be_played() > ply {
+                moves.extend(state.take_back());
             }
-            _ => panic!("Unexpected grid type sent"),
+        }
+        to_js(&moves)
+    }
+
+    /// Evaluation of the position for an advantage bar, as `{ score,
+    /// advantage, evaluation }`: the score is from the first player's
+    /// perspective and the advantage is scaled to -1..1 (1 meaning the
+    /// first player has won). It's the static evaluation, or the
+    /// score of a search to the given depth.
+    pub fn evaluate(&self, depth: Option<u32>) -> Result<JsEvaluation, JsValue> {
+        let state = self.state.borrow();
+        let evaluator = Evaluator::default();
+        let score = search_for_first_player(&state.game, &evaluator, depth.unwrap_or(0));
+        to_js(&Evaluation {
+            score,
+            advantage: 2.0 * expected_result(score) - 1.0,
+            evaluation: pp_evaluation(score, state.game.ply_to_be_played()),
+        })
+    }
+
+    /// All the legal moves of the player to move (none once the game
+    /// is over).
+    pub fn legal_moves(&self) -> Result<JsGameMoves, JsValue> {
+        let state = self.state.borrow();
+        to_js(&state.legal_moves_iter().collect::<Vec<_>>())
+    }
+
+    /// Legal moves taking a die (or the card, for Surprise moves) from
+    /// the card at the given coordinate.
+    pub fn moves_from(&self, coord_value: &JsCoord) -> Result<JsGameMoves, JsValue> {
+        let state = self.state.borrow();
+        let coord: Coord = from_js(coord_value)?;
+        let moves: Vec<_> = state.legal_moves_iter().filter(|m| m.source() == Some(coord)).collect();
+        to_js(&moves)
+    }
+
+    /// Cards where the given die can be placed.
+    pub fn placements_for(&self, die_value: &JsDie) -> Result<JsCoords, JsValue> {
+        let state = self.state.borrow();
+        let die: Die = from_js(die_value)?;
+        let targets: Vec<_> = state
+            .legal_moves_iter()
+            .filter_map(|m| match m {
+                GameMove::Place(d, to) if d == die => Some(to),
+                _ => None,
+            })
+            .collect();
+        to_js(&targets)
+    }
+
+    /// Applies the move and returns its outcome (fight result and
+    /// whether the game has been won while the die was in flight).
+    pub fn send_move(&self, mov_value: &JsGameMove) -> Result<JsMoveOutcome, JsValue> {
+        let state = &mut *self.state.borrow_mut();
+        let mov: GameMove<Coord> = from_js(mov_value)?;
+        let outcome = state.game.apply_move(&mov).or_js_error(ErrorKind::InvalidMove)?;
+        state.search = None;
+        to_js(&outcome)
+    }
+
+    /// User coordinates (row and card, starting with 1) of a position
+    /// on the current board. They change as cards are moved by
+    /// surprise moves.
+    pub fn to_user_coord(&self, x: i8, y: i8, z: i8) -> Result<JsUserCoord, JsValue> {
+        let state = self.state.borrow();
+        let coord = state
+            .game
+            .board
+            .convert_coordinates_to_user(&Coord { x, y, z })
+            .or_js_error(ErrorKind::InvalidCoord)?;
+        to_js(&coord)
+    }
+
+    /// Cube coordinates of a position on the current board given in
+    /// user coordinates (whether there is a card or not).
+    pub fn to_cube_coord(&self, row: u8, card: u8) -> Result<JsCoord, JsValue> {
+        let state = self.state.borrow();
+        let coord = state
+            .game
+            .board
+            .convert_user_position(&UserCoord::new(row, card))
+            .or_js_error(ErrorKind::InvalidCoord)?;
+        to_js(&coord)
+    }
+
+    /// The move with all the positions given in user coordinates.
+    pub fn userify_move(&self, mov_value: &JsGameMove) -> Result<JsUserMove, JsValue> {
+        let state = self.state.borrow();
+        let mov: GameMove<Coord> = from_js(mov_value)?;
+        let user_move = state.game.board.user_move(&mov).or_js_error(ErrorKind::InvalidCoord)?;
+        to_js(&user_move)
+    }
+
+    /// The move given in user coordinates with cube coordinates.
+    pub fn deuserify_move(&self, user_move_value: &JsUserMove) -> Result<JsGameMove, JsValue> {
+        let state = self.state.borrow();
+        let user_move: GameMove<UserCoord, UserCoord> = from_js(user_move_value)?;
+        let mov = state
+            .game
+            .board
+            .convert_move_coords(&user_move)
+            .or_js_error(ErrorKind::InvalidCoord)?;
+        to_js(&mov)
+    }
+
+    /// Tries the move in a puzzle: it's made only if it's correct, in
+    /// which case the opponent replies right away. Returns `{ correct,
+    /// reply, solved }`.
+    pub fn try_move(&self, mov_value: &JsGameMove) -> Result<JsAttempt, JsValue> {
+        let state = &mut *self.state.borrow_mut();
+        let mov: GameMove<Coord> = from_js(mov_value)?;
+        let puzzle = state
+            .puzzle
+            .as_mut()
+            .ok_or_else(|| js_error(ErrorKind::NotAPuzzle, "The game is not a puzzle"))?;
+        let attempt = puzzle
+            .try_move(&mut state.game, &mov)
+            .or_js_error(ErrorKind::InvalidMove)?;
+        state.search = None;
+        to_js(&attempt)
+    }
+
+    /// The rest of the puzzle's solution from the current position.
+    pub fn reveal_solution(&self) -> Result<JsGameMoves, JsValue> {
+        let state = &mut *self.state.borrow_mut();
+        let puzzle = state
+            .puzzle
+            .as_mut()
+            .ok_or_else(|| js_error(ErrorKind::NotAPuzzle, "The game is not a puzzle"))?;
+        let solution = puzzle
+            .reveal_solution(&state.game)
+            .or_js_error(ErrorKind::InvalidPuzzle)?;
+        to_js(&solution)
+    }
+}
+
+impl Playground {
+    fn with_game(opts: Opts, game: Game) -> Result<Playground, JsValue> {
+        let bots = opts.bots().or_js_error(ErrorKind::InvalidOptions)?;
+        let state = PlaygroundState {
+            bots,
+            opts,
+            game,
+            search: None,
+            puzzle: None,
         };
+        Ok(Playground {
+            state: Rc::new(RefCell::new(state)),
+        })
+    }
+}
 
-        let ai = AlphaBetaAI::with_duration(opts.bot_goes_first, opts.duration as u64);
-        Self { ai, game }
+impl PlaygroundState {
+    fn take_back(&mut self) -> Option<GameMove<Coord>> {
+        let entry = self.game.history.last().cloned()?;
+        self.game.undo_move(&entry.game_move, entry.fight_result);
+        self.search = None;
+        if let Some(puzzle) = &mut self.puzzle {
+            puzzle.forget_solution();
+        }
+        Some(entry.game_move)
+    }
+
+    fn legal_moves_iter(&self) -> impl Iterator<Item = GameMove<Coord>> {
+        let moves = if self.game.is_game_over() {
+            vec![]
+        } else {
+            self.game.generate_moves()
+        };
+        moves.into_iter()
+    }
+}
+
+/// Several playgrounds in a single module (for tabs or lists of
+/// puzzles), keyed by the ids they are created with.
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct Playgrounds {
+    playgrounds: HashMap<u32, Playground>,
+    next_id: u32,
+}
+
+#[wasm_bindgen]
+impl Playgrounds {
+    pub fn new() -> Playgrounds {
+        utils::set_panic_hook();
+        Playgrounds::default()
     }
 
-    pub fn get_game(&self) -> JsValue {
-        JsValue::from_serde(&self.game).unwrap()
+    /// Creates a playground and returns its id.
+    pub fn create(&mut self, opts: Opts) -> Result<u32, JsValue> {
+        let playground = Playground::new(opts)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.playgrounds.insert(id, playground);
+        Ok(id)
     }
 
-    pub fn get_move(&mut self) -> JsValue {
-        let mov = self.ai.get_move(&self.game);
-        self.game.apply_move(&mov).expect("get_move: Can't apply AI's move");
-        JsValue::from_serde(&mov).expect("get_move: Serde serialization failed")
+    /// Playground with the given id, which shares the game with the
+    /// one kept here.
+    pub fn get(&self, id: u32) -> Result<Playground, JsValue> {
+        self.playgrounds
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| js_error(ErrorKind::UnknownPlayground, format!("No playground with id {}", id)))
     }
 
-    pub fn validate_move(&self, mov_value: &JsValue) -> Result<(), JsValue> {
-        let mov: GameMove<Coord> = mov_value.into_serde().map_err(|e| JsValue::from_str(&e.to_string()))?;
-        self.game
-            .validate_move(&mov)
-            .map_err(|e| JsValue::from_str(&e.to_string()))
+    /// Forgets the playground with the given id (its game lives on while
+    /// JS holds it) and returns whether there was one.
+    #[wasm_bindgen(js_name = drop)]
+    pub fn remove(&mut self, id: u32) -> bool {
+        self.playgrounds.remove(&id).is_some()
     }
 
-    pub fn send_move(&mut self, mov_value: &JsValue) {
-        let mov: GameMove<Coord> = mov_value.into_serde().unwrap();
-        self.game.apply_move(&mov).unwrap();
+    /// Ids of the playgrounds, in the order of creation.
+    pub fn ids(&self) -> Vec<u32> {
+        let mut ids: Vec<_> = self.playgrounds.keys().cloned().collect();
+        ids.sort();
+        ids
     }
 }
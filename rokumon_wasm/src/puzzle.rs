@@ -0,0 +1,160 @@
+//! Puzzles: positions where the player to move can force a win. The
+//! player is expected to follow the solution, but other moves which
+//! still win (as proven by the solver) are accepted too.
+
+use failure::{bail, Fallible};
+use serde::Serialize;
+
+use rokumon_core::coord::Coord;
+use rokumon_core::game::{Game, GameMove, GameResult};
+use rokumon_core::pns::{self, ProofTree, Value};
+
+/// Maximal number of nodes in each proof-number search checking the
+/// moves which deviate from the solution.
+const SOLVE_NODES: usize = 100_000;
+
+pub struct Puzzle {
+    /// Whether the player solving the puzzle is the first one.
+    solver_is_player1: bool,
+    /// The rest of the solution: moves of the solver alternating with
+    /// the replies of their opponent.
+    solution: Vec<GameMove<Coord>>,
+}
+
+/// What has happened after a move has been tried.
+#[derive(Serialize)]
+pub struct Attempt {
+    /// Whether the move still wins. Incorrect moves are not made.
+    pub correct: bool,
+    /// The reply of the opponent, made right away.
+    pub reply: Option<GameMove<Coord>>,
+    /// Whether the puzzle has been solved, i.e. the game is won.
+    pub solved: bool,
+}
+
+impl Puzzle {
+    /// Puzzle for the player to move in `game`. The solution starts
+    /// with their move and is checked to be legal (but not to win).
+    pub fn new(game: &Game, solution: Vec<GameMove<Coord>>) -> Fallible<Self> {
+        let mut replayed = game.clone();
+        for m in &solution {
+            replayed.apply_move(m)?;
+        }
+        if game.is_game_over() {
+            bail!("The puzzle has no moves to make");
+        }
+        let mut puzzle = Puzzle {
+            solver_is_player1: game.player1_moves,
+            solution,
+        };
+        if puzzle.solution.is_empty() {
+            puzzle.solution = puzzle.solve(game)?;
+        }
+        Ok(puzzle)
+    }
+
+    /// Checks the move of the solver and makes it (and the reply to it)
+    /// if it's correct.
+    pub fn try_move(&mut self, game: &mut Game, m: &GameMove<Coord>) -> Fallible<Attempt> {
+        if self.is_solved(game) || game.player1_moves != self.solver_is_player1 {
+            bail!("It's not the solver's turn");
+        }
+        let mut next = game.clone();
+        next.apply_move(m)?;
+        let correct = if self.solution.first() == Some(m) {
+            self.solution.remove(0);
+            true
+        } else if next.is_game_over() {
+            self.solution.clear();
+            self.is_solved(&next)
+        } else if let Some(proof) = self.proof(&next) {
+            // Another way to win: the rest of the solution is given
+            // by the solver.
+            self.solution = first_line(&proof);
+            true
+        } else {
+            false
+        };
+        if !correct {
+            return Ok(Attempt {
+                correct,
+                reply: None,
+                solved: false,
+            });
+        }
+
+        *game = next;
+        let mut reply = None;
+        if !game.is_game_over() {
+            if self.solution.is_empty() {
+                self.solution = self.solve(game)?;
+            }
+            let m = self.solution.remove(0);
+            game.apply_move(&m)?;
+            reply = Some(m);
+        }
+        Ok(Attempt {
+            correct,
+            reply,
+            solved: self.is_solved(game),
+        })
+    }
+
+    /// The rest of the solution from the current position.
+    pub fn reveal_solution(&mut self, game: &Game) -> Fallible<Vec<GameMove<Coord>>> {
+        if self.solution.is_empty() && !game.is_game_over() {
+            self.solution = self.solve(game)?;
+        }
+        Ok(self.solution.clone())
+    }
+
+    /// Drops the rest of the solution (when moves are taken back), so
+    /// that it's found by the solver again.
+    pub fn forget_solution(&mut self) {
+        self.solution.clear();
+    }
+
+    fn is_solved(&self, game: &Game) -> bool {
+        game.result == won_by(self.solver_is_player1)
+    }
+
+    /// A winning line for the solver from the position (the first
+    /// reply at every move of the opponent). It ends early at the
+    /// positions which have been proven elsewhere in the proof tree.
+    fn solve(&self, game: &Game) -> Fallible<Vec<GameMove<Coord>>> {
+        match self.proof(game) {
+            Some(proof) => Ok(first_line(&proof)),
+            None => bail!("The solver can't find a win in the puzzle"),
+        }
+    }
+
+    /// Proof of the solver's win, if it's found.
+    fn proof(&self, game: &Game) -> Option<ProofTree> {
+        let solution = pns::solve(game, SOLVE_NODES);
+        let solver_to_move = game.player1_moves == self.solver_is_player1;
+        let win = if solver_to_move { Value::Win } else { Value::Loss };
+        if solution.value == Some(win) {
+            solution.proof
+        } else {
+            None
+        }
+    }
+}
+
+fn first_line(tree: &ProofTree) -> Vec<GameMove<Coord>> {
+    let mut line = vec![];
+    let mut tree = tree;
+    while let Some((m, subtree)) = tree.moves.first() {
+        line.push(m.clone());
+        tree = subtree;
+    }
+    line
+}
+
+fn won_by(player1: bool) -> GameResult {
+    if player1 {
+        GameResult::FirstPlayerWon
+    } else {
+        GameResult::SecondPlayerWon
+    }
+}
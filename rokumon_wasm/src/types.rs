@@ -0,0 +1,198 @@
+//! TypeScript types of the values exchanged as JSON, so that they are
+//! part of the generated package. They follow the serde representation
+//! of the `rokumon_core` types and have to be kept in sync with it.
+
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(typescript_custom_section)]
+const TYPES: &'static str = r#"
+export type DiceColor = "Red" | "Black" | "White";
+export interface Die { color: DiceColor; value: number; }
+
+export type CardKind = "Jade" | "Gold" | "Fort";
+export type Ability = "Peaceful";
+export interface Card { kind: CardKind; dice: Die[]; ability: Ability | null; }
+
+/** Cube coordinates: `z` is `-x - y` on hex grids and 0 on square ones. */
+export interface Coord { x: number; y: number; z: number; }
+
+export type Grid = "Hex" | "Square";
+export type Layout = "Rectangle6" | "Bricks7" | "Hex7" | { Custom: [Grid, Coord[]] };
+
+export interface Board {
+    grid: Grid;
+    cards: [Coord, Card][];
+    layout: Layout;
+    adj_triples: [Coord, Coord, Coord][];
+    neighbours: [Coord, Coord[]][];
+}
+
+/** Row and card, starting with 1 (0 for positions above or left of the cards). */
+export interface UserCoord { row: number; card: number; }
+
+/** Surprise moves flip the card at the first coordinate to the second one. */
+export type GameMoveIn<C> =
+    | { Place: [Die, C] }
+    | { Move: [Die, C, C] }
+    | { Fight: C }
+    | { Surprise: [C, C] }
+    | "Submit";
+export type GameMove = GameMoveIn<Coord>;
+export type UserMove = GameMoveIn<UserCoord>;
+
+export type ZIndex = "Top" | "Bottom";
+export interface FightResult { losing_die: Die; losing_position: ZIndex; }
+
+export interface Duration { secs: number; nanos: number; }
+export interface HistoryEntry {
+    game_move: GameMove;
+    fight_result: FightResult | null;
+    elapsed: Duration | null;
+}
+
+export type GameResult = "InProgress" | "FirstPlayerWon" | "SecondPlayerWon";
+export type WinReason =
+    | { ThreeInARow: { coords: [Coord, Coord, Coord] } }
+    | { ThreeInStack: { coord: Coord } }
+    | "NoMoves"
+    | "Submitted";
+
+export interface MoveOutcome {
+    fight_result: FightResult | null;
+    won_in_flight: boolean;
+    result: GameResult;
+}
+
+export interface Player { name: string; dice: Die[]; }
+export interface Rules { enable_fight_move: boolean; enable_surprise_move: boolean; }
+
+export interface Game {
+    board: Board;
+    rules: Rules;
+    player1: Player;
+    player2: Player;
+    player1_moves: boolean;
+    player1_surprises: number;
+    player2_surprises: number;
+    result: GameResult;
+    history: HistoryEntry[];
+}
+
+export interface CardView {
+    coord: Coord;
+    user_coord: UserCoord | null;
+    row: number;
+    column: number;
+    kind: CardKind;
+    dice: Die[];
+}
+
+/** Returned by `Playground.view`. */
+export interface GameView {
+    cards: CardView[];
+    stock: [Die[], Die[]];
+    surprises_used: [number, number];
+    to_move: 1 | 2;
+    result: GameResult;
+}
+
+export interface Outcome {
+    result: GameResult;
+    reason: WinReason | null;
+    winning_coords: Coord[];
+}
+
+export interface PlayedMove { move: GameMove; text: string; }
+
+export interface Hint {
+    move: GameMove;
+    score: number;
+    evaluation: string;
+    pv: GameMove[];
+}
+
+export interface Evaluation { score: number; advantage: number; evaluation: string; }
+
+export interface SearchProgress {
+    depth: number;
+    best_move: GameMove | null;
+    score: number | null;
+    done: boolean;
+}
+
+/** Returned by `Playground.try_move` (incorrect moves are not made). */
+export interface Attempt { correct: boolean; reply: GameMove | null; solved: boolean; }
+
+export type ErrorKind =
+    | "InvalidOptions"
+    | "Serialization"
+    | "InvalidCoord"
+    | "InvalidMove"
+    | "GameOver"
+    | "NotBotsTurn"
+    | "NoSearch"
+    | "InvalidDepth"
+    | "InvalidPuzzle"
+    | "NotAPuzzle"
+    | "UnknownPlayground";
+
+/** Thrown by the methods of `Playground` and `Playgrounds`. */
+export interface RokumonError { kind: ErrorKind; message: string; }
+"#;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(typescript_type = "Game")]
+    pub type JsGame;
+
+    #[wasm_bindgen(typescript_type = "GameView")]
+    pub type JsGameView;
+
+    #[wasm_bindgen(typescript_type = "GameMove")]
+    pub type JsGameMove;
+
+    #[wasm_bindgen(typescript_type = "GameMove | null")]
+    pub type JsOptionalGameMove;
+
+    #[wasm_bindgen(typescript_type = "GameMove[]")]
+    pub type JsGameMoves;
+
+    #[wasm_bindgen(typescript_type = "Coord")]
+    pub type JsCoord;
+
+    #[wasm_bindgen(typescript_type = "Coord[]")]
+    pub type JsCoords;
+
+    #[wasm_bindgen(typescript_type = "UserCoord")]
+    pub type JsUserCoord;
+
+    #[wasm_bindgen(typescript_type = "UserMove")]
+    pub type JsUserMove;
+
+    #[wasm_bindgen(typescript_type = "Die")]
+    pub type JsDie;
+
+    #[wasm_bindgen(typescript_type = "WinReason | null")]
+    pub type JsOptionalWinReason;
+
+    #[wasm_bindgen(typescript_type = "MoveOutcome")]
+    pub type JsMoveOutcome;
+
+    #[wasm_bindgen(typescript_type = "Outcome")]
+    pub type JsOutcome;
+
+    #[wasm_bindgen(typescript_type = "PlayedMove[]")]
+    pub type JsPlayedMoves;
+
+    #[wasm_bindgen(typescript_type = "Attempt")]
+    pub type JsAttempt;
+
+    #[wasm_bindgen(typescript_type = "Hint")]
+    pub type JsHint;
+
+    #[wasm_bindgen(typescript_type = "Evaluation")]
+    pub type JsEvaluation;
+
+    #[wasm_bindgen(typescript_type = "SearchProgress")]
+    pub type JsSearchProgress;
+}
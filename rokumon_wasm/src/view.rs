@@ -0,0 +1,63 @@
+//! View of the game made for drawing it, unlike the serialized `Game`,
+//! which is made for restoring it.
+
+use serde::Serialize;
+
+use rokumon_core::card::{CardKind, Die};
+use rokumon_core::coord::{Coord, UserCoord};
+use rokumon_core::game::{Game, GameResult};
+
+#[derive(Serialize)]
+pub struct CardView {
+    coord: Coord,
+    user_coord: Option<UserCoord>,
+    /// Row from the top, starting with 0.
+    row: i16,
+    /// Horizontal position in halves of a card from the leftmost one,
+    /// starting with 0 (see `Board::drawing_column`).
+    column: i16,
+    kind: CardKind,
+    /// Stack of dice from the bottom one to the top one.
+    dice: Vec<Die>,
+}
+
+#[derive(Serialize)]
+pub struct GameView {
+    /// Cards row by row, from left to right.
+    cards: Vec<CardView>,
+    /// Dice in the stock of the first and the second player.
+    stock: [Vec<Die>; 2],
+    /// Surprise moves made by the first and the second player.
+    surprises_used: [u8; 2],
+    /// Player to move: 1 for the first player, 2 for the second one.
+    to_move: u8,
+    result: GameResult,
+}
+
+impl GameView {
+    pub fn new(game: &Game) -> Self {
+        let board = &game.board;
+        let (_, _, top, _) = board.bounding_box();
+        let left = board.coords_iter().map(|c| board.drawing_column(c)).min().unwrap_or(0);
+        let mut cards: Vec<_> = board
+            .coord_cards_iter()
+            .map(|(coord, card)| CardView {
+                coord: *coord,
+                user_coord: board.convert_coordinates_to_user(coord).ok(),
+                row: i16::from(coord.y) - i16::from(top),
+                column: board.drawing_column(coord) - left,
+                kind: card.kind,
+                dice: card.dice.to_vec(),
+            })
+            .collect();
+        cards.sort_by_key(|card| (card.row, card.column));
+
+        GameView {
+            cards,
+            stock: [game.stock(true).to_vec(), game.stock(false).to_vec()],
+            surprises_used: [game.surprises_used(true), game.surprises_used(false)],
+            to_move: if game.player1_moves { 1 } else { 2 },
+            result: game.result,
+        }
+    }
+}
@@ -177,7 +177,7 @@ export class Game extends React.Component {
       player1_moves: game.player1_moves,
       player1: game.player1,
       player2: game.player2,
-      history: game.history,
+      history: game.history.map((entry) => entry.game_move),
       rules: game.rules,
 
       selected_card: null,
@@ -284,7 +284,7 @@ export class Game extends React.Component {
       this.checkGameResult();
       this.setState({ selected_die: null, selected_card: null });
     } catch (e) {
-      alert(e);
+      alert(e.message || e);
       this.setState({ selected_card: null, selected_die: null });
     }
   }